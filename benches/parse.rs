@@ -0,0 +1,51 @@
+#[macro_use]
+extern crate criterion;
+extern crate rustatsd;
+
+use criterion::Criterion;
+use rustatsd::metrics::{parse_lines, StatMsg};
+
+const SINGLE: &str = "api.requests.count:1|c";
+
+// Roughly what a busy client packs into a single 1432 byte datagram.
+fn realistic_packet() -> String {
+    let templates = ["web{}.api.requests.count:1|c|@0.1",
+                     "web{}.api.response_time:238|ms|@0.5",
+                     "web{}.db.pool.active:+2|g",
+                     "web{}.db.pool.idle:14|g",
+                     "web{}.users.unique:48213|s",
+                     "web{}.cache.hit:27|c"];
+    let mut packet = String::new();
+    let mut i = 0;
+    loop {
+        let line = templates[i % templates.len()].replace("{}", &(i % 16).to_string());
+        if packet.len() + line.len() + 1 > 1432 {
+            break;
+        }
+        packet.push_str(&line);
+        packet.push('\n');
+        i += 1;
+    }
+    packet
+}
+
+fn bench_single(c: &mut Criterion) {
+    c.bench_function("single line: parse_lines", |b| {
+        b.iter(|| parse_lines(SINGLE.as_bytes()).filter(|line| line.is_ok()).count())
+    });
+    c.bench_function("single line: StatMsg::from_str",
+                     |b| b.iter(|| SINGLE.parse::<StatMsg>().unwrap()));
+}
+
+fn bench_packet(c: &mut Criterion) {
+    let packet = realistic_packet();
+    c.bench_function("1432 byte packet: parse_lines", move |b| {
+        b.iter(|| parse_lines(packet.as_bytes()).filter(|line| line.is_ok()).count())
+    });
+    let packet = realistic_packet();
+    c.bench_function("1432 byte packet: StatMsg::from_str",
+                     move |b| b.iter(|| packet.parse::<StatMsg>().unwrap()));
+}
+
+criterion_group!(benches, bench_single, bench_packet);
+criterion_main!(benches);
@@ -0,0 +1,1349 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+use metrics::{Aggregate, StatKind, StatMsg};
+use pattern::Glob;
+use persist::State;
+use sketch::{Digest, HyperLogLog};
+use tags;
+
+/// What happens to a bucket that received nothing since the last flush, per kind. Deleted
+/// buckets disappear from the next flush, kept ones are sent again as zeros (counters, timers,
+/// sets) or as their last value (gauges). Mirrors etsy's `deleteCounters` and friends.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DeleteIdle {
+    pub counters: bool,
+    pub timers: bool,
+    pub gauges: bool,
+    pub sets: bool,
+}
+
+impl DeleteIdle {
+    /// The same setting for every kind, like etsy's `deleteIdleStats`.
+    pub fn all(delete: bool) -> DeleteIdle {
+        DeleteIdle {
+            counters: delete,
+            timers: delete,
+            gauges: delete,
+            sets: delete,
+        }
+    }
+}
+
+/// A cap on the number of buckets whose names start with `prefix`, across all kinds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixLimit {
+    pub prefix: String,
+    pub max_buckets: usize,
+}
+
+/// Caps on the number of distinct buckets, so a deploy that puts request ids into metric names
+/// can't grow the aggregator without bound. Buckets that already exist always accept values,
+/// only new ones are refused once a limit is hit.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Limits {
+    /// Across all kinds.
+    pub max_buckets: Option<usize>,
+    pub max_counters: Option<usize>,
+    pub max_timers: Option<usize>,
+    pub max_gauges: Option<usize>,
+    pub max_sets: Option<usize>,
+    pub prefixes: Vec<PrefixLimit>,
+    /// Name of the bucket refused values are folded into (keeping their kind), `None` drops them.
+    pub overflow: Option<String>,
+}
+
+/// The quantile summary a `TimerSketch` keeps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SketchKind {
+    /// Quantiles within `relative_error` of their value.
+    DDSketch { relative_error: f64 },
+    /// Quantiles within about π / (2 · `compression`) in rank, closer at the tails.
+    TDigest { compression: f64 },
+}
+
+impl SketchKind {
+    pub fn digest(&self) -> Digest {
+        match *self {
+            SketchKind::DDSketch { relative_error } => Digest::ddsketch(relative_error),
+            SketchKind::TDigest { compression } => Digest::tdigest(compression),
+        }
+    }
+}
+
+/// Timers and histograms whose names match `pattern` are summarized in a sketch as samples
+/// arrive, instead of keeping every sample until the flush. Percentiles become approximate,
+/// count, sum, mean, lower and upper stay exact.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimerSketch {
+    pub pattern: Glob,
+    pub kind: SketchKind,
+}
+
+/// Buckets whose measurement (their name without tags, see `tags`) matches `pattern` are flushed
+/// every `interval`, a multiple of the flush interval, instead of with every flush.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricInterval {
+    pub pattern: Glob,
+    pub interval: Duration,
+}
+
+/// The tags a `Rollup` keeps, either the ones listed or all but those.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagSubset {
+    Keep(Vec<String>),
+    Drop(Vec<String>),
+}
+
+/// Extra series aggregated over fewer tags (see `tags`). Every tagged counter, timer and set
+/// whose measurement matches `pattern` also goes into the series named like it with only the
+/// tags `tags` keeps, e.g. `api.requests;host=a1;service=web` into `api.requests;service=web`
+/// without `host`. Counters add up, timers merge their digests and sets their members. Gauges
+/// aren't rolled up. A series that has a bucket of its own gets the rolled up data merged into
+/// it the same way, and keeps going to every backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rollup {
+    pub pattern: Glob,
+    pub tags: TagSubset,
+    /// The backends the rolled up series go to, `None` for all of them.
+    pub backends: Option<Vec<String>>,
+}
+
+impl Rollup {
+    /// The series `name` rolls up into, `None` if it doesn't match or would keep every tag.
+    pub fn name(&self, name: &str) -> Option<String> {
+        let (measurement, tags) = tags::split(name);
+        if !self.pattern.matches(measurement) {
+            return None;
+        }
+        let kept: Vec<(&str, &str)> = tags.iter()
+            .cloned()
+            .filter(|&(key, _)| {
+                match self.tags {
+                    TagSubset::Keep(ref keys) => keys.iter().any(|kept| kept == key),
+                    TagSubset::Drop(ref keys) => !keys.iter().any(|dropped| dropped == key),
+                }
+            })
+            .collect();
+        if kept.len() == tags.len() {
+            None
+        } else {
+            Some(tags::join(measurement, &kept))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregatorOptions {
+    pub flush_interval: Duration,
+    /// Percentiles reported for every timer, e.g. `mean_90` and `upper_90` for 90.
+    pub percent_threshold: Vec<f64>,
+    pub delete_idle: DeleteIdle,
+    pub limits: Limits,
+    /// The first one matching a timer applies, timers matching none keep exact samples.
+    pub timer_sketches: Vec<TimerSketch>,
+    /// Timers that aren't sketched keep at most this many samples per flush, a uniform random
+    /// sample of what arrived. Count, sum, mean, std, lower and upper stay exact, percentiles
+    /// and median come from the sample.
+    pub timer_reservoir: Option<usize>,
+    /// Every one applies, to the buckets as they are at the flush rather than to each other's
+    /// series. A bucket goes into a series once even if several rollups name it.
+    pub rollups: Vec<Rollup>,
+    /// The first one matching a bucket applies, the flusher aggregates those buckets over their
+    /// interval before handing them to the backends.
+    pub intervals: Vec<MetricInterval>,
+}
+
+impl Default for AggregatorOptions {
+    fn default() -> AggregatorOptions {
+        AggregatorOptions {
+            flush_interval: Duration::from_secs(10),
+            percent_threshold: vec![90.0],
+            delete_idle: DeleteIdle::default(),
+            limits: Limits::default(),
+            timer_sketches: Vec::new(),
+            timer_reservoir: None,
+            rollups: Vec::new(),
+            intervals: Vec::new(),
+        }
+    }
+}
+
+/// Everything one flush hands to the backends, laid out like the `metrics` object etsy passes
+/// to its backends.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Snapshot {
+    /// Unix time of the flush, in seconds.
+    pub timestamp: u64,
+    pub flush_interval: Duration,
+    /// Sample rate corrected counts since the last flush.
+    pub counters: BTreeMap<String, f64>,
+    /// Counts divided by the flush interval in seconds.
+    pub counter_rates: BTreeMap<String, f64>,
+    /// Raw timer samples received here, sorted ascending.
+    pub timers: BTreeMap<String, Vec<f64>>,
+    /// Sample rate corrected number of timer samples.
+    pub timer_counters: BTreeMap<String, f64>,
+    /// Derived timer statistics (`mean`, `upper_90`, ...) for every timer.
+    pub timer_data: BTreeMap<String, BTreeMap<String, f64>>,
+    pub gauges: BTreeMap<String, f64>,
+    /// Members received since the last flush. Sets merged from other nodes only have
+    /// `set_counts` and `set_sketches`.
+    pub sets: BTreeMap<String, HashSet<i64>>,
+    /// Number of distinct members of every set, estimated for sets merged from other nodes.
+    pub set_counts: BTreeMap<String, f64>,
+    /// Every sample of timers merged from other nodes, with the local samples added. Timers
+    /// that overflowed `timer_reservoir` have one too, built from the sample and their totals.
+    pub timer_digests: BTreeMap<String, Digest>,
+    /// Members of sets merged from other nodes, with the local members added.
+    pub set_sketches: BTreeMap<String, HyperLogLog>,
+    pub percent_threshold: Vec<f64>,
+    /// New buckets refused by `Limits` since the last flush, by the first two segments of their
+    /// names, so the offending prefixes can be reported.
+    pub rejected: BTreeMap<String, u64>,
+    /// The backends buckets from a `Rollup` with `backends` go to, others go to all of them.
+    pub routes: BTreeMap<String, Vec<String>>,
+}
+
+impl Snapshot {
+    /// Number of distinct buckets in the snapshot.
+    pub fn len(&self) -> usize {
+        self.counters.len() + self.timers.len() + self.gauges.len() + self.sets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether there is a bucket of any kind named `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.counters.contains_key(name) || self.timers.contains_key(name) || self.gauges.contains_key(name) ||
+        self.sets.contains_key(name)
+    }
+
+    /// Renames the buckets `rename` returns a new name for.
+    pub fn rename<F: FnMut(&str) -> Option<String>>(&mut self, mut rename: F) {
+        let mut names = HashMap::new();
+        let all = self.counters.keys().chain(self.timers.keys()).chain(self.gauges.keys()).chain(self.sets.keys());
+        for name in all {
+            if let Some(renamed) = rename(name) {
+                names.insert(name.clone(), renamed);
+            }
+        }
+        if names.is_empty() {
+            return;
+        }
+        rename_keys(&mut self.counters, &names);
+        rename_keys(&mut self.counter_rates, &names);
+        rename_keys(&mut self.timers, &names);
+        rename_keys(&mut self.timer_counters, &names);
+        rename_keys(&mut self.timer_data, &names);
+        rename_keys(&mut self.timer_digests, &names);
+        rename_keys(&mut self.gauges, &names);
+        rename_keys(&mut self.sets, &names);
+        rename_keys(&mut self.set_counts, &names);
+        rename_keys(&mut self.set_sketches, &names);
+        rename_keys(&mut self.routes, &names);
+    }
+
+    /// Keeps only the buckets `keep` returns true for, it is handed the kind (histograms are
+    /// timers) and name of each.
+    pub fn retain<F: FnMut(&StatKind, &str) -> bool>(&mut self, mut keep: F) {
+        self.counters.retain(|name, _| keep(&StatKind::Counter, name));
+        let counters = &self.counters;
+        self.counter_rates.retain(|name, _| counters.contains_key(name));
+        self.timers.retain(|name, _| keep(&StatKind::Timer, name));
+        let timers = &self.timers;
+        self.timer_counters.retain(|name, _| timers.contains_key(name));
+        self.timer_data.retain(|name, _| timers.contains_key(name));
+        self.timer_digests.retain(|name, _| timers.contains_key(name));
+        self.gauges.retain(|name, _| keep(&StatKind::Gauge, name));
+        self.sets.retain(|name, _| keep(&StatKind::Sets, name));
+        let sets = &self.sets;
+        self.set_counts.retain(|name, _| sets.contains_key(name));
+        self.set_sketches.retain(|name, _| sets.contains_key(name));
+        let (counters, timers) = (&self.counters, &self.timers);
+        self.routes
+            .retain(|name, _| counters.contains_key(name) || timers.contains_key(name) || sets.contains_key(name));
+    }
+}
+
+fn rename_keys<V>(map: &mut BTreeMap<String, V>, names: &HashMap<String, String>) {
+    // Everything comes out first, a new name may be the old name of another bucket.
+    let moved: Vec<(&String, V)> = names.iter()
+        .filter_map(|(name, renamed)| map.remove(name).map(|value| (renamed, value)))
+        .collect();
+    for (renamed, value) in moved {
+        map.insert(renamed.clone(), value);
+    }
+}
+
+/// Collects incoming metrics into buckets between flushes.
+#[derive(Debug, Default)]
+pub struct Aggregator {
+    options: AggregatorOptions,
+    counters: HashMap<String, f64>,
+    timers: HashMap<String, Vec<f64>>,
+    timer_counters: HashMap<String, f64>,
+    gauges: HashMap<String, f64>,
+    sets: HashMap<String, HashSet<i64>>,
+    // What other nodes forwarded since the last flush, and samples of timers matching
+    // `options.timer_sketches`, for buckets that are also in `timers` and `sets`.
+    timer_digests: HashMap<String, Digest>,
+    set_sketches: HashMap<String, HyperLogLog>,
+    // Everything timers received since the last flush, with `options.timer_reservoir`.
+    timer_totals: HashMap<String, Totals>,
+    // State of the random numbers picking which samples stay in a full reservoir.
+    reservoir_seed: u64,
+    // Buckets currently matching each of `options.limits.prefixes`.
+    prefix_counts: Vec<usize>,
+    rejected: HashMap<String, u64>,
+}
+
+// How many leading name segments identify an offender in `Snapshot::rejected`.
+const OFFENDER_SEGMENTS: usize = 2;
+
+impl Aggregator {
+    pub fn new(options: AggregatorOptions) -> Aggregator {
+        let prefixes = options.limits.prefixes.len();
+        Aggregator {
+            options: options,
+            prefix_counts: vec![0; prefixes],
+            ..Aggregator::default()
+        }
+    }
+
+    pub fn options(&self) -> &AggregatorOptions {
+        &self.options
+    }
+
+    pub fn process(&mut self, mut msg: StatMsg) {
+        if let StatMsg::Bat(msgs) = msg {
+            for msg in msgs {
+                self.process(msg);
+            }
+        } else if self.admit(&mut msg) {
+            self.process_unlimited(msg);
+        }
+    }
+
+    /// Like `process` but exempt from `Limits`, for the server's own metrics.
+    pub fn process_unlimited(&mut self, msg: StatMsg) {
+        match msg {
+            StatMsg::Bat(msgs) => {
+                for msg in msgs {
+                    self.process_unlimited(msg);
+                }
+            }
+            StatMsg::Inc(StatKind::Gauge, name, delta, _) => {
+                *self.gauges.entry(name).or_insert(0.0) += delta as f64;
+            }
+            StatMsg::Set(StatKind::Gauge, name, value, _) => {
+                self.gauges.insert(name, value as f64);
+            }
+            StatMsg::Gauge(name, value) => {
+                self.gauges.insert(name, value);
+            }
+            StatMsg::Inc(StatKind::Counter, name, value, sr) |
+            StatMsg::Set(StatKind::Counter, name, value, sr) => {
+                *self.counters.entry(name).or_insert(0.0) += value as f64 / sr;
+            }
+            StatMsg::Inc(StatKind::Timer, name, value, sr) |
+            StatMsg::Set(StatKind::Timer, name, value, sr) |
+            StatMsg::Inc(StatKind::Histogram, name, value, sr) |
+            StatMsg::Set(StatKind::Histogram, name, value, sr) => {
+                *self.timer_counters.entry(name.clone()).or_insert(0.0) += 1.0 / sr;
+                if let Some(digest) = self.timer_digests.get_mut(&name) {
+                    digest.add(value as f64);
+                } else if let Some(sketch) = self.options
+                    .timer_sketches
+                    .iter()
+                    .find(|sketch| sketch.pattern.matches(&name)) {
+                    let mut digest = sketch.kind.digest();
+                    digest.add(value as f64);
+                    self.timers.entry(name.clone()).or_default();
+                    self.timer_digests.insert(name, digest);
+                } else if let Some(size) = self.options.timer_reservoir {
+                    let totals = self.timer_totals.entry(name.clone()).or_default();
+                    totals.add(value as f64);
+                    let values = self.timers.entry(name).or_default();
+                    if values.len() < size {
+                        values.push(value as f64);
+                    } else {
+                        // Keeps every sample seen so far with the same probability.
+                        let slot = (next_random(&mut self.reservoir_seed) % totals.seen) as usize;
+                        if slot < size {
+                            values[slot] = value as f64;
+                        }
+                    }
+                } else {
+                    self.timers.entry(name).or_default().push(value as f64);
+                }
+            }
+            StatMsg::Inc(StatKind::Sets, name, value, _) |
+            StatMsg::Set(StatKind::Sets, name, value, _) => {
+                self.sets.entry(name).or_default().insert(value);
+            }
+            StatMsg::Merge(name, Aggregate::Count(count)) => {
+                *self.counters.entry(name).or_insert(0.0) += count;
+            }
+            StatMsg::Merge(name, Aggregate::Timer(count, digest)) => {
+                *self.timer_counters.entry(name.clone()).or_insert(0.0) += count;
+                self.timers.entry(name.clone()).or_default();
+                match self.timer_digests.get_mut(&name) {
+                    Some(merged) => merged.merge(&digest),
+                    None => {
+                        self.timer_digests.insert(name, digest);
+                    }
+                }
+            }
+            StatMsg::Merge(name, Aggregate::Set(hll)) => {
+                self.sets.entry(name.clone()).or_default();
+                self.set_sketches.entry(name).or_default().merge(&hll);
+            }
+            StatMsg::Del(kind, name) => {
+                self.delete(&kind, &name);
+            }
+        }
+    }
+
+    /// Forgets the bucket of `kind` called `name`, so it stops showing up in flushes until it
+    /// receives a new value. Returns whether there was such a bucket.
+    pub fn delete(&mut self, kind: &StatKind, name: &str) -> bool {
+        let deleted = match *kind {
+            StatKind::Counter => self.counters.remove(name).is_some(),
+            StatKind::Timer | StatKind::Histogram => {
+                self.timer_counters.remove(name);
+                self.timer_digests.remove(name);
+                self.timer_totals.remove(name);
+                self.timers.remove(name).is_some()
+            }
+            StatKind::Gauge => self.gauges.remove(name).is_some(),
+            StatKind::Sets => {
+                self.set_sketches.remove(name);
+                self.sets.remove(name).is_some()
+            }
+        };
+        if deleted {
+            for (limit, count) in self.options.limits.prefixes.iter().zip(self.prefix_counts.iter_mut()) {
+                if name.starts_with(&limit.prefix) {
+                    *count -= 1;
+                }
+            }
+        }
+        deleted
+    }
+
+    /// New buckets refused by `Limits` since the last flush, see `Snapshot::rejected`.
+    pub fn rejected(&self) -> &HashMap<String, u64> {
+        &self.rejected
+    }
+
+    /// Number of distinct buckets across all kinds.
+    pub fn len(&self) -> usize {
+        self.counters.len() + self.timers.len() + self.gauges.len() + self.sets.len()
+    }
+
+    /// Whether there is a bucket of any kind named `name`.
+    pub fn has_bucket(&self, name: &str) -> bool {
+        self.counters.contains_key(name) || self.timers.contains_key(name) || self.gauges.contains_key(name) ||
+        self.sets.contains_key(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains(&self, kind: &StatKind, name: &str) -> bool {
+        match *kind {
+            StatKind::Counter => self.counters.contains_key(name),
+            StatKind::Timer | StatKind::Histogram => self.timers.contains_key(name),
+            StatKind::Gauge => self.gauges.contains_key(name),
+            StatKind::Sets => self.sets.contains_key(name),
+        }
+    }
+
+    // Applies `Limits` to a message about to create a new bucket: either lets it through,
+    // renames it to the overflow bucket, or returns false to drop it.
+    fn admit(&mut self, msg: &mut StatMsg) -> bool {
+        let kind = match *msg {
+            StatMsg::Del(..) | StatMsg::Bat(_) => return true,
+            ref msg => msg.kind().unwrap(),
+        };
+        let refused = {
+            let name = msg.name().unwrap();
+            if self.contains(&kind, name) {
+                return true;
+            }
+            if self.over_limit(&kind, name) {
+                let offender = name.splitn(OFFENDER_SEGMENTS + 1, '.')
+                    .take(OFFENDER_SEGMENTS)
+                    .collect::<Vec<_>>()
+                    .join(".");
+                *self.rejected.entry(offender).or_insert(0) += 1;
+                true
+            } else {
+                for (limit, count) in self.options.limits.prefixes.iter().zip(self.prefix_counts.iter_mut()) {
+                    if name.starts_with(&limit.prefix) {
+                        *count += 1;
+                    }
+                }
+                false
+            }
+        };
+        if !refused {
+            return true;
+        }
+        match self.options.limits.overflow {
+            Some(ref overflow) => {
+                *msg.name_mut().unwrap() = overflow.clone();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn over_limit(&self, kind: &StatKind, name: &str) -> bool {
+        let limits = &self.options.limits;
+        if limits.overflow.as_deref() == Some(name) {
+            return false;
+        }
+        let (of_kind, max_of_kind) = match *kind {
+            StatKind::Counter => (self.counters.len(), limits.max_counters),
+            StatKind::Timer | StatKind::Histogram => (self.timers.len(), limits.max_timers),
+            StatKind::Gauge => (self.gauges.len(), limits.max_gauges),
+            StatKind::Sets => (self.sets.len(), limits.max_sets),
+        };
+        let full = |count: usize, max: Option<usize>| max.is_some_and(|max| count >= max);
+        if full(of_kind, max_of_kind) || full(self.len(), limits.max_buckets) {
+            return true;
+        }
+        limits.prefixes
+            .iter()
+            .zip(self.prefix_counts.iter())
+            .any(|(limit, &count)| name.starts_with(&limit.prefix) && count >= limit.max_buckets)
+    }
+
+    // Recounts the buckets under every limited prefix after buckets were dropped in bulk.
+    fn count_prefixes(&mut self) {
+        for (limit, count) in self.options.limits.prefixes.iter().zip(self.prefix_counts.iter_mut()) {
+            *count = self.counters
+                .keys()
+                .chain(self.timers.keys())
+                .chain(self.gauges.keys())
+                .chain(self.sets.keys())
+                .filter(|name| name.starts_with(&limit.prefix))
+                .count();
+        }
+    }
+
+    /// The buckets worth keeping across a restart, see `persist::StateFile`.
+    pub fn state(&self, sets: bool, counters: bool) -> State {
+        let mut state = State::default();
+        state.gauges.extend(self.gauges.iter().map(|(name, &value)| (name.clone(), value)));
+        if counters {
+            state.counters.extend(self.counters.iter().map(|(name, &value)| (name.clone(), value)));
+        }
+        if sets {
+            for (name, set) in self.sets.iter() {
+                let mut members: Vec<i64> = set.iter().cloned().collect();
+                members.sort();
+                state.sets.insert(name.clone(), members);
+            }
+        }
+        state
+    }
+
+    /// Merges a saved state into the live buckets.
+    pub fn restore(&mut self, state: State) {
+        self.gauges.extend(state.gauges);
+        for (name, count) in state.counters {
+            *self.counters.entry(name).or_insert(0.0) += count;
+        }
+        for (name, members) in state.sets {
+            self.sets.entry(name).or_default().extend(members);
+        }
+        self.count_prefixes();
+    }
+
+    /// Hands out everything collected since the last flush, then deletes or resets the buckets
+    /// according to `delete_idle`.
+    pub fn flush(&mut self, timestamp: u64) -> Snapshot {
+        let snapshot = self.snapshot(timestamp);
+        self.rejected.clear();
+        self.clear_idle();
+        self.count_prefixes();
+        snapshot
+    }
+
+    /// What a flush at `timestamp` would hand out, without resetting anything.
+    pub fn snapshot(&self, timestamp: u64) -> Snapshot {
+        let interval = self.options.flush_interval;
+        let seconds = interval.as_secs() as f64 + interval.subsec_nanos() as f64 / 1e9;
+
+        let mut snapshot = Snapshot {
+            timestamp: timestamp,
+            flush_interval: interval,
+            percent_threshold: self.options.percent_threshold.clone(),
+            ..Snapshot::default()
+        };
+        for (name, &count) in self.counters.iter() {
+            snapshot.counters.insert(name.clone(), count);
+            snapshot.counter_rates.insert(name.clone(), count / seconds);
+        }
+        for (name, values) in self.timers.iter() {
+            let mut values = values.clone();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let count = self.timer_counters.get(name).cloned().unwrap_or(0.0);
+            let thresholds = &self.options.percent_threshold;
+            let data = match self.timer_digests.get(name) {
+                Some(digest) => {
+                    let mut digest = digest.clone();
+                    // Samples taken before a merge arrived may be a reservoir standing in for more.
+                    match self.timer_totals.get(name) {
+                        Some(totals) => {
+                            digest.add_sample(&values,
+                                              totals.seen,
+                                              totals.sum,
+                                              totals.sum_squares,
+                                              totals.min,
+                                              totals.max)
+                        }
+                        None => {
+                            for &value in values.iter() {
+                                digest.add(value);
+                            }
+                        }
+                    }
+                    digest.compress();
+                    let data = timer_data(&digest, count, seconds, thresholds);
+                    snapshot.timer_digests.insert(name.clone(), digest);
+                    data
+                }
+                None => {
+                    match self.timer_totals.get(name) {
+                        Some(totals) if totals.seen as usize > values.len() => {
+                            let reservoir = Reservoir {
+                                sample: Sorted::new(&values),
+                                totals: totals,
+                            };
+                            let data = timer_data(&reservoir, count, seconds, thresholds);
+                            // For forwarding and rollups, which merge digests.
+                            let mut digest = Digest::default();
+                            digest.add_sample(&values,
+                                              totals.seen,
+                                              totals.sum,
+                                              totals.sum_squares,
+                                              totals.min,
+                                              totals.max);
+                            snapshot.timer_digests.insert(name.clone(), digest);
+                            data
+                        }
+                        _ => timer_data(&Sorted::new(&values), count, seconds, thresholds),
+                    }
+                }
+            };
+            snapshot.timer_data.insert(name.clone(), data);
+            snapshot.timer_counters.insert(name.clone(), count);
+            snapshot.timers.insert(name.clone(), values);
+        }
+        for (name, &value) in self.gauges.iter() {
+            snapshot.gauges.insert(name.clone(), value);
+        }
+        for (name, set) in self.sets.iter() {
+            let count = match self.set_sketches.get(name) {
+                Some(hll) => {
+                    let mut hll = hll.clone();
+                    for &member in set.iter() {
+                        hll.insert(member);
+                    }
+                    let count = hll.estimate().round();
+                    snapshot.set_sketches.insert(name.clone(), hll);
+                    count
+                }
+                None => set.len() as f64,
+            };
+            snapshot.set_counts.insert(name.clone(), count);
+            snapshot.sets.insert(name.clone(), set.clone());
+        }
+        if !self.options.rollups.is_empty() {
+            self.roll_up(&mut snapshot, seconds);
+        }
+
+        snapshot.rejected.extend(self.rejected.iter().map(|(prefix, &count)| (prefix.clone(), count)));
+        snapshot
+    }
+
+    // Adds the series of `options.rollups` to a snapshot of the buckets.
+    fn roll_up(&self, snapshot: &mut Snapshot, seconds: f64) {
+        let mut counters: BTreeMap<String, f64> = BTreeMap::new();
+        let mut timers: BTreeMap<String, (f64, Digest)> = BTreeMap::new();
+        let mut sets: BTreeMap<String, (HashSet<i64>, Option<HyperLogLog>)> = BTreeMap::new();
+        let mut routes = BTreeMap::new();
+        // A series goes into every distinct series the rollups name once, with the backends of the
+        // first rollup naming it. Series that have a bucket of its own aren't routed, the bucket
+        // would go missing from the other backends.
+        let mut names = |name: &str| {
+            let mut rolled: Vec<String> = Vec::new();
+            for rollup in self.options.rollups.iter() {
+                match rollup.name(name) {
+                    Some(name) if !rolled.contains(&name) => {
+                        if let Some(ref backends) = rollup.backends {
+                            if !snapshot.contains(&name) {
+                                routes.entry(name.clone()).or_insert_with(|| backends.clone());
+                            }
+                        }
+                        rolled.push(name);
+                    }
+                    _ => {}
+                }
+            }
+            rolled
+        };
+        // Rolled up series start out with what their own bucket holds, if they have one.
+        for (source, &count) in snapshot.counters.iter() {
+            for rolled in names(source) {
+                let total = counters.entry(rolled)
+                    .or_insert_with_key(|rolled| snapshot.counters.get(rolled).cloned().unwrap_or(0.0));
+                *total += count;
+            }
+        }
+        for (source, values) in snapshot.timers.iter() {
+            for rolled in names(source) {
+                let (count, digest) = timers.entry(rolled).or_insert_with_key(|rolled| {
+                    let count = snapshot.timer_counters.get(rolled).cloned().unwrap_or(0.0);
+                    if let Some(digest) = snapshot.timer_digests.get(rolled) {
+                        return (count, digest.clone());
+                    }
+                    let sketch = self.options.timer_sketches.iter().find(|sketch| sketch.pattern.matches(rolled));
+                    let mut digest = sketch.map(|sketch| sketch.kind.digest()).unwrap_or_default();
+                    for &value in snapshot.timers.get(rolled).into_iter().flatten() {
+                        digest.add(value);
+                    }
+                    (count, digest)
+                });
+                *count += snapshot.timer_counters.get(source).cloned().unwrap_or(0.0);
+                match snapshot.timer_digests.get(source) {
+                    Some(source) => digest.merge(source),
+                    None => {
+                        for &value in values.iter() {
+                            digest.add(value);
+                        }
+                    }
+                }
+            }
+        }
+        for (source, members) in snapshot.sets.iter() {
+            for rolled in names(source) {
+                let (union, hll) = sets.entry(rolled).or_insert_with_key(|rolled| {
+                    let members = snapshot.sets.get(rolled).cloned().unwrap_or_default();
+                    (members, snapshot.set_sketches.get(rolled).cloned())
+                });
+                union.extend(members.iter().cloned());
+                if let Some(source) = snapshot.set_sketches.get(source) {
+                    hll.get_or_insert_with(HyperLogLog::new).merge(source);
+                }
+            }
+        }
+
+        for (name, count) in counters {
+            snapshot.counter_rates.insert(name.clone(), count / seconds);
+            snapshot.counters.insert(name, count);
+        }
+        for (name, (count, mut digest)) in timers {
+            digest.compress();
+            let data = timer_data(&digest, count, seconds, &self.options.percent_threshold);
+            snapshot.timer_data.insert(name.clone(), data);
+            snapshot.timer_counters.insert(name.clone(), count);
+            snapshot.timer_digests.insert(name.clone(), digest);
+            snapshot.timers.insert(name, Vec::new());
+        }
+        for (name, (members, hll)) in sets {
+            let count = match hll {
+                Some(mut hll) => {
+                    for &member in members.iter() {
+                        hll.insert(member);
+                    }
+                    let count = hll.estimate().round();
+                    snapshot.set_sketches.insert(name.clone(), hll);
+                    count
+                }
+                None => members.len() as f64,
+            };
+            snapshot.set_counts.insert(name.clone(), count);
+            snapshot.sets.insert(name, members);
+        }
+        snapshot.routes.extend(routes);
+    }
+
+    fn clear_idle(&mut self) {
+        self.timer_digests.clear();
+        self.timer_totals.clear();
+        self.set_sketches.clear();
+        let delete = &self.options.delete_idle;
+        if delete.counters {
+            self.counters.clear();
+        } else {
+            for count in self.counters.values_mut() {
+                *count = 0.0;
+            }
+        }
+        if delete.timers {
+            self.timers.clear();
+            self.timer_counters.clear();
+        } else {
+            for values in self.timers.values_mut() {
+                values.clear();
+            }
+            for count in self.timer_counters.values_mut() {
+                *count = 0.0;
+            }
+        }
+        if delete.gauges {
+            self.gauges.clear();
+        }
+        if delete.sets {
+            self.sets.clear();
+        } else {
+            for set in self.sets.values_mut() {
+                set.clear();
+            }
+        }
+    }
+}
+
+// What `timer_data` needs to know about a timer's samples, which are either all there or
+// summarized in a `Digest`.
+trait Samples {
+    fn len(&self) -> usize;
+
+    // The value at `rank` of the samples in ascending order.
+    fn nth(&self, rank: usize) -> f64;
+
+    // Sum and sum of squares of the samples ranked `start..end`.
+    fn range_sums(&self, start: usize, end: usize) -> (f64, f64);
+
+    fn variance(&self) -> f64;
+}
+
+// Samples sorted ascending, with running sums.
+struct Sorted<'a> {
+    values: &'a [f64],
+    cumulative: Vec<f64>,
+    cumulative_squares: Vec<f64>,
+}
+
+impl<'a> Sorted<'a> {
+    fn new(values: &'a [f64]) -> Sorted<'a> {
+        let mut cumulative = Vec::with_capacity(values.len());
+        let mut cumulative_squares = Vec::with_capacity(values.len());
+        let (mut sum, mut sum_squares) = (0.0, 0.0);
+        for &value in values {
+            sum += value;
+            sum_squares += value * value;
+            cumulative.push(sum);
+            cumulative_squares.push(sum_squares);
+        }
+        Sorted {
+            values: values,
+            cumulative: cumulative,
+            cumulative_squares: cumulative_squares,
+        }
+    }
+}
+
+impl<'a> Samples for Sorted<'a> {
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn nth(&self, rank: usize) -> f64 {
+        self.values[rank]
+    }
+
+    fn range_sums(&self, start: usize, end: usize) -> (f64, f64) {
+        let before = if start == 0 { 0.0 } else { self.cumulative[start - 1] };
+        let before_squares = if start == 0 { 0.0 } else { self.cumulative_squares[start - 1] };
+        (self.cumulative[end - 1] - before, self.cumulative_squares[end - 1] - before_squares)
+    }
+
+    fn variance(&self) -> f64 {
+        let mean = self.cumulative[self.len() - 1] / self.len() as f64;
+        self.values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / self.len() as f64
+    }
+}
+
+// Exact totals of a timer's samples, for when not all of them are kept.
+#[derive(Debug, Clone, PartialEq)]
+struct Totals {
+    seen: u64,
+    sum: f64,
+    sum_squares: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for Totals {
+    fn default() -> Totals {
+        Totals {
+            seen: 0,
+            sum: 0.0,
+            sum_squares: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+}
+
+impl Totals {
+    fn add(&mut self, value: f64) {
+        self.seen += 1;
+        self.sum += value;
+        self.sum_squares += value * value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+// splitmix64, good enough to pick reservoir slots.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut x = *state;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// A uniform sample of a timer's samples, standing in for all `totals.seen` of them.
+struct Reservoir<'a> {
+    sample: Sorted<'a>,
+    totals: &'a Totals,
+}
+
+impl<'a> Reservoir<'a> {
+    // The sample rank standing for `rank` of all samples.
+    fn sample_rank(&self, rank: usize) -> usize {
+        let scaled = rank as f64 * self.sample.len() as f64 / self.totals.seen as f64;
+        cmp::min(scaled.round() as usize, self.sample.len())
+    }
+}
+
+impl<'a> Samples for Reservoir<'a> {
+    fn len(&self) -> usize {
+        self.totals.seen as usize
+    }
+
+    fn nth(&self, rank: usize) -> f64 {
+        if rank == 0 {
+            return self.totals.min;
+        }
+        if rank + 1 == self.len() {
+            return self.totals.max;
+        }
+        let scaled = (rank as f64 + 0.5) * self.sample.len() as f64 / self.totals.seen as f64;
+        self.sample.nth(cmp::min(scaled as usize, self.sample.len() - 1))
+    }
+
+    fn range_sums(&self, start: usize, end: usize) -> (f64, f64) {
+        if start == 0 && end == self.len() {
+            return (self.totals.sum, self.totals.sum_squares);
+        }
+        let (sample_start, sample_end) = (self.sample_rank(start), self.sample_rank(end));
+        if sample_end <= sample_start {
+            let value = self.nth(start);
+            return (value * (end - start) as f64, value * value * (end - start) as f64);
+        }
+        // Scaled up from the sample to the number of samples in the range.
+        let (sum, sum_squares) = self.sample.range_sums(sample_start, sample_end);
+        let scale = (end - start) as f64 / (sample_end - sample_start) as f64;
+        (sum * scale, sum_squares * scale)
+    }
+
+    fn variance(&self) -> f64 {
+        let mean = self.totals.sum / self.totals.seen as f64;
+        (self.totals.sum_squares / self.totals.seen as f64 - mean * mean).max(0.0)
+    }
+}
+
+impl Samples for Digest {
+    fn len(&self) -> usize {
+        self.count() as usize
+    }
+
+    fn nth(&self, rank: usize) -> f64 {
+        Digest::nth(self, rank as u64).unwrap_or(0.0)
+    }
+
+    fn range_sums(&self, start: usize, end: usize) -> (f64, f64) {
+        if start == 0 && end == self.len() {
+            return (self.sum(), self.sum_squares());
+        }
+        let (mut sum, mut sum_squares, mut rank) = (0.0, 0.0, 0);
+        for (value, n) in self.bins() {
+            let n = n as usize;
+            let overlap = cmp::min(rank + n, end).saturating_sub(cmp::max(rank, start));
+            sum += value * overlap as f64;
+            sum_squares += value * value * overlap as f64;
+            rank += n;
+            if rank >= end {
+                break;
+            }
+        }
+        (sum, sum_squares)
+    }
+
+    fn variance(&self) -> f64 {
+        let mean = self.sum() / self.len() as f64;
+        (self.sum_squares() / self.len() as f64 - mean * mean).max(0.0)
+    }
+}
+
+// The same statistics etsy's `process_metrics` derives for a timer.
+fn timer_data<S: Samples>(samples: &S, count: f64, seconds: f64, percent_threshold: &[f64]) -> BTreeMap<String, f64> {
+    let mut data = BTreeMap::new();
+    data.insert("count".to_string(), count);
+    data.insert("count_ps".to_string(), count / seconds);
+    let len = samples.len();
+    if len == 0 {
+        return data;
+    }
+    let (sum, sum_squares) = samples.range_sums(0, len);
+
+    // Negative thresholds look at the top of the range instead, e.g. `lower_top10` for -10.
+    for &pct in percent_threshold {
+        if len <= 1 {
+            break;
+        }
+        let suffix = pct.to_string().replace('.', "_").replace('-', "top");
+        let threshold = (pct.abs() / 100.0 * len as f64).round() as usize;
+        if threshold == 0 {
+            continue;
+        }
+        let (bound, (sum_pct, squares_pct)) = if pct > 0.0 {
+            (samples.nth(threshold - 1), samples.range_sums(0, threshold))
+        } else {
+            (samples.nth(len - threshold), samples.range_sums(len - threshold, len))
+        };
+        let name = if pct > 0.0 { "upper_" } else { "lower_" };
+        data.insert(format!("mean_{}", suffix), sum_pct / threshold as f64);
+        data.insert(format!("{}{}", name, suffix), bound);
+        data.insert(format!("sum_{}", suffix), sum_pct);
+        data.insert(format!("sum_squares_{}", suffix), squares_pct);
+    }
+
+    let mean = sum / len as f64;
+    let mid = len / 2;
+    let median = if len % 2 == 1 {
+        samples.nth(mid)
+    } else {
+        (samples.nth(mid - 1) + samples.nth(mid)) / 2.0
+    };
+    data.insert("std".to_string(), samples.variance().sqrt());
+    data.insert("upper".to_string(), samples.nth(len - 1));
+    data.insert("lower".to_string(), samples.nth(0));
+    data.insert("sum".to_string(), sum);
+    data.insert("sum_squares".to_string(), sum_squares);
+    data.insert("mean".to_string(), mean);
+    data.insert("median".to_string(), median);
+    data
+}
+
+#[cfg(test)]
+fn msgs(raw: &str) -> StatMsg {
+    raw.parse().unwrap()
+}
+
+#[test]
+fn test_counter_count_and_rate() {
+    let mut agg = Aggregator::new(AggregatorOptions::default());
+    agg.process(msgs("hits:10|c\nhits:5|c|@0.5\nmisses|c"));
+    let snapshot = agg.flush(1000);
+    assert_eq!(snapshot.counters["hits"], 20.0);
+    assert_eq!(snapshot.counter_rates["hits"], 2.0);
+    assert_eq!(snapshot.counters["misses"], 1.0);
+    assert_eq!(snapshot.timestamp, 1000);
+}
+
+#[test]
+fn test_idle_buckets_send_zeros_by_default() {
+    let mut agg = Aggregator::new(AggregatorOptions::default());
+    agg.process(msgs("hits:10|c\nlatency:20|ms\nload:3|g\nusers:7|s"));
+    agg.flush(0);
+
+    let idle = agg.flush(10);
+    assert_eq!(idle.counters["hits"], 0.0);
+    assert_eq!(idle.counter_rates["hits"], 0.0);
+    assert_eq!(idle.timers["latency"], Vec::<f64>::new());
+    assert_eq!(idle.timer_data["latency"]["count"], 0.0);
+    assert!(!idle.timer_data["latency"].contains_key("mean"));
+    assert_eq!(idle.gauges["load"], 3.0);
+    assert!(idle.sets["users"].is_empty());
+}
+
+#[test]
+fn test_delete_idle_stats() {
+    let options = AggregatorOptions { delete_idle: DeleteIdle::all(true), ..AggregatorOptions::default() };
+    let mut agg = Aggregator::new(options);
+    agg.process(msgs("hits:10|c\nlatency:20|ms\nload:3|g\nusers:7|s"));
+    assert_eq!(agg.flush(0).len(), 4);
+    assert!(agg.flush(10).is_empty());
+}
+
+#[test]
+fn test_delete_per_kind() {
+    let delete = DeleteIdle { counters: true, gauges: false, ..DeleteIdle::default() };
+    let options = AggregatorOptions { delete_idle: delete, ..AggregatorOptions::default() };
+    let mut agg = Aggregator::new(options);
+    agg.process(msgs("hits:10|c\nload:3|g\nload:+2|g"));
+    agg.flush(0);
+    let idle = agg.flush(10);
+    assert!(!idle.counters.contains_key("hits"));
+    assert_eq!(idle.gauges["load"], 5.0);
+}
+
+#[test]
+fn test_delete_message() {
+    let mut agg = Aggregator::new(AggregatorOptions::default());
+    agg.process(msgs("host1.load:3|g\nhost2.load:4|g\nhost1.load:1|c\nlatency:20|ms"));
+    agg.process(msgs("host1.load:delete|g\nlatency:delete|ms"));
+    let snapshot = agg.flush(0);
+    assert!(!snapshot.gauges.contains_key("host1.load"));
+    assert_eq!(snapshot.gauges["host2.load"], 4.0);
+    assert_eq!(snapshot.counters["host1.load"], 1.0);
+    assert!(snapshot.timers.is_empty() && snapshot.timer_counters.is_empty());
+
+    // Deleted buckets come back as soon as they receive a new value.
+    assert!(!agg.delete(&StatKind::Gauge, "host1.load"));
+    agg.process(msgs("host1.load:7|g"));
+    assert_eq!(agg.flush(10).gauges["host1.load"], 7.0);
+}
+
+#[test]
+fn test_limits_drop_new_buckets() {
+    let limits = Limits {
+        max_buckets: Some(3),
+        max_gauges: Some(1),
+        ..Limits::default()
+    };
+    let options = AggregatorOptions { limits: limits, ..AggregatorOptions::default() };
+    let mut agg = Aggregator::new(options);
+    agg.process(msgs("load:1|g\nmem:1|g\nhits:1|c\napi.req.1:1|c\napi.req.2:1|c\nhits:1|c\nload:2|g"));
+    assert_eq!(agg.len(), 3);
+    let snapshot = agg.flush(0);
+    assert_eq!(snapshot.gauges.keys().collect::<Vec<_>>(), vec!["load"]);
+    assert_eq!(snapshot.counters["hits"], 2.0);
+    assert!(!snapshot.counters.contains_key("api.req.2"));
+    assert_eq!(snapshot.rejected["mem"], 1);
+    assert_eq!(snapshot.rejected["api.req"], 1);
+
+    // Existing buckets keep working and the report starts over every flush.
+    agg.process(msgs("api.req.1:5|c"));
+    let snapshot = agg.flush(10);
+    assert_eq!(snapshot.counters["api.req.1"], 5.0);
+    assert!(snapshot.rejected.is_empty());
+}
+
+#[test]
+fn test_limits_prefix_overflow() {
+    let limits = Limits {
+        prefixes: vec![PrefixLimit { prefix: "api.".to_string(), max_buckets: 2 }],
+        overflow: Some("overflow".to_string()),
+        ..Limits::default()
+    };
+    let options = AggregatorOptions { limits: limits, ..AggregatorOptions::default() };
+    let mut agg = Aggregator::new(options);
+    for i in 0..5 {
+        agg.process(StatMsg::Inc(StatKind::Counter, format!("api.req.{}", i), 1, 1.0));
+    }
+    agg.process(msgs("web.hits:1|c"));
+    let snapshot = agg.flush(0);
+    assert_eq!(snapshot.counters.len(), 4);
+    assert_eq!(snapshot.counters["overflow"], 3.0);
+    assert_eq!(snapshot.rejected["api.req"], 3);
+
+    // Deleting a bucket under the prefix makes room for a new one.
+    agg.process(msgs("api.req.0:delete|c\napi.req.9:1|c"));
+    assert!(agg.flush(10).counters.contains_key("api.req.9"));
+}
+
+#[test]
+fn test_timer_data() {
+    let mut agg = Aggregator::new(AggregatorOptions::default());
+    for i in 1..11 {
+        agg.process(StatMsg::Set(StatKind::Timer, "latency".to_string(), i, 1.0));
+    }
+    agg.process(msgs("latency:100|ms|@0.5"));
+    let snapshot = agg.flush(0);
+    let data = &snapshot.timer_data["latency"];
+    assert_eq!(data["count"], 12.0);
+    assert_eq!(data["count_ps"], 1.2);
+    assert_eq!(data["lower"], 1.0);
+    assert_eq!(data["upper"], 100.0);
+    assert_eq!(data["sum"], 155.0);
+    assert_eq!(data["median"], 6.0);
+    // 90% of 11 samples rounds to 10, so the outlier is excluded.
+    assert_eq!(data["upper_90"], 10.0);
+    assert_eq!(data["sum_90"], 55.0);
+    assert_eq!(data["mean_90"], 5.5);
+}
+
+#[test]
+fn test_timer_sketches() {
+    let sketches = vec![TimerSketch {
+                            pattern: Glob::new("api.*"),
+                            kind: SketchKind::TDigest { compression: 100.0 },
+                        },
+                        TimerSketch {
+                            pattern: Glob::new("*"),
+                            kind: SketchKind::DDSketch { relative_error: 0.01 },
+                        }];
+    let mut exact = Aggregator::new(AggregatorOptions::default());
+    let mut sketched = Aggregator::new(AggregatorOptions { timer_sketches: sketches, ..AggregatorOptions::default() });
+    for i in 0..10000 {
+        let lines = format!("api.latency:{}|ms\ndb.latency:{}|h", i % 997, i % 1009);
+        exact.process(msgs(&lines));
+        sketched.process(msgs(&lines));
+    }
+    let (exact, sketched) = (exact.flush(0), sketched.flush(0));
+    assert!(sketched.timers.values().all(|values| values.is_empty()));
+    assert_eq!(sketched.timer_digests["api.latency"].error_bound(), Digest::tdigest(100.0).error_bound());
+    assert_eq!(sketched.timer_digests["db.latency"].error_bound(), Digest::ddsketch(0.01).error_bound());
+    for name in ["api.latency", "db.latency"].iter() {
+        let (data, exact_data) = (&sketched.timer_data[*name], &exact.timer_data[*name]);
+        for stat in ["count", "sum", "mean", "lower", "upper"].iter() {
+            assert_eq!(data[*stat], exact_data[*stat], "{} {}", name, stat);
+        }
+        for stat in ["median", "upper_90"].iter() {
+            assert!((data[*stat] - exact_data[*stat]).abs() <= exact_data[*stat] * 0.02, "{} {}", name, stat);
+        }
+    }
+    assert!(exact.timer_digests.is_empty());
+}
+
+#[test]
+fn test_timer_reservoir() {
+    let options = AggregatorOptions { timer_reservoir: Some(100), ..AggregatorOptions::default() };
+    let mut sampled = Aggregator::new(options);
+    let mut exact = Aggregator::new(AggregatorOptions::default());
+    for i in 0..10000 {
+        let line = format!("latency:{}|ms", i);
+        sampled.process(msgs(&line));
+        exact.process(msgs(&line));
+    }
+    sampled.process(msgs("small:1|ms\nsmall:2|ms"));
+    let (sampled, exact) = (sampled.flush(0), exact.flush(0));
+    assert_eq!(sampled.timers["latency"].len(), 100);
+    let (data, exact_data) = (&sampled.timer_data["latency"], &exact.timer_data["latency"]);
+    for stat in ["count", "sum", "sum_squares", "mean", "lower", "upper"].iter() {
+        assert_eq!(data[*stat], exact_data[*stat], "{}", stat);
+    }
+    assert!((data["std"] - exact_data["std"]).abs() < 1e-6 * exact_data["std"]);
+    for stat in ["median", "upper_90", "mean_90"].iter() {
+        assert!((data[*stat] - exact_data[*stat]).abs() < 1500.0, "{} {}", stat, data[*stat]);
+    }
+    // The sum of the lower 90% is scaled up from the sample.
+    assert!((data["sum_90"] / exact_data["sum_90"] - 1.0).abs() < 0.2, "{}", data["sum_90"]);
+
+    // Timers under the cap keep everything.
+    assert_eq!(sampled.timers["small"], vec![1.0, 2.0]);
+    assert_eq!(sampled.timer_data["small"]["median"], 1.5);
+
+    // Forwarded or rolled up, the totals stay exact.
+    use backends::forward::ForwardBackend;
+    use config::ForwardConfig;
+    use frontends::forward::parse_entry;
+    let exact_totals = ["count", "sum", "sum_squares", "mean", "lower", "upper"];
+    let mut central = Aggregator::new(AggregatorOptions::default());
+    for line in ForwardBackend::new(&ForwardConfig::default()).lines(&sampled).lines().skip(1) {
+        central.process(parse_entry(line.as_bytes()).unwrap());
+    }
+    let forwarded = &central.flush(0).timer_data["latency"];
+    for stat in exact_totals.iter() {
+        assert_eq!(forwarded[*stat], exact_data[*stat], "{}", stat);
+    }
+
+    let rollup = Rollup {
+        pattern: Glob::new("latency"),
+        tags: TagSubset::Drop(vec!["host".to_string()]),
+        backends: None,
+    };
+    let options = AggregatorOptions {
+        timer_reservoir: Some(100),
+        rollups: vec![rollup],
+        ..AggregatorOptions::default()
+    };
+    let mut tagged = Aggregator::new(options);
+    for i in 0..10000 {
+        tagged.process(msgs(&format!("latency;host=h{}:{}|ms", i % 2, i)));
+    }
+    let rolled = &tagged.flush(0).timer_data["latency"];
+    for stat in exact_totals.iter() {
+        assert_eq!(rolled[*stat], exact_data[*stat], "{}", stat);
+    }
+
+    // A merge arriving after the reservoir filled up keeps the totals of everything sampled.
+    let options = AggregatorOptions { timer_reservoir: Some(100), ..AggregatorOptions::default() };
+    let mut mixed = Aggregator::new(options);
+    for i in 0..9000 {
+        mixed.process(msgs(&format!("latency:{}|ms", i)));
+    }
+    let mut digest = Digest::default();
+    for i in 9000..10000 {
+        digest.add(i as f64);
+    }
+    mixed.process(StatMsg::Merge("latency".to_string(), Aggregate::Timer(1000.0, digest)));
+    let merged = &mixed.flush(0).timer_data["latency"];
+    for stat in exact_totals.iter() {
+        assert_eq!(merged[*stat], exact_data[*stat], "{}", stat);
+    }
+}
+
+#[test]
+fn test_rollups() {
+    let without_host = Rollup {
+        pattern: Glob::new("api.*"),
+        tags: TagSubset::Drop(vec!["host".to_string()]),
+        backends: None,
+    };
+    let by_service = Rollup {
+        pattern: Glob::new("*"),
+        tags: TagSubset::Keep(vec!["service".to_string()]),
+        backends: Some(vec!["graphite".to_string()]),
+    };
+    let options = AggregatorOptions { rollups: vec![without_host, by_service], ..AggregatorOptions::default() };
+    let mut agg = Aggregator::new(options);
+    for host in 0..4 {
+        for i in 0..100 {
+            let lines = format!("api.requests;endpoint=/users;host=h{0};service=web:1|c\n\
+                                 api.latency;host=h{0};service=web:{1}|ms\n\
+                                 api.users;host=h{0};service=web:{2}|s\n\
+                                 api.load;host=h{0};service=web:{0}|g",
+                                host,
+                                host * 100 + i,
+                                i);
+            agg.process(msgs(&lines));
+        }
+    }
+    agg.process(msgs("api.requests;service=web:5|c\ndb.queries;host=h0:1|c\n\
+                      api.latency;service=web:1000|ms\napi.users;service=web:500|s"));
+    let snapshot = agg.flush(0);
+
+    assert_eq!(snapshot.counters["api.requests;endpoint=/users;service=web"], 400.0);
+    assert_eq!(snapshot.counter_rates["api.requests;endpoint=/users;service=web"], 40.0);
+    // A series with a bucket of its own gets the rolled up data merged in, and isn't routed.
+    assert_eq!(snapshot.counters["api.requests;service=web"], 405.0);
+    assert!(!snapshot.routes.contains_key("api.requests;service=web"));
+    assert_eq!(snapshot.counters["db.queries"], 1.0);
+    assert_eq!(snapshot.routes["db.queries"], vec!["graphite".to_string()]);
+    assert!(!snapshot.routes.contains_key("api.requests;endpoint=/users;service=web"));
+
+    let timer = &snapshot.timer_data["api.latency;service=web"];
+    assert_eq!((timer["count"], timer["lower"], timer["upper"], timer["sum"]), (401.0, 0.0, 1000.0, 80800.0));
+    assert!((timer["median"] - 200.0).abs() <= 200.0 * 0.02);
+    assert_eq!(snapshot.set_counts["api.users;service=web"], 101.0);
+    assert_eq!(snapshot.gauges.len(), 4);
+
+    let mut view = snapshot.clone();
+    view.retain(|_, name| !name.starts_with("db."));
+    assert!(view.routes.is_empty());
+}
@@ -1,52 +1,62 @@
-use mio;
-use mio::*;
 use std::io;
-use std::result;
-use std::str;
-
-const RX_TOKEN: Token = Token(0);
+use std::time::Duration;
+use aggregator::Snapshot;
+use sanitize::KeySanitizer;
+use config::ConsoleConfig;
+use super::{backend_key, Backend};
 
+/// Logs every flush, handy for checking what the server would send elsewhere.
 pub struct ConsoleBackend {
-    rx: channel::Receiver<String>,
-    pub tx: channel::Sender<String>,
-}
-
-pub trait Backend {
-    fn run(&mut self, rx: channel::Receiver<String>);
-    // fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()>;
-
-    // fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()>;
-
-    // fn deregister(&self, poll: &Poll) -> Result<()>;
+    sanitizer: Option<KeySanitizer>,
+    flush_interval: Option<Duration>,
 }
 
 impl ConsoleBackend {
-    pub fn new() -> ConsoleBackend {
-        let (tx, rx) = channel::channel::<String>();
-        ConsoleBackend { rx: rx, tx: tx, }
+    pub fn new(config: &ConsoleConfig) -> ConsoleBackend {
+        ConsoleBackend {
+            sanitizer: config.key_sanitize,
+            flush_interval: config.flush_interval,
+        }
     }
+}
 
-    pub fn run(&mut self) {
-        let mut poll = Poll::new().unwrap();
-        let mut events = Events::with_capacity(1024);
+impl Backend for ConsoleBackend {
+    fn name(&self) -> &str {
+        "console"
+    }
 
-        poll.register(&self.rx, RX_TOKEN, Ready::all(), PollOpt::edge()).unwrap();
+    fn supports_tags(&self) -> bool {
+        true
+    }
 
-        loop {
-            poll.poll(&mut events, None).unwrap();
+    fn flush_interval(&self) -> Option<Duration> {
+        self.flush_interval
+    }
 
-            for event in events.iter() {
-                let tk = (event.token(), event.kind());
-                trace!("Event: {:?}", &tk);
-                match tk {
-                    (RX_TOKEN, kind) => {
-                        let msg = self.rx.try_recv().unwrap();
-                        info!("Rx: {:?}", msg);
-                    }
-                    _ => unreachable!(),
+    fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        info!("Flushing {} buckets at {}", snapshot.len(), snapshot.timestamp);
+        for (name, count) in snapshot.counters.iter() {
+            let rate = snapshot.counter_rates.get(name).cloned().unwrap_or(0.0);
+            info!("counter {} count={} rate={}", backend_key(self.sanitizer, name), count, rate);
+        }
+        for (name, data) in snapshot.timer_data.iter() {
+            let stats: Vec<String> = data.iter().map(|(stat, value)| format!("{}={}", stat, value)).collect();
+            match snapshot.timer_digests.get(name) {
+                Some(digest) => {
+                    info!("timer {} {} (percentiles {})",
+                          backend_key(self.sanitizer, name),
+                          stats.join(" "),
+                          digest.error_bound())
                 }
+                None => info!("timer {} {}", backend_key(self.sanitizer, name), stats.join(" ")),
             }
         }
+        for (name, value) in snapshot.gauges.iter() {
+            info!("gauge {} value={}", backend_key(self.sanitizer, name), value);
+        }
+        for (name, count) in snapshot.set_counts.iter() {
+            info!("set {} count={}", backend_key(self.sanitizer, name), count);
+        }
+        Ok(())
     }
 }
-
@@ -0,0 +1,155 @@
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use aggregator::Snapshot;
+use config::ForwardConfig;
+use frontends::forward::HEADER;
+use metrics::{Aggregate, StatMsg};
+use sketch::{Digest, HyperLogLog};
+use super::Backend;
+
+const CONNECT_TIMEOUT_MS: u64 = 5000;
+
+/// Ships every flush, pre-aggregated, to the forward listener of another RuStatsD (see
+/// `frontends::forward`), which merges it with what other nodes send. Buckets that saw
+/// nothing since the last flush are left out, the receiving node decides about idle buckets.
+pub struct ForwardBackend {
+    config: ForwardConfig,
+}
+
+impl ForwardBackend {
+    pub fn new(config: &ForwardConfig) -> ForwardBackend {
+        ForwardBackend { config: config.clone() }
+    }
+
+    /// Renders a snapshot in the forwarding protocol, header included.
+    pub fn lines(&self, snapshot: &Snapshot) -> String {
+        let mut out = format!("{}\n", HEADER);
+        let mut line = |entry: String| {
+            out.push_str(&entry);
+            out.push('\n');
+        };
+
+        for (name, &count) in snapshot.counters.iter().filter(|&(_, &count)| count != 0.0) {
+            line(StatMsg::Merge(name.clone(), Aggregate::Count(count)).to_string());
+        }
+        for (name, values) in snapshot.timers.iter() {
+            let digest = match snapshot.timer_digests.get(name) {
+                Some(digest) => digest.clone(),
+                None => {
+                    let mut digest = Digest::ddsketch(self.config.relative_error);
+                    for &value in values.iter() {
+                        digest.add(value);
+                    }
+                    digest
+                }
+            };
+            if !digest.is_empty() {
+                let count = snapshot.timer_counters.get(name).cloned().unwrap_or(0.0);
+                line(StatMsg::Merge(name.clone(), Aggregate::Timer(count, digest)).to_string());
+            }
+        }
+        for (name, members) in snapshot.sets.iter() {
+            let hll = match snapshot.set_sketches.get(name) {
+                Some(hll) => hll.clone(),
+                None if members.is_empty() => continue,
+                None => {
+                    let mut hll = HyperLogLog::new();
+                    for &member in members.iter() {
+                        hll.insert(member);
+                    }
+                    hll
+                }
+            };
+            line(StatMsg::Merge(name.clone(), Aggregate::Set(hll)).to_string());
+        }
+        for (name, value) in snapshot.gauges.iter() {
+            line(format!("g {} {}", value, name));
+        }
+        out
+    }
+}
+
+impl Backend for ForwardBackend {
+    fn name(&self) -> &str {
+        "forward"
+    }
+
+    // The central tier runs its own templates and backends, it gets tagged buckets as they are.
+    fn supports_tags(&self) -> bool {
+        true
+    }
+
+    fn flush_interval(&self) -> Option<Duration> {
+        self.config.flush_interval
+    }
+
+    fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        if snapshot.is_empty() {
+            return Ok(());
+        }
+        let lines = self.lines(snapshot);
+        let address = (self.config.host.as_str(), self.config.port);
+        let address = match try!(address.to_socket_addrs()).next() {
+            Some(address) => address,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "forward host did not resolve")),
+        };
+        let mut stream = try!(TcpStream::connect_timeout(&address,
+                                                         Duration::from_millis(CONNECT_TIMEOUT_MS)));
+        try!(stream.write_all(lines.as_bytes()));
+        debug!("Forwarded {} bytes to {}", lines.len(), address);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_forward_merges_tiers() {
+    use aggregator::{Aggregator, AggregatorOptions};
+    use frontends::forward::parse_entry;
+
+    // Two agents see half of the traffic each, the central tier merges their flushes.
+    let backend = ForwardBackend::new(&ForwardConfig::default());
+    let mut central = Aggregator::new(AggregatorOptions::default());
+    let mut exact = Aggregator::new(AggregatorOptions::default());
+    for agent in 0..2 {
+        let mut local = Aggregator::new(AggregatorOptions::default());
+        for i in 0..100 {
+            let lines = format!("hits:1|c|@0.5\nlatency:{}|ms\nusers:{}|s\nload:{}|g", agent * 100 + i, i, agent);
+            local.process(lines.parse().unwrap());
+            exact.process(lines.parse().unwrap());
+        }
+        for line in backend.lines(&local.flush(0)).lines().skip(1) {
+            central.process(parse_entry(line.as_bytes()).unwrap());
+        }
+    }
+    let (merged, exact) = (central.flush(0), exact.flush(0));
+    assert_eq!(merged.counters["hits"], 400.0);
+    assert_eq!(merged.gauges["load"], 1.0);
+    assert_eq!(merged.set_counts["users"], 100.0);
+    let (timer, exact_timer) = (&merged.timer_data["latency"], &exact.timer_data["latency"]);
+    for stat in ["count", "sum", "sum_squares", "mean", "lower", "upper", "std"].iter() {
+        assert!((timer[*stat] - exact_timer[*stat]).abs() < 1e-9, "{}", stat);
+    }
+    for stat in ["median", "upper_90", "mean_90"].iter() {
+        assert!((timer[*stat] - exact_timer[*stat]).abs() <= exact_timer[*stat] * 0.02, "{}", stat);
+    }
+
+    // Idle buckets stay home.
+    let mut idle = Aggregator::new(AggregatorOptions::default());
+    idle.process("hits:1|c\nlatency:1|ms\nusers:1|s".parse().unwrap());
+    idle.flush(0);
+    assert_eq!(backend.lines(&idle.flush(10)), format!("{}\n", HEADER));
+
+    // Tagged buckets arrive tagged, the flusher doesn't give them dotted names first.
+    assert!(backend.supports_tags());
+    let mut tagged = Aggregator::new(AggregatorOptions::default());
+    tagged.process("hits:2|c|#env:prod\nlatency:5|ms|#env:prod\nusers:1|s|#env:prod".parse().unwrap());
+    let mut central = Aggregator::new(AggregatorOptions::default());
+    for line in backend.lines(&tagged.flush(0)).lines().skip(1) {
+        central.process(parse_entry(line.as_bytes()).unwrap());
+    }
+    let merged = central.flush(0);
+    assert_eq!(merged.counters["hits;env=prod"], 2.0);
+    assert_eq!(merged.timer_data["latency;env=prod"]["sum"], 5.0);
+    assert_eq!(merged.set_counts["users;env=prod"], 1.0);
+}
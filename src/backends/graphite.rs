@@ -8,4 +8,188 @@
 // Events (only supported by the graphite web app over HTTP)
 // $ curl -X POST "http://graphite/events/"
 //    -d '{ "what": "Event - deploy", "tags": ["deploy"],
-//    "data": "deploy of master branch happened at Wed Jul  6 22:34:41 UTC 2016" }'
\ No newline at end of file
+//    "data": "deploy of master branch happened at Wed Jul  6 22:34:41 UTC 2016" }'
+use std::io;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use aggregator::Snapshot;
+use sanitize::KeySanitizer;
+use config::GraphiteConfig;
+use tags;
+use super::{backend_key, Backend};
+
+const CONNECT_TIMEOUT_MS: u64 = 5000;
+
+/// Sends every flush to carbon using the plaintext protocol, with the same namespaces as etsy's
+/// graphite backend.
+pub struct GraphiteBackend {
+    config: GraphiteConfig,
+    sanitizer: Option<KeySanitizer>,
+}
+
+impl GraphiteBackend {
+    pub fn new(config: &GraphiteConfig) -> GraphiteBackend {
+        GraphiteBackend {
+            config: config.clone(),
+            sanitizer: config.key_sanitize,
+        }
+    }
+
+    // Tags go after the whole path, as Graphite 1.1 expects them.
+    fn path(&self, namespace: &[&str], name: &str, suffix: Option<&str>) -> String {
+        let (name, tags) = tags::split(name);
+        let mut path: Vec<&str> = namespace.iter().cloned().filter(|part| !part.is_empty()).collect();
+        let name = backend_key(self.sanitizer, name);
+        path.push(&name);
+        if let Some(suffix) = suffix {
+            path.push(suffix);
+        }
+        tags::join(&path.join("."), &tags)
+    }
+
+    /// Renders a snapshot as plaintext protocol lines.
+    pub fn lines(&self, snapshot: &Snapshot) -> String {
+        let config = &self.config;
+        let ts = snapshot.timestamp;
+        let mut out = String::new();
+        let mut line = |path: String, value: f64| {
+            out.push_str(&format!("{} {} {}\n", path, value, ts));
+        };
+
+        // The legacy namespace puts rates under `stats.` and counts under `stats_counts.` and
+        // ignores the configured prefixes.
+        let (counters, timers, gauges, sets) = if config.legacy_namespace {
+            (vec!["stats"], vec!["stats", "timers"], vec!["stats", "gauges"], vec!["stats", "sets"])
+        } else {
+            let global = config.global_prefix.as_str();
+            (vec![global, config.prefix_counter.as_str()],
+             vec![global, config.prefix_timer.as_str()],
+             vec![global, config.prefix_gauge.as_str()],
+             vec![global, config.prefix_set.as_str()])
+        };
+
+        for (name, &count) in snapshot.counters.iter() {
+            let rate = snapshot.counter_rates.get(name).cloned().unwrap_or(0.0);
+            if config.legacy_namespace {
+                line(self.path(&counters, name, None), rate);
+                line(self.path(&["stats_counts"], name, None), count);
+            } else {
+                line(self.path(&counters, name, Some("rate")), rate);
+                line(self.path(&counters, name, Some("count")), count);
+            }
+        }
+        for (name, data) in snapshot.timer_data.iter() {
+            for (stat, &value) in data.iter() {
+                line(self.path(&timers, name, Some(stat)), value);
+            }
+        }
+        for (name, &value) in snapshot.gauges.iter() {
+            line(self.path(&gauges, name, None), value);
+        }
+        for (name, &count) in snapshot.set_counts.iter() {
+            line(self.path(&sets, name, Some("count")), count);
+        }
+        out
+    }
+}
+
+impl Backend for GraphiteBackend {
+    fn name(&self) -> &str {
+        "graphite"
+    }
+
+    fn supports_tags(&self) -> bool {
+        self.config.tags
+    }
+
+    fn flush_interval(&self) -> Option<Duration> {
+        self.config.flush_interval
+    }
+
+    fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        if snapshot.is_empty() {
+            return Ok(());
+        }
+        let lines = self.lines(snapshot);
+        let address = (self.config.host.as_str(), self.config.port);
+        let address = match try!(address.to_socket_addrs()).next() {
+            Some(address) => address,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "graphite host did not resolve")),
+        };
+        let mut stream = try!(TcpStream::connect_timeout(&address,
+                                                         Duration::from_millis(CONNECT_TIMEOUT_MS)));
+        try!(stream.write_all(lines.as_bytes()));
+        debug!("Sent {} bytes to graphite at {}", lines.len(), address);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_snapshot() -> Snapshot {
+    use aggregator::{Aggregator, AggregatorOptions};
+
+    let mut agg = Aggregator::new(AggregatorOptions::default());
+    agg.process("hits:20|c\nload:3|g\nusers:1|s\nusers:2|s\nlatency:5|ms".parse().unwrap());
+    agg.flush(1234)
+}
+
+#[test]
+fn test_graphite_legacy_namespace() {
+    let backend = GraphiteBackend::new(&GraphiteConfig::default());
+    let lines = backend.lines(&test_snapshot());
+    assert!(lines.contains("stats.hits 2 1234\n"));
+    assert!(lines.contains("stats_counts.hits 20 1234\n"));
+    assert!(lines.contains("stats.timers.latency.upper 5 1234\n"));
+    assert!(lines.contains("stats.gauges.load 3 1234\n"));
+    assert!(lines.contains("stats.sets.users.count 2 1234\n"));
+}
+
+#[test]
+fn test_graphite_namespace() {
+    let config = GraphiteConfig {
+        legacy_namespace: false,
+        global_prefix: "prod".to_string(),
+        key_sanitize: Some(KeySanitizer::Prometheus),
+        ..GraphiteConfig::default()
+    };
+    let lines = GraphiteBackend::new(&config).lines(&test_snapshot());
+    assert!(lines.contains("prod.counters.hits.rate 2 1234\n"));
+    assert!(lines.contains("prod.counters.hits.count 20 1234\n"));
+    assert!(lines.contains("prod.timers.latency.count_ps 0.1 1234\n"));
+    assert!(lines.contains("prod.gauges.load 3 1234\n"));
+}
+
+#[test]
+fn test_graphite_tags() {
+    use aggregator::{Aggregator, AggregatorOptions};
+
+    let mut agg = Aggregator::new(AggregatorOptions::default());
+    agg.process("api.requests;env=prod:10|c\napi.latency;env=prod:5|ms".parse().unwrap());
+    let lines = GraphiteBackend::new(&GraphiteConfig::default()).lines(&agg.flush(1234));
+    assert!(lines.contains("stats.api.requests;env=prod 1 1234\n"));
+    assert!(lines.contains("stats.timers.api.latency.upper;env=prod 5 1234\n"));
+}
+
+#[test]
+fn test_graphite_flush() {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = GraphiteConfig {
+        port: listener.local_addr().unwrap().port(),
+        ..GraphiteConfig::default()
+    };
+    let receiver = thread::spawn(move || {
+        let mut received = String::new();
+        listener.accept().unwrap().0.read_to_string(&mut received).unwrap();
+        received
+    });
+    let mut backend = GraphiteBackend::new(&config);
+    let snapshot = test_snapshot();
+    backend.flush(&snapshot).unwrap();
+    drop(backend);
+    assert_eq!(receiver.join().unwrap(), GraphiteBackend::new(&config).lines(&snapshot));
+}
@@ -1 +1,41 @@
-pub mod console;
\ No newline at end of file
+use std::borrow::Cow;
+use std::io;
+use std::time::Duration;
+use aggregator::Snapshot;
+use metrics::StatKind;
+use sanitize::KeySanitizer;
+
+pub mod console;
+pub mod graphite;
+pub mod forward;
+
+/// Something that receives every flush of the aggregator.
+pub trait Backend: Send {
+    fn name(&self) -> &str;
+
+    fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()>;
+
+    /// Whether the backend understands tagged bucket names (see `tags`). The others get buckets
+    /// that `tags::Templates` tagged under their dotted names.
+    fn supports_tags(&self) -> bool {
+        false
+    }
+
+    /// How often the backend wants to be flushed, a multiple of the aggregator's flush interval.
+    /// It then gets buckets aggregated over that interval. `None` for every flush.
+    fn flush_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called when a bucket is deleted, so backends that remember metrics between flushes can
+    /// forget it too. Stateless backends have nothing to do.
+    fn delete(&mut self, _kind: &StatKind, _name: &str) {}
+}
+
+// Applies a backend's own `key_sanitize` rules on top of the ones the parser already applied.
+fn backend_key<'a>(sanitizer: Option<KeySanitizer>, name: &'a str) -> Cow<'a, str> {
+    match sanitizer {
+        Some(sanitizer) => sanitizer.sanitize(name),
+        None => Cow::Borrowed(name),
+    }
+}
@@ -0,0 +1,497 @@
+//! A statsd client speaking the same line protocol the server parses.
+//!
+//! ```no_run
+//! use rustatsd::client::{Client, UdpSink};
+//!
+//! let client = Client::new(UdpSink::connect("127.0.0.1:8125").unwrap()).with_prefix("myapp");
+//! client.incr("requests").unwrap();
+//! client.count("bytes", 512).sample_rate(0.1).tag("route", "/home").send().unwrap();
+//! {
+//!     let _timer = client.time("render");
+//!     // ... timed on drop
+//! }
+//! client.flush().unwrap();
+//! ```
+
+use std::io::{self, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use metrics::{StatKind, StatMsg};
+
+/// Payload size that fits a single ethernet frame once IP and UDP headers are added.
+pub const DEFAULT_MTU: usize = 1432;
+
+/// Where the client's lines go. Sinks may buffer, `flush` pushes out whatever is pending.
+pub trait Sink: Send {
+    fn send(&mut self, line: &str) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Packs newline separated lines into datagrams of at most `mtu` bytes. A single line longer
+/// than that still goes out, on its own.
+#[derive(Debug)]
+pub struct Packer {
+    buf: Vec<u8>,
+    mtu: usize,
+}
+
+impl Packer {
+    pub fn new(mtu: usize) -> Packer {
+        Packer {
+            buf: Vec::with_capacity(mtu),
+            mtu: mtu,
+        }
+    }
+
+    /// Adds `line`, first handing the pending datagram to `send` if the line doesn't fit.
+    pub fn push<F: FnMut(&[u8]) -> io::Result<()>>(&mut self, line: &str, send: F) -> io::Result<()> {
+        if !self.buf.is_empty() && self.buf.len() + 1 + line.len() > self.mtu {
+            try!(self.flush(send));
+        }
+        if !self.buf.is_empty() {
+            self.buf.push(b'\n');
+        }
+        self.buf.extend_from_slice(line.as_bytes());
+        Ok(())
+    }
+
+    pub fn flush<F: FnMut(&[u8]) -> io::Result<()>>(&mut self, mut send: F) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let result = send(&self.buf);
+        self.buf.clear();
+        result
+    }
+}
+
+pub struct UdpSink {
+    socket: UdpSocket,
+    packer: Packer,
+}
+
+impl UdpSink {
+    /// Sends to the first address `addr` resolves to, from a socket of the same family.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSink> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "statsd host did not resolve")),
+        };
+        let socket = try!(UdpSocket::bind(if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }));
+        try!(socket.connect(addr));
+        Ok(UdpSink {
+            socket: socket,
+            packer: Packer::new(DEFAULT_MTU),
+        })
+    }
+
+    pub fn with_mtu(mut self, mtu: usize) -> UdpSink {
+        self.packer = Packer::new(mtu);
+        self
+    }
+}
+
+impl Sink for UdpSink {
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        let socket = &self.socket;
+        self.packer.push(line, |datagram| socket.send(datagram).map(|_| ()))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let socket = &self.socket;
+        self.packer.flush(|datagram| socket.send(datagram).map(|_| ()))
+    }
+}
+
+/// Datagrams over a Unix socket, which skip the network stack entirely.
+#[cfg(unix)]
+pub struct UnixSink {
+    socket: UnixDatagram,
+    packer: Packer,
+}
+
+#[cfg(unix)]
+impl UnixSink {
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixSink> {
+        let socket = try!(UnixDatagram::unbound());
+        try!(socket.connect(path));
+        Ok(UnixSink {
+            socket: socket,
+            packer: Packer::new(8192),
+        })
+    }
+
+    pub fn with_mtu(mut self, mtu: usize) -> UnixSink {
+        self.packer = Packer::new(mtu);
+        self
+    }
+}
+
+#[cfg(unix)]
+impl Sink for UnixSink {
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        let socket = &self.socket;
+        self.packer.push(line, |datagram| socket.send(datagram).map(|_| ()))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let socket = &self.socket;
+        self.packer.flush(|datagram| socket.send(datagram).map(|_| ()))
+    }
+}
+
+/// Newline terminated lines over a buffered TCP connection.
+pub struct TcpSink {
+    stream: BufWriter<TcpStream>,
+}
+
+impl TcpSink {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpSink> {
+        let stream = try!(TcpStream::connect(addr));
+        Ok(TcpSink { stream: BufWriter::with_capacity(DEFAULT_MTU, stream) })
+    }
+}
+
+impl Sink for TcpSink {
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        try!(self.stream.write_all(line.as_bytes()));
+        self.stream.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+struct Inner {
+    sink: Box<dyn Sink>,
+    // xorshift state for client side sampling.
+    rng: u64,
+}
+
+impl Inner {
+    fn sampled(&mut self, sample_rate: f64) -> bool {
+        if sample_rate >= 1.0 {
+            return true;
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        ((self.rng >> 11) as f64 / (1u64 << 53) as f64) < sample_rate
+    }
+}
+
+/// A statsd client, safe to share between threads. Buffered lines go out once a datagram is
+/// full, on `flush` and when the client is dropped.
+pub struct Client {
+    prefix: String,
+    tags: Vec<String>,
+    inner: Mutex<Inner>,
+}
+
+impl Client {
+    pub fn new<S: Sink + 'static>(sink: S) -> Client {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64 ^ d.as_secs())
+            .unwrap_or(0);
+        Client {
+            prefix: String::new(),
+            tags: Vec::new(),
+            inner: Mutex::new(Inner {
+                sink: Box::new(sink),
+                rng: seed | 1,
+            }),
+        }
+    }
+
+    /// Prepended, with a `.`, to every metric name.
+    pub fn with_prefix(mut self, prefix: &str) -> Client {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// A DogStatsD tag added to every metric. The server keeps tags in the name, see `tags`.
+    pub fn with_tag(mut self, key: &str, value: &str) -> Client {
+        self.tags.push(format_tag(key, value));
+        self
+    }
+
+    pub fn incr(&self, name: &str) -> io::Result<()> {
+        self.count(name, 1).send()
+    }
+
+    pub fn decr(&self, name: &str) -> io::Result<()> {
+        self.count(name, -1).send()
+    }
+
+    pub fn count<'a>(&'a self, name: &str, value: i64) -> Metric<'a> {
+        self.metric(StatMsg::Inc(StatKind::Counter, self.name(name), value, 1.0))
+    }
+
+    /// A timing in milliseconds.
+    pub fn timing<'a>(&'a self, name: &str, ms: i64) -> Metric<'a> {
+        self.metric(StatMsg::Set(StatKind::Timer, self.name(name), ms, 1.0))
+    }
+
+    pub fn histogram<'a>(&'a self, name: &str, value: i64) -> Metric<'a> {
+        self.metric(StatMsg::Set(StatKind::Histogram, self.name(name), value, 1.0))
+    }
+
+    pub fn gauge<'a>(&'a self, name: &str, value: i64) -> Metric<'a> {
+        self.metric(StatMsg::Set(StatKind::Gauge, self.name(name), value, 1.0))
+    }
+
+    /// Moves a gauge by `delta` from its current value.
+    pub fn gauge_delta<'a>(&'a self, name: &str, delta: i64) -> Metric<'a> {
+        self.metric(StatMsg::Inc(StatKind::Gauge, self.name(name), delta, 1.0))
+    }
+
+    /// Counts `value` as a distinct member of the set.
+    pub fn set<'a>(&'a self, name: &str, value: i64) -> Metric<'a> {
+        self.metric(StatMsg::Set(StatKind::Sets, self.name(name), value, 1.0))
+    }
+
+    /// Starts a timer that is sent when the returned guard is dropped.
+    pub fn time<'a>(&'a self, name: &str) -> TimerGuard<'a> {
+        TimerGuard {
+            client: self,
+            name: name.to_string(),
+            tags: Vec::new(),
+            start: Instant::now(),
+            done: false,
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.lock().unwrap().sink.flush()
+    }
+
+    fn name(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.prefix, name)
+        }
+    }
+
+    fn metric<'a>(&'a self, msg: StatMsg) -> Metric<'a> {
+        Metric {
+            client: self,
+            msg: msg,
+            tags: Vec::new(),
+        }
+    }
+
+    fn send(&self, msg: &StatMsg, tags: &[String]) -> io::Result<()> {
+        let sample_rate = match *msg {
+            StatMsg::Inc(_, _, _, sr) | StatMsg::Set(_, _, _, sr) => sr,
+            _ => 1.0,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.sampled(sample_rate) {
+            return Ok(());
+        }
+        inner.sink.send(&format_line(msg, &self.tags, tags))
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            let _ = inner.sink.flush();
+        }
+    }
+}
+
+/// One metric about to be sent, see the `Client` methods.
+pub struct Metric<'a> {
+    client: &'a Client,
+    msg: StatMsg,
+    tags: Vec<String>,
+}
+
+impl<'a> Metric<'a> {
+    /// Only sends the metric with probability `sample_rate`, telling the server to scale it
+    /// back up.
+    pub fn sample_rate(mut self, sample_rate: f64) -> Metric<'a> {
+        match self.msg {
+            StatMsg::Inc(_, _, _, ref mut sr) | StatMsg::Set(_, _, _, ref mut sr) => *sr = sample_rate,
+            _ => {}
+        }
+        self
+    }
+
+    pub fn tag(mut self, key: &str, value: &str) -> Metric<'a> {
+        self.tags.push(format_tag(key, value));
+        self
+    }
+
+    pub fn send(self) -> io::Result<()> {
+        self.client.send(&self.msg, &self.tags)
+    }
+
+    /// The line this metric is sent as.
+    pub fn line(&self) -> String {
+        format_line(&self.msg, &self.client.tags, &self.tags)
+    }
+}
+
+/// Times from its creation until it is dropped or stopped, then sends a timer.
+pub struct TimerGuard<'a> {
+    client: &'a Client,
+    name: String,
+    tags: Vec<String>,
+    start: Instant,
+    done: bool,
+}
+
+impl<'a> TimerGuard<'a> {
+    pub fn tag(mut self, key: &str, value: &str) -> TimerGuard<'a> {
+        self.tags.push(format_tag(key, value));
+        self
+    }
+
+    /// Sends the timer now and returns what it measured.
+    pub fn stop(mut self) -> io::Result<Duration> {
+        self.send()
+    }
+
+    fn send(&mut self) -> io::Result<Duration> {
+        self.done = true;
+        let elapsed = self.start.elapsed();
+        let ms = elapsed.as_secs() as i64 * 1000 + elapsed.subsec_nanos() as i64 / 1_000_000;
+        let msg = StatMsg::Set(StatKind::Timer, self.client.name(&self.name), ms, 1.0);
+        try!(self.client.send(&msg, &self.tags));
+        Ok(elapsed)
+    }
+}
+
+impl<'a> Drop for TimerGuard<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.send();
+        }
+    }
+}
+
+fn format_tag(key: &str, value: &str) -> String {
+    if value.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}:{}", key, value)
+    }
+}
+
+fn format_line(msg: &StatMsg, client_tags: &[String], tags: &[String]) -> String {
+    let line = msg.to_string();
+    if client_tags.is_empty() && tags.is_empty() {
+        return line;
+    }
+    let tags: Vec<&str> = client_tags.iter().chain(tags.iter()).map(|tag| tag.as_str()).collect();
+    // An absolute negative gauge takes two lines, both get the tags.
+    line.lines().map(|line| format!("{}|#{}", line, tags.join(","))).collect::<Vec<_>>().join("\n")
+}
+
+#[cfg(test)]
+struct Lines(::std::sync::Arc<Mutex<Vec<String>>>);
+
+#[cfg(test)]
+impl Sink for Lines {
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        self.0.lock().unwrap().push(line.to_string());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_client_lines_round_trip() {
+    let client = Client::new(Lines(Default::default())).with_prefix("app").with_tag("env", "prod");
+    let name = |name: &str| format!("app.{};env=prod", name);
+    let cases = vec![(client.count("hits", 2), StatMsg::Inc(StatKind::Counter, name("hits"), 2, 1.0)),
+                     (client.count("hits", 1).sample_rate(0.5),
+                      StatMsg::Inc(StatKind::Counter, name("hits"), 1, 0.5)),
+                     (client.timing("latency", 120), StatMsg::Set(StatKind::Timer, name("latency"), 120, 1.0)),
+                     (client.histogram("size", 9), StatMsg::Set(StatKind::Histogram, name("size"), 9, 1.0)),
+                     (client.gauge("load", 3), StatMsg::Set(StatKind::Gauge, name("load"), 3, 1.0)),
+                     (client.gauge_delta("load", -1), StatMsg::Inc(StatKind::Gauge, name("load"), -1, 1.0)),
+                     (client.set("users", 42).tag("region", "eu"),
+                      StatMsg::Set(StatKind::Sets, "app.users;env=prod;region=eu".to_string(), 42, 1.0))];
+    for (metric, expected) in cases {
+        let line = metric.line();
+        assert!(line.contains("|#env:prod"), "{}", line);
+        assert_eq!(line.parse::<StatMsg>().unwrap(), expected);
+    }
+    assert_eq!(client.set("users", 42).tag("region", "eu").tag("canary", "").line(),
+               "app.users:42|s|#env:prod,region:eu,canary");
+}
+
+#[test]
+fn test_client_sampling_and_timer_guard() {
+    let lines = ::std::sync::Arc::new(Mutex::new(Vec::new()));
+    let client = Client::new(Lines(lines.clone()));
+    for _ in 0..1000 {
+        client.count("sampled", 1).sample_rate(0.1).send().unwrap();
+    }
+    let sent = lines.lock().unwrap().len();
+    assert!(sent > 30 && sent < 250, "sent {} of 1000 at 10%", sent);
+
+    lines.lock().unwrap().clear();
+    {
+        let _timer = client.time("block").tag("step", "one");
+    }
+    client.time("stopped").stop().unwrap();
+    let lines = lines.lock().unwrap();
+    assert!(lines[0].starts_with("block:") && lines[0].ends_with("|ms|#step:one"));
+    assert!(lines[1].starts_with("stopped:") && lines[1].ends_with("|ms"));
+    assert_eq!(lines.len(), 2);
+}
+
+#[test]
+fn test_udp_sink_packs_datagrams() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let client = Client::new(UdpSink::connect(server.local_addr().unwrap()).unwrap().with_mtu(64));
+    for i in 0..10 {
+        client.count(&format!("metric.{}", i), i).send().unwrap();
+    }
+    client.flush().unwrap();
+
+    let mut buf = [0; 1500];
+    let mut received = Vec::new();
+    while received.len() < 10 {
+        let len = server.recv(&mut buf).unwrap();
+        assert!(len <= 64);
+        match ::std::str::from_utf8(&buf[..len]).unwrap().parse::<StatMsg>().unwrap() {
+            StatMsg::Bat(msgs) => received.extend(msgs),
+            msg => received.push(msg),
+        }
+    }
+    assert_eq!(received[9], StatMsg::Inc(StatKind::Counter, "metric.9".to_string(), 9, 1.0));
+}
+
+#[test]
+fn test_udp_sink_ipv6() {
+    // Not every sandbox has an IPv6 loopback.
+    let server = match UdpSocket::bind("[::1]:0") {
+        Ok(server) => server,
+        Err(_) => return,
+    };
+    server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let client = Client::new(UdpSink::connect(server.local_addr().unwrap()).unwrap());
+    client.incr("hits").unwrap();
+    client.flush().unwrap();
+    let mut buf = [0; 1500];
+    let len = server.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"hits:1|c");
+}
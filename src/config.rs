@@ -0,0 +1,1319 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::time::Duration;
+use toml;
+use aggregator::{AggregatorOptions, DeleteIdle, MetricInterval, PrefixLimit, Rollup, SketchKind, TagSubset,
+                 TimerSketch};
+use persist::StateFile;
+use rules::{Action, Rule, Rules};
+use tags::{self, Template, Templates};
+use metrics::{ParseMode, StatKind};
+use sanitize::KeySanitizer;
+use pattern::Glob;
+use sketch::{DEFAULT_COMPRESSION, DEFAULT_RELATIVE_ERROR};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref err) => write!(f, "could not read config: {}", err),
+            ConfigError::Parse(ref msg) => write!(f, "could not parse config: {}", msg),
+            ConfigError::Invalid(ref msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> ConfigError {
+        ConfigError::Io(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, ConfigError>;
+
+/// An address the UDP frontend listens on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpListener {
+    /// An IPv4 or IPv6 address (`::` or `[::]`, brackets are optional) or a host name.
+    pub host: String,
+    pub port: u16,
+    /// Prepended (with a dot) to every name received on this listener, e.g. a tenant.
+    pub prefix: Option<String>,
+    /// Whether an IPv6 listener also accepts IPv4 (as mapped addresses), ignored for IPv4.
+    pub dual_stack: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpConfig {
+    pub host: String,
+    pub port: u16,
+    pub prefix: Option<String>,
+    pub dual_stack: bool,
+    /// More addresses to listen on, from `[[udp.listener]]`. Every one of them gets its own
+    /// workers, with the settings below.
+    pub listeners: Vec<UdpListener>,
+    /// Number of sockets (and reader threads) bound to the address.
+    pub workers: usize,
+    /// Bind every worker socket with SO_REUSEPORT so the kernel spreads datagrams across them.
+    pub reuse_port: bool,
+    /// Kernel-side SO_RCVBUF size in bytes, `None` keeps the system default.
+    pub recv_buffer_size: Option<usize>,
+    /// Maximum datagrams read per `recvmmsg` call on Linux.
+    pub batch_size: usize,
+}
+
+impl Default for UdpConfig {
+    fn default() -> UdpConfig {
+        UdpConfig {
+            host: "127.0.0.1".to_string(),
+            port: 13265,
+            prefix: None,
+            dual_stack: true,
+            listeners: Vec::new(),
+            workers: 1,
+            reuse_port: false,
+            recv_buffer_size: None,
+            batch_size: 32,
+        }
+    }
+}
+
+impl UdpConfig {
+    /// `[udp]`'s own address followed by the ones in `listeners`.
+    pub fn all_listeners(&self) -> Vec<UdpListener> {
+        let mut all = vec![UdpListener {
+                               host: self.host.clone(),
+                               port: self.port,
+                               prefix: self.prefix.clone(),
+                               dual_stack: self.dual_stack,
+                           }];
+        all.extend(self.listeners.iter().cloned());
+        all
+    }
+}
+
+/// The etsy style management interface, see `frontends::admin`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for AdminConfig {
+    fn default() -> AdminConfig {
+        AdminConfig {
+            enabled: true,
+            host: "127.0.0.1".to_string(),
+            port: 8126,
+        }
+    }
+}
+
+/// `/healthz`, `/readyz` and `/status` over HTTP, see `frontends::http`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    /// Accept metrics with `POST /metrics`.
+    pub ingest: bool,
+    /// Largest request body taken, in bytes, both as sent and after gunzipping.
+    pub max_body_size: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            enabled: false,
+            host: "127.0.0.1".to_string(),
+            port: 8127,
+            ingest: false,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Graphite plaintext and pickle listeners, see `frontends::carbon`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CarbonConfig {
+    pub enabled: bool,
+    pub host: String,
+    /// `path value timestamp` lines, over both TCP and UDP.
+    pub plaintext_port: u16,
+    /// Length prefixed pickled lists of `(path, (timestamp, value))`, over TCP.
+    pub pickle_port: u16,
+}
+
+impl Default for CarbonConfig {
+    fn default() -> CarbonConfig {
+        CarbonConfig {
+            enabled: false,
+            host: "127.0.0.1".to_string(),
+            plaintext_port: 2003,
+            pickle_port: 2004,
+        }
+    }
+}
+
+/// A statsd node behind the proxy, see `proxy`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyNode {
+    pub host: String,
+    /// Where metric lines go, over UDP.
+    pub port: u16,
+    /// The node's admin interface, asked for `health` to tell whether the node is up.
+    pub admin_port: u16,
+}
+
+/// Proxy mode: instead of aggregating, every line is forwarded to the node its name hashes to,
+/// like etsy's `proxy.js`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyConfig {
+    pub nodes: Vec<ProxyNode>,
+    /// Points each node gets on the hash ring, more spread names more evenly.
+    pub vnodes: usize,
+    /// Time between health checks of every node, also the timeout of a check.
+    pub check_interval: Duration,
+}
+
+impl ProxyConfig {
+    pub fn new(nodes: Vec<ProxyNode>) -> ProxyConfig {
+        ProxyConfig {
+            nodes: nodes,
+            vnodes: 160,
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What a Unix socket frontend prefixes names with when it can tell who sent them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerTag {
+    /// `pid-1234.`
+    Pid,
+    /// `container-<first 12 characters of the id>.`, found in the sender's cgroup. Senders that
+    /// don't run in a container are left alone.
+    Container,
+}
+
+/// A Unix domain socket frontend, see `frontends::unix_server`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    /// Permission bits set on the socket file after binding, e.g. `0o660`.
+    pub mode: Option<u32>,
+    /// Remove a socket file left behind by a previous run if nothing answers on it anymore.
+    pub remove_stale: bool,
+    pub peer_tag: Option<PeerTag>,
+}
+
+impl UnixSocketConfig {
+    pub fn new<P: Into<PathBuf>>(path: P) -> UnixSocketConfig {
+        UnixSocketConfig {
+            path: path.into(),
+            mode: None,
+            remove_stale: true,
+            peer_tag: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ParserConfig {
+    /// Whether the valid lines of a partly malformed packet are kept (`lenient`, the default) or
+    /// the whole packet is dropped (`strict`).
+    pub mode: ParseMode,
+    /// How many rejected lines to log per minute for each listener, 0 disables the log.
+    pub log_bad_lines_per_minute: usize,
+    /// Rewrites every incoming name, etsy statsd compatible by default.
+    pub key_sanitize: KeySanitizer,
+}
+
+/// The server's own metrics (packets received, bad lines, flush timings ...), reported with
+/// every flush.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfMetricsConfig {
+    pub enabled: bool,
+    pub prefix: String,
+}
+
+impl Default for SelfMetricsConfig {
+    fn default() -> SelfMetricsConfig {
+        SelfMetricsConfig {
+            enabled: true,
+            prefix: "statsd".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConsoleConfig {
+    /// Extra sanitizing applied to names on top of `parser.key_sanitize`, for this backend only.
+    pub key_sanitize: Option<KeySanitizer>,
+    /// See `Backend::flush_interval`.
+    pub flush_interval: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphiteConfig {
+    pub host: String,
+    pub port: u16,
+    /// etsy's legacy layout: rates under `stats.`, counts under `stats_counts.`, no prefixes.
+    pub legacy_namespace: bool,
+    pub global_prefix: String,
+    pub prefix_counter: String,
+    pub prefix_timer: String,
+    pub prefix_gauge: String,
+    pub prefix_set: String,
+    pub key_sanitize: Option<KeySanitizer>,
+    /// Send tagged names (see `tags`) as Graphite 1.1 tagged series, instead of the dotted
+    /// names templates split them from.
+    pub tags: bool,
+    /// See `Backend::flush_interval`.
+    pub flush_interval: Option<Duration>,
+}
+
+impl Default for GraphiteConfig {
+    fn default() -> GraphiteConfig {
+        GraphiteConfig {
+            host: "127.0.0.1".to_string(),
+            port: 2003,
+            legacy_namespace: true,
+            global_prefix: "stats".to_string(),
+            prefix_counter: "counters".to_string(),
+            prefix_timer: "timers".to_string(),
+            prefix_gauge: "gauges".to_string(),
+            prefix_set: "sets".to_string(),
+            key_sanitize: None,
+            tags: false,
+            flush_interval: None,
+        }
+    }
+}
+
+/// The `forward` backend, which ships every flush to another RuStatsD, see `backends::forward`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardConfig {
+    pub host: String,
+    pub port: u16,
+    /// Relative error of the timer digests sent along.
+    pub relative_error: f64,
+    /// See `Backend::flush_interval`.
+    pub flush_interval: Option<Duration>,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> ForwardConfig {
+        ForwardConfig {
+            host: "127.0.0.1".to_string(),
+            port: 8128,
+            relative_error: DEFAULT_RELATIVE_ERROR,
+            flush_interval: None,
+        }
+    }
+}
+
+/// Receives what the `forward` backends of other nodes flush, see `frontends::forward`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardListenerConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ForwardListenerConfig {
+    fn default() -> ForwardListenerConfig {
+        ForwardListenerConfig {
+            enabled: false,
+            host: "127.0.0.1".to_string(),
+            port: 8128,
+        }
+    }
+}
+
+pub const BACKENDS: &[&str] = &["console", "graphite", "forward"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Names of the backends every flush is sent to.
+    pub backends: Vec<String>,
+    pub udp: UdpConfig,
+    pub admin: AdminConfig,
+    pub http: HttpConfig,
+    /// Unix datagram socket frontend, off unless a path is set.
+    pub unix_datagram: Option<UnixSocketConfig>,
+    /// Newline separated lines over a Unix stream socket, off unless a path is set.
+    pub unix_stream: Option<UnixSocketConfig>,
+    pub carbon: CarbonConfig,
+    pub forward_listener: ForwardListenerConfig,
+    /// Forward lines to other nodes instead of aggregating them, off unless nodes are set.
+    pub proxy: Option<ProxyConfig>,
+    pub parser: ParserConfig,
+    pub aggregator: AggregatorOptions,
+    /// Rewrite and routing rules applied before aggregating, in order.
+    pub rules: Rules,
+    /// Split dotted names into a measurement and tags after the rules.
+    pub templates: Templates,
+    /// Where gauges (and optionally sets and counters) survive restarts, off unless a path is set.
+    pub persistence: Option<StateFile>,
+    pub self_metrics: SelfMetricsConfig,
+    pub console: ConsoleConfig,
+    pub graphite: GraphiteConfig,
+    pub forward: ForwardConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            backends: vec!["console".to_string()],
+            udp: UdpConfig::default(),
+            admin: AdminConfig::default(),
+            http: HttpConfig::default(),
+            unix_datagram: None,
+            unix_stream: None,
+            carbon: CarbonConfig::default(),
+            forward_listener: ForwardListenerConfig::default(),
+            proxy: None,
+            parser: ParserConfig::default(),
+            aggregator: AggregatorOptions::default(),
+            rules: Rules::default(),
+            templates: Templates::default(),
+            persistence: None,
+            self_metrics: SelfMetricsConfig::default(),
+            console: ConsoleConfig::default(),
+            graphite: GraphiteConfig::default(),
+            forward: ForwardConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let mut file = try!(File::open(path));
+        let mut raw = String::new();
+        try!(file.read_to_string(&mut raw));
+        Config::from_toml(&raw)
+    }
+
+    pub fn from_toml(raw: &str) -> Result<Config> {
+        let mut parser = toml::Parser::new(raw);
+        let root = match parser.parse() {
+            Some(table) => toml::Value::Table(table),
+            None => {
+                let msgs: Vec<String> = parser.errors
+                    .iter()
+                    .map(|err| {
+                        let (line, col) = parser.to_linecol(err.lo);
+                        format!("{} at line {} column {}", err.desc, line + 1, col + 1)
+                    })
+                    .collect();
+                return Err(ConfigError::Parse(msgs.join("; ")));
+            }
+        };
+
+        let mut config = Config::default();
+        {
+            let udp = &mut config.udp;
+            if let Some(host) = try!(get_str(&root, "udp.host")) {
+                udp.host = host.to_string();
+            }
+            if let Some(port) = try!(get_port(&root, "udp.port")) {
+                udp.port = port;
+            }
+            udp.prefix = try!(get_str(&root, "udp.prefix")).map(|prefix| prefix.to_string());
+            if let Some(dual_stack) = try!(get_bool(&root, "udp.dual_stack")) {
+                udp.dual_stack = dual_stack;
+            }
+            if let Some(tables) = try!(get_array(&root, "udp.listener")) {
+                for table in tables {
+                    let port = match try!(get_port(table, "port")) {
+                        Some(port) => port,
+                        None => {
+                            return Err(ConfigError::Invalid("every [[udp.listener]] needs a port"
+                                .to_string()))
+                        }
+                    };
+                    udp.listeners.push(UdpListener {
+                        host: try!(get_str(table, "host")).unwrap_or(&udp.host).to_string(),
+                        port: port,
+                        prefix: try!(get_str(table, "prefix")).map(|prefix| prefix.to_string()),
+                        dual_stack: try!(get_bool(table, "dual_stack")).unwrap_or(udp.dual_stack),
+                    });
+                }
+            }
+            if let Some(workers) = try!(get_int(&root, "udp.workers")) {
+                udp.workers = workers as usize;
+            }
+            if let Some(reuse_port) = try!(get_bool(&root, "udp.reuse_port")) {
+                udp.reuse_port = reuse_port;
+            }
+            if let Some(size) = try!(get_int(&root, "udp.recv_buffer_size")) {
+                udp.recv_buffer_size = Some(size as usize);
+            }
+            if let Some(size) = try!(get_int(&root, "udp.batch_size")) {
+                udp.batch_size = size as usize;
+            }
+            if udp.workers == 0 {
+                return Err(ConfigError::Invalid("udp.workers must be at least 1".to_string()));
+            }
+            if udp.batch_size == 0 {
+                return Err(ConfigError::Invalid("udp.batch_size must be at least 1".to_string()));
+            }
+        }
+        match try!(get_str(&root, "parser.mode")) {
+            Some("lenient") => config.parser.mode = ParseMode::Lenient,
+            Some("strict") => config.parser.mode = ParseMode::Strict,
+            Some(other) => {
+                return Err(ConfigError::Invalid(format!("unknown parser.mode `{}`", other)));
+            }
+            None => {}
+        }
+        if let Some(limit) = try!(get_int(&root, "parser.log_bad_lines_per_minute")) {
+            config.parser.log_bad_lines_per_minute = limit as usize;
+        }
+        if let Some(sanitizer) = try!(get_sanitizer(&root, "parser.key_sanitize")) {
+            config.parser.key_sanitize = sanitizer;
+        }
+        config.console.key_sanitize = try!(get_sanitizer(&root, "console.key_sanitize"));
+
+        if let Some(backends) = try!(get_str_array(&root, "backends")) {
+            for backend in backends.iter() {
+                if !BACKENDS.contains(&backend.as_str()) {
+                    return Err(ConfigError::Invalid(format!("unknown backend `{}`", backend)));
+                }
+            }
+            config.backends = backends;
+        }
+
+        if let Some(tables) = try!(get_array(&root, "rules")) {
+            let mut rules = Vec::with_capacity(tables.len());
+            for table in tables {
+                rules.push(try!(get_rule(table)));
+            }
+            config.rules = Rules::new(rules);
+        }
+        if let Some(templates) = try!(get_str_array(&root, "tags.templates")) {
+            let mut parsed = Vec::with_capacity(templates.len());
+            for template in templates.iter() {
+                parsed.push(try!(template.parse::<Template>().map_err(ConfigError::Invalid)));
+            }
+            config.templates = Templates::new(parsed);
+        }
+
+        {
+            let limits = &mut config.aggregator.limits;
+            limits.max_buckets = try!(get_int(&root, "limits.max_buckets")).map(|n| n as usize);
+            limits.max_counters = try!(get_int(&root, "limits.max_counters")).map(|n| n as usize);
+            limits.max_timers = try!(get_int(&root, "limits.max_timers")).map(|n| n as usize);
+            limits.max_gauges = try!(get_int(&root, "limits.max_gauges")).map(|n| n as usize);
+            limits.max_sets = try!(get_int(&root, "limits.max_sets")).map(|n| n as usize);
+            limits.overflow = try!(get_str(&root, "limits.overflow")).map(|name| name.to_string());
+            if let Some(tables) = try!(get_array(&root, "limits.prefix")) {
+                for table in tables {
+                    let prefix = try!(get_str(table, "prefix"));
+                    let max = try!(get_int(table, "max_buckets"));
+                    match (prefix, max) {
+                        (Some(prefix), Some(max)) => {
+                            limits.prefixes.push(PrefixLimit {
+                                prefix: prefix.to_string(),
+                                max_buckets: max as usize,
+                            })
+                        }
+                        _ => {
+                            return Err(ConfigError::Invalid("every [[limits.prefix]] needs a prefix \
+                                                             and max_buckets"
+                                .to_string()))
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(tables) = try!(get_array(&root, "proxy.node")) {
+            let mut nodes = Vec::new();
+            for table in tables {
+                let port = match try!(get_port(table, "port")) {
+                    Some(port) => port,
+                    None => return Err(ConfigError::Invalid("every [[proxy.node]] needs a port".to_string())),
+                };
+                nodes.push(ProxyNode {
+                    host: try!(get_str(table, "host")).unwrap_or("127.0.0.1").to_string(),
+                    port: port,
+                    admin_port: try!(get_port(table, "admin_port")).unwrap_or(8126),
+                });
+            }
+            let mut proxy = ProxyConfig::new(nodes);
+            if let Some(vnodes) = try!(get_int(&root, "proxy.vnodes")) {
+                if vnodes == 0 {
+                    return Err(ConfigError::Invalid("proxy.vnodes must be at least 1".to_string()));
+                }
+                proxy.vnodes = vnodes as usize;
+            }
+            if let Some(ms) = try!(get_int(&root, "proxy.check_interval")) {
+                if ms == 0 {
+                    return Err(ConfigError::Invalid("proxy.check_interval must be at least 1ms".to_string()));
+                }
+                proxy.check_interval = Duration::from_millis(ms as u64);
+            }
+            config.proxy = Some(proxy);
+        }
+
+        if let Some(path) = try!(get_str(&root, "persistence.path")) {
+            let mut file = StateFile::new(path);
+            if let Some(ms) = try!(get_int(&root, "persistence.interval")) {
+                if ms == 0 {
+                    return Err(ConfigError::Invalid("persistence.interval must be at least 1ms"
+                        .to_string()));
+                }
+                file.interval = Duration::from_millis(ms as u64);
+            }
+            file.sets = try!(get_bool(&root, "persistence.sets")).unwrap_or(false);
+            file.counters = try!(get_bool(&root, "persistence.counters")).unwrap_or(false);
+            config.persistence = Some(file);
+        }
+
+        if let Some(enabled) = try!(get_bool(&root, "self_metrics.enabled")) {
+            config.self_metrics.enabled = enabled;
+        }
+        try!(set_str(&root, "self_metrics.prefix", &mut config.self_metrics.prefix));
+
+        {
+            let admin = &mut config.admin;
+            if let Some(enabled) = try!(get_bool(&root, "admin.enabled")) {
+                admin.enabled = enabled;
+            }
+            try!(set_str(&root, "admin.host", &mut admin.host));
+            if let Some(port) = try!(get_port(&root, "admin.port")) {
+                admin.port = port;
+            }
+        }
+
+        {
+            let http = &mut config.http;
+            if let Some(enabled) = try!(get_bool(&root, "http.enabled")) {
+                http.enabled = enabled;
+            }
+            try!(set_str(&root, "http.host", &mut http.host));
+            if let Some(port) = try!(get_port(&root, "http.port")) {
+                http.port = port;
+            }
+            if let Some(ingest) = try!(get_bool(&root, "http.ingest")) {
+                http.ingest = ingest;
+            }
+            if let Some(size) = try!(get_int(&root, "http.max_body_size")) {
+                http.max_body_size = size as usize;
+            }
+        }
+
+        {
+            let carbon = &mut config.carbon;
+            if let Some(enabled) = try!(get_bool(&root, "carbon.enabled")) {
+                carbon.enabled = enabled;
+            }
+            try!(set_str(&root, "carbon.host", &mut carbon.host));
+            if let Some(port) = try!(get_port(&root, "carbon.plaintext_port")) {
+                carbon.plaintext_port = port;
+            }
+            if let Some(port) = try!(get_port(&root, "carbon.pickle_port")) {
+                carbon.pickle_port = port;
+            }
+        }
+
+        config.unix_datagram = try!(get_unix_socket(&root, "unix_datagram"));
+        config.unix_stream = try!(get_unix_socket(&root, "unix_stream"));
+
+        {
+            let aggregator = &mut config.aggregator;
+            if let Some(ms) = try!(get_int(&root, "aggregator.flush_interval")) {
+                if ms == 0 {
+                    return Err(ConfigError::Invalid("aggregator.flush_interval must be at least 1ms"
+                        .to_string()));
+                }
+                aggregator.flush_interval = Duration::from_millis(ms as u64);
+            }
+            if let Some(pcts) = try!(get_float_array(&root, "aggregator.percent_threshold")) {
+                if pcts.iter().any(|&pct| !(pct > 0.0 && pct <= 100.0)) {
+                    return Err(ConfigError::Invalid("aggregator.percent_threshold values must be in (0, 100]"
+                        .to_string()));
+                }
+                aggregator.percent_threshold = pcts;
+            }
+            // Every delete_* flag falls back to delete_idle_stats, like in etsy statsd.
+            let all = try!(get_bool(&root, "aggregator.delete_idle_stats")).unwrap_or(false);
+            aggregator.delete_idle = DeleteIdle {
+                counters: try!(get_bool(&root, "aggregator.delete_counters")).unwrap_or(all),
+                timers: try!(get_bool(&root, "aggregator.delete_timers")).unwrap_or(all),
+                gauges: try!(get_bool(&root, "aggregator.delete_gauges")).unwrap_or(all),
+                sets: try!(get_bool(&root, "aggregator.delete_sets")).unwrap_or(all),
+            };
+            if let Some(size) = try!(get_int(&root, "aggregator.timer_reservoir")) {
+                if size <= 0 {
+                    return Err(ConfigError::Invalid("aggregator.timer_reservoir must be at least 1".to_string()));
+                }
+                aggregator.timer_reservoir = Some(size as usize);
+            }
+            if let Some(tables) = try!(get_array(&root, "aggregator.timer_sketch")) {
+                for table in tables {
+                    aggregator.timer_sketches.push(try!(get_timer_sketch(table)));
+                }
+            }
+            if let Some(tables) = try!(get_array(&root, "aggregator.rollup")) {
+                for table in tables {
+                    aggregator.rollups.push(try!(get_rollup(table)));
+                }
+            }
+            if let Some(tables) = try!(get_array(&root, "aggregator.interval")) {
+                for table in tables {
+                    aggregator.intervals.push(try!(get_metric_interval(table, aggregator.flush_interval)));
+                }
+            }
+        }
+        // Backend intervals are multiples of the aggregator's.
+        let base = config.aggregator.flush_interval;
+        config.console.flush_interval = try!(get_interval(&root, "console.flush_interval", base));
+
+        {
+            let graphite = &mut config.graphite;
+            try!(set_str(&root, "graphite.host", &mut graphite.host));
+            if let Some(port) = try!(get_port(&root, "graphite.port")) {
+                graphite.port = port;
+            }
+            if let Some(legacy) = try!(get_bool(&root, "graphite.legacy_namespace")) {
+                graphite.legacy_namespace = legacy;
+            }
+            try!(set_str(&root, "graphite.global_prefix", &mut graphite.global_prefix));
+            try!(set_str(&root, "graphite.prefix_counter", &mut graphite.prefix_counter));
+            try!(set_str(&root, "graphite.prefix_timer", &mut graphite.prefix_timer));
+            try!(set_str(&root, "graphite.prefix_gauge", &mut graphite.prefix_gauge));
+            try!(set_str(&root, "graphite.prefix_set", &mut graphite.prefix_set));
+            graphite.key_sanitize = try!(get_sanitizer(&root, "graphite.key_sanitize"));
+            graphite.tags = try!(get_bool(&root, "graphite.tags")).unwrap_or(false);
+            graphite.flush_interval = try!(get_interval(&root, "graphite.flush_interval", base));
+        }
+
+        {
+            let forward = &mut config.forward;
+            try!(set_str(&root, "forward.host", &mut forward.host));
+            if let Some(port) = try!(get_port(&root, "forward.port")) {
+                forward.port = port;
+            }
+            if let Some(error) = try!(get_float(&root, "forward.relative_error")) {
+                if !(error > 0.0 && error < 1.0) {
+                    return Err(ConfigError::Invalid("forward.relative_error must be between 0 and 1"
+                        .to_string()));
+                }
+                forward.relative_error = error;
+            }
+            forward.flush_interval = try!(get_interval(&root, "forward.flush_interval", base));
+
+            let listener = &mut config.forward_listener;
+            if let Some(enabled) = try!(get_bool(&root, "forward_listener.enabled")) {
+                listener.enabled = enabled;
+            }
+            try!(set_str(&root, "forward_listener.host", &mut listener.host));
+            if let Some(port) = try!(get_port(&root, "forward_listener.port")) {
+                listener.port = port;
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn wrong_type(key: &str, expected: &str, actual: &toml::Value) -> ConfigError {
+    ConfigError::Invalid(format!("`{}` should be {} but was {}", key, expected, actual.type_str()))
+}
+
+fn get_str<'a>(root: &'a toml::Value, key: &'a str) -> Result<Option<&'a str>> {
+    match root.lookup(key) {
+        None => Ok(None),
+        Some(value) => value.as_str().map(Some).ok_or_else(|| wrong_type(key, "a string", value)),
+    }
+}
+
+fn set_str(root: &toml::Value, key: &str, field: &mut String) -> Result<()> {
+    if let Some(value) = try!(get_str(root, key)) {
+        *field = value.to_string();
+    }
+    Ok(())
+}
+
+fn get_int(root: &toml::Value, key: &str) -> Result<Option<i64>> {
+    match root.lookup(key) {
+        None => Ok(None),
+        Some(value) => {
+            match value.as_integer() {
+                Some(n) if n >= 0 => Ok(Some(n)),
+                Some(_) => Err(ConfigError::Invalid(format!("`{}` must not be negative", key))),
+                None => Err(wrong_type(key, "an integer", value)),
+            }
+        }
+    }
+}
+
+fn get_port(root: &toml::Value, key: &str) -> Result<Option<u16>> {
+    match try!(get_int(root, key)) {
+        Some(port) if port > u16::MAX as i64 => {
+            Err(ConfigError::Invalid(format!("`{}` must be a port between 0 and 65535", key)))
+        }
+        port => Ok(port.map(|port| port as u16)),
+    }
+}
+
+fn get_float(root: &toml::Value, key: &str) -> Result<Option<f64>> {
+    match root.lookup(key) {
+        None => Ok(None),
+        Some(value) => {
+            match value.as_float().or_else(|| value.as_integer().map(|n| n as f64)) {
+                Some(f) => Ok(Some(f)),
+                None => Err(wrong_type(key, "a number", value)),
+            }
+        }
+    }
+}
+
+fn get_bool(root: &toml::Value, key: &str) -> Result<Option<bool>> {
+    match root.lookup(key) {
+        None => Ok(None),
+        Some(value) => value.as_bool().map(Some).ok_or_else(|| wrong_type(key, "a boolean", value)),
+    }
+}
+
+fn get_rule(table: &toml::Value) -> Result<Rule> {
+    let invalid = |message: &str| Err(ConfigError::Invalid(format!("[[rules]]: {}", message)));
+    let mut kinds = Vec::new();
+    for kind in try!(get_str_array(table, "kinds")).unwrap_or_default() {
+        kinds.push(try!(kind.parse::<StatKind>().map_err(ConfigError::Invalid)));
+    }
+
+    let mut actions = Vec::new();
+    if let Some(template) = try!(get_str(table, "rename")) {
+        actions.push(Action::Rename(template.to_string()));
+    }
+    if try!(get_bool(table, "drop")).unwrap_or(false) {
+        actions.push(Action::Drop);
+    }
+    if let Some(prefix) = try!(get_str(table, "prefix")) {
+        actions.push(Action::Prefix(prefix.to_string()));
+    }
+    if let Some(tag) = try!(get_str(table, "tag")) {
+        let mut parts = tag.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.is_empty() && tags::valid(key.as_bytes()) &&
+                                        tags::valid(value.as_bytes()) => {
+                actions.push(Action::Tag(key.to_string(), value.to_string()))
+            }
+            _ => return invalid("tag must look like `key=value`, without another `;` or `=`"),
+        }
+    }
+    if let Some(backends) = try!(get_str_array(table, "route")) {
+        if let Some(backend) = backends.iter().find(|backend| !BACKENDS.contains(&backend.as_str())) {
+            return invalid(&format!("unknown backend `{}`", backend));
+        }
+        actions.push(Action::Route(backends));
+    }
+    let action = match actions.len() {
+        1 => actions.pop().unwrap(),
+        _ => return invalid("every rule needs exactly one of rename, drop, prefix, tag and route"),
+    };
+
+    match (try!(get_str(table, "match")), try!(get_str(table, "glob"))) {
+        (Some(pattern), None) => {
+            Rule::new(pattern, kinds, action)
+                .map_err(|err| ConfigError::Invalid(format!("[[rules]]: bad pattern `{}`: {}", pattern, err)))
+        }
+        (None, Some(glob)) => Ok(Rule::glob(&Glob::new(glob), kinds, action)),
+        _ => invalid("every rule needs either match or glob"),
+    }
+}
+
+fn get_timer_sketch(table: &toml::Value) -> Result<TimerSketch> {
+    let pattern = match try!(get_str(table, "pattern")) {
+        Some(pattern) => Glob::new(pattern),
+        None => return Err(ConfigError::Invalid("every [[aggregator.timer_sketch]] needs a pattern".to_string())),
+    };
+    let kind = match try!(get_str(table, "kind")).unwrap_or("ddsketch") {
+        "ddsketch" => {
+            let relative_error = try!(get_float(table, "relative_error")).unwrap_or(DEFAULT_RELATIVE_ERROR);
+            if !(relative_error > 0.0 && relative_error < 1.0) {
+                return Err(ConfigError::Invalid("timer_sketch relative_error must be between 0 and 1".to_string()));
+            }
+            SketchKind::DDSketch { relative_error: relative_error }
+        }
+        "tdigest" => {
+            let compression = try!(get_float(table, "compression")).unwrap_or(DEFAULT_COMPRESSION);
+            if compression < 10.0 {
+                return Err(ConfigError::Invalid("timer_sketch compression must be at least 10".to_string()));
+            }
+            SketchKind::TDigest { compression: compression }
+        }
+        kind => return Err(ConfigError::Invalid(format!("unknown timer_sketch kind `{}`", kind))),
+    };
+    Ok(TimerSketch {
+        pattern: pattern,
+        kind: kind,
+    })
+}
+
+fn get_rollup(table: &toml::Value) -> Result<Rollup> {
+    let invalid = |message: &str| Err(ConfigError::Invalid(format!("[[aggregator.rollup]]: {}", message)));
+    let pattern = match try!(get_str(table, "pattern")) {
+        Some(pattern) => Glob::new(pattern),
+        None => return invalid("every rollup needs a pattern"),
+    };
+    let tags = match (try!(get_str_array(table, "keep")), try!(get_str_array(table, "drop"))) {
+        (Some(keep), None) => TagSubset::Keep(keep),
+        (None, Some(drop)) => TagSubset::Drop(drop),
+        _ => return invalid("every rollup needs either keep or drop"),
+    };
+    let backends = try!(get_str_array(table, "backends"));
+    if let Some(backend) = backends.iter().flatten().find(|backend| !BACKENDS.contains(&backend.as_str())) {
+        return invalid(&format!("unknown backend `{}`", backend));
+    }
+    Ok(Rollup {
+        pattern: pattern,
+        tags: tags,
+        backends: backends,
+    })
+}
+
+fn get_metric_interval(table: &toml::Value, base: Duration) -> Result<MetricInterval> {
+    let pattern = match try!(get_str(table, "pattern")) {
+        Some(pattern) => Glob::new(pattern),
+        None => return Err(ConfigError::Invalid("every [[aggregator.interval]] needs a pattern".to_string())),
+    };
+    match try!(get_interval(table, "interval", base)) {
+        Some(interval) => {
+            Ok(MetricInterval {
+                pattern: pattern,
+                interval: interval,
+            })
+        }
+        None => Err(ConfigError::Invalid("every [[aggregator.interval]] needs an interval".to_string())),
+    }
+}
+
+// A flush interval in milliseconds, which has to be a multiple of `base`.
+fn get_interval(root: &toml::Value, key: &str, base: Duration) -> Result<Option<Duration>> {
+    let ms = match try!(get_int(root, key)) {
+        Some(ms) => ms as u64,
+        None => return Ok(None),
+    };
+    let base_ms = base.as_millis() as u64;
+    if ms == 0 || ms % base_ms != 0 {
+        return Err(ConfigError::Invalid(format!("`{}` must be a multiple of aggregator.flush_interval ({}ms)",
+                                                key,
+                                                base_ms)));
+    }
+    Ok(Some(Duration::from_millis(ms)))
+}
+
+fn get_array<'a>(root: &'a toml::Value, key: &'a str) -> Result<Option<&'a [toml::Value]>> {
+    match root.lookup(key) {
+        None => Ok(None),
+        Some(value) => value.as_slice().map(Some).ok_or_else(|| wrong_type(key, "an array", value)),
+    }
+}
+
+fn get_str_array(root: &toml::Value, key: &str) -> Result<Option<Vec<String>>> {
+    let values = match try!(get_array(root, key)) {
+        Some(values) => values,
+        None => return Ok(None),
+    };
+    let mut strs = Vec::with_capacity(values.len());
+    for value in values {
+        match value.as_str() {
+            Some(s) => strs.push(s.to_string()),
+            None => return Err(wrong_type(key, "an array of strings", value)),
+        }
+    }
+    Ok(Some(strs))
+}
+
+fn get_float_array(root: &toml::Value, key: &str) -> Result<Option<Vec<f64>>> {
+    let values = match try!(get_array(root, key)) {
+        Some(values) => values,
+        None => return Ok(None),
+    };
+    let mut floats = Vec::with_capacity(values.len());
+    for value in values {
+        match value.as_float().or_else(|| value.as_integer().map(|n| n as f64)) {
+            Some(f) => floats.push(f),
+            None => return Err(wrong_type(key, "an array of numbers", value)),
+        }
+    }
+    Ok(Some(floats))
+}
+
+fn get_unix_socket(root: &toml::Value, section: &str) -> Result<Option<UnixSocketConfig>> {
+    let key = |name: &str| format!("{}.{}", section, name);
+    let mut socket = match try!(get_str(root, &key("path"))) {
+        Some(path) => UnixSocketConfig::new(path),
+        None => return Ok(None),
+    };
+    // TOML has no octal integers, so the mode is written as a string like "0660".
+    if let Some(mode) = try!(get_str(root, &key("mode"))) {
+        match u32::from_str_radix(mode, 8) {
+            Ok(bits) if bits <= 0o7777 => socket.mode = Some(bits),
+            _ => return Err(ConfigError::Invalid(format!("`{}` is not an octal mode", key("mode")))),
+        }
+    }
+    if let Some(remove_stale) = try!(get_bool(root, &key("remove_stale"))) {
+        socket.remove_stale = remove_stale;
+    }
+    match try!(get_str(root, &key("peer_tag"))) {
+        Some("pid") => socket.peer_tag = Some(PeerTag::Pid),
+        Some("container") => socket.peer_tag = Some(PeerTag::Container),
+        Some(other) => {
+            return Err(ConfigError::Invalid(format!("unknown {} `{}`", key("peer_tag"), other)));
+        }
+        None => {}
+    }
+    Ok(Some(socket))
+}
+
+fn get_sanitizer(root: &toml::Value, key: &str) -> Result<Option<KeySanitizer>> {
+    match try!(get_str(root, key)) {
+        None => Ok(None),
+        Some(name) => {
+            name.parse()
+                .map(Some)
+                .map_err(|err| ConfigError::Invalid(format!("`{}`: {}", key, err)))
+        }
+    }
+}
+
+#[test]
+fn test_config_defaults() {
+    let config = Config::from_toml("").unwrap();
+    assert_eq!(config, Config::default());
+}
+
+#[test]
+fn test_config_udp() {
+    let config = Config::from_toml("[udp]\nhost = \"0.0.0.0\"\nport = 8125\nworkers = 4\n\
+                                    reuse_port = true\nrecv_buffer_size = 8388608\n")
+        .unwrap();
+    assert_eq!(config.udp.host, "0.0.0.0");
+    assert_eq!(config.udp.port, 8125);
+    assert_eq!(config.udp.workers, 4);
+    assert!(config.udp.reuse_port);
+    assert_eq!(config.udp.recv_buffer_size, Some(8388608));
+}
+
+#[test]
+fn test_config_udp_listeners() {
+    let config = Config::from_toml(r#"
+[udp]
+host = "::"
+dual_stack = false
+
+[[udp.listener]]
+port = 9125
+prefix = "tenant-a"
+
+[[udp.listener]]
+host = "10.0.0.1"
+port = 9126
+dual_stack = true
+"#)
+        .unwrap();
+    let listeners = config.udp.all_listeners();
+    assert_eq!(listeners.len(), 3);
+    assert_eq!((listeners[0].host.as_str(), listeners[0].port), ("::", 13265));
+    assert_eq!(listeners[1],
+               UdpListener {
+                   host: "::".to_string(),
+                   port: 9125,
+                   prefix: Some("tenant-a".to_string()),
+                   dual_stack: false,
+               });
+    assert_eq!((listeners[2].host.as_str(), listeners[2].prefix.clone()), ("10.0.0.1", None));
+    assert!(listeners[2].dual_stack);
+    assert!(Config::from_toml("[[udp.listener]]\nhost = \"::1\"\n").is_err());
+}
+
+#[test]
+fn test_config_parser() {
+    let config = Config::from_toml("[parser]\nmode = \"strict\"\nlog_bad_lines_per_minute = 5\n")
+        .unwrap();
+    assert_eq!(config.parser.mode, ParseMode::Strict);
+    assert_eq!(config.parser.log_bad_lines_per_minute, 5);
+    assert!(Config::from_toml("[parser]\nmode = \"sloppy\"\n").is_err());
+}
+
+#[test]
+fn test_config_key_sanitize() {
+    let config = Config::from_toml("[parser]\nkey_sanitize = \"off\"\n\
+                                    [console]\nkey_sanitize = \"prometheus\"\n")
+        .unwrap();
+    assert_eq!(config.parser.key_sanitize, KeySanitizer::Off);
+    assert_eq!(config.console.key_sanitize, Some(KeySanitizer::Prometheus));
+    assert_eq!(Config::default().parser.key_sanitize, KeySanitizer::Etsy);
+    assert!(Config::from_toml("[parser]\nkey_sanitize = \"graphite\"\n").is_err());
+}
+
+#[test]
+fn test_config_aggregator() {
+    let config = Config::from_toml("backends = [\"graphite\", \"console\"]\n\
+                                    [aggregator]\nflush_interval = 1000\n\
+                                    percent_threshold = [90.0, 99.5]\n\
+                                    delete_idle_stats = true\ndelete_gauges = false\n")
+        .unwrap();
+    assert_eq!(config.backends, vec!["graphite", "console"]);
+    assert_eq!(config.aggregator.flush_interval, Duration::from_secs(1));
+    assert_eq!(config.aggregator.percent_threshold, vec![90.0, 99.5]);
+    for pcts in &["[0.0]", "[90.0, 100.5]", "[-5.0]"] {
+        let raw = format!("[aggregator]\npercent_threshold = {}\n", pcts);
+        match Config::from_toml(&raw) {
+            Err(ConfigError::Invalid(_)) => {}
+            other => panic!("{} was not rejected: {:?}", pcts, other),
+        }
+    }
+    assert_eq!(config.aggregator.delete_idle,
+               DeleteIdle { gauges: false, ..DeleteIdle::all(true) });
+    assert!(Config::from_toml("backends = [\"carbon\"]\n").is_err());
+    assert_eq!(config.aggregator.timer_reservoir, None);
+    let config = Config::from_toml("[aggregator]\ntimer_reservoir = 1000\n").unwrap();
+    assert_eq!(config.aggregator.timer_reservoir, Some(1000));
+    assert!(Config::from_toml("[aggregator]\ntimer_reservoir = 0\n").is_err());
+}
+
+#[test]
+fn test_config_rules() {
+    let config = Config::from_toml("[[rules]]\nmatch = '^legacy\\.(.*)'\nrename = \"app.$1\"\n\
+                                    [[rules]]\nglob = \"debug.*\"\nkinds = [\"c\", \"ms\"]\ndrop = true\n\
+                                    [[rules]]\nglob = \"app.*\"\ntag = \"env=prod\"\n\
+                                    [[rules]]\nglob = \"billing.*\"\nroute = [\"graphite\"]\n")
+        .unwrap();
+    let glob = |glob: &str, kinds: Vec<StatKind>, action: Action| Rule::glob(&Glob::new(glob), kinds, action);
+    assert_eq!(config.rules,
+               Rules::new(vec![Rule::new(r"^legacy\.(.*)", vec![], Action::Rename("app.$1".to_string())).unwrap(),
+                               glob("debug.*", vec![StatKind::Counter, StatKind::Timer], Action::Drop),
+                               glob("app.*", vec![], Action::Tag("env".to_string(), "prod".to_string())),
+                               glob("billing.*", vec![], Action::Route(vec!["graphite".to_string()]))]));
+    assert!(Config::from_toml("[[rules]]\nglob = \"a\"\n").is_err());
+    assert!(Config::from_toml("[[rules]]\nglob = \"a\"\ndrop = true\nprefix = \"b\"\n").is_err());
+    assert!(Config::from_toml("[[rules]]\nmatch = \"(\"\ndrop = true\n").is_err());
+    assert!(Config::from_toml("[[rules]]\nglob = \"a\"\nroute = [\"carbon\"]\n").is_err());
+    assert!(Config::from_toml("[[rules]]\nglob = \"a\"\nkinds = [\"x\"]\ndrop = true\n").is_err());
+    assert!(Config::from_toml("[[rules]]\ndrop = true\n").is_err());
+    assert!(Config::from_toml("[[rules]]\nglob = \"a\"\ntag = \"env=prod;dc=eu\"\n").is_err());
+}
+
+#[test]
+fn test_config_templates() {
+    let config = Config::from_toml("[tags]\ntemplates = [\"*.*.api.* env.host.measurement* source=statsd\", \
+                                    \"host.measurement*\"]\n\
+                                    [graphite]\ntags = true\n")
+        .unwrap();
+    assert_eq!(config.templates,
+               Templates::new(vec!["*.*.api.* env.host.measurement* source=statsd".parse().unwrap(),
+                                   "host.measurement*".parse().unwrap()]));
+    assert!(config.graphite.tags);
+    assert!(!Config::default().graphite.tags);
+    assert!(Config::from_toml("[tags]\ntemplates = [\"a b c d\"]\n").is_err());
+}
+
+#[test]
+fn test_config_timer_sketches() {
+    let config = Config::from_toml("[[aggregator.timer_sketch]]\npattern = \"api.*\"\nkind = \"tdigest\"\n\
+                                    compression = 200\n\
+                                    [[aggregator.timer_sketch]]\npattern = \"*\"\nrelative_error = 0.02\n")
+        .unwrap();
+    assert_eq!(config.aggregator.timer_sketches,
+               vec![TimerSketch {
+                        pattern: Glob::new("api.*"),
+                        kind: SketchKind::TDigest { compression: 200.0 },
+                    },
+                    TimerSketch {
+                        pattern: Glob::new("*"),
+                        kind: SketchKind::DDSketch { relative_error: 0.02 },
+                    }]);
+    assert!(Config::default().aggregator.timer_sketches.is_empty());
+    assert!(Config::from_toml("[[aggregator.timer_sketch]]\nkind = \"tdigest\"\n").is_err());
+    assert!(Config::from_toml("[[aggregator.timer_sketch]]\npattern = \"*\"\nkind = \"hdr\"\n").is_err());
+    assert!(Config::from_toml("[[aggregator.timer_sketch]]\npattern = \"*\"\nrelative_error = 1.5\n").is_err());
+}
+
+#[test]
+fn test_config_intervals() {
+    let config = Config::from_toml("[aggregator]\nflush_interval = 1000\n\
+                                    [[aggregator.interval]]\npattern = \"batch.*\"\ninterval = 60000\n\
+                                    [graphite]\nflush_interval = 10000\n")
+        .unwrap();
+    assert_eq!(config.aggregator.intervals,
+               vec![MetricInterval {
+                        pattern: Glob::new("batch.*"),
+                        interval: Duration::from_secs(60),
+                    }]);
+    assert_eq!(config.graphite.flush_interval, Some(Duration::from_secs(10)));
+    assert_eq!(config.console.flush_interval, None);
+    let uneven = "[aggregator]\nflush_interval = 10000\n[console]\nflush_interval = 15000\n";
+    assert!(Config::from_toml(uneven).is_err());
+    assert!(Config::from_toml("[[aggregator.interval]]\npattern = \"*\"\n").is_err());
+    assert!(Config::from_toml("[[aggregator.interval]]\ninterval = 20000\n").is_err());
+}
+
+#[test]
+fn test_config_rollups() {
+    let config = Config::from_toml("[[aggregator.rollup]]\npattern = \"api.*\"\ndrop = [\"host\"]\n\
+                                    [[aggregator.rollup]]\npattern = \"*\"\nkeep = [\"service\"]\n\
+                                    backends = [\"graphite\"]\n")
+        .unwrap();
+    assert_eq!(config.aggregator.rollups,
+               vec![Rollup {
+                        pattern: Glob::new("api.*"),
+                        tags: TagSubset::Drop(vec!["host".to_string()]),
+                        backends: None,
+                    },
+                    Rollup {
+                        pattern: Glob::new("*"),
+                        tags: TagSubset::Keep(vec!["service".to_string()]),
+                        backends: Some(vec!["graphite".to_string()]),
+                    }]);
+    assert!(Config::from_toml("[[aggregator.rollup]]\ndrop = [\"host\"]\n").is_err());
+    assert!(Config::from_toml("[[aggregator.rollup]]\npattern = \"*\"\n").is_err());
+    assert!(Config::from_toml("[[aggregator.rollup]]\npattern = \"*\"\nkeep = []\ndrop = []\n").is_err());
+    assert!(Config::from_toml("[[aggregator.rollup]]\npattern = \"*\"\nkeep = []\nbackends = [\"x\"]\n").is_err());
+}
+
+#[test]
+fn test_config_limits() {
+    let config = Config::from_toml("[limits]\nmax_buckets = 100000\nmax_timers = 5000\n\
+                                    overflow = \"rustatsd.overflow\"\n\
+                                    [[limits.prefix]]\nprefix = \"api.\"\nmax_buckets = 1000\n")
+        .unwrap();
+    let limits = config.aggregator.limits;
+    assert_eq!(limits.max_buckets, Some(100000));
+    assert_eq!(limits.max_timers, Some(5000));
+    assert_eq!(limits.max_counters, None);
+    assert_eq!(limits.overflow, Some("rustatsd.overflow".to_string()));
+    assert_eq!(limits.prefixes,
+               vec![PrefixLimit { prefix: "api.".to_string(), max_buckets: 1000 }]);
+    assert!(Config::from_toml("[[limits.prefix]]\nprefix = \"api.\"\n").is_err());
+}
+
+#[test]
+fn test_config_self_metrics() {
+    assert_eq!(Config::from_toml("").unwrap().self_metrics.prefix, "statsd");
+    let config = Config::from_toml("[self_metrics]\nprefix = \"rustatsd.prod\"\n").unwrap();
+    assert!(config.self_metrics.enabled);
+    assert_eq!(config.self_metrics.prefix, "rustatsd.prod");
+}
+
+#[test]
+fn test_config_persistence() {
+    assert_eq!(Config::from_toml("").unwrap().persistence, None);
+    let config = Config::from_toml("[persistence]\npath = \"/var/lib/rustatsd/state\"\n\
+                                    interval = 5000\nsets = true\n")
+        .unwrap();
+    let file = config.persistence.unwrap();
+    assert_eq!(file.path.to_str(), Some("/var/lib/rustatsd/state"));
+    assert_eq!(file.interval, Duration::from_secs(5));
+    assert!(file.sets && !file.counters);
+}
+
+#[test]
+fn test_config_unix_sockets() {
+    let config = Config::from_toml("[unix_datagram]\npath = \"/run/statsd.sock\"\nmode = \"0660\"\n\
+                                    peer_tag = \"container\"\n\
+                                    [unix_stream]\npath = \"/run/statsd-stream.sock\"\n\
+                                    remove_stale = false\n")
+        .unwrap();
+    let datagram = config.unix_datagram.unwrap();
+    assert_eq!(datagram.path, PathBuf::from("/run/statsd.sock"));
+    assert_eq!(datagram.mode, Some(0o660));
+    assert!(datagram.remove_stale);
+    assert_eq!(datagram.peer_tag, Some(PeerTag::Container));
+    let stream = config.unix_stream.unwrap();
+    assert_eq!((stream.mode, stream.remove_stale, stream.peer_tag), (None, false, None));
+
+    assert!(Config::from_toml("[unix_stream]\npath = \"/s\"\nmode = \"0999\"\n").is_err());
+    assert!(Config::from_toml("[unix_stream]\npath = \"/s\"\npeer_tag = \"uid\"\n").is_err());
+}
+
+#[test]
+fn test_config_carbon() {
+    assert!(!Config::default().carbon.enabled);
+    let config = Config::from_toml("[carbon]\nenabled = true\npickle_port = 3004\n").unwrap();
+    assert!(config.carbon.enabled);
+    assert_eq!(config.carbon.host, "127.0.0.1");
+    assert_eq!(config.carbon.plaintext_port, 2003);
+    assert_eq!(config.carbon.pickle_port, 3004);
+}
+
+#[test]
+fn test_config_proxy() {
+    assert_eq!(Config::default().proxy, None);
+    let config = Config::from_toml("[proxy]\nvnodes = 50\n[[proxy.node]]\nport = 8125\n\
+                                    [[proxy.node]]\nhost = \"10.0.0.2\"\nport = 8125\nadmin_port = 9126\n")
+        .unwrap();
+    let proxy = config.proxy.unwrap();
+    assert_eq!(proxy.vnodes, 50);
+    assert_eq!(proxy.check_interval, Duration::from_secs(1));
+    assert_eq!(proxy.nodes,
+               vec![ProxyNode {
+                        host: "127.0.0.1".to_string(),
+                        port: 8125,
+                        admin_port: 8126,
+                    },
+                    ProxyNode {
+                        host: "10.0.0.2".to_string(),
+                        port: 8125,
+                        admin_port: 9126,
+                    }]);
+    assert!(Config::from_toml("[[proxy.node]]\nhost = \"a\"\n").is_err());
+    assert!(Config::from_toml("[proxy]\nvnodes = 0\n[[proxy.node]]\nport = 1\n").is_err());
+}
+
+#[test]
+fn test_config_forward() {
+    let config = Config::from_toml("backends = [\"forward\"]\n[forward]\nhost = \"central\"\n\
+                                    relative_error = 0.02\n[forward_listener]\nenabled = true\n")
+        .unwrap();
+    assert_eq!(config.forward.host, "central");
+    assert_eq!(config.forward.port, 8128);
+    assert_eq!(config.forward.relative_error, 0.02);
+    assert!(config.forward_listener.enabled);
+    assert_eq!(config.forward_listener.port, 8128);
+    assert!(Config::from_toml("[forward]\nrelative_error = 1\n").is_err());
+}
+
+#[test]
+fn test_config_graphite() {
+    let config = Config::from_toml("[graphite]\nhost = \"carbon\"\nlegacy_namespace = false\n\
+                                    global_prefix = \"prod\"\n")
+        .unwrap();
+    assert_eq!(config.graphite.host, "carbon");
+    assert_eq!(config.graphite.port, 2003);
+    assert!(!config.graphite.legacy_namespace);
+    assert_eq!(config.graphite.global_prefix, "prod");
+    assert_eq!(config.graphite.prefix_counter, "counters");
+}
+
+#[test]
+fn test_config_wrong_type() {
+    match Config::from_toml("[udp]\nworkers = \"four\"\n") {
+        Err(ConfigError::Invalid(message)) => assert!(message.contains("udp.workers"), "{}", message),
+        other => panic!("expected an invalid config, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_config_port_range() {
+    assert_eq!(Config::from_toml("[udp]\nport = 65535\n").unwrap().udp.port, 65535);
+    assert!(Config::from_toml("[udp]\nport = 70000\n").is_err());
+    assert!(Config::from_toml("[graphite]\nport = 65536\n").is_err());
+    assert!(Config::from_toml("[[proxy.node]]\nport = 8125\nadmin_port = 99999\n").is_err());
+}
@@ -0,0 +1,884 @@
+use mio::*;
+use mio::channel::{channel, Receiver, Sender};
+use std::sync::mpsc::{self, TryRecvError};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use aggregator::{Aggregator, AggregatorOptions, Snapshot};
+use metrics::{StatKind, StatMsg};
+use pattern::Glob;
+use persist::StateFile;
+use rules::{Outcome, Rules};
+use tags::{self, Templates};
+use stats::ServerStats;
+use sync_token;
+use backends::Backend;
+
+const RX_TOKEN: Token = Token(0);
+const COMMAND_TOKEN: Token = Token(1);
+// How long the loop may sleep before it looks at the shutdown token again.
+const SHUTDOWN_CHECK: u64 = 200;
+// Offending prefixes named in the log when bucket limits refuse new buckets.
+const TOP_OFFENDERS: usize = 5;
+
+/// Requests the flusher answers from its own thread, see `Flusher::commands`. Metrics already
+/// sent to the flusher are processed before a command is.
+pub enum Command {
+    /// Flush to the backends right away and send back what was flushed.
+    Flush(mpsc::Sender<Snapshot>),
+    /// Send back what the next flush would contain, without flushing.
+    Snapshot(mpsc::Sender<Snapshot>),
+}
+
+/// Owns the aggregator: feeds it everything the frontends send and hands a snapshot to every
+/// backend once per flush interval.
+pub struct Flusher {
+    aggregator: Aggregator,
+    backends: Vec<Box<dyn Backend>>,
+    rx: Receiver<StatMsg>,
+    commands: Receiver<Command>,
+    commands_tx: Sender<Command>,
+    state_file: Option<StateFile>,
+    shutdown: Option<Arc<sync_token::Token>>,
+    stats: Arc<ServerStats>,
+    self_metrics: Option<SelfMetrics>,
+    rules: Rules,
+    // Backends of the buckets `rules` routed, by bucket name.
+    routes: HashMap<(StatKind, String), Vec<String>>,
+    templates: Templates,
+    // The dotted names of tagged buckets, for backends without tags. Those `templates` tagged
+    // keep the name they came in with.
+    untagged: HashMap<String, String>,
+    // `AggregatorOptions::intervals` and every backend's flush interval, in flushes.
+    intervals: Vec<(Glob, u64)>,
+    backend_flushes: Vec<u64>,
+    // The buckets backends get aggregated over more than one flush, by number of flushes.
+    resolutions: Vec<Resolution>,
+    flushes: u64,
+}
+
+// A second aggregator for the buckets some backend gets every `every` flushes: those whose
+// interval is `every` for backends flushed more often, and all of them for backends flushed every
+// `every` flushes. It sees the same messages as the flusher's own aggregator, so every kind comes
+// out like it would from a longer flush interval.
+struct Resolution {
+    every: u64,
+    aggregator: Aggregator,
+}
+
+// What the `statsd.*` self-metrics of the next flush are computed from.
+struct SelfMetrics {
+    prefix: String,
+    // Totals at the last flush, the counters report the difference.
+    packets_received: usize,
+    metrics_received: usize,
+    bad_lines_seen: usize,
+    // Timings of the last flush, reported with the next one like etsy does.
+    processing_time: Option<Duration>,
+    backend_timings: Vec<(String, Duration, bool)>,
+}
+
+impl Flusher {
+    /// Buckets of backends or `AggregatorOptions::intervals` with longer flush intervals than
+    /// the aggregator's are aggregated over those intervals as well. Gauges the aggregator
+    /// already has, e.g. restored ones, are copied there.
+    pub fn new(aggregator: Aggregator, backends: Vec<Box<dyn Backend>>) -> (Flusher, Sender<StatMsg>) {
+        let (tx, rx) = channel::<StatMsg>();
+        let (commands_tx, commands) = channel::<Command>();
+        let options = aggregator.options().clone();
+        let base = options.flush_interval;
+        let intervals: Vec<(Glob, u64)> = options.intervals
+            .iter()
+            .map(|interval| (interval.pattern.clone(), flushes(Some(interval.interval), base)))
+            .collect();
+        let backend_flushes: Vec<u64> =
+            backends.iter().map(|backend| flushes(backend.flush_interval(), base)).collect();
+        let mut levels = Vec::new();
+        for metric in Some(1).into_iter().chain(intervals.iter().map(|&(_, every)| every)) {
+            for &backend in backend_flushes.iter() {
+                let every = cmp::max(metric, backend);
+                if every > 1 && !levels.contains(&every) {
+                    levels.push(every);
+                }
+            }
+        }
+        levels.sort();
+        let resolutions: Vec<Resolution> = levels.into_iter()
+            .map(|every| {
+                Resolution {
+                    every: every,
+                    aggregator: Aggregator::new(AggregatorOptions {
+                        flush_interval: base * every as u32,
+                        ..options.clone()
+                    }),
+                }
+            })
+            .collect();
+        let gauges = if resolutions.is_empty() { Default::default() } else { aggregator.snapshot(0).gauges };
+        let mut flusher = Flusher {
+            aggregator: aggregator,
+            backends: backends,
+            rx: rx,
+            commands: commands,
+            commands_tx: commands_tx,
+            state_file: None,
+            shutdown: None,
+            stats: Arc::new(ServerStats::new()),
+            self_metrics: None,
+            rules: Rules::default(),
+            routes: HashMap::new(),
+            templates: Templates::default(),
+            untagged: HashMap::new(),
+            intervals: intervals,
+            backend_flushes: backend_flushes,
+            resolutions: resolutions,
+            flushes: 0,
+        };
+        for (name, value) in gauges {
+            flusher.dispatch(&StatMsg::Gauge(name, value), true);
+        }
+        (flusher, tx)
+    }
+
+    /// A channel for `Command`s, usable from any thread while the flusher runs.
+    pub fn commands(&self) -> Sender<Command> {
+        self.commands_tx.clone()
+    }
+
+    /// Saves the aggregator state to `file` periodically and when the flusher stops, and right after
+    /// each flush if counters or sets are kept.
+    pub fn set_state_file(&mut self, file: StateFile) {
+        self.state_file = Some(file);
+    }
+
+    /// Stops the flusher (after a last flush) once `token` is triggered.
+    pub fn set_shutdown(&mut self, token: Arc<sync_token::Token>) {
+        self.shutdown = Some(token);
+    }
+
+    /// Shares the server stats the frontends count into, the flusher adds flush and backend
+    /// outcomes to them.
+    pub fn set_stats(&mut self, stats: Arc<ServerStats>) {
+        self.stats = stats;
+    }
+
+    /// Reports the server's own metrics with every flush, under `prefix` (`statsd` in etsy).
+    pub fn set_self_metrics(&mut self, prefix: &str) {
+        self.self_metrics = Some(SelfMetrics {
+            prefix: prefix.to_string(),
+            packets_received: 0,
+            metrics_received: 0,
+            bad_lines_seen: 0,
+            processing_time: None,
+            backend_timings: Vec::new(),
+        });
+    }
+
+    /// Rewrites, drops and routes every metric received with `rules` before aggregating it.
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.rules = rules;
+    }
+
+    /// Splits the names of metrics received into a measurement and tags with `templates`,
+    /// after `rules`. Backends that don't support tags still get the dotted names.
+    pub fn set_templates(&mut self, templates: Templates) {
+        self.templates = templates;
+    }
+
+    /// Runs until every sender has hung up or shutdown was requested, flushing one last time on
+    /// the way out.
+    pub fn run(&mut self) {
+        let poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(1024);
+        poll.register(&self.rx, RX_TOKEN, Ready::readable(), PollOpt::edge()).unwrap();
+        poll.register(&self.commands, COMMAND_TOKEN, Ready::readable(), PollOpt::edge()).unwrap();
+
+        let interval = self.aggregator.options().flush_interval;
+        let mut next_flush = Instant::now() + interval;
+        let mut next_save = self.state_file.as_ref().map(|file| Instant::now() + file.interval);
+        loop {
+            self.stats.heartbeat();
+            if self.shutdown_requested() {
+                info!("Shutting down");
+                self.drain();
+                break;
+            }
+            let now = Instant::now();
+            if now >= next_flush {
+                self.flush();
+                next_flush += interval;
+                continue;
+            }
+            if let Some(at) = next_save {
+                if now >= at {
+                    self.save();
+                    next_save = self.state_file.as_ref().map(|file| at + file.interval);
+                    continue;
+                }
+            }
+
+            let mut timeout = cmp::min(next_flush, next_save.unwrap_or(next_flush)) - now;
+            if self.shutdown.is_some() {
+                timeout = cmp::min(timeout, Duration::from_millis(SHUTDOWN_CHECK));
+            }
+            poll.poll(&mut events, Some(timeout)).unwrap();
+            let mut disconnected = false;
+            for event in events.iter() {
+                match event.token() {
+                    RX_TOKEN => disconnected |= !self.drain(),
+                    COMMAND_TOKEN => {
+                        disconnected |= !self.drain();
+                        self.run_commands();
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            if disconnected {
+                break;
+            }
+        }
+        self.flush_all();
+        self.save();
+    }
+
+    fn shutdown_requested(&self) -> bool {
+        match self.shutdown {
+            Some(ref token) => token.is_triggered(),
+            None => false,
+        }
+    }
+
+    // Returns false once all senders are gone.
+    fn drain(&mut self) -> bool {
+        loop {
+            match self.rx.try_recv() {
+                Ok(msg) => {
+                    let msg = if self.rules.is_empty() && self.templates.is_empty() {
+                        msg
+                    } else {
+                        match self.rewrite(msg) {
+                            Some(msg) => msg,
+                            None => continue,
+                        }
+                    };
+                    self.forget(&msg);
+                    self.dispatch(&msg, false);
+                    self.aggregator.process(msg);
+                }
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    // Runs `msg`, or every message of a batch, through the rules and templates. `None` once
+    // everything in it is dropped.
+    fn rewrite(&mut self, msg: StatMsg) -> Option<StatMsg> {
+        let mut msg = match msg {
+            StatMsg::Bat(msgs) => {
+                let msgs = msgs.into_iter().filter_map(|msg| self.rewrite(msg)).collect();
+                return StatMsg::from_msgs(msgs);
+            }
+            msg => msg,
+        };
+        let kind = msg.kind().unwrap();
+        let (mut name, route) = match self.rules.apply(&kind, msg.name().unwrap()) {
+            Outcome::Dropped => return None,
+            Outcome::Kept(name, route) => (name, route),
+        };
+        if let Some(tagged) = self.templates.apply(&name) {
+            if !self.untagged.contains_key(&tagged) {
+                self.untagged.insert(tagged.clone(), name);
+            }
+            name = tagged;
+        }
+        if let Some(backends) = route {
+            // Histograms are aggregated as timers, and flushed as ones.
+            let kind = if kind == StatKind::Histogram { StatKind::Timer } else { kind };
+            self.routes.entry((kind, name.clone())).or_insert(backends);
+        }
+        *msg.name_mut().unwrap() = name;
+        Some(msg)
+    }
+
+    // Hands the aggregators of `resolutions` what they need of `msg`.
+    fn dispatch(&mut self, msg: &StatMsg, unlimited: bool) {
+        for i in 0..self.resolutions.len() {
+            let every = self.resolutions[i].every;
+            if let Some(msg) = self.select(msg, every) {
+                let aggregator = &mut self.resolutions[i].aggregator;
+                if unlimited {
+                    aggregator.process_unlimited(msg);
+                } else {
+                    aggregator.process(msg);
+                }
+            }
+        }
+    }
+
+    // The part of `msg` some backend gets aggregated over `every` flushes. Deletes go everywhere.
+    fn select(&self, msg: &StatMsg, every: u64) -> Option<StatMsg> {
+        match *msg {
+            StatMsg::Bat(ref msgs) => {
+                StatMsg::from_msgs(msgs.iter().filter_map(|msg| self.select(msg, every)).collect())
+            }
+            StatMsg::Del(..) => Some(msg.clone()),
+            _ => {
+                let metric = metric_flushes(&self.intervals, msg.name().unwrap());
+                if self.backend_flushes.iter().any(|&backend| cmp::max(metric, backend) == every) {
+                    Some(msg.clone())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn run_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            // Nobody may be waiting for the answer anymore, which is fine.
+            let _ = match command {
+                Command::Flush(reply) => reply.send(self.flush()),
+                Command::Snapshot(reply) => reply.send(self.aggregator.snapshot(unix_time())),
+            };
+        }
+    }
+
+    // Passes deletes on to the backends before the aggregator consumes the message.
+    fn forget(&mut self, msg: &StatMsg) {
+        match *msg {
+            StatMsg::Del(ref kind, ref name) => {
+                debug!("Deleting {:?} {}", kind, name);
+                for backend in self.backends.iter_mut() {
+                    backend.delete(kind, name);
+                }
+            }
+            StatMsg::Bat(ref msgs) => {
+                for msg in msgs {
+                    self.forget(msg);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn save(&mut self) {
+        if let Some(ref file) = self.state_file {
+            match file.save(&self.aggregator) {
+                Ok(()) => debug!("Saved state to {}", file.path.display()),
+                Err(err) => error!("Could not save state to {}: {}", file.path.display(), err),
+            }
+        }
+    }
+
+    /// Flushes to every backend that is due and returns what was flushed at the aggregator's
+    /// flush interval.
+    pub fn flush(&mut self) -> Snapshot {
+        self.flush_due(false)
+    }
+
+    // Flushes longer intervals early too, on the way out.
+    fn flush_all(&mut self) -> Snapshot {
+        self.flush_due(true)
+    }
+
+    fn flush_due(&mut self, all: bool) -> Snapshot {
+        let timestamp = unix_time();
+        self.record_self_metrics();
+
+        let start = Instant::now();
+        let snapshot = self.aggregator.flush(timestamp);
+        let processing_time = start.elapsed();
+        // Counters and sets restart with every flush: a state saved before it would send them again
+        // after a crash.
+        let save = match self.state_file {
+            Some(ref file) => file.counters || file.sets,
+            None => false,
+        };
+        if save {
+            self.save();
+        }
+        self.stats.record_flush(timestamp, snapshot.len());
+        trace!("Flushing {} buckets", snapshot.len());
+        if !snapshot.rejected.is_empty() {
+            let top: Vec<String> = top_offenders(&snapshot.rejected)
+                .iter()
+                .map(|&(prefix, count)| format!("{} ({})", prefix, count))
+                .collect();
+            warn!("Bucket limits refused {} new buckets, top prefixes: {}",
+                  snapshot.rejected.values().sum::<u64>(),
+                  top.join(", "));
+        }
+
+        self.flushes += 1;
+        let mut backend_timings = Vec::with_capacity(self.backends.len());
+        self.untag(&snapshot);
+        self.flush_backends(&snapshot, 1, &mut backend_timings);
+        for i in 0..self.resolutions.len() {
+            let every = self.resolutions[i].every;
+            if all || self.flushes.is_multiple_of(every) {
+                let snapshot = self.resolutions[i].aggregator.flush(timestamp);
+                self.untag(&snapshot);
+                self.flush_backends(&snapshot, every, &mut backend_timings);
+            }
+        }
+
+        // Buckets the aggregator forgot don't need their routes and names anymore.
+        let resolutions = &self.resolutions;
+        let known = |name: &str| {
+            snapshot.contains(name) || resolutions.iter().any(|resolution| resolution.aggregator.has_bucket(name))
+        };
+        self.routes.retain(|route, _| known(&route.1));
+        self.untagged.retain(|name, _| known(name));
+
+        if let Some(ref mut metrics) = self.self_metrics {
+            metrics.processing_time = Some(processing_time);
+            metrics.backend_timings = backend_timings;
+        }
+        snapshot
+    }
+
+    // Gives tagged buckets that no template named, like rollups and tags from clients, a dotted
+    // name for backends without tag support. A dotted name another bucket already goes by would
+    // overwrite that bucket, so those keep their tagged name.
+    fn untag(&mut self, snapshot: &Snapshot) {
+        let names: Vec<&String> = snapshot.counters
+            .keys()
+            .chain(snapshot.timers.keys())
+            .chain(snapshot.gauges.keys())
+            .chain(snapshot.sets.keys())
+            .collect();
+        let mut taken: HashSet<String> = names.iter()
+            .filter(|name| !name.contains(';'))
+            .map(|&name| name.clone())
+            .collect();
+        // Dotted names handed out before get the first claim on them.
+        let (known, new): (Vec<&String>, Vec<&String>) = names.into_iter()
+            .filter(|name| name.contains(';'))
+            .partition(|&name| self.untagged.contains_key(name));
+        for name in known.into_iter().chain(new) {
+            let dotted = match self.untagged.get(name) {
+                Some(dotted) => dotted.clone(),
+                None => tags::dotted(name),
+            };
+            if dotted == *name || taken.insert(dotted.clone()) {
+                self.untagged.insert(name.clone(), dotted);
+            } else {
+                warn!("{} would be flushed as {}, which another bucket goes by, keeping its tags", name, dotted);
+                self.untagged.insert(name.clone(), name.clone());
+            }
+        }
+    }
+
+    // Hands `snapshot`, aggregated over `every` flushes, to the backends it is for: those flushed
+    // every `every` flushes get all but buckets with longer intervals, those flushed more often
+    // only buckets with an interval of `every`.
+    fn flush_backends(&mut self, snapshot: &Snapshot, every: u64, timings: &mut Vec<(String, Duration, bool)>) {
+        let (routes, untagged, intervals) = (&self.routes, &self.untagged, &self.intervals);
+        for (backend, &backend_every) in self.backends.iter_mut().zip(self.backend_flushes.iter()) {
+            if backend_every > every || (intervals.is_empty() && backend_every != every) {
+                continue;
+            }
+            let start = Instant::now();
+            let untag = !backend.supports_tags() && !untagged.is_empty();
+            let result = if routes.is_empty() && snapshot.routes.is_empty() && intervals.is_empty() && !untag {
+                backend.flush(snapshot)
+            } else {
+                let mut view = snapshot.clone();
+                view.retain(|kind, name| {
+                    routes.get(&(kind.clone(), name.to_string()))
+                        .or_else(|| snapshot.routes.get(name))
+                        .is_none_or(|backends| backends.iter().any(|routed| routed == backend.name())) &&
+                    cmp::max(metric_flushes(intervals, name), backend_every) == every
+                });
+                if untag {
+                    view.rename(|name| untagged.get(name).cloned());
+                }
+                backend.flush(&view)
+            };
+            if let Err(ref err) = result {
+                error!("{} backend failed to flush: {}", backend.name(), err);
+            }
+            self.stats.record_backend(backend.name(),
+                                      snapshot.timestamp,
+                                      result.as_ref().map(|_| ()).map_err(|err| err.to_string()));
+            timings.push((backend.name().to_string(), start.elapsed(), result.is_ok()));
+        }
+    }
+
+    // Feeds the self-metrics into the aggregator right before it is flushed, so they reach the
+    // backends like any other bucket.
+    fn record_self_metrics(&mut self) {
+        let metrics = match self.self_metrics {
+            Some(ref mut metrics) => metrics,
+            None => return,
+        };
+        let prefix = &metrics.prefix;
+        let mut msgs = Vec::new();
+        {
+            let mut counter = |name: &str, value: usize| {
+                msgs.push(StatMsg::Inc(StatKind::Counter, format!("{}.{}", prefix, name), value as i64, 1.0));
+            };
+            let stats = &self.stats;
+            let packets = stats.packets_received();
+            counter("packets_received", packets - metrics.packets_received);
+            metrics.packets_received = packets;
+            let received = stats.metrics_received();
+            counter("metrics_received", received - metrics.metrics_received);
+            metrics.metrics_received = received;
+            let bad_lines = stats.bad_lines_seen();
+            counter("bad_lines_seen", bad_lines - metrics.bad_lines_seen);
+            metrics.bad_lines_seen = bad_lines;
+        }
+
+        let rejected = self.aggregator.rejected();
+        if !rejected.is_empty() {
+            let total: u64 = rejected.values().sum();
+            msgs.push(StatMsg::Inc(StatKind::Counter, format!("{}.limits.rejected", prefix), total as i64, 1.0));
+            for &(offender, count) in top_offenders(rejected).iter() {
+                let name = format!("{}.limits.rejected_by_prefix.{}", prefix, offender);
+                msgs.push(StatMsg::Inc(StatKind::Counter, name, count as i64, 1.0));
+            }
+        }
+
+        let mut gauge = |name: String, value: u64| {
+            msgs.push(StatMsg::Set(StatKind::Gauge, name, value as i64, 1.0));
+        };
+        gauge(format!("{}.numStats", prefix), self.aggregator.len() as u64);
+        if let Some(time) = metrics.processing_time {
+            gauge(format!("{}.processing_time", prefix), millis(time));
+        }
+        for &(ref backend, time, ok) in metrics.backend_timings.iter() {
+            gauge(format!("{}.backends.{}.flush_time", prefix, backend), millis(time));
+            gauge(format!("{}.backends.{}.flush_failed", prefix, backend), if ok { 0 } else { 1 });
+        }
+        for msg in msgs {
+            self.dispatch(&msg, true);
+            self.aggregator.process_unlimited(msg);
+        }
+    }
+}
+
+// The prefixes that had the most new buckets refused, worst first.
+fn top_offenders<'a, I>(rejected: I) -> Vec<(&'a str, u64)>
+    where I: IntoIterator<Item = (&'a String, &'a u64)>
+{
+    let mut offenders: Vec<(&str, u64)> =
+        rejected.into_iter().map(|(prefix, &count)| (prefix.as_str(), count)).collect();
+    offenders.sort_by_key(|&(_, count)| cmp::Reverse(count));
+    offenders.truncate(TOP_OFFENDERS);
+    offenders
+}
+
+// How many flushes of `base` make `interval`, one without an interval.
+fn flushes(interval: Option<Duration>, base: Duration) -> u64 {
+    interval.map_or(1, |interval| cmp::max(1, (interval.as_nanos() / base.as_nanos()) as u64))
+}
+
+// The number of flushes `name` is aggregated over, going by its measurement.
+fn metric_flushes(intervals: &[(Glob, u64)], name: &str) -> u64 {
+    let (measurement, _) = tags::split(name);
+    intervals.iter().find(|(pattern, _)| pattern.matches(measurement)).map_or(1, |&(_, every)| every)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+#[cfg(test)]
+struct Recorder(Arc<::std::sync::Mutex<Vec<::aggregator::Snapshot>>>);
+
+#[cfg(test)]
+impl Backend for Recorder {
+    fn name(&self) -> &str {
+        "recorder"
+    }
+
+    fn flush(&mut self, snapshot: &::aggregator::Snapshot) -> ::std::io::Result<()> {
+        self.0.lock().unwrap().push(snapshot.clone());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_self_metrics() {
+    use aggregator::AggregatorOptions;
+
+    let flushed = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let backends: Vec<Box<dyn Backend>> = vec![Box::new(Recorder(flushed.clone()))];
+    let (mut flusher, _tx) = Flusher::new(Aggregator::new(AggregatorOptions::default()), backends);
+    let stats = Arc::new(ServerStats::new());
+    flusher.set_stats(stats.clone());
+    flusher.set_self_metrics("statsd");
+
+    stats.record_packet(3);
+    stats.record_packet(1);
+    flusher.aggregator.process("a:1|c\nb:1|c\nc:1|g\nd:1|s".parse().unwrap());
+    flusher.flush();
+    stats.record_packet(2);
+    flusher.flush();
+
+    let flushed = flushed.lock().unwrap();
+    assert_eq!(flushed[0].counters["statsd.packets_received"], 2.0);
+    assert_eq!(flushed[0].counters["statsd.metrics_received"], 4.0);
+    assert_eq!(flushed[0].counters["statsd.bad_lines_seen"], 0.0);
+    assert_eq!(flushed[0].gauges["statsd.numStats"], 4.0);
+    assert!(!flushed[0].gauges.contains_key("statsd.backends.recorder.flush_time"));
+    assert_eq!(flushed[1].counters["statsd.packets_received"], 1.0);
+    assert_eq!(flushed[1].counters["statsd.metrics_received"], 2.0);
+    assert_eq!(flushed[1].gauges["statsd.backends.recorder.flush_failed"], 0.0);
+    assert!(flushed[1].gauges.contains_key("statsd.processing_time"));
+    assert!(stats.flush_status().backends["recorder"].last_success.is_some());
+}
+
+// A backend recording what it gets under a given name, with or without tag support.
+#[cfg(test)]
+struct Named(&'static str, bool, Arc<::std::sync::Mutex<Vec<Snapshot>>>);
+
+#[cfg(test)]
+impl Backend for Named {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn supports_tags(&self) -> bool {
+        self.1
+    }
+
+    fn flush(&mut self, snapshot: &Snapshot) -> ::std::io::Result<()> {
+        self.2.lock().unwrap().push(snapshot.clone());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_rules_route() {
+    use aggregator::AggregatorOptions;
+    use rules::{Action, Rule};
+    use pattern::Glob;
+
+    let graphite = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let console = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let backends: Vec<Box<dyn Backend>> = vec![Box::new(Named("graphite", false, graphite.clone())),
+                                               Box::new(Named("console", false, console.clone()))];
+    let (mut flusher, tx) = Flusher::new(Aggregator::new(AggregatorOptions::default()), backends);
+    let route = Action::Route(vec!["graphite".to_string()]);
+    flusher.set_rules(Rules::new(vec![Rule::glob(&Glob::new("noisy.*"), vec![], Action::Drop),
+                                      Rule::glob(&Glob::new("billing.*"), vec![], route.clone()),
+                                      Rule::glob(&Glob::new("web.*"), vec![StatKind::Timer], route)]));
+    tx.send("noisy.x:1|c\nbilling.total:5|c\nweb.hits:5|ms\nweb.hits:1|c\nbilling.users:3|s".parse().unwrap())
+        .unwrap();
+    flusher.drain();
+    flusher.flush();
+
+    let (graphite, console) = (&graphite.lock().unwrap()[0], &console.lock().unwrap()[0]);
+    assert_eq!(graphite.counters.keys().collect::<Vec<_>>(), vec!["billing.total", "web.hits"]);
+    assert_eq!(graphite.set_counts["billing.users"], 1.0);
+    assert_eq!(console.counters.keys().collect::<Vec<_>>(), vec!["web.hits"]);
+    // Routes only hold for the kinds their rule matched.
+    assert_eq!(graphite.timers["web.hits"], vec![5.0]);
+    assert!(console.timers.is_empty());
+    assert!(!console.counter_rates.contains_key("billing.total"));
+    assert!(console.sets.is_empty() && console.set_counts.is_empty());
+}
+
+#[test]
+fn test_templates_untag() {
+    use aggregator::{AggregatorOptions, DeleteIdle};
+    use rules::{Action, Rule};
+
+    let tagged = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let dotted = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let backends: Vec<Box<dyn Backend>> = vec![Box::new(Named("tagged", true, tagged.clone())),
+                                               Box::new(Named("dotted", false, dotted.clone()))];
+    let options = AggregatorOptions { delete_idle: DeleteIdle::all(true), ..AggregatorOptions::default() };
+    let (mut flusher, tx) = Flusher::new(Aggregator::new(options), backends);
+    let rename = Action::Rename("prod.$1".to_string());
+    flusher.set_rules(Rules::new(vec![Rule::new(r"^legacy\.(.*)", vec![], rename).unwrap()]));
+    flusher.set_templates(Templates::new(vec!["prod.* env.host.measurement*".parse().unwrap()]));
+    tx.send("prod.web01.api.requests:1|c\nlegacy.web02.api.requests:2|c\nlocal.hits:1|c".parse().unwrap())
+        .unwrap();
+    flusher.drain();
+    flusher.flush();
+    {
+        let (tagged, dotted) = (&tagged.lock().unwrap()[0], &dotted.lock().unwrap()[0]);
+        assert_eq!(tagged.counters.keys().collect::<Vec<_>>(),
+                   vec!["api.requests;env=prod;host=web01", "api.requests;env=prod;host=web02", "local.hits"]);
+        assert_eq!(dotted.counters.keys().collect::<Vec<_>>(),
+                   vec!["local.hits", "prod.web01.api.requests", "prod.web02.api.requests"]);
+        assert_eq!(dotted.counter_rates["prod.web02.api.requests"], 0.2);
+    }
+
+    // Names of buckets that are gone are forgotten.
+    flusher.flush();
+    assert!(flusher.untagged.is_empty());
+}
+
+#[test]
+fn test_save_after_flush() {
+    use aggregator::AggregatorOptions;
+    use persist::StateFile;
+
+    let path = ::std::env::temp_dir().join(format!("rustatsd-flushed-{}", ::std::process::id()));
+    let file = StateFile { sets: true, counters: true, ..StateFile::new(&path) };
+    let (mut flusher, tx) = Flusher::new(Aggregator::new(AggregatorOptions::default()), vec![]);
+    flusher.set_state_file(file.clone());
+    tx.send("hits:3|c\nusers:1|s\nload:5|g".parse().unwrap()).unwrap();
+    flusher.drain();
+    flusher.save();
+    assert_eq!(flusher.flush().counters["hits"], 3.0);
+
+    // A crash now must not bring back what was just flushed.
+    let mut restarted = Aggregator::new(AggregatorOptions::default());
+    file.restore(&mut restarted).unwrap();
+    let snapshot = restarted.flush(0);
+    assert_eq!(snapshot.counters.get("hits").cloned().unwrap_or(0.0), 0.0);
+    assert!(snapshot.sets.get("users").into_iter().all(|members| members.is_empty()));
+    assert_eq!(snapshot.gauges["load"], 5.0);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_rollups_untag() {
+    use aggregator::{AggregatorOptions, Rollup, TagSubset};
+    use pattern::Glob;
+
+    let tagged = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let dotted = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let backends: Vec<Box<dyn Backend>> = vec![Box::new(Named("tagged", true, tagged.clone())),
+                                               Box::new(Named("dotted", false, dotted.clone()))];
+    let rollup = Rollup {
+        pattern: Glob::new("api.*"),
+        tags: TagSubset::Keep(vec!["service".to_string()]),
+        backends: None,
+    };
+    let options = AggregatorOptions { rollups: vec![rollup], ..AggregatorOptions::default() };
+    let (mut flusher, tx) = Flusher::new(Aggregator::new(options), backends);
+    flusher.set_templates(Templates::new(vec!["prod.* env.host.measurement*".parse().unwrap()]));
+    // Tags from templates, in names and from DogStatsD clients all roll up.
+    // A client tagged bucket whose dotted name is taken doesn't replace the other bucket.
+    let lines = "api.requests;host=h1;service=web:1|c\napi.requests:2|c|#host:h2,service:web\n\
+                 prod.web01.api.hits:1|c\ndb.queries.eu:5|c\ndb.queries:6|c|#dc:eu";
+    tx.send(lines.parse().unwrap()).unwrap();
+    flusher.drain();
+    flusher.flush();
+
+    {
+        let (tagged, dotted) = (&tagged.lock().unwrap()[0], &dotted.lock().unwrap()[0]);
+        assert_eq!(tagged.counters["api.requests;service=web"], 3.0);
+        assert_eq!(tagged.counters["api.hits;env=prod;host=web01"], 1.0);
+        assert_eq!(dotted.counters.keys().collect::<Vec<_>>(),
+                   vec!["api.hits",
+                        "api.requests.h1.web",
+                        "api.requests.h2.web",
+                        "api.requests.web",
+                        "db.queries.eu",
+                        "db.queries;dc=eu",
+                        "prod.web01.api.hits"]);
+        assert_eq!(dotted.counters["api.requests.web"], 3.0);
+        assert_eq!((dotted.counters["db.queries.eu"], dotted.counters["db.queries;dc=eu"]), (5.0, 6.0));
+    }
+
+    // A bucket turning up under a dotted name handed out before takes it back.
+    tx.send("cache.hits:1|c|#dc:eu".parse().unwrap()).unwrap();
+    flusher.drain();
+    flusher.flush();
+    tx.send("cache.hits.eu:2|c".parse().unwrap()).unwrap();
+    flusher.drain();
+    flusher.flush();
+    let dotted = &dotted.lock().unwrap();
+    assert_eq!(dotted[1].counters["cache.hits.eu"], 1.0);
+    assert_eq!((dotted[2].counters["cache.hits.eu"], dotted[2].counters["cache.hits;dc=eu"]), (2.0, 0.0));
+}
+
+// A backend flushed at its own interval rather than with every flush.
+#[cfg(test)]
+struct Slow(Duration, Named);
+
+#[cfg(test)]
+impl Backend for Slow {
+    fn name(&self) -> &str {
+        self.1.name()
+    }
+
+    fn supports_tags(&self) -> bool {
+        self.1.supports_tags()
+    }
+
+    fn flush_interval(&self) -> Option<Duration> {
+        Some(self.0)
+    }
+
+    fn flush(&mut self, snapshot: &Snapshot) -> ::std::io::Result<()> {
+        self.1.flush(snapshot)
+    }
+}
+
+#[test]
+fn test_flush_intervals() {
+    use aggregator::{AggregatorOptions, MetricInterval};
+
+    let fine = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let coarse = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let backends: Vec<Box<dyn Backend>> =
+        vec![Box::new(Named("fine", true, fine.clone())),
+             Box::new(Slow(Duration::from_secs(3), Named("coarse", true, coarse.clone())))];
+    let intervals = vec![MetricInterval {
+                             pattern: Glob::new("slow.*"),
+                             interval: Duration::from_secs(3),
+                         }];
+    let options = AggregatorOptions {
+        flush_interval: Duration::from_secs(1),
+        intervals: intervals,
+        ..AggregatorOptions::default()
+    };
+    let mut aggregator = Aggregator::new(options);
+    aggregator.process("restored:7|g".parse().unwrap());
+    let (mut flusher, tx) = Flusher::new(aggregator, backends);
+    for t in 1..4 {
+        let lines = format!("web.hits:1|c\nweb.latency:{0}|ms\nweb.load:{0}|g\nweb.users:{0}|s\n\
+                             slow.jobs;host=a:1|c",
+                            t);
+        tx.send(lines.parse().unwrap()).unwrap();
+        flusher.drain();
+        flusher.flush();
+    }
+
+    // Every flush at the aggregator's interval, and the slow buckets once they're due.
+    {
+        let fine = fine.lock().unwrap();
+        assert_eq!(fine.len(), 4);
+        assert!(fine[..3].iter().all(|snapshot| snapshot.counters["web.hits"] == 1.0));
+        assert!(!fine[0].counters.contains_key("slow.jobs;host=a"));
+        assert_eq!(fine[3].counters.keys().collect::<Vec<_>>(), vec!["slow.jobs;host=a"]);
+        assert_eq!(fine[3].counter_rates["slow.jobs;host=a"], 1.0);
+    }
+
+    // Everything downsampled to three flushes.
+    {
+        let coarse = coarse.lock().unwrap();
+        assert_eq!(coarse.len(), 1);
+        let snapshot = &coarse[0];
+        assert_eq!(snapshot.flush_interval, Duration::from_secs(3));
+        assert_eq!((snapshot.counters["web.hits"], snapshot.counter_rates["web.hits"]), (3.0, 1.0));
+        assert_eq!(snapshot.counters["slow.jobs;host=a"], 3.0);
+        assert_eq!(snapshot.timers["web.latency"], vec![1.0, 2.0, 3.0]);
+        assert_eq!(snapshot.timer_data["web.latency"]["count_ps"], 1.0);
+        assert_eq!((snapshot.gauges["web.load"], snapshot.gauges["restored"]), (3.0, 7.0));
+        assert_eq!(snapshot.set_counts["web.users"], 3.0);
+    }
+
+    // Leaving flushes what the longer intervals have so far.
+    tx.send("web.hits:1|c".parse().unwrap()).unwrap();
+    flusher.drain();
+    flusher.flush_all();
+    assert_eq!(coarse.lock().unwrap()[1].counters["web.hits"], 1.0);
+}
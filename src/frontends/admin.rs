@@ -0,0 +1,118 @@
+use mio::channel::Sender;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use metrics::{StatKind, StatMsg};
+use sync_token::Token;
+use config::AdminConfig;
+
+const HELP: &str = "Commands: delcounters, deltimers, delgauges, delsets, health, help, quit\n\n";
+
+/// etsy's management interface: a line based TCP protocol for poking at a running server,
+/// e.g. `echo "delgauges host1.load host1.mem" | nc 127.0.0.1 8126`.
+pub struct AdminServer {
+    listener: TcpListener,
+    shutdown: Option<Arc<Token>>,
+}
+
+impl AdminServer {
+    pub fn bind(config: &AdminConfig) -> io::Result<AdminServer> {
+        let listener = try!(TcpListener::bind((config.host.as_str(), config.port)));
+        Ok(AdminServer {
+            listener: listener,
+            shutdown: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Stops accepting connections once `token` is triggered.
+    pub fn set_shutdown(&mut self, token: Arc<Token>) -> io::Result<()> {
+        try!(self.listener.set_nonblocking(true));
+        self.shutdown = Some(token);
+        Ok(())
+    }
+
+    /// Serves every connection on its own thread until shut down.
+    pub fn run(&self, tx: Sender<StatMsg>) {
+        info!("Admin interface listening on {:?}", self.listener.local_addr());
+        while let Some(stream) = super::accept(&self.listener, self.shutdown.as_deref()) {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Admin interface failed to accept: {}", err);
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            thread::spawn(move || {
+                if let Err(err) = serve(stream, tx) {
+                    debug!("Admin connection closed: {}", err);
+                }
+            });
+        }
+    }
+}
+
+fn serve(stream: TcpStream, tx: Sender<StatMsg>) -> io::Result<()> {
+    let mut writer = try!(stream.try_clone());
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let line = try!(line);
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue,
+        };
+        let kind = match command {
+            "delcounters" => StatKind::Counter,
+            "deltimers" => StatKind::Timer,
+            "delgauges" => StatKind::Gauge,
+            "delsets" => StatKind::Sets,
+            "health" => {
+                try!(writer.write_all(b"health: up\n"));
+                continue;
+            }
+            "help" => {
+                try!(writer.write_all(HELP.as_bytes()));
+                continue;
+            }
+            "quit" => return Ok(()),
+            _ => {
+                try!(writer.write_all(b"ERROR\n"));
+                continue;
+            }
+        };
+        for name in words {
+            if tx.send(StatMsg::Del(kind.clone(), name.to_string())).is_err() {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "aggregator hung up"));
+            }
+            try!(writeln!(writer, "deleted: {}", name));
+        }
+        try!(writer.write_all(b"END\n\n"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_admin_delete() {
+    use mio::channel::channel;
+    use std::io::Read;
+
+    let config = AdminConfig { port: 0, ..AdminConfig::default() };
+    let server = AdminServer::bind(&config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let (tx, rx) = channel();
+    thread::spawn(move || server.run(tx));
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"delgauges host1.load host1.mem\nhealth\nquit\n").unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "deleted: host1.load\ndeleted: host1.mem\nEND\n\nhealth: up\n");
+    assert_eq!(rx.try_recv().unwrap(), StatMsg::Del(StatKind::Gauge, "host1.load".to_string()));
+    assert_eq!(rx.try_recv().unwrap(), StatMsg::Del(StatKind::Gauge, "host1.mem".to_string()));
+}
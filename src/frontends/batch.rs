@@ -0,0 +1,143 @@
+// Batched datagram reads. On Linux a whole batch is pulled out of the kernel with a single
+// `recvmmsg(2)` call, everywhere else we fall back to looping over `recv_from`.
+
+use mio::udp::UdpSocket;
+use std::io;
+use std::net::SocketAddr;
+
+#[cfg(target_os = "linux")]
+use libc;
+#[cfg(target_os = "linux")]
+use std::mem;
+#[cfg(target_os = "linux")]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::ptr;
+
+pub struct Batch {
+    bufs: Vec<Vec<u8>>,
+    lens: Vec<usize>,
+    peers: Vec<Option<SocketAddr>>,
+    truncated: Vec<bool>,
+}
+
+impl Batch {
+    pub fn new(capacity: usize, buffer_size: usize) -> Batch {
+        Batch {
+            bufs: (0..capacity).map(|_| vec![0; buffer_size]).collect(),
+            lens: vec![0; capacity],
+            peers: vec![None; capacity],
+            truncated: vec![false; capacity],
+        }
+    }
+
+    /// The `index`th datagram of the last successful `recv`.
+    pub fn get(&self, index: usize) -> &[u8] {
+        &self.bufs[index][..self.lens[index]]
+    }
+
+    /// Where the `index`th datagram of the last successful `recv` came from.
+    pub fn peer(&self, index: usize) -> Option<SocketAddr> {
+        self.peers[index]
+    }
+
+    /// Whether the `index`th datagram of the last successful `recv` was larger than its buffer
+    /// and got cut off.
+    pub fn truncated(&self, index: usize) -> bool {
+        self.truncated[index]
+    }
+
+    /// Reads as many datagrams as are waiting, up to the batch capacity, and returns how many
+    /// were read. `Ok(0)` means the socket would block.
+    #[cfg(target_os = "linux")]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        let mut iovecs: Vec<libc::iovec> = self.bufs
+            .iter_mut()
+            .map(|buf| {
+                libc::iovec {
+                    iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: buf.len(),
+                }
+            })
+            .collect();
+        let mut names: Vec<libc::sockaddr_storage> = (0..iovecs.len())
+            .map(|_| unsafe { mem::zeroed() })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs.iter_mut()
+            .zip(names.iter_mut())
+            .map(|(iov, name)| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+
+        let count = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(),
+                           msgs.as_mut_ptr(),
+                           msgs.len() as libc::c_uint,
+                           libc::MSG_DONTWAIT,
+                           ptr::null_mut())
+        };
+        if count < 0 {
+            let err = io::Error::last_os_error();
+            return if err.kind() == io::ErrorKind::WouldBlock {
+                Ok(0)
+            } else {
+                Err(err)
+            };
+        }
+
+        let count = count as usize;
+        for i in 0..count {
+            self.lens[i] = msgs[i].msg_len as usize;
+            self.peers[i] = to_socket_addr(&names[i]);
+            self.truncated[i] = msgs[i].msg_hdr.msg_flags & libc::MSG_TRUNC != 0;
+        }
+        Ok(count)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        let mut count = 0;
+        while count < self.bufs.len() {
+            match try!(socket.recv_from(&mut self.bufs[count])) {
+                Some((len, peer)) => {
+                    self.lens[count] = len;
+                    self.peers[count] = Some(peer);
+                    // Without the message flags a datagram that fills the buffer exactly has to
+                    // be assumed to have been cut off.
+                    self.truncated[count] = len == self.bufs[count].len();
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn to_socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip,
+                                                  u16::from_be(addr.sin6_port),
+                                                  addr.sin6_flowinfo,
+                                                  addr.sin6_scope_id)))
+        }
+        _ => None,
+    }
+}
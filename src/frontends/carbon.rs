@@ -0,0 +1,688 @@
+//! Graphite's own protocols, so RuStatsD can stand in for a carbon-relay: plaintext
+//! `path value timestamp` lines over TCP and UDP, and pickled batches of
+//! `(path, (timestamp, value))` over TCP. Every point becomes a gauge, the timestamp is
+//! dropped since the value goes out with the next flush like everything else.
+
+use mio::channel::Sender;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::str;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use metrics::{ParseErrorKind, ParseMessageError, StatMsg};
+use sanitize::KeySanitizer;
+use stats::ServerStats;
+use sync_token::Token;
+use config::ParserConfig;
+use super::ConnectionLimit;
+use super::parser::BadLineLog;
+
+// carbon refuses larger pickle frames too.
+const MAX_PICKLE_FRAME: usize = 1024 * 1024;
+const MAX_DATAGRAM: usize = 65536;
+
+/// Turns Graphite points into gauge messages, counting and logging the bad ones like
+/// `PacketParser` does for StatsD lines.
+pub struct PointParser {
+    sanitizer: KeySanitizer,
+    stats: Arc<ServerStats>,
+    bad_lines: BadLineLog,
+}
+
+impl PointParser {
+    pub fn new(stats: Arc<ServerStats>, config: &ParserConfig) -> PointParser {
+        PointParser {
+            sanitizer: config.key_sanitize,
+            stats: stats,
+            bad_lines: BadLineLog::new(config.log_bad_lines_per_minute),
+        }
+    }
+
+    /// Parses every `path value [timestamp]` line of `packet`, skipping the bad ones.
+    pub fn parse_lines<S: fmt::Display>(&mut self, packet: &[u8], source: S) -> Option<StatMsg> {
+        let mut msgs = Vec::new();
+        for line in packet.split(|&b| b == b'\n') {
+            let line = if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line };
+            if line.is_empty() {
+                continue;
+            }
+            match parse_plaintext(line) {
+                Ok((path, value)) => msgs.extend(self.point(path, value, line, &source)),
+                Err(err) => self.reject(&err, &source),
+            }
+        }
+        self.stats.record_packet(msgs.len());
+        StatMsg::from_msgs(msgs)
+    }
+
+    /// Parses one pickle frame (without its length prefix), skipping the bad points.
+    pub fn parse_pickle<S: fmt::Display>(&mut self, frame: &[u8], source: S) -> Option<StatMsg> {
+        let mut msgs = Vec::new();
+        match unpickle(frame) {
+            Ok(Pickle::List(points)) => {
+                for point in points {
+                    match pickled_point(&point) {
+                        Some((path, value)) => msgs.extend(self.point(path, value, path.as_bytes(), &source)),
+                        None => {
+                            let err = ParseMessageError::new(ParseErrorKind::BadValue,
+                                                             format!("{:?}", point).as_bytes(),
+                                                             0);
+                            self.reject(&err, &source);
+                        }
+                    }
+                }
+            }
+            Ok(other) => {
+                let err = ParseMessageError::new(ParseErrorKind::BadValue, format!("{:?}", other).as_bytes(), 0);
+                self.reject(&err, &source);
+            }
+            Err(reason) => {
+                let err = ParseMessageError::new(ParseErrorKind::BadValue, reason.as_bytes(), 0);
+                self.reject(&err, &source);
+            }
+        }
+        self.stats.record_packet(msgs.len());
+        StatMsg::from_msgs(msgs)
+    }
+
+    fn point(&mut self, path: &str, value: f64, line: &[u8], source: &dyn fmt::Display) -> Option<StatMsg> {
+        let path = self.sanitizer.sanitize(path);
+        if path.is_empty() {
+            self.reject(&ParseMessageError::new(ParseErrorKind::EmptyName, line, 0), source);
+            return None;
+        }
+        Some(StatMsg::Gauge(path.into_owned(), value))
+    }
+
+    fn reject(&mut self, err: &ParseMessageError, source: &dyn fmt::Display) {
+        self.stats.parse_errors().record(err.kind());
+        self.bad_lines.log(err, source);
+    }
+}
+
+fn parse_plaintext(line: &[u8]) -> Result<(&str, f64), ParseMessageError> {
+    let text = try!(str::from_utf8(line).map_err(|_| ParseMessageError::new(ParseErrorKind::BadName, line, 0)));
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let offset = |field: &str| field.as_ptr() as usize - text.as_ptr() as usize;
+    if fields.len() < 2 || fields.len() > 3 {
+        return Err(ParseMessageError::new(ParseErrorKind::BadValue, line, 0));
+    }
+    let value = match fields[1].parse::<f64>() {
+        Ok(value) if value.is_finite() => value,
+        _ => return Err(ParseMessageError::new(ParseErrorKind::BadValue, line, offset(fields[1]))),
+    };
+    if fields.len() == 3 && fields[2].parse::<f64>().is_err() {
+        return Err(ParseMessageError::new(ParseErrorKind::BadValue, line, offset(fields[2])));
+    }
+    Ok((fields[0], value))
+}
+
+// `(path, (timestamp, value))`, lists work as well as tuples.
+fn pickled_point(point: &Pickle) -> Option<(&str, f64)> {
+    let (path, datapoint) = match point.items() {
+        Some(&[Pickle::Str(ref path), ref datapoint]) => (path, datapoint),
+        _ => return None,
+    };
+    match datapoint.items() {
+        Some([timestamp, value]) if timestamp.number().is_some() => {
+            value.number().filter(|value| value.is_finite()).map(|value| (path.as_str(), value))
+        }
+        _ => None,
+    }
+}
+
+/// Which protocol a `CarbonServer` speaks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Plaintext,
+    Pickle,
+}
+
+/// Accepts Graphite connections on TCP, every connection is read on its own thread.
+pub struct CarbonServer {
+    protocol: Protocol,
+    listener: TcpListener,
+    shutdown: Option<Arc<Token>>,
+    connections: ConnectionLimit,
+}
+
+impl CarbonServer {
+    pub fn bind(host: &str, port: u16, protocol: Protocol) -> io::Result<CarbonServer> {
+        let listener = try!(TcpListener::bind((host, port)));
+        Ok(CarbonServer {
+            protocol: protocol,
+            listener: listener,
+            shutdown: None,
+            connections: ConnectionLimit::new(super::MAX_CONNECTIONS),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Stops accepting connections, and makes open connections hang up, once `token` is
+    /// triggered.
+    pub fn set_shutdown(&mut self, token: Arc<Token>) -> io::Result<()> {
+        try!(self.listener.set_nonblocking(true));
+        self.shutdown = Some(token);
+        Ok(())
+    }
+
+    pub fn run(&self, stats: Arc<ServerStats>, parser: &ParserConfig, tx: Sender<StatMsg>) {
+        info!("Carbon {:?} listener on {:?}", self.protocol, self.listener.local_addr());
+        while let Some(stream) = super::accept(&self.listener, self.shutdown.as_deref()) {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Carbon listener failed to accept: {}", err);
+                    continue;
+                }
+            };
+            let slot = match self.connections.acquire() {
+                Some(slot) => slot,
+                None => {
+                    warn!("Carbon listener is at its connection limit, refusing {:?}", stream.peer_addr());
+                    continue;
+                }
+            };
+            let parser = PointParser::new(stats.clone(), parser);
+            let protocol = self.protocol;
+            let shutdown = self.shutdown.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                if let Err(err) = serve(stream, protocol, parser, shutdown.as_deref(), tx) {
+                    debug!("Carbon connection closed: {}", err);
+                }
+            });
+        }
+    }
+}
+
+fn serve(stream: TcpStream,
+         protocol: Protocol,
+         mut parser: PointParser,
+         shutdown: Option<&Token>,
+         tx: Sender<StatMsg>)
+         -> io::Result<()> {
+    let source = try!(stream.peer_addr()).to_string();
+    if shutdown.is_some() {
+        try!(stream.set_read_timeout(Some(Duration::from_millis(super::SHUTDOWN_CHECK))));
+    }
+    let send = |msg: Option<StatMsg>| match msg {
+        Some(msg) => tx.send(msg).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver hung up")),
+        None => Ok(()),
+    };
+    match protocol {
+        Protocol::Plaintext => {
+            super::read_lines(stream, shutdown, |line| send(parser.parse_lines(line, &source)))
+        }
+        Protocol::Pickle => {
+            let mut stream = stream;
+            let mut header = [0; 4];
+            while try!(read_full(&mut stream, &mut header, shutdown)) {
+                let length = ((header[0] as usize) << 24) | ((header[1] as usize) << 16) |
+                             ((header[2] as usize) << 8) | header[3] as usize;
+                if length > MAX_PICKLE_FRAME {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "pickle frame too large"));
+                }
+                let mut frame = vec![0; length];
+                if !try!(read_full(&mut stream, &mut frame, shutdown)) {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated pickle frame"));
+                }
+                try!(send(parser.parse_pickle(&frame, &source)));
+            }
+            Ok(())
+        }
+    }
+}
+
+// Fills `buf`, retrying reads that time out. Returns false if the stream closed (or shutdown
+// was triggered) before the first byte.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8], shutdown: Option<&Token>) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        if shutdown.is_some_and(|token| token.is_triggered()) {
+            return Ok(false);
+        }
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-frame")),
+            Ok(n) => read += n,
+            Err(ref err) if super::timed_out(err) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+/// Reads Graphite plaintext datagrams, any number of lines each.
+pub struct CarbonUdpReader {
+    socket: UdpSocket,
+    shutdown: Option<Arc<Token>>,
+}
+
+impl CarbonUdpReader {
+    pub fn bind(host: &str, port: u16) -> io::Result<CarbonUdpReader> {
+        let socket = try!(UdpSocket::bind((host, port)));
+        Ok(CarbonUdpReader {
+            socket: socket,
+            shutdown: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Makes `run` return once `token` is triggered.
+    pub fn set_shutdown(&mut self, token: Arc<Token>) -> io::Result<()> {
+        try!(self.socket.set_read_timeout(Some(Duration::from_millis(super::SHUTDOWN_CHECK))));
+        self.shutdown = Some(token);
+        Ok(())
+    }
+
+    pub fn run(&self, mut parser: PointParser, tx: Sender<StatMsg>) {
+        info!("Carbon plaintext listener on {:?} (UDP)", self.socket.local_addr());
+        let mut buf = vec![0; MAX_DATAGRAM];
+        while !self.shutdown.as_ref().is_some_and(|token| token.is_triggered()) {
+            let (len, peer) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref err) if super::timed_out(err) => continue,
+                Err(err) => {
+                    error!("Carbon UDP listener failed: {}", err);
+                    return;
+                }
+            };
+            if let Some(msg) = parser.parse_lines(&buf[..len], peer) {
+                if tx.send(msg).is_err() {
+                    info!("Receiver hung up, stopping carbon UDP listener");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The values a pickled carbon batch is made of.
+#[derive(Debug, Clone, PartialEq)]
+enum Pickle {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<Pickle>),
+    Tuple(Vec<Pickle>),
+}
+
+impl Pickle {
+    fn items(&self) -> Option<&[Pickle]> {
+        match *self {
+            Pickle::List(ref items) | Pickle::Tuple(ref items) => Some(items),
+            _ => None,
+        }
+    }
+
+    // Values and string bytes in the tree, what copying it costs.
+    fn size(&self) -> usize {
+        match *self {
+            Pickle::Str(ref s) => 1 + s.len(),
+            Pickle::List(ref items) | Pickle::Tuple(ref items) => {
+                1 + items.iter().map(Pickle::size).sum::<usize>()
+            }
+            _ => 1,
+        }
+    }
+
+    fn number(&self) -> Option<f64> {
+        match *self {
+            Pickle::Int(n) => Some(n as f64),
+            Pickle::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+}
+
+// A stack item, marks delimit the items of `LIST`, `TUPLE` and `APPENDS`.
+enum Item {
+    Mark,
+    Value(Pickle),
+}
+
+/// Decodes the subset of the pickle format (protocols 0 to 5) needed for lists of tuples of
+/// strings and numbers, which is what carbon clients send. Anything that would construct
+/// arbitrary objects is refused, so a frame can't run code. Memoized values are copied, and
+/// all copies together may not be larger than a frame, or a small frame reusing the memo level
+/// by level could grow exponentially.
+fn unpickle(data: &[u8]) -> Result<Pickle, String> {
+    let mut input = data;
+    let mut stack: Vec<Item> = Vec::new();
+    let mut memo: HashMap<u64, Pickle> = HashMap::new();
+    let mut budget = MAX_PICKLE_FRAME;
+    let mut copy = |value: &Pickle| {
+        let size = value.size();
+        if size > budget {
+            return Err("pickle expands too much".to_string());
+        }
+        budget -= size;
+        Ok(value.clone())
+    };
+    loop {
+        let op = try!(take(&mut input, 1))[0];
+        match op {
+            0x80 => {
+                try!(take(&mut input, 1));
+            }
+            0x95 => {
+                try!(take(&mut input, 8));
+            }
+            b'.' => {
+                return match stack.pop() {
+                    Some(Item::Value(value)) => Ok(value),
+                    _ => Err("pickle ended without a value".to_string()),
+                }
+            }
+            b'(' => stack.push(Item::Mark),
+            b'N' => stack.push(Item::Value(Pickle::None)),
+            0x88 => stack.push(Item::Value(Pickle::Bool(true))),
+            0x89 => stack.push(Item::Value(Pickle::Bool(false))),
+            b']' => stack.push(Item::Value(Pickle::List(Vec::new()))),
+            b')' => stack.push(Item::Value(Pickle::Tuple(Vec::new()))),
+            b'l' => {
+                let items = try!(pop_mark(&mut stack));
+                stack.push(Item::Value(Pickle::List(items)));
+            }
+            b't' => {
+                let items = try!(pop_mark(&mut stack));
+                stack.push(Item::Value(Pickle::Tuple(items)));
+            }
+            0x85..=0x87 => {
+                let count = (op - 0x84) as usize;
+                if stack.len() < count {
+                    return Err("tuple of missing values".to_string());
+                }
+                let at = stack.len() - count;
+                let items = try!(values(stack.split_off(at)));
+                stack.push(Item::Value(Pickle::Tuple(items)));
+            }
+            b'a' => {
+                let item = try!(pop_value(&mut stack));
+                try!(append(&mut stack, vec![item]));
+            }
+            b'e' => {
+                let items = try!(pop_mark(&mut stack));
+                try!(append(&mut stack, items));
+            }
+            b'K' => stack.push(Item::Value(Pickle::Int(try!(take(&mut input, 1))[0] as i64))),
+            b'M' => stack.push(Item::Value(Pickle::Int(try!(le_uint(&mut input, 2)) as i64))),
+            b'J' => stack.push(Item::Value(Pickle::Int(try!(le_uint(&mut input, 4)) as u32 as i32 as i64))),
+            0x8a => {
+                let len = try!(take(&mut input, 1))[0] as usize;
+                let bytes = try!(take(&mut input, len));
+                stack.push(Item::Value(Pickle::Int(try!(long_le(bytes)))));
+            }
+            b'I' | b'L' => {
+                let line = try!(text_line(&mut input));
+                let value = match line.trim_end_matches('L') {
+                    "00" => Pickle::Bool(false),
+                    "01" => Pickle::Bool(true),
+                    digits => Pickle::Int(try!(digits.parse().map_err(|_| format!("bad integer {:?}", line)))),
+                };
+                stack.push(Item::Value(value));
+            }
+            b'G' => {
+                let bytes = try!(take(&mut input, 8));
+                let mut bits = 0u64;
+                for &b in bytes {
+                    bits = (bits << 8) | b as u64;
+                }
+                stack.push(Item::Value(Pickle::Float(f64::from_bits(bits))));
+            }
+            b'F' => {
+                let line = try!(text_line(&mut input));
+                let value = try!(line.parse().map_err(|_| format!("bad float {:?}", line)));
+                stack.push(Item::Value(Pickle::Float(value)));
+            }
+            b'X' | b'T' | b'B' => {
+                let len = try!(le_uint(&mut input, 4)) as usize;
+                stack.push(Item::Value(Pickle::Str(try!(string(&mut input, len)))));
+            }
+            0x8c | b'U' | b'C' => {
+                let len = try!(take(&mut input, 1))[0] as usize;
+                stack.push(Item::Value(Pickle::Str(try!(string(&mut input, len)))));
+            }
+            0x8d | 0x8e => {
+                let len = try!(le_uint(&mut input, 8)) as usize;
+                stack.push(Item::Value(Pickle::Str(try!(string(&mut input, len)))));
+            }
+            b'V' => {
+                let line = try!(text_line(&mut input));
+                stack.push(Item::Value(Pickle::Str(line)));
+            }
+            b'S' => {
+                let line = try!(text_line(&mut input));
+                let quoted = line.len() >= 2 && (line.starts_with('\'') || line.starts_with('"')) &&
+                             line.ends_with(&line[..1]);
+                if !quoted {
+                    return Err(format!("bad string {:?}", line));
+                }
+                stack.push(Item::Value(Pickle::Str(line[1..line.len() - 1].to_string())));
+            }
+            b'p' | b'q' | b'r' | 0x94 => {
+                let key = match op {
+                    b'p' => try!(text_line(&mut input).and_then(|line| line.parse().map_err(|_| line))),
+                    b'q' => try!(take(&mut input, 1))[0] as u64,
+                    b'r' => try!(le_uint(&mut input, 4)),
+                    _ => memo.len() as u64,
+                };
+                match stack.last() {
+                    Some(Item::Value(value)) => memo.insert(key, try!(copy(value))),
+                    _ => return Err("memoizing a missing value".to_string()),
+                };
+            }
+            b'g' | b'h' | b'j' => {
+                let key = match op {
+                    b'g' => try!(text_line(&mut input).and_then(|line| line.parse().map_err(|_| line))),
+                    b'h' => try!(take(&mut input, 1))[0] as u64,
+                    _ => try!(le_uint(&mut input, 4)),
+                };
+                match memo.get(&key) {
+                    Some(value) => stack.push(Item::Value(try!(copy(value)))),
+                    None => return Err(format!("unknown memo key {}", key)),
+                }
+            }
+            op => return Err(format!("unsupported pickle opcode 0x{:02x}", op)),
+        }
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if input.len() < len {
+        return Err("truncated pickle".to_string());
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn le_uint(input: &mut &[u8], len: usize) -> Result<u64, String> {
+    let bytes = try!(take(input, len));
+    Ok(bytes.iter().rev().fold(0, |n, &b| (n << 8) | b as u64))
+}
+
+// A little endian two's complement integer of up to 8 bytes.
+fn long_le(bytes: &[u8]) -> Result<i64, String> {
+    if bytes.is_empty() {
+        return Ok(0);
+    }
+    if bytes.len() > 8 {
+        return Err("integer too large".to_string());
+    }
+    let mut n = bytes.iter().rev().fold(0u64, |n, &b| (n << 8) | b as u64);
+    if bytes[bytes.len() - 1] & 0x80 != 0 && bytes.len() < 8 {
+        n |= !0u64 << (bytes.len() * 8);
+    }
+    Ok(n as i64)
+}
+
+fn string(input: &mut &[u8], len: usize) -> Result<String, String> {
+    let bytes = try!(take(input, len));
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn text_line(input: &mut &[u8]) -> Result<String, String> {
+    match input.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let line = String::from_utf8_lossy(&input[..end]).into_owned();
+            *input = &input[end + 1..];
+            Ok(line)
+        }
+        None => Err("truncated pickle".to_string()),
+    }
+}
+
+fn pop_value(stack: &mut Vec<Item>) -> Result<Pickle, String> {
+    match stack.pop() {
+        Some(Item::Value(value)) => Ok(value),
+        _ => Err("missing value".to_string()),
+    }
+}
+
+fn pop_mark(stack: &mut Vec<Item>) -> Result<Vec<Pickle>, String> {
+    let mark = try!(stack.iter().rposition(|item| matches!(*item, Item::Mark)).ok_or("missing mark"));
+    let items = stack.split_off(mark + 1);
+    stack.pop();
+    values(items)
+}
+
+fn values(items: Vec<Item>) -> Result<Vec<Pickle>, String> {
+    items.into_iter()
+        .map(|item| match item {
+            Item::Value(value) => Ok(value),
+            Item::Mark => Err("unexpected mark".to_string()),
+        })
+        .collect()
+}
+
+fn append(stack: &mut [Item], items: Vec<Pickle>) -> Result<(), String> {
+    match stack.last_mut() {
+        Some(&mut Item::Value(Pickle::List(ref mut list))) => {
+            list.extend(items);
+            Ok(())
+        }
+        _ => Err("appending to something that is not a list".to_string()),
+    }
+}
+
+#[cfg(test)]
+fn points(msg: Option<StatMsg>) -> Vec<StatMsg> {
+    match msg {
+        Some(StatMsg::Bat(msgs)) => msgs,
+        Some(msg) => vec![msg],
+        None => Vec::new(),
+    }
+}
+
+#[test]
+fn test_parse_plaintext() {
+    let stats = Arc::new(ServerStats::new());
+    let mut parser = PointParser::new(stats.clone(), &ParserConfig::default());
+    let msg = parser.parse_lines(b"servers.web1.load 0.53 1700000000\r\nbad\nservers.web1.up 1\n\
+                                   servers.web1.load nan 1700000000\nx 1 soon\n",
+                                 "test");
+    assert_eq!(points(msg),
+               vec![StatMsg::Gauge("servers.web1.load".to_string(), 0.53),
+                    StatMsg::Gauge("servers.web1.up".to_string(), 1.0)]);
+    assert_eq!(stats.parse_errors().get(ParseErrorKind::BadValue), 3);
+    assert_eq!(stats.metrics_received(), 2);
+}
+
+#[test]
+fn test_unpickle() {
+    let expected = vec![StatMsg::Gauge("servers.web1.load".to_string(), 1.5),
+                        StatMsg::Gauge("servers.web1.procs".to_string(), 42.0)];
+    // `pickle.dumps([("servers.web1.load", (1700000000, 1.5)), ("servers.web1.procs",
+    // (1700000000, 42))], protocol=n)` for protocols 0, 2 and 4.
+    let frames: [&[u8]; 3] = [b"(lp0\n(Vservers.web1.load\np1\n(I1700000000\nF1.5\ntp2\ntp3\na\
+                                (Vservers.web1.procs\np4\n(I1700000000\nI42\ntp5\ntp6\na.",
+                              b"\x80\x02]q\x00(X\x11\x00\x00\x00servers.web1.loadq\x01J\x00\xf1SeG?\xf8\
+                                \x00\x00\x00\x00\x00\x00\x86q\x02\x86q\x03X\x12\x00\x00\x00servers.web1.procs\
+                                q\x04J\x00\xf1SeK*\x86q\x05\x86q\x06e.",
+                              b"\x80\x04\x95K\x00\x00\x00\x00\x00\x00\x00]\x94(\x8c\x11servers.web1.load\
+                                \x94J\x00\xf1SeG?\xf8\x00\x00\x00\x00\x00\x00\x86\x94\x86\x94\
+                                \x8c\x12servers.web1.procs\x94J\x00\xf1SeK*\x86\x94\x86\x94e."];
+    let stats = Arc::new(ServerStats::new());
+    let mut parser = PointParser::new(stats.clone(), &ParserConfig::default());
+    for frame in frames.iter() {
+        assert_eq!(points(parser.parse_pickle(frame, "test")), expected);
+    }
+
+    // `os.system` by way of GLOBAL and REDUCE.
+    assert_eq!(unpickle(b"cos\nsystem\n(S'true'\ntR."),
+               Err("unsupported pickle opcode 0x63".to_string()));
+    assert_eq!(parser.parse_pickle(b"\x80\x02]q\x00(X\x01\x00", "test"), None);
+    // Every level is a pair of the level below it out of the memo, 2^40 values from 250 bytes.
+    let mut bomb = b"\x80\x04K\x01\x94".to_vec();
+    for level in 0..40 {
+        bomb.extend_from_slice(&[b'h', level, b'h', level, 0x86, 0x94]);
+    }
+    bomb.push(b'.');
+    assert_eq!(unpickle(&bomb), Err("pickle expands too much".to_string()));
+    assert_eq!(long_le(&[0xff, 0xff]), Ok(-1));
+    assert_eq!(long_le(&[0x00, 0xf1, 0x53, 0x65]), Ok(1700000000));
+}
+
+#[test]
+fn test_carbon_server() {
+    use mio::channel::channel;
+    use std::io::Write;
+    use std::net::UdpSocket;
+    use std::time::Instant;
+    use sync_token::TokenSource;
+
+    let shutdown = TokenSource::new();
+    let stats = Arc::new(ServerStats::new());
+    let (tx, rx) = channel();
+    let mut threads = Vec::new();
+    let mut addrs = Vec::new();
+    for &protocol in [Protocol::Plaintext, Protocol::Pickle].iter() {
+        let mut server = CarbonServer::bind("127.0.0.1", 0, protocol).unwrap();
+        server.set_shutdown(shutdown.get_token()).unwrap();
+        addrs.push(server.local_addr().unwrap());
+        let (stats, tx) = (stats.clone(), tx.clone());
+        threads.push(thread::spawn(move || server.run(stats, &ParserConfig::default(), tx)));
+    }
+    let mut udp = CarbonUdpReader::bind("127.0.0.1", 0).unwrap();
+    udp.set_shutdown(shutdown.get_token()).unwrap();
+    let udp_addr = udp.local_addr().unwrap();
+    let parser = PointParser::new(stats.clone(), &ParserConfig::default());
+    threads.push(thread::spawn(move || udp.run(parser, tx)));
+
+    TcpStream::connect(addrs[0]).unwrap().write_all(b"a 1 1700000000\n").unwrap();
+    let frame = b"\x80\x02]X\x01\x00\x00\x00bJ\x00\xf1SeK\x02\x86\x86a.";
+    let mut pickle = TcpStream::connect(addrs[1]).unwrap();
+    pickle.write_all(&[0, 0, 0, frame.len() as u8]).unwrap();
+    pickle.write_all(frame).unwrap();
+    UdpSocket::bind("127.0.0.1:0").unwrap().send_to(b"c 3\n", udp_addr).unwrap();
+
+    let mut received = Vec::new();
+    let start = Instant::now();
+    while received.len() < 3 && start.elapsed() < Duration::from_secs(5) {
+        match rx.try_recv() {
+            Ok(msg) => received.push(msg.to_string()),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    received.sort();
+    assert_eq!(received, vec!["a:1|g", "b:2|g", "c:3|g"]);
+
+    shutdown.trigger();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+//! Receives the flushes of other RuStatsD nodes' `forward` backends, so hosts can run a local
+//! agent that aggregates first and a central tier merges what the agents send.
+//!
+//! A connection starts with the `HEADER` line, followed by one line per bucket with the kind,
+//! the value(s) and the name last:
+//!
+//! ```text
+//! rustatsd-forward 1
+//! c 42 api.requests
+//! g 0.75 host1.load
+//! t 12 <digest> api.latency
+//! s <sketch> api.users
+//! ```
+//!
+//! Counters are sample rate corrected counts that add up, gauges are last values, timers
+//! carry their corrected count and a `sketch::Digest`, sets a `sketch::HyperLogLog`.
+
+use mio::channel::Sender;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use metrics::{Aggregate, ParseErrorKind, ParseMessageError, StatMsg};
+use sketch::{Digest, HyperLogLog};
+use stats::ServerStats;
+use sync_token::Token;
+use config::ForwardListenerConfig;
+use super::ConnectionLimit;
+use super::parser::BadLineLog;
+
+/// The first line of every connection. The version changes with the format.
+pub const HEADER: &str = "rustatsd-forward 1";
+
+pub struct ForwardServer {
+    listener: TcpListener,
+    shutdown: Option<Arc<Token>>,
+    connections: ConnectionLimit,
+}
+
+impl ForwardServer {
+    pub fn bind(config: &ForwardListenerConfig) -> io::Result<ForwardServer> {
+        let listener = try!(TcpListener::bind((config.host.as_str(), config.port)));
+        Ok(ForwardServer {
+            listener: listener,
+            shutdown: None,
+            connections: ConnectionLimit::new(super::MAX_CONNECTIONS),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Stops accepting connections, and makes open connections hang up, once `token` is
+    /// triggered.
+    pub fn set_shutdown(&mut self, token: Arc<Token>) -> io::Result<()> {
+        try!(self.listener.set_nonblocking(true));
+        self.shutdown = Some(token);
+        Ok(())
+    }
+
+    /// Serves every connection on its own thread until shut down.
+    pub fn run(&self, stats: Arc<ServerStats>, log_bad_lines_per_minute: usize, tx: Sender<StatMsg>) {
+        info!("Forward listener on {:?}", self.listener.local_addr());
+        while let Some(stream) = super::accept(&self.listener, self.shutdown.as_deref()) {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Forward listener failed to accept: {}", err);
+                    continue;
+                }
+            };
+            let slot = match self.connections.acquire() {
+                Some(slot) => slot,
+                None => {
+                    warn!("Forward listener is at its connection limit, refusing {:?}", stream.peer_addr());
+                    continue;
+                }
+            };
+            let stats = stats.clone();
+            let shutdown = self.shutdown.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                let bad_lines = BadLineLog::new(log_bad_lines_per_minute);
+                if let Err(err) = serve(stream, stats, bad_lines, shutdown.as_deref(), tx) {
+                    debug!("Forward connection closed: {}", err);
+                }
+            });
+        }
+    }
+}
+
+fn serve(stream: TcpStream,
+         stats: Arc<ServerStats>,
+         mut bad_lines: BadLineLog,
+         shutdown: Option<&Token>,
+         tx: Sender<StatMsg>)
+         -> io::Result<()> {
+    let source = try!(stream.peer_addr()).to_string();
+    if shutdown.is_some() {
+        try!(stream.set_read_timeout(Some(Duration::from_millis(super::SHUTDOWN_CHECK))));
+    }
+    let mut header = true;
+    super::read_lines(stream, shutdown, |line| {
+        if header {
+            header = false;
+            if line != HEADER.as_bytes() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("expected {:?}, got {:?}", HEADER, String::from_utf8_lossy(line))));
+            }
+            return Ok(());
+        }
+        match parse_entry(line) {
+            Ok(msg) => {
+                stats.record_packet(1);
+                tx.send(msg).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver hung up"))
+            }
+            Err(err) => {
+                stats.parse_errors().record(err.kind());
+                bad_lines.log(&err, &source);
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Parses one bucket line, the inverse of `StatMsg`'s `Display` for `Merge` messages (and
+/// `g <value> <name>` for gauges).
+pub fn parse_entry(line: &[u8]) -> Result<StatMsg, ParseMessageError> {
+    let bad = |kind, offset| ParseMessageError::new(kind, line, offset);
+    let text = try!(str::from_utf8(line).map_err(|_| bad(ParseErrorKind::BadName, 0)));
+    let fields = if text.starts_with("t ") { 4 } else { 3 };
+    let parts: Vec<&str> = text.splitn(fields, ' ').collect();
+    if parts.len() < fields || parts[fields - 1].is_empty() {
+        return Err(bad(ParseErrorKind::EmptyName, 0));
+    }
+    let name = parts[fields - 1].to_string();
+    let number = |field: &str| field.parse::<f64>().ok().filter(|value| value.is_finite());
+    let msg = match parts[0] {
+        "c" => number(parts[1]).map(|count| StatMsg::Merge(name, Aggregate::Count(count))),
+        "g" => number(parts[1]).map(|value| StatMsg::Gauge(name, value)),
+        "t" => {
+            match (number(parts[1]), Digest::decode(parts[2])) {
+                (Some(count), Some(digest)) => Some(StatMsg::Merge(name, Aggregate::Timer(count, digest))),
+                _ => None,
+            }
+        }
+        "s" => HyperLogLog::decode(parts[1]).map(|hll| StatMsg::Merge(name, Aggregate::Set(hll))),
+        _ => return Err(bad(ParseErrorKind::BadType, 0)),
+    };
+    msg.ok_or_else(|| bad(ParseErrorKind::BadValue, 2))
+}
+
+#[test]
+fn test_parse_entry() {
+    let mut digest = Digest::default();
+    digest.add(3.0);
+    digest.add(5.0);
+    let mut hll = HyperLogLog::new();
+    hll.insert(7);
+    let msgs = vec![StatMsg::Merge("api requests".to_string(), Aggregate::Count(2.5)),
+                    StatMsg::Merge("api.latency".to_string(), Aggregate::Timer(20.0, digest)),
+                    StatMsg::Merge("api.users".to_string(), Aggregate::Set(hll))];
+    for msg in msgs {
+        assert_eq!(parse_entry(msg.to_string().as_bytes()), Ok(msg));
+    }
+    assert_eq!(parse_entry(b"g -0.5 host1.load"), Ok(StatMsg::Gauge("host1.load".to_string(), -0.5)));
+    assert_eq!(parse_entry(b"x 1 a").unwrap_err().kind(), ParseErrorKind::BadType);
+    assert_eq!(parse_entry(b"c nan a").unwrap_err().kind(), ParseErrorKind::BadValue);
+    assert_eq!(parse_entry(b"t 1 0.01/2/1/1/1/1/0// a").unwrap_err().kind(), ParseErrorKind::BadValue);
+    assert_eq!(parse_entry(b"c 1").unwrap_err().kind(), ParseErrorKind::EmptyName);
+}
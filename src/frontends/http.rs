@@ -0,0 +1,672 @@
+use flate2::read::GzDecoder;
+use mio::channel::Sender;
+use serde_json::{self, Value};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use metrics::{ParseMessageError, ParseMode, StatMsg};
+use stats::ServerStats;
+use sync_token::Token;
+use config::HttpConfig;
+use super::ConnectionLimit;
+use super::parser::PacketParser;
+
+// Request heads (request line plus headers) larger than this are refused.
+const MAX_HEAD: usize = 8192;
+
+/// Why a request could not be read. Only an oversized body is told apart, it gets its own status.
+#[derive(Debug)]
+pub enum RequestError {
+    Io(io::Error),
+    TooLarge,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RequestError::Io(ref err) => write!(f, "{}", err),
+            RequestError::TooLarge => write!(f, "request body too large"),
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(err: io::Error) -> RequestError {
+        RequestError::Io(err)
+    }
+}
+
+/// Just enough of an HTTP/1.x request for the endpoints below.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercased.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|header| header.0 == name).map(|header| header.1.as_str())
+    }
+
+    /// Reads one request, refusing bodies over `max_body` bytes.
+    pub fn read<R: BufRead>(reader: &mut R, max_body: usize) -> Result<Request, RequestError> {
+        let mut head_len = 0;
+        let mut line = String::new();
+        let request_line = try!(read_head_line(reader, &mut line, &mut head_len));
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+            _ => return Err(RequestError::Io(invalid("malformed request line"))),
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            let header = try!(read_head_line(reader, &mut line, &mut head_len));
+            if header.is_empty() {
+                break;
+            }
+            match header.find(':') {
+                Some(colon) => {
+                    headers.push((header[..colon].trim().to_lowercase(), header[colon + 1..].trim().to_string()))
+                }
+                None => return Err(RequestError::Io(invalid("malformed header"))),
+            }
+        }
+
+        let mut request = Request {
+            method: method,
+            path: path,
+            headers: headers,
+            body: Vec::new(),
+        };
+        let length = match request.header("content-length") {
+            Some(length) => try!(length.parse::<usize>().map_err(|_| invalid("bad content-length"))),
+            None => 0,
+        };
+        if length > max_body {
+            return Err(RequestError::TooLarge);
+        }
+        request.body.resize(length, 0);
+        try!(reader.read_exact(&mut request.body));
+        Ok(request)
+    }
+}
+
+fn read_head_line<R: BufRead>(reader: &mut R, line: &mut String, head_len: &mut usize) -> io::Result<String> {
+    line.clear();
+    let read = try!(reader.by_ref().take((MAX_HEAD - *head_len) as u64).read_line(line));
+    *head_len += read;
+    if read == 0 || !line.ends_with('\n') {
+        return Err(invalid("request head truncated or too large"));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: u16, body: &str) -> Response {
+        Response {
+            status: status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.to_string(),
+        }
+    }
+
+    pub fn json(status: u16, body: String) -> Response {
+        Response {
+            status: status,
+            content_type: "application/json",
+            body: body,
+        }
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            503 => "Service Unavailable",
+            _ => "",
+        };
+        try!(write!(out,
+                    "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    self.status,
+                    reason,
+                    self.content_type,
+                    self.body.len()));
+        out.write_all(self.body.as_bytes())
+    }
+}
+
+// Why one line (or JSON array element, counting from 1) of a `POST /metrics` was rejected.
+struct LineError {
+    line: usize,
+    kind: &'static str,
+    offset: Option<usize>,
+    message: String,
+}
+
+impl LineError {
+    fn parse(line: usize, err: &ParseMessageError) -> LineError {
+        LineError {
+            line: line,
+            kind: err.kind().as_str(),
+            offset: Some(err.offset()),
+            message: err.to_string(),
+        }
+    }
+
+    fn json(line: usize, message: String) -> LineError {
+        LineError {
+            line: line,
+            kind: "bad_json",
+            offset: None,
+            message: message,
+        }
+    }
+}
+
+struct Ingest {
+    parser: PacketParser,
+    tx: Sender<StatMsg>,
+}
+
+/// Liveness, readiness and status endpoints for orchestrators, answered from the same
+/// `ServerStats` the self-metrics are built from. Optionally takes metrics with
+/// `POST /metrics`, for senders that can't do UDP like browsers and serverless functions.
+pub struct HttpServer {
+    listener: TcpListener,
+    stats: Arc<ServerStats>,
+    max_heartbeat_age: Duration,
+    max_body_size: usize,
+    ingest: Option<Mutex<Ingest>>,
+    shutdown: Option<Arc<Token>>,
+    connections: ConnectionLimit,
+}
+
+impl HttpServer {
+    /// `max_heartbeat_age` is how long the flusher may go quiet before `/healthz` fails, it
+    /// should comfortably exceed the flush interval.
+    pub fn bind(config: &HttpConfig, stats: Arc<ServerStats>, max_heartbeat_age: Duration) -> io::Result<HttpServer> {
+        let listener = try!(TcpListener::bind((config.host.as_str(), config.port)));
+        Ok(HttpServer {
+            listener: listener,
+            stats: stats,
+            max_heartbeat_age: max_heartbeat_age,
+            max_body_size: config.max_body_size,
+            ingest: None,
+            shutdown: None,
+            connections: ConnectionLimit::new(super::MAX_CONNECTIONS),
+        })
+    }
+
+    /// Accepts `POST /metrics`, either StatsD lines (`text/plain`) or a JSON array of
+    /// `{"name": .., "type": .., "value": .., "sample_rate": .., "tags": [..]}` objects
+    /// (`application/json`), optionally gzipped.
+    pub fn set_ingest(&mut self, parser: PacketParser, tx: Sender<StatMsg>) {
+        self.ingest = Some(Mutex::new(Ingest {
+            parser: parser,
+            tx: tx,
+        }));
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Stops accepting connections once `token` is triggered.
+    pub fn set_shutdown(&mut self, token: Arc<Token>) -> io::Result<()> {
+        try!(self.listener.set_nonblocking(true));
+        self.shutdown = Some(token);
+        Ok(())
+    }
+
+    /// Serves every connection on its own thread until shut down.
+    pub fn run(self) {
+        info!("HTTP listener on {:?}", self.listener.local_addr());
+        let server = Arc::new(self);
+        while let Some(stream) = super::accept(&server.listener, server.shutdown.as_deref()) {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("HTTP listener failed to accept: {}", err);
+                    continue;
+                }
+            };
+            let slot = match server.connections.acquire() {
+                Some(slot) => slot,
+                None => {
+                    warn!("HTTP listener is at its connection limit, refusing {:?}", stream.peer_addr());
+                    let _ = Response::text(503, "too many connections\n").write(&mut &stream);
+                    continue;
+                }
+            };
+            let server = server.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                if let Err(err) = server.serve(stream) {
+                    debug!("HTTP connection closed: {}", err);
+                }
+            });
+        }
+    }
+
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        try!(stream.set_read_timeout(Some(Duration::from_secs(10))));
+        let mut writer = try!(stream.try_clone());
+        let mut reader = BufReader::new(stream);
+        let max_body = if self.ingest.is_some() { self.max_body_size } else { 0 };
+        let response = match Request::read(&mut reader, max_body) {
+            Ok(request) => self.handle(&request),
+            Err(RequestError::TooLarge) => Response::text(413, "request body too large\n"),
+            Err(err) => Response::text(400, &format!("{}\n", err)),
+        };
+        response.write(&mut writer)
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        if request.path == "/metrics" && self.ingest.is_some() {
+            return match request.method.as_str() {
+                "POST" => self.ingest(request),
+                _ => Response::text(405, "method not allowed\n"),
+            };
+        }
+        if request.method != "GET" {
+            return Response::text(405, "method not allowed\n");
+        }
+        match request.path.as_str() {
+            "/healthz" => self.healthz(),
+            "/readyz" => self.readyz(),
+            "/status" => Response::json(200, self.status()),
+            _ => Response::text(404, "not found\n"),
+        }
+    }
+
+    fn healthz(&self) -> Response {
+        match self.stats.flush_status().heartbeat {
+            Some(heartbeat) if heartbeat.elapsed() <= self.max_heartbeat_age => Response::text(200, "ok\n"),
+            Some(heartbeat) => {
+                Response::text(503, &format!("event loop stalled for {}s\n", heartbeat.elapsed().as_secs()))
+            }
+            None => Response::text(503, "event loop not running\n"),
+        }
+    }
+
+    fn readyz(&self) -> Response {
+        if !self.stats.frontends_bound() {
+            return Response::text(503, "frontends not bound\n");
+        }
+        let failing: Vec<String> = self.stats
+            .flush_status()
+            .backends
+            .into_iter()
+            .filter(|backend| backend.1.failing)
+            .map(|(name, _)| name)
+            .collect();
+        if failing.is_empty() {
+            Response::text(200, "ok\n")
+        } else {
+            Response::text(503, &format!("backends failing: {}\n", failing.join(", ")))
+        }
+    }
+
+    fn status(&self) -> String {
+        let stats = &self.stats;
+        let flush = stats.flush_status();
+        let mut json = format!("{{\"uptime\":{},\"frontends_bound\":{},\"packets_received\":{},\
+                                \"metrics_received\":{},\"bad_lines_seen\":{},\"num_stats\":{},\
+                                \"last_flush\":{},\"backends\":{{",
+                               stats.uptime().as_secs(),
+                               stats.frontends_bound(),
+                               stats.packets_received(),
+                               stats.metrics_received(),
+                               stats.bad_lines_seen(),
+                               flush.num_stats,
+                               json_option(flush.last_flush));
+        for (i, (name, status)) in flush.backends.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let last_error = match status.last_error {
+                Some((time, ref message)) => {
+                    format!("{{\"time\":{},\"message\":{}}}", time, json_string(message))
+                }
+                None => "null".to_string(),
+            };
+            json.push_str(&format!("{}:{{\"last_success\":{},\"last_error\":{},\"failing\":{}}}",
+                                   json_string(name),
+                                   json_option(status.last_success),
+                                   last_error,
+                                   status.failing));
+        }
+        json.push_str("}}");
+        json
+    }
+
+    fn ingest(&self, request: &Request) -> Response {
+        let body = match self.decode_body(request) {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        let content_type = request.header("content-type").unwrap_or("text/plain");
+        let lines = match content_type.split(';').next().unwrap().trim() {
+            "text/plain" => text_lines(&body),
+            "application/json" => {
+                match serde_json::from_slice::<Value>(&body) {
+                    Ok(Value::Array(values)) => values.iter().enumerate().map(|(i, value)| (i + 1, json_line(value))).collect(),
+                    Ok(_) => return json_error(400, "expected a JSON array of metrics"),
+                    Err(err) => return json_error(400, &format!("invalid JSON: {}", err)),
+                }
+            }
+            other => return Response::text(415, &format!("unsupported content type {}\n", other)),
+        };
+        if lines.is_empty() {
+            return json_error(400, "no metrics in body");
+        }
+
+        let mut ingest = self.ingest.as_ref().unwrap().lock().unwrap();
+        let mut msgs = Vec::new();
+        let mut errors = Vec::new();
+        for (number, line) in lines {
+            match line {
+                Ok(line) => {
+                    let parsed = ingest.parser.parse_packet(&line, "HTTP");
+                    msgs.extend(parsed.msg);
+                    errors.extend(parsed.errors.iter().map(|err| LineError::parse(number, err)));
+                }
+                Err(message) => errors.push(LineError::json(number, message)),
+            }
+        }
+        // Like a packet, a strict parser takes all of a body or nothing.
+        if ingest.parser.mode() == ParseMode::Strict && !errors.is_empty() {
+            msgs.clear();
+        }
+        let accepted = msgs.len();
+        if let Some(msg) = StatMsg::from_msgs(msgs) {
+            if ingest.tx.send(msg).is_err() {
+                return json_error(503, "server is shutting down");
+            }
+        }
+
+        if errors.is_empty() {
+            return Response::json(200, format!("{{\"accepted\":{}}}", accepted));
+        }
+        let errors: Vec<String> = errors.iter()
+            .map(|err| {
+                format!("{{\"line\":{},\"kind\":{},\"offset\":{},\"message\":{}}}",
+                        err.line,
+                        json_string(err.kind),
+                        json_option(err.offset.map(|offset| offset as u64)),
+                        json_string(&err.message))
+            })
+            .collect();
+        Response::json(400, format!("{{\"accepted\":{},\"errors\":[{}]}}", accepted, errors.join(",")))
+    }
+
+    fn decode_body<'a>(&self, request: &'a Request) -> Result<Cow<'a, [u8]>, Response> {
+        match request.header("content-encoding") {
+            None | Some("identity") => Ok(Cow::Borrowed(&request.body)),
+            Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => {
+                let mut body = Vec::new();
+                let mut decoder = GzDecoder::new(&request.body[..]).take(self.max_body_size as u64 + 1);
+                match decoder.read_to_end(&mut body) {
+                    Err(err) => Err(json_error(400, &format!("invalid gzip body: {}", err))),
+                    Ok(_) if body.len() > self.max_body_size => Err(Response::text(413, "request body too large\n")),
+                    Ok(_) => Ok(Cow::Owned(body)),
+                }
+            }
+            Some(other) => Err(Response::text(415, &format!("unsupported content encoding {}\n", other))),
+        }
+    }
+}
+
+// The non-blank lines of a text body, numbered from 1.
+fn text_lines(body: &[u8]) -> Vec<(usize, Result<Vec<u8>, String>)> {
+    body.split(|&b| b == b'\n')
+        .map(|line| if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line })
+        .enumerate()
+        .filter(|line| !line.1.is_empty())
+        .map(|(i, line)| (i + 1, Ok(line.to_vec())))
+        .collect()
+}
+
+// Turns one metric object into a StatsD line, so it goes through the same parser as the rest.
+// Fields may not hold the separators of the line format, or they'd change what it means.
+fn json_line(value: &Value) -> Result<Vec<u8>, String> {
+    let object = try!(value.as_object().ok_or("expected an object"));
+    let name = try!(object.get("name").and_then(Value::as_str).ok_or("missing \"name\""));
+    let name = try!(plain_field("name", name, &[':', '|', '#']));
+    let kind = try!(object.get("type").and_then(Value::as_str).ok_or("missing \"type\""));
+    let kind = try!(plain_field("type", kind, &['|']));
+    let mut line = match object.get("value") {
+        Some(Value::Number(n)) => format!("{}:{}|{}", name, n, kind),
+        Some(Value::String(s)) => format!("{}:{}|{}", name, try!(plain_field("value", s, &['|'])), kind),
+        _ => return Err("\"value\" must be a number or a string".to_string()),
+    };
+    if let Some(rate) = object.get("sample_rate") {
+        line.push_str(&format!("|@{}", try!(rate.as_f64().ok_or("\"sample_rate\" must be a number"))));
+    }
+    match object.get("tags") {
+        Some(Value::Array(tags)) => {
+            let mut joined = Vec::with_capacity(tags.len());
+            for tag in tags {
+                let tag = try!(tag.as_str().ok_or("\"tags\" must be strings"));
+                joined.push(try!(plain_field("tags", tag, &['|', '#', ','])));
+            }
+            line.push_str("|#");
+            line.push_str(&joined.join(","));
+        }
+        Some(_) => return Err("\"tags\" must be an array".to_string()),
+        None => {}
+    }
+    if line.contains('\n') {
+        return Err("metrics must not contain newlines".to_string());
+    }
+    Ok(line.into_bytes())
+}
+
+fn plain_field<'a>(field: &str, value: &'a str, reserved: &[char]) -> Result<&'a str, String> {
+    match value.chars().find(|c| reserved.contains(c)) {
+        Some(c) => Err(format!("\"{}\" must not contain {:?}", field, c)),
+        None => Ok(value),
+    }
+}
+
+fn json_error(status: u16, message: &str) -> Response {
+    Response::json(status, format!("{{\"error\":{}}}", json_string(message)))
+}
+
+fn json_option(value: Option<u64>) -> String {
+    value.map_or_else(|| "null".to_string(), |value| value.to_string())
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+fn get(path: &str) -> Request {
+    Request::read(&mut format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes(), 0).unwrap()
+}
+
+#[test]
+fn test_read_request() {
+    let raw = b"POST /metrics HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\na:1|c";
+    let request = Request::read(&mut &raw[..], 1024).unwrap();
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/metrics");
+    assert_eq!(request.header("content-type"), Some("text/plain"));
+    assert_eq!(request.body, b"a:1|c");
+    match Request::read(&mut &raw[..], 4) {
+        Err(RequestError::TooLarge) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(Request::read(&mut &b"GET\r\n\r\n"[..], 0).is_err());
+    // Invalid UTF-8 in the head is a bad request, not an oversized one.
+    match Request::read(&mut &b"GET / HTTP/1.1\r\nX-Name: \xff\r\n\r\n"[..], 0) {
+        Err(RequestError::Io(ref err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_health_endpoints() {
+    let stats = Arc::new(ServerStats::new());
+    let config = HttpConfig { port: 0, ..HttpConfig::default() };
+    let server = HttpServer::bind(&config, stats.clone(), Duration::from_secs(60)).unwrap();
+
+    assert_eq!(server.handle(&get("/healthz")).status, 503);
+    assert_eq!(server.handle(&get("/readyz")).status, 503);
+    stats.heartbeat();
+    stats.set_frontends_bound(true);
+    assert_eq!(server.handle(&get("/healthz")).status, 200);
+    assert_eq!(server.handle(&get("/readyz")).status, 200);
+
+    stats.record_backend("graphite", 100, Err("connection \"refused\"".to_string()));
+    stats.record_flush(100, 3);
+    let readyz = server.handle(&get("/readyz"));
+    assert_eq!((readyz.status, readyz.body.as_str()), (503, "backends failing: graphite\n"));
+    let status = server.handle(&get("/status")).body;
+    assert!(status.contains("\"num_stats\":3,\"last_flush\":100,"));
+    assert!(status.contains("\"graphite\":{\"last_success\":null,\
+                             \"last_error\":{\"time\":100,\"message\":\"connection \\\"refused\\\"\"},\
+                             \"failing\":true}"));
+    assert_eq!(server.handle(&get("/nope")).status, 404);
+}
+
+#[test]
+fn test_ingest() {
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use mio::channel::channel;
+    use metrics::StatKind;
+    use config::ParserConfig;
+
+    let post = |content_type: &str, encoding: &str, body: &[u8]| {
+        let mut raw = format!("POST /metrics HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+                              content_type,
+                              body.len())
+            .into_bytes();
+        if !encoding.is_empty() {
+            raw.extend(format!("Content-Encoding: {}\r\n", encoding).into_bytes());
+        }
+        raw.extend(b"\r\n");
+        raw.extend(body);
+        Request::read(&mut &raw[..], 1024).unwrap()
+    };
+    let stats = Arc::new(ServerStats::new());
+    let config = HttpConfig { port: 0, max_body_size: 64, ..HttpConfig::default() };
+    let mut server = HttpServer::bind(&config, stats.clone(), Duration::from_secs(60)).unwrap();
+    assert_eq!(server.handle(&post("text/plain", "", b"a:1|c")).status, 405);
+    let (tx, rx) = channel();
+    server.set_ingest(PacketParser::new(stats.clone(), &ParserConfig::default()), tx);
+
+    let response = server.handle(&post("text/plain", "", b"a:1|c\r\n\nb:2|x\nc:3|g\n"));
+    assert_eq!(response.status, 400);
+    assert_eq!(response.body,
+               "{\"accepted\":2,\"errors\":[{\"line\":3,\"kind\":\"bad_type\",\"offset\":4,\
+                \"message\":\"unknown metric type at byte 4 of \\\"b:2|x\\\"\"}]}");
+    assert_eq!(rx.try_recv().unwrap(),
+               StatMsg::Bat(vec![StatMsg::Inc(StatKind::Counter, "a".to_string(), 1, 1.0),
+                                 StatMsg::Set(StatKind::Gauge, "c".to_string(), 3, 1.0)]));
+
+    let json = br#"[{"name":"t","type":"ms","value":12,"sample_rate":0.5,"tags":["env:prod"]},
+                    {"name":"x"}]"#;
+    let response = server.handle(&post("application/json; charset=utf-8", "", json));
+    assert_eq!(response.status, 400);
+    assert!(response.body.contains("{\"line\":2,\"kind\":\"bad_json\",\"offset\":null,"));
+    assert_eq!(rx.try_recv().unwrap(), StatMsg::Set(StatKind::Timer, "t;env=prod".to_string(), 12, 0.5));
+
+    // Separators smuggled into a field would turn one metric into a different one.
+    let json = br#"[{"name":"a:1|c","type":"c","value":1},
+                    {"name":"b","type":"c","value":1,"tags":["env:prod|@0.01"]},
+                    {"name":"c","type":"c","value":"1|ms"},
+                    {"name":"d","type":"c","value":1,"tags":["env:prod"]}]"#;
+    let response = server.handle(&post("application/json", "", json));
+    assert_eq!(response.status, 400);
+    for line in 1..4 {
+        assert!(response.body.contains(&format!("{{\"line\":{},\"kind\":\"bad_json\"", line)));
+    }
+    assert!(response.body.contains("\\\"name\\\" must not contain ':'"));
+    assert_eq!(rx.try_recv().unwrap(), StatMsg::Inc(StatKind::Counter, "d;env=prod".to_string(), 1, 1.0));
+
+    let mut gzipped = GzEncoder::new(Vec::new(), Compression::default());
+    gzipped.write_all(b"hits:5|c\n").unwrap();
+    let response = server.handle(&post("text/plain", "gzip", &gzipped.finish().unwrap()));
+    assert_eq!((response.status, response.body.as_str()), (200, "{\"accepted\":1}"));
+    assert_eq!(rx.try_recv().unwrap(), StatMsg::Inc(StatKind::Counter, "hits".to_string(), 5, 1.0));
+
+    // The limit holds after decompressing too.
+    let mut bomb = GzEncoder::new(Vec::new(), Compression::default());
+    bomb.write_all(&[b'\n'; 1000]).unwrap();
+    assert_eq!(server.handle(&post("text/plain", "gzip", &bomb.finish().unwrap())).status, 413);
+    assert_eq!(server.handle(&post("application/json", "", b"{}")).status, 400);
+    assert_eq!(server.handle(&post("text/html", "", b"a:1|c")).status, 415);
+    assert_eq!(server.handle(&get("/metrics")).status, 405);
+}
+
+#[test]
+fn test_connection_limit() {
+    use std::time::Instant;
+
+    let stats = Arc::new(ServerStats::new());
+    let config = HttpConfig { port: 0, ..HttpConfig::default() };
+    let mut server = HttpServer::bind(&config, stats, Duration::from_secs(60)).unwrap();
+    server.connections = ConnectionLimit::new(1);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let request = |path: &str| -> io::Result<String> {
+        let mut stream = try!(TcpStream::connect(addr));
+        try!(write!(stream, "GET {} HTTP/1.1\r\n\r\n", path));
+        let mut response = String::new();
+        try!(stream.read_to_string(&mut response));
+        Ok(response)
+    };
+    // An idle connection holds the only slot, the next one is answered right away. It doesn't
+    // send a request, unread data would turn the close into a reset.
+    let idle = TcpStream::connect(addr).unwrap();
+    let mut refused = String::new();
+    TcpStream::connect(addr).unwrap().read_to_string(&mut refused).unwrap();
+    assert!(refused.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+    // Until the slot is given back requests are refused, maybe with a reset.
+    drop(idle);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !request("/nope").map(|response| response.starts_with("HTTP/1.1 404 ")).unwrap_or(false) {
+        assert!(Instant::now() < deadline, "slot was not given back");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
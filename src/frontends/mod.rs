@@ -1,2 +1,160 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use metrics::StatMsg;
+use sync_token::Token;
+
 pub mod udp_server;
-pub mod tcp_server;
\ No newline at end of file
+pub mod tcp_server;
+#[cfg(unix)]
+pub mod unix_server;
+pub mod parser;
+pub mod admin;
+pub mod http;
+pub mod carbon;
+pub mod forward;
+mod batch;
+
+// How often listeners that can be shut down look at their token.
+const SHUTDOWN_CHECK: u64 = 100;
+// Stream connections sending longer lines than this are dropped.
+const MAX_LINE: usize = 65536;
+// Most connections one stream listener serves at once, every one of them holds a thread.
+const MAX_CONNECTIONS: usize = 1024;
+
+// The stream listeners `accept` works with.
+trait Listener {
+    type Stream;
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+    fn set_blocking(stream: &Self::Stream) -> io::Result<()>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_stream(&self) -> io::Result<TcpStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn set_blocking(stream: &TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(false)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn set_blocking(stream: &UnixStream) -> io::Result<()> {
+        stream.set_nonblocking(false)
+    }
+}
+
+// Accepts the next connection, or returns `None` once `shutdown` is triggered. Listeners with a
+// shutdown token are non-blocking and get polled instead.
+fn accept<L: Listener>(listener: &L, shutdown: Option<&Token>) -> Option<io::Result<L::Stream>> {
+    let shutdown = match shutdown {
+        Some(shutdown) => shutdown,
+        None => return Some(listener.accept_stream()),
+    };
+    while !shutdown.is_triggered() {
+        match listener.accept_stream() {
+            Ok(stream) => return Some(L::set_blocking(&stream).map(|_| stream)),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(SHUTDOWN_CHECK))
+            }
+            Err(err) => return Some(Err(err)),
+        }
+    }
+    None
+}
+
+// Counts the open connections of one stream listener, so a flood of clients can't start an
+// unbounded number of threads.
+struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            open: Arc::new(AtomicUsize::new(0)),
+            max: max,
+        }
+    }
+
+    // Takes a slot for a new connection, or `None` if `max` connections are open already. The
+    // slot is given back when it is dropped.
+    fn acquire(&self) -> Option<ConnectionSlot> {
+        let open = self.open.fetch_add(1, Ordering::SeqCst);
+        let slot = ConnectionSlot(self.open.clone());
+        if open < self.max {
+            Some(slot)
+        } else {
+            None
+        }
+    }
+}
+
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Puts `prefix` in front of every name in `msg`, for listeners that namespace what they receive.
+fn add_prefix(msg: &mut StatMsg, prefix: &str) {
+    msg.map_names(&mut |name: &mut String| name.insert_str(0, prefix));
+}
+
+// Whether a read on a socket with a read timeout gave up waiting.
+fn timed_out(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+// Calls `handle` with every non-empty line of `stream`, without its newline, until the stream
+// closes or `shutdown` is triggered. With a shutdown token the stream needs a read timeout.
+fn read_lines<R, F>(stream: R, shutdown: Option<&Token>, mut handle: F) -> io::Result<()>
+    where R: Read,
+          F: FnMut(&[u8]) -> io::Result<()>
+{
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    loop {
+        if shutdown.is_some_and(|token| token.is_triggered()) {
+            return Ok(());
+        }
+        // A timed out read keeps what it read so far in `line`, the next one continues it.
+        match reader.by_ref().take((MAX_LINE + 1 - line.len()) as u64).read_until(b'\n', &mut line) {
+            Ok(0) => {
+                // The last line doesn't need a newline.
+                return if line.is_empty() { Ok(()) } else { handle(&line) };
+            }
+            Ok(_) if line.ends_with(b"\n") => {
+                let end = if line.ends_with(b"\r\n") { line.len() - 2 } else { line.len() - 1 };
+                if end > 0 {
+                    try!(handle(&line[..end]));
+                }
+                line.clear();
+            }
+            Ok(_) => {}
+            Err(ref err) if timed_out(err) => {}
+            Err(err) => return Err(err),
+        }
+        if line.len() > MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+    }
+}
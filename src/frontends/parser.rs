@@ -0,0 +1,176 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use metrics::{parse_lines, ParseErrorKind, ParseMessageError, ParseMode, ParsedPacket, StatMsg};
+use sanitize::KeySanitizer;
+use stats::ServerStats;
+use config::ParserConfig;
+
+/// Logs at most a configured number of rejected lines per minute and summarizes the rest, so
+/// a misbehaving client can't flood the log.
+pub struct BadLineLog {
+    per_minute: usize,
+    window_start: Instant,
+    logged: usize,
+    suppressed: usize,
+}
+
+impl BadLineLog {
+    pub fn new(per_minute: usize) -> BadLineLog {
+        BadLineLog {
+            per_minute: per_minute,
+            window_start: Instant::now(),
+            logged: 0,
+            suppressed: 0,
+        }
+    }
+
+    pub fn log<S: fmt::Display>(&mut self, err: &ParseMessageError, source: S) {
+        if self.per_minute == 0 {
+            return;
+        }
+        if self.window_start.elapsed() >= Duration::from_secs(60) {
+            if self.suppressed > 0 {
+                warn!("Suppressed {} more rejected lines in the last minute", self.suppressed);
+            }
+            self.window_start = Instant::now();
+            self.logged = 0;
+            self.suppressed = 0;
+        }
+        if self.logged < self.per_minute {
+            self.logged += 1;
+            warn!("Rejected line from {}: {} ({})", source, err, err.kind());
+        } else {
+            self.suppressed += 1;
+        }
+    }
+}
+
+/// The parse path shared by every frontend. Turns a raw packet into a `StatMsg` with sanitized
+/// names, counting packets, metrics and rejected lines (by kind) and logging a sample of the
+/// rejects.
+pub struct PacketParser {
+    mode: ParseMode,
+    sanitizer: KeySanitizer,
+    stats: Arc<ServerStats>,
+    bad_lines: BadLineLog,
+}
+
+impl PacketParser {
+    pub fn new(stats: Arc<ServerStats>, config: &ParserConfig) -> PacketParser {
+        PacketParser {
+            mode: config.mode,
+            sanitizer: config.key_sanitize,
+            stats: stats,
+            bad_lines: BadLineLog::new(config.log_bad_lines_per_minute),
+        }
+    }
+
+    pub fn mode(&self) -> ParseMode {
+        self.mode
+    }
+
+    /// Parses every line of `packet`. In lenient mode the valid lines of a partly bad packet
+    /// are kept, in strict mode a single bad line rejects the whole packet.
+    pub fn parse<S: fmt::Display>(&mut self, packet: &[u8], source: S) -> Option<StatMsg> {
+        self.parse_packet(packet, source).msg
+    }
+
+    /// Like `parse`, but also hands back why lines were rejected, for frontends that can
+    /// answer the sender.
+    pub fn parse_packet<S: fmt::Display>(&mut self, packet: &[u8], source: S) -> ParsedPacket {
+        // Names stay borrowed from the packet until they are sanitized, so every accepted line
+        // without tags allocates its name once. Lines whose names are sanitized away completely
+        // are rejected as if they had no name, which doesn't reject the packet in strict mode.
+        let mut msgs = Vec::new();
+        let mut errors = Vec::new();
+        let mut bad_lines = false;
+        for line in parse_lines(packet) {
+            let err = match line {
+                Ok(stat) => {
+                    let name = self.sanitizer.sanitize(stat.name());
+                    if !name.is_empty() {
+                        msgs.push(stat.to_msg_named(name.into_owned()));
+                        continue;
+                    }
+                    ParseMessageError::new(ParseErrorKind::EmptyName, stat.name().as_bytes(), 0)
+                }
+                Err(err) => {
+                    bad_lines = true;
+                    err
+                }
+            };
+            self.reject(&err, &source);
+            errors.push(err);
+        }
+        if msgs.is_empty() && errors.is_empty() {
+            let err = ParseMessageError::new(ParseErrorKind::Empty, packet, 0);
+            self.reject(&err, &source);
+            errors.push(err);
+        }
+        if self.mode == ParseMode::Strict && bad_lines {
+            msgs.clear();
+        }
+        self.stats.record_packet(msgs.len());
+        ParsedPacket {
+            msg: StatMsg::from_msgs(msgs),
+            errors: errors,
+        }
+    }
+
+    /// Counts and logs a datagram that didn't fit the read buffer. Its last line is cut off
+    /// somewhere unknown, so none of it is parsed.
+    pub fn truncated<S: fmt::Display>(&mut self, packet: &[u8], source: S) {
+        let err = ParseMessageError::new(ParseErrorKind::Truncated, &packet[..packet.len().min(64)], 0);
+        self.reject(&err, source);
+        self.stats.record_packet(0);
+    }
+
+    fn reject<S: fmt::Display>(&mut self, err: &ParseMessageError, source: S) {
+        self.stats.parse_errors().record(err.kind());
+        self.bad_lines.log(err, source);
+    }
+}
+
+#[test]
+fn test_packet_parser_counts_rejects() {
+    use metrics::{ParseErrorKind, StatKind};
+
+    let stats = Arc::new(ServerStats::new());
+    let strict = ParserConfig { mode: ParseMode::Strict, ..ParserConfig::default() };
+    let mut parser = PacketParser::new(stats.clone(), &strict);
+    assert_eq!(parser.parse(b"test.key:1|c", "test"),
+               Some(StatMsg::Inc(StatKind::Counter, "test.key".to_string(), 1, 1.0)));
+    assert_eq!(parser.parse(b"test.key:1|c\ntest.key:1|x", "test"), None);
+    assert_eq!(parser.parse(b"\n", "test"), None);
+    assert_eq!(stats.parse_errors().get(ParseErrorKind::BadType), 1);
+    assert_eq!(stats.parse_errors().get(ParseErrorKind::Empty), 1);
+
+    let mut parser = PacketParser::new(stats.clone(), &ParserConfig::default());
+    assert_eq!(parser.parse(b"test.key:1|c\ntest.key:1|x", "test"),
+               Some(StatMsg::Inc(StatKind::Counter, "test.key".to_string(), 1, 1.0)));
+    assert_eq!(stats.parse_errors().get(ParseErrorKind::BadType), 2);
+    assert_eq!(stats.packets_received(), 4);
+    assert_eq!(stats.metrics_received(), 2);
+
+    parser.truncated(b"test.key:1|c\ntest.ke", "test");
+    assert_eq!(stats.parse_errors().get(ParseErrorKind::Truncated), 1);
+    assert_eq!(stats.packets_received(), 5);
+    assert_eq!(stats.metrics_received(), 2);
+}
+
+#[test]
+fn test_packet_parser_sanitizes_names() {
+    use metrics::StatKind;
+
+    let stats = Arc::new(ServerStats::new());
+    let mut parser = PacketParser::new(stats.clone(), &ParserConfig::default());
+    assert_eq!(parser.parse(b"my app/requests:1|c\n!!!:1|c", "test"),
+               Some(StatMsg::Inc(StatKind::Counter, "my_app-requests".to_string(), 1, 1.0)));
+    assert_eq!(stats.parse_errors().get(ParseErrorKind::EmptyName), 1);
+
+    let off = ParserConfig { key_sanitize: KeySanitizer::Off, ..ParserConfig::default() };
+    let mut parser = PacketParser::new(stats.clone(), &off);
+    assert_eq!(parser.parse(b"my app/requests:1|c", "test"),
+               Some(StatMsg::Inc(StatKind::Counter, "my app/requests".to_string(), 1, 1.0)));
+}
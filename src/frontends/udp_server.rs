@@ -1,16 +1,27 @@
-use mio;
 use mio::*;
+use mio::channel::Sender;
 use mio::udp::*;
+use net2::{UdpBuilder, UdpSocketExt};
+#[cfg(unix)]
+use net2::unix::UnixUdpBuilderExt;
 use std::io;
-use std::net::{SocketAddr, AddrParseError};
+use std::net::{self, SocketAddr, AddrParseError, ToSocketAddrs};
 use std::result;
 use std::str;
-use bytes::{Buf, RingBuf, MutBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use metrics::StatMsg;
+use sync_token;
+use config::{UdpConfig, UdpListener};
+use super::batch::Batch;
+use super::parser::PacketParser;
 
 // Setup some tokens to allow us to identify which event is for which socket.
 const INPUT_TOKEN: Token = Token(0);
-const RX_TOKEN: Token = Token(1);
-const READ_BUFFER_SIZE: usize = 1024;
+// Large enough for any UDP datagram, so only a sender going past the protocol limit gets cut off.
+const READ_BUFFER_SIZE: usize = 65536;
+// How long a reader with a shutdown token may block before looking at it again.
+const SHUTDOWN_CHECK: u64 = 200;
 
 #[derive(Debug)]
 pub enum UdpError {
@@ -42,69 +53,325 @@ pub type Result<T> = result::Result<T, UdpError>;
 pub struct UdpReader {
     address: SocketAddr,
     socket: UdpSocket,
+    batch_size: usize,
+    // The listener's prefix, with its trailing dot.
+    prefix: Option<String>,
+    shutdown: Option<Arc<sync_token::Token>>,
 }
 
 impl UdpReader {
     pub fn new(host: &str, port: &str) -> Result<UdpReader> {
-        let connection_string = format!("{}:{}", host, port);
-        let address = try!(connection_string.parse::<SocketAddr>());
+        let port = try!(port.parse::<u16>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port")));
+        let address = try!(resolve(host, port));
         let socket = try!(UdpSocket::bind(&address));
         let server = UdpReader {
             address: address,
             socket: socket,
+            batch_size: 1,
+            prefix: None,
+            shutdown: None,
         };
         Ok(server)
     }
 
-    pub fn run(&mut self) {
-        let mut poll = Poll::new().unwrap();
+    /// Binds one reader per configured worker on every listener. With `reuse_port` every
+    /// reader gets its own socket on the same address and the kernel load balances between
+    /// them, otherwise the readers share clones of a single socket.
+    pub fn bind_all(config: &UdpConfig) -> Result<Vec<UdpReader>> {
+        let listeners = config.all_listeners();
+        let mut readers = Vec::with_capacity(config.workers * listeners.len());
+        for listener in listeners.iter() {
+            readers.extend(try!(UdpReader::bind_listener(listener, config)));
+        }
+        Ok(readers)
+    }
+
+    fn bind_listener(listener: &UdpListener, config: &UdpConfig) -> Result<Vec<UdpReader>> {
+        let address = try!(resolve(&listener.host, listener.port));
+        let mut readers = Vec::with_capacity(config.workers);
+        for _ in 0..config.workers {
+            let socket = if config.reuse_port || readers.is_empty() {
+                try!(bind_socket(&address, listener.dual_stack, config))
+            } else {
+                let first: &UdpReader = &readers[0];
+                try!(first.socket.try_clone())
+            };
+            readers.push(UdpReader {
+                address: address,
+                socket: socket,
+                batch_size: config.batch_size,
+                prefix: listener.prefix.as_ref().map(|prefix| format!("{}.", prefix)),
+                shutdown: None,
+            });
+        }
+        Ok(readers)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The address actually bound, which tells the port when binding port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Makes `run` return once `token` is triggered.
+    pub fn set_shutdown(&mut self, token: Arc<sync_token::Token>) {
+        self.shutdown = Some(token);
+    }
+
+    pub fn run(&mut self, mut parser: PacketParser, tx: Sender<StatMsg>) {
+        let poll = Poll::new().unwrap();
         let mut events = Events::with_capacity(1024);
-        let mut buf = RingBuf::new(READ_BUFFER_SIZE);
+        let mut batch = Batch::new(self.batch_size, READ_BUFFER_SIZE);
 
-        let (tx, rx) = mio::channel::channel::<String>();
-        poll.register(&rx, RX_TOKEN, Ready::all(), PollOpt::edge()).unwrap();
         // Register the stream with `Poll`
-        poll.register(&self.socket, INPUT_TOKEN, Ready::all(), PollOpt::edge()).unwrap();
-        info!("Registered listener");
+        poll.register(&self.socket, INPUT_TOKEN, Ready::readable(), PollOpt::edge()).unwrap();
+        info!("Registered listener on {}", self.address);
 
+        let timeout = self.shutdown.as_ref().map(|_| Duration::from_millis(SHUTDOWN_CHECK));
         loop {
+            if self.shutdown.as_ref().map(|token| token.is_triggered()) == Some(true) {
+                info!("Stopping listener on {}", self.address);
+                return;
+            }
             trace!("Polling");
             // Wait for the socket to become ready
-            poll.poll(&mut events, None).unwrap();
+            poll.poll(&mut events, timeout).unwrap();
 
             for event in events.iter() {
                 let tk = (event.token(), event.kind());
                 trace!("Event: {:?}", &tk);
                 match tk {
                     (INPUT_TOKEN, kind) => {
-                        if kind.is_readable() {
-                            match unsafe { self.socket.recv_from(buf.mut_bytes()) } {
-                                Err(e) => {
-                                    error!("Error: {:?}", e);
-                                    return;
-                                }
-                                Ok(None) => {
-                                    error!("No Result");
-                                    return;
-                                }
-                                Ok(Some((read_size, addr))) => {
-                                    unsafe {
-                                        MutBuf::advance(&mut buf, read_size);
-                                    }
-                                    let msg = str::from_utf8(buf.bytes()).unwrap();
-                                    info!("Result: {:?} ({:?} on {:?})", msg, read_size, addr);
-                                    tx.send(msg.to_string());
-                                }
-                            };
+                        if kind.is_readable() && !self.drain(&mut batch, &mut parser, &tx) {
+                            return;
                         }
                     }
-                    (RX_TOKEN, kind) => {
-                        let msg = rx.try_recv().unwrap();
-                        info!("Rx: {:?}", msg);
-                    }
                     _ => unreachable!(),
                 }
             }
         }
     }
+
+    // Edge triggered polling only tells us once that data arrived, so keep reading batches
+    // until the socket would block. Returns false once the reader should stop.
+    fn drain(&self, batch: &mut Batch, parser: &mut PacketParser, tx: &Sender<StatMsg>) -> bool {
+        loop {
+            match batch.recv(&self.socket) {
+                Err(e) => {
+                    error!("Error: {:?}", e);
+                    return false;
+                }
+                Ok(0) => return true,
+                Ok(count) => {
+                    trace!("Read {} datagrams on {}", count, self.address);
+                    for i in 0..count {
+                        let source = batch.peer(i).unwrap_or(self.address);
+                        if batch.truncated(i) {
+                            parser.truncated(batch.get(i), source);
+                            continue;
+                        }
+                        if let Some(mut msg) = parser.parse(batch.get(i), source) {
+                            if let Some(ref prefix) = self.prefix {
+                                super::add_prefix(&mut msg, prefix);
+                            }
+                            if tx.send(msg).is_err() {
+                                info!("Receiver hung up, stopping listener on {}", self.address);
+                                return false;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Host names resolve to their first address, IPv6 addresses may come in brackets.
+fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match try!((host, port).to_socket_addrs()).next() {
+        Some(address) => Ok(address),
+        None => Err(UdpError::Io(io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host)))),
+    }
+}
+
+fn bind_socket(address: &SocketAddr, dual_stack: bool, config: &UdpConfig) -> Result<UdpSocket> {
+    let builder = match *address {
+        SocketAddr::V4(_) => try!(UdpBuilder::new_v4()),
+        SocketAddr::V6(_) => {
+            let builder = try!(UdpBuilder::new_v6());
+            try!(builder.only_v6(!dual_stack));
+            builder
+        }
+    };
+    if config.reuse_port {
+        try!(set_reuse_port(&builder));
+    }
+    let socket = try!(builder.bind(address));
+    if let Some(size) = config.recv_buffer_size {
+        try!(set_recv_buffer_size(&socket, size));
+    }
+    Ok(try!(UdpSocket::from_socket(socket)))
+}
+
+#[cfg(unix)]
+fn set_reuse_port(builder: &UdpBuilder) -> io::Result<()> {
+    try!(builder.reuse_port(true));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_: &UdpBuilder) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "SO_REUSEPORT is not supported on this platform"))
+}
+
+fn set_recv_buffer_size(socket: &net::UdpSocket, size: usize) -> io::Result<()> {
+    try!(socket.set_recv_buffer_size(size));
+    // The kernel is free to clamp (or on Linux double) the requested size, so report what we got.
+    let actual = try!(socket.recv_buffer_size());
+    if actual < size {
+        warn!("Requested SO_RCVBUF of {} bytes but the kernel granted {}", size, actual);
+    } else {
+        info!("SO_RCVBUF set to {} bytes", actual);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_bind_all_reuse_port() {
+    let config = UdpConfig {
+        port: 0,
+        workers: 1,
+        reuse_port: true,
+        ..UdpConfig::default()
+    };
+    let first = UdpReader::bind_all(&config).unwrap();
+    let port = first[0].socket.local_addr().unwrap().port();
+
+    // A second set of sockets can join the first one on the same port.
+    let config = UdpConfig {
+        port: port,
+        workers: 3,
+        ..config
+    };
+    let readers = UdpReader::bind_all(&config).unwrap();
+    assert_eq!(readers.len(), 3);
+    for reader in readers.iter() {
+        assert_eq!(reader.socket.local_addr().unwrap().port(), port);
+    }
+}
+
+#[test]
+fn test_batch_recv() {
+    let config = UdpConfig {
+        port: 0,
+        batch_size: 8,
+        ..UdpConfig::default()
+    };
+    let readers = UdpReader::bind_all(&config).unwrap();
+    let target = readers[0].socket.local_addr().unwrap();
+    let sender = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    for i in 0..3 {
+        sender.send_to(format!("test.key:{}|c", i).as_bytes(), target).unwrap();
+    }
+    ::std::thread::sleep(::std::time::Duration::from_millis(50));
+
+    let mut batch = Batch::new(config.batch_size, READ_BUFFER_SIZE);
+    let count = batch.recv(&readers[0].socket).unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(batch.get(2), b"test.key:2|c");
+    assert!(!batch.truncated(2));
+    assert_eq!(batch.recv(&readers[0].socket).unwrap(), 0);
+
+    // The client packs datagrams up to its MTU, those have to arrive whole.
+    let large = vec![b'a'; ::client::DEFAULT_MTU];
+    sender.send_to(&large, target).unwrap();
+    sender.send_to(b"test.key:1|c\ntest.key:2|c", target).unwrap();
+    ::std::thread::sleep(::std::time::Duration::from_millis(50));
+    assert_eq!(batch.recv(&readers[0].socket).unwrap(), 2);
+    assert_eq!(batch.get(0).len(), large.len());
+    assert!(!batch.truncated(0));
+
+    let mut small = Batch::new(1, 16);
+    sender.send_to(b"test.key:1|c\ntest.key:2|c", target).unwrap();
+    ::std::thread::sleep(::std::time::Duration::from_millis(50));
+    assert_eq!(small.recv(&readers[0].socket).unwrap(), 1);
+    assert_eq!(small.get(0), b"test.key:1|c\ntes");
+    assert!(small.truncated(0));
+}
+
+#[test]
+fn test_bind_all_listeners() {
+    use mio::channel::channel;
+    use std::thread;
+    use std::time::Instant;
+    use metrics::StatKind;
+    use stats::ServerStats;
+    use sync_token::TokenSource;
+    use config::ParserConfig;
+
+    let config = UdpConfig {
+        host: "[::]".to_string(),
+        port: 0,
+        listeners: vec![UdpListener {
+                            host: "127.0.0.1".to_string(),
+                            port: 0,
+                            prefix: Some("tenant-a".to_string()),
+                            dual_stack: true,
+                        }],
+        ..UdpConfig::default()
+    };
+    let readers = UdpReader::bind_all(&config).unwrap();
+    assert_eq!(readers.len(), 2);
+    let addrs: Vec<SocketAddr> = readers.iter().map(|reader| reader.local_addr().unwrap()).collect();
+    assert!(addrs[0].is_ipv6() && addrs[1].is_ipv4());
+
+    let shutdown = TokenSource::new();
+    let stats = Arc::new(ServerStats::new());
+    let (tx, rx) = channel();
+    let threads: Vec<_> = readers.into_iter()
+        .map(|mut reader| {
+            reader.set_shutdown(shutdown.get_token());
+            let parser = PacketParser::new(stats.clone(), &ParserConfig::default());
+            let tx = tx.clone();
+            thread::spawn(move || reader.run(parser, tx))
+        })
+        .collect();
+
+    // The dual-stack IPv6 listener takes IPv4 datagrams too.
+    let sender = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.send_to(b"hits:1|c", ("127.0.0.1", addrs[0].port())).unwrap();
+    sender.send_to(b"hits:2|c", addrs[1]).unwrap();
+    let mut received = Vec::new();
+    let start = Instant::now();
+    while received.len() < 2 && start.elapsed() < Duration::from_secs(5) {
+        match rx.try_recv() {
+            Ok(msg) => received.push(msg),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    received.sort_by_key(|msg| msg.to_string());
+    assert_eq!(received,
+               vec![StatMsg::Inc(StatKind::Counter, "hits".to_string(), 1, 1.0),
+                    StatMsg::Inc(StatKind::Counter, "tenant-a.hits".to_string(), 2, 1.0)]);
+
+    shutdown.trigger();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // Without dual-stack the IPv6 socket leaves IPv4 to others.
+    let config = UdpConfig {
+        host: "::".to_string(),
+        port: 0,
+        dual_stack: false,
+        ..UdpConfig::default()
+    };
+    let v6 = UdpReader::bind_all(&config).unwrap();
+    let port = v6[0].local_addr().unwrap().port();
+    net::UdpSocket::bind(("127.0.0.1", port)).unwrap();
 }
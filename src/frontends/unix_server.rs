@@ -0,0 +1,509 @@
+//! Unix domain socket frontends for senders on the same host, e.g. containers that get the
+//! socket mounted instead of talking UDP through the network stack. Datagrams are parsed like
+//! UDP packets, stream connections carry newline separated lines.
+
+use mio::channel::Sender;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, Permissions};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use metrics::StatMsg;
+use stats::ServerStats;
+use sync_token::Token;
+use config::{ParserConfig, PeerTag, UnixSocketConfig};
+use super::ConnectionLimit;
+use super::parser::PacketParser;
+
+// Unix datagrams aren't bound by an MTU, clients usually stay well below this.
+const MAX_DATAGRAM: usize = 65536;
+// The datagram reader forgets the prefixes it looked up once it knows this many senders.
+const PEER_CACHE_SIZE: usize = 1024;
+
+/// Reads metrics from a Unix datagram socket.
+pub struct UnixDatagramReader {
+    config: UnixSocketConfig,
+    socket: UnixDatagram,
+    shutdown: Option<Arc<Token>>,
+}
+
+impl UnixDatagramReader {
+    pub fn bind(config: &UnixSocketConfig) -> io::Result<UnixDatagramReader> {
+        try!(prepare_path(config, |path| UnixDatagram::unbound().and_then(|socket| socket.connect(path))));
+        let socket = try!(UnixDatagram::bind(&config.path));
+        let reader = UnixDatagramReader {
+            config: config.clone(),
+            socket: socket,
+            shutdown: None,
+        };
+        try!(set_mode(config));
+        if config.peer_tag.is_some() {
+            try!(pass_credentials(&reader.socket));
+        }
+        Ok(reader)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.config.path
+    }
+
+    /// Makes `run` return once `token` is triggered.
+    pub fn set_shutdown(&mut self, token: Arc<Token>) -> io::Result<()> {
+        try!(self.socket.set_read_timeout(Some(Duration::from_millis(super::SHUTDOWN_CHECK))));
+        self.shutdown = Some(token);
+        Ok(())
+    }
+
+    pub fn run(&mut self, mut parser: PacketParser, tx: Sender<StatMsg>) {
+        info!("Listening for datagrams on {}", self.config.path.display());
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut prefixes = HashMap::new();
+        loop {
+            if is_triggered(&self.shutdown) {
+                info!("Stopping listener on {}", self.config.path.display());
+                return;
+            }
+            let with_pid = self.config.peer_tag.is_some();
+            let (len, pid, truncated) = match recv_with_pid(&self.socket, &mut buf, with_pid) {
+                Ok(received) => received,
+                Err(ref err) if super::timed_out(err) => continue,
+                Err(err) => {
+                    error!("Error reading from {}: {}", self.config.path.display(), err);
+                    return;
+                }
+            };
+            let source = Source {
+                pid: pid,
+                path: &self.config.path,
+            };
+            if truncated {
+                parser.truncated(&buf[..len], source);
+                continue;
+            }
+            let mut msg = match parser.parse(&buf[..len], source) {
+                Some(msg) => msg,
+                None => continue,
+            };
+            if let (Some(tag), Some(pid)) = (self.config.peer_tag, pid) {
+                if prefixes.len() >= PEER_CACHE_SIZE {
+                    prefixes.clear();
+                }
+                if let Some(ref prefix) = *prefixes.entry(pid).or_insert_with(|| peer_prefix(tag, pid)) {
+                    super::add_prefix(&mut msg, prefix);
+                }
+            }
+            if tx.send(msg).is_err() {
+                info!("Receiver hung up, stopping listener on {}", self.config.path.display());
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for UnixDatagramReader {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.config.path);
+    }
+}
+
+/// Accepts connections on a Unix stream socket, every connection sends newline separated lines.
+pub struct UnixStreamServer {
+    config: UnixSocketConfig,
+    listener: UnixListener,
+    shutdown: Option<Arc<Token>>,
+    connections: ConnectionLimit,
+}
+
+impl UnixStreamServer {
+    pub fn bind(config: &UnixSocketConfig) -> io::Result<UnixStreamServer> {
+        try!(prepare_path(config, |path| UnixStream::connect(path).map(|_| ())));
+        let listener = try!(UnixListener::bind(&config.path));
+        let server = UnixStreamServer {
+            config: config.clone(),
+            listener: listener,
+            shutdown: None,
+            connections: ConnectionLimit::new(super::MAX_CONNECTIONS),
+        };
+        try!(set_mode(config));
+        Ok(server)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.config.path
+    }
+
+    /// Stops accepting connections, and makes open connections hang up, once `token` is
+    /// triggered.
+    pub fn set_shutdown(&mut self, token: Arc<Token>) -> io::Result<()> {
+        try!(self.listener.set_nonblocking(true));
+        self.shutdown = Some(token);
+        Ok(())
+    }
+
+    /// Reads every connection on its own thread, with its own parser, until shut down.
+    pub fn run(&self, stats: Arc<ServerStats>, parser: &ParserConfig, tx: Sender<StatMsg>) {
+        info!("Listening for connections on {}", self.config.path.display());
+        while let Some(stream) = super::accept(&self.listener, self.shutdown.as_deref()) {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Failed to accept on {}: {}", self.config.path.display(), err);
+                    continue;
+                }
+            };
+            let slot = match self.connections.acquire() {
+                Some(slot) => slot,
+                None => {
+                    warn!("{} is at its connection limit, refusing a connection", self.config.path.display());
+                    continue;
+                }
+            };
+            let connection = Connection {
+                parser: PacketParser::new(stats.clone(), parser),
+                peer_tag: self.config.peer_tag,
+                tx: tx.clone(),
+                shutdown: self.shutdown.clone(),
+            };
+            thread::spawn(move || {
+                let _slot = slot;
+                if let Err(err) = connection.serve(stream) {
+                    debug!("Unix stream connection closed: {}", err);
+                }
+            });
+        }
+        info!("Stopping listener on {}", self.config.path.display());
+    }
+}
+
+impl Drop for UnixStreamServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.config.path);
+    }
+}
+
+struct Connection {
+    parser: PacketParser,
+    peer_tag: Option<PeerTag>,
+    tx: Sender<StatMsg>,
+    shutdown: Option<Arc<Token>>,
+}
+
+impl Connection {
+    fn serve(mut self, stream: UnixStream) -> io::Result<()> {
+        let pid = match self.peer_tag {
+            Some(_) => Some(try!(peer_pid(&stream))),
+            None => None,
+        };
+        let prefix = match (self.peer_tag, pid) {
+            (Some(tag), Some(pid)) => peer_prefix(tag, pid),
+            _ => None,
+        };
+        let source = pid.map_or_else(|| "unix stream".to_string(), |pid| format!("pid {}", pid));
+        if self.shutdown.is_some() {
+            try!(stream.set_read_timeout(Some(Duration::from_millis(super::SHUTDOWN_CHECK))));
+        }
+
+        let shutdown = self.shutdown.clone();
+        super::read_lines(stream, shutdown.as_deref(), |line| self.handle(line, &source, &prefix))
+    }
+
+    fn handle(&mut self, line: &[u8], source: &str, prefix: &Option<String>) -> io::Result<()> {
+        if let Some(mut msg) = self.parser.parse(line, source) {
+            if let Some(ref prefix) = *prefix {
+                super::add_prefix(&mut msg, prefix);
+            }
+            if self.tx.send(msg).is_err() {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "receiver hung up"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_triggered(shutdown: &Option<Arc<Token>>) -> bool {
+    shutdown.as_ref().is_some_and(|token| token.is_triggered())
+}
+
+// Makes sure `config.path` can be bound: a socket file nothing answers on anymore (`probe`
+// fails) is removed if the config allows it, anything else is left alone for bind to fail on.
+fn prepare_path<F: Fn(&Path) -> io::Result<()>>(config: &UnixSocketConfig, probe: F) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(&config.path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() || !config.remove_stale {
+        return Ok(());
+    }
+    if probe(&config.path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                  format!("{} is in use by another server", config.path.display())));
+    }
+    info!("Removing stale socket {}", config.path.display());
+    fs::remove_file(&config.path)
+}
+
+fn set_mode(config: &UnixSocketConfig) -> io::Result<()> {
+    match config.mode {
+        Some(mode) => fs::set_permissions(&config.path, Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+// Where a datagram came from, only formatted when one of its lines is logged.
+struct Source<'a> {
+    pid: Option<i32>,
+    path: &'a Path,
+}
+
+impl<'a> fmt::Display for Source<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "pid {}", pid),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+// The name prefix for metrics sent by `pid`, `None` leaves them alone.
+fn peer_prefix(tag: PeerTag, pid: i32) -> Option<String> {
+    match tag {
+        PeerTag::Pid => Some(format!("pid-{}.", pid)),
+        PeerTag::Container => {
+            let mut cgroup = String::new();
+            let path = format!("/proc/{}/cgroup", pid);
+            if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_string(&mut cgroup)) {
+                debug!("Could not read {}: {}", path, err);
+                return None;
+            }
+            container_id(&cgroup).map(|id| format!("container-{}.", &id[..12]))
+        }
+    }
+}
+
+/// Finds the container id (64 hex digits, as used by Docker, containerd and CRI-O) in the
+/// contents of a `/proc/<pid>/cgroup` file.
+pub fn container_id(cgroup: &str) -> Option<&str> {
+    cgroup.lines()
+        .flat_map(|line| line.split(|c: char| !c.is_ascii_alphanumeric()))
+        .find(|part| part.len() == 64 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod credentials {
+    use libc;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::{UnixDatagram, UnixStream};
+    use std::ptr;
+
+    // Asks the kernel to attach the sender's credentials to every datagram.
+    pub fn pass_credentials(socket: &UnixDatagram) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(socket.as_raw_fd(),
+                             libc::SOL_SOCKET,
+                             libc::SO_PASSCRED,
+                             &on as *const libc::c_int as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Reads one datagram, returning its length, the sender's pid when `with_pid` is set and
+    /// whether the datagram was cut off to fit `buf`.
+    pub fn recv_with_pid(socket: &UnixDatagram,
+                         buf: &mut [u8],
+                         with_pid: bool)
+                         -> io::Result<(usize, Option<i32>, bool)> {
+        // Room for one SCM_CREDENTIALS message, u64s for the alignment cmsghdr needs.
+        let mut control = [0u64; 8];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            if with_pid {
+                msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_controllen = mem::size_of_val(&control) as _;
+            }
+            let len = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut pid = None;
+            if with_pid {
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+                while !cmsg.is_null() {
+                    if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS {
+                        let cred = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred);
+                        pid = Some(cred.pid);
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                }
+            }
+            Ok((len as usize, pid, msg.msg_flags & libc::MSG_TRUNC != 0))
+        }
+    }
+
+    pub fn peer_pid(stream: &UnixStream) -> io::Result<i32> {
+        let mut cred: libc::ucred = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(stream.as_raw_fd(),
+                             libc::SOL_SOCKET,
+                             libc::SO_PEERCRED,
+                             &mut cred as *mut libc::ucred as *mut libc::c_void,
+                             &mut len)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(cred.pid)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod credentials {
+    use std::io;
+    use std::os::unix::net::{UnixDatagram, UnixStream};
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "peer credentials are only supported on Linux")
+    }
+
+    pub fn pass_credentials(_: &UnixDatagram) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    // Without the message flags a datagram that fills the buffer exactly has to be assumed to
+    // have been cut off.
+    pub fn recv_with_pid(socket: &UnixDatagram, buf: &mut [u8], _: bool)
+                         -> io::Result<(usize, Option<i32>, bool)> {
+        let len = try!(socket.recv(buf));
+        Ok((len, None, len == buf.len()))
+    }
+
+    pub fn peer_pid(_: &UnixStream) -> io::Result<i32> {
+        Err(unsupported())
+    }
+}
+
+use self::credentials::{pass_credentials, peer_pid, recv_with_pid};
+
+#[cfg(test)]
+fn temp_socket(name: &str) -> UnixSocketConfig {
+    use std::env;
+    let path = env::temp_dir().join(format!("rustatsd-{}-{}.sock", name, ::std::process::id()));
+    let _ = fs::remove_file(&path);
+    UnixSocketConfig::new(path)
+}
+
+#[cfg(test)]
+fn recv_timeout(rx: &::mio::channel::Receiver<StatMsg>) -> StatMsg {
+    use std::time::Instant;
+    let start = Instant::now();
+    loop {
+        if let Ok(msg) = rx.try_recv() {
+            return msg;
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "no message received");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_unix_datagram_reader() {
+    use mio::channel::channel;
+    use metrics::{ParseErrorKind, StatKind};
+    use sync_token::TokenSource;
+
+    let mut config = temp_socket("datagram");
+    config.mode = Some(0o600);
+    config.peer_tag = Some(PeerTag::Pid);
+    // A socket file left behind by a crashed server.
+    drop(UnixDatagram::bind(&config.path).unwrap());
+
+    let shutdown = TokenSource::new();
+    let mut reader = UnixDatagramReader::bind(&config).unwrap();
+    reader.set_shutdown(shutdown.get_token()).unwrap();
+    assert_eq!(fs::metadata(&config.path).unwrap().permissions().mode() & 0o777, 0o600);
+    // A live server's socket isn't taken over.
+    assert_eq!(UnixDatagramReader::bind(&config).err().unwrap().kind(), io::ErrorKind::AddrInUse);
+
+    let (tx, rx) = channel();
+    let stats = Arc::new(ServerStats::new());
+    let parser = PacketParser::new(stats.clone(), &ParserConfig::default());
+    let reader = thread::spawn(move || reader.run(parser, tx));
+    let client = UnixDatagram::unbound().unwrap();
+    client.send_to(b"hits:2|c", &config.path).unwrap();
+    let name = if cfg!(target_os = "linux") {
+        format!("pid-{}.hits", ::std::process::id())
+    } else {
+        "hits".to_string()
+    };
+    assert_eq!(recv_timeout(&rx), StatMsg::Inc(StatKind::Counter, name.clone(), 2, 1.0));
+
+    // A datagram too large for the read buffer is dropped rather than parsed up to the cut.
+    let mut large = b"hits:1|c\n".repeat(MAX_DATAGRAM / 9 + 1);
+    large.extend_from_slice(b"hits:1|c");
+    client.send_to(&large, &config.path).unwrap();
+    client.send_to(b"hits:3|c", &config.path).unwrap();
+    assert_eq!(recv_timeout(&rx), StatMsg::Inc(StatKind::Counter, name, 3, 1.0));
+    assert_eq!(stats.parse_errors().get(ParseErrorKind::Truncated), 1);
+
+    shutdown.trigger();
+    reader.join().unwrap();
+    assert!(!config.path.exists());
+}
+
+#[test]
+fn test_unix_stream_server() {
+    use mio::channel::channel;
+    use std::io::Write;
+    use metrics::StatKind;
+    use sync_token::TokenSource;
+
+    let config = temp_socket("stream");
+    let shutdown = TokenSource::new();
+    let mut server = UnixStreamServer::bind(&config).unwrap();
+    server.set_shutdown(shutdown.get_token()).unwrap();
+    let (tx, rx) = channel();
+    let stats = Arc::new(ServerStats::new());
+    let server = thread::spawn(move || server.run(stats, &ParserConfig::default(), tx));
+
+    let mut client = UnixStream::connect(&config.path).unwrap();
+    client.write_all(b"hits:1|c\nlo").unwrap();
+    client.flush().unwrap();
+    assert_eq!(recv_timeout(&rx), StatMsg::Inc(StatKind::Counter, "hits".to_string(), 1, 1.0));
+    thread::sleep(Duration::from_millis(150));
+    client.write_all(b"ad:3|g").unwrap();
+    drop(client);
+    assert_eq!(recv_timeout(&rx), StatMsg::Set(StatKind::Gauge, "load".to_string(), 3, 1.0));
+
+    shutdown.trigger();
+    server.join().unwrap();
+    assert!(!config.path.exists());
+}
+
+#[test]
+fn test_container_id() {
+    let id = "4f1e5a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7";
+    assert_eq!(container_id(&format!("12:memory:/docker/{}\n", id)), Some(id));
+    assert_eq!(container_id(&format!("0::/system.slice/docker-{}.scope\n", id)), Some(id));
+    assert_eq!(container_id(&format!("0::/kubepods/burstable/pod1234/cri-containerd-{}.scope", id)),
+               Some(id));
+    assert_eq!(container_id("0::/user.slice/user-1000.slice/session-2.scope\n"), None);
+}
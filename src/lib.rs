@@ -1,4 +1,30 @@
-mod sync_token;
+#[macro_use]
+extern crate log;
+extern crate mio;
+extern crate toml;
+extern crate net2;
+extern crate libc;
+extern crate flate2;
+extern crate serde_json;
+extern crate regex;
+
+pub mod aggregator;
+pub mod backends;
+pub mod client;
+pub mod config;
+pub mod flusher;
+pub mod frontends;
+pub mod metrics;
+pub mod pattern;
+pub mod persist;
+pub mod proxy;
+pub mod rules;
+pub mod sanitize;
+pub mod server;
+pub mod sketch;
+pub mod stats;
+pub mod sync_token;
+pub mod tags;
 use sync_token::*;
 use std::thread;
 use std::time::Duration;
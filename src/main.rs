@@ -6,19 +6,27 @@ extern crate log4rs;
 extern crate clap;
 extern crate mio;
 extern crate bytes;
+extern crate toml;
+extern crate net2;
+extern crate libc;
+extern crate rustatsd;
 
 use std::cell::Cell;
 use std::collections::HashMap;
 use std::env;
-use std::io::Read;
+use std::io::{self, Read};
 use std::process;
+use std::thread;
+use std::time::Duration;
 use clap::{Arg, App, AppSettings};
 
-mod metrics;
-mod frontends;
-mod backends;
-use frontends::*;
-use backends::*;
+mod signals;
+use rustatsd::config;
+use rustatsd::metrics::parse_line;
+use rustatsd::rules::{Outcome, Rules};
+use rustatsd::tags::Templates;
+use rustatsd::server::ServerBuilder;
+use rustatsd::sync_token::TokenSource;
 
 struct UserConfiguration {
     owner: String,
@@ -35,6 +43,11 @@ struct Bucket {
 }
 
 fn main() {
+    // Before log4rs starts its config watcher, see `signals`.
+    let shutdown = TokenSource::new();
+    let token = shutdown.get_token();
+    signals::shutdown_on_signal(shutdown);
+
     log4rs::init_file("log4rs.toml", Default::default()).unwrap();
     let ver: version::Version = std::str::FromStr::from_str(version!()).unwrap();
     println!("RuStatsD v{}", ver);
@@ -44,11 +57,48 @@ fn main() {
     let bucket = buckets.entry("stats.counters.whatever").or_insert(0);
     *bucket += 1;
 
-    // main_tcp();
-    let host = "127.0.0.1";
-    let port = "13265";
-    let mut server = udp_server::UdpReader::new(&host, &port).unwrap();
-    server.run();
+    let matches = App::new("rustatsd")
+        .setting(AppSettings::ColoredHelp)
+        .version(version!())
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .value_name("FILE")
+            .help("TOML configuration file")
+            .takes_value(true))
+        .arg(Arg::with_name("test-rules")
+            .long("test-rules")
+            .help("Print what the configured rules do to the statsd lines on stdin, then exit"))
+        .get_matches();
+
+    let config = match matches.value_of("config") {
+        Some(path) => {
+            match config::Config::from_file(path) {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("{}", err);
+                    println!("{}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        None => config::Config::default(),
+    };
+    if matches.is_present("test-rules") {
+        process::exit(test_rules(&config.rules, &config.templates));
+    }
+
+    let server = match ServerBuilder::from_config(config).start() {
+        Ok(server) => server,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+    while !token.is_triggered() {
+        thread::sleep(Duration::from_millis(200));
+    }
+    server.shutdown();
 }
 
     // env_check("INBOUND_ADDRESS", "HTTP endpoint for incoming StatsD messages");
@@ -150,6 +200,39 @@ fn main() {
     // _ => panic!("Unsupported option combination"),
     // };
 
+// Prints one line per statsd line on stdin, exits non-zero if any of them doesn't parse.
+fn test_rules(rules: &Rules, templates: &Templates) -> i32 {
+    let mut input = String::new();
+    if let Err(err) = io::stdin().read_to_string(&mut input) {
+        println!("Could not read stdin: {}", err);
+        return 1;
+    }
+    let mut status = 0;
+    for line in input.lines().filter(|line| !line.trim().is_empty()) {
+        let stat = match parse_line(line.as_bytes()) {
+            Ok(stat) => stat,
+            Err(err) => {
+                println!("{} => {}", line, err);
+                status = 1;
+                continue;
+            }
+        };
+        let (name, route) = match rules.apply(stat.kind(), stat.name()) {
+            Outcome::Dropped => {
+                println!("{} => dropped", line);
+                continue;
+            }
+            Outcome::Kept(name, route) => (name, route),
+        };
+        let name = templates.apply(&name).unwrap_or(name);
+        match route {
+            None => println!("{} => {}|{}", line, name, stat.kind()),
+            Some(backends) => println!("{} => {}|{} to {}", line, name, stat.kind(), backends.join(", ")),
+        }
+    }
+    status
+}
+
 fn env_check(env_var: &str, help: &str) {
     if let Err(_) = env::var(env_var) {
         println!("Missing environment variable: `{}` -- {}", env_var, help);
@@ -1,35 +1,145 @@
+use std::borrow::Cow;
 use std::io;
 use std::result;
-use std::str::{from_utf8, FromStr};
+use std::str::{from_utf8, from_utf8_unchecked, FromStr};
 use std::time::Duration;
 use std::fmt;
-use std::num;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::vec::Vec;
+use sketch::{Digest, HyperLogLog};
+use tags;
+
+/// Why a line was rejected by the parser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParseErrorKind {
+    /// The packet held no metric lines at all.
+    Empty,
+    EmptyName,
+    /// The name was not valid UTF-8.
+    BadName,
+    BadType,
+    BadValue,
+    BadSampleRate,
+    BadTag,
+    /// The datagram was larger than the read buffer and got cut off.
+    Truncated,
+}
+
+impl ParseErrorKind {
+    pub fn all() -> &'static [ParseErrorKind] {
+        static ALL: [ParseErrorKind; 8] = [ParseErrorKind::Empty,
+                                           ParseErrorKind::EmptyName,
+                                           ParseErrorKind::BadName,
+                                           ParseErrorKind::BadType,
+                                           ParseErrorKind::BadValue,
+                                           ParseErrorKind::BadSampleRate,
+                                           ParseErrorKind::BadTag,
+                                           ParseErrorKind::Truncated];
+        &ALL
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ParseErrorKind::Empty => "empty",
+            ParseErrorKind::EmptyName => "empty_name",
+            ParseErrorKind::BadName => "bad_name",
+            ParseErrorKind::BadType => "bad_type",
+            ParseErrorKind::BadValue => "bad_value",
+            ParseErrorKind::BadSampleRate => "bad_sample_rate",
+            ParseErrorKind::BadTag => "bad_tag",
+            ParseErrorKind::Truncated => "truncated",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ParseMessageError {
-    _priv: (),
+    kind: ParseErrorKind,
+    line: String,
+    offset: usize,
+}
+
+impl ParseMessageError {
+    pub fn new(kind: ParseErrorKind, line: &[u8], offset: usize) -> ParseMessageError {
+        ParseMessageError {
+            kind: kind,
+            line: String::from_utf8_lossy(line).into_owned(),
+            offset: offset,
+        }
+    }
+
+    // Points the error at `field`, which must be a subslice of `line`.
+    fn at(kind: ParseErrorKind, line: &[u8], field: &[u8]) -> ParseMessageError {
+        let offset = field.as_ptr() as usize - line.as_ptr() as usize;
+        ParseMessageError::new(kind, line, offset)
+    }
+
+    pub fn kind(&self) -> ParseErrorKind {
+        self.kind
+    }
+
+    /// The offending line, with invalid UTF-8 replaced.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Byte offset into the line of the field that could not be parsed.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
 }
 
 impl fmt::Display for ParseMessageError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        "provided string was not a properly formated statsd message".fmt(f)
+        let reason = match self.kind {
+            ParseErrorKind::Empty => "no metrics in message",
+            ParseErrorKind::EmptyName => "metric name is empty",
+            ParseErrorKind::BadName => "metric name is not valid utf8",
+            ParseErrorKind::BadType => "unknown metric type",
+            ParseErrorKind::BadValue => "value is not an integer",
+            ParseErrorKind::BadSampleRate => "sample rate is not a number in (0, 1]",
+            ParseErrorKind::BadTag => "malformed tag",
+            ParseErrorKind::Truncated => "datagram was cut off",
+        };
+        write!(f, "{} at byte {} of {:?}", reason, self.offset, self.line)
     }
 }
 
-impl From<num::ParseIntError> for ParseMessageError {
-    fn from(err: num::ParseIntError) -> ParseMessageError {
-        ParseMessageError { _priv: () }
-    }
+/// Running count of rejected lines for each `ParseErrorKind`, safe to share between threads.
+#[derive(Debug, Default)]
+pub struct ParseErrorCounts {
+    counts: [AtomicUsize; 8],
 }
 
-impl From<num::ParseFloatError> for ParseMessageError {
-    fn from(err: num::ParseFloatError) -> ParseMessageError {
-        ParseMessageError { _priv: () }
+impl ParseErrorCounts {
+    pub fn new() -> ParseErrorCounts {
+        ParseErrorCounts::default()
+    }
+
+    pub fn record(&self, kind: ParseErrorKind) {
+        self.counts[kind.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, kind: ParseErrorKind) -> usize {
+        self.counts[kind.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> usize {
+        ParseErrorKind::all().iter().map(|&kind| self.get(kind)).sum()
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum StatKind {
     Counter,
     Timer,
@@ -43,24 +153,37 @@ pub enum StatMsg {
     Inc(StatKind, String, i64, f64),
     Set(StatKind, String, i64, f64),
     Del(StatKind, String),
+    /// A gauge set to a value that need not be an integer, from frontends like Graphite's.
+    Gauge(String, f64),
+    /// A bucket another RuStatsD already aggregated, merged into the local one.
+    Merge(String, Aggregate),
     Bat(Vec<StatMsg>),
 }
 
+/// What a forwarding RuStatsD flushed for a bucket, see `backends::forward`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aggregate {
+    /// The sample rate corrected count.
+    Count(f64),
+    /// The sample rate corrected number of samples, and the samples themselves.
+    Timer(f64, Digest),
+    Set(HyperLogLog),
+}
+
 impl FromStr for StatMsg {
     type Err = ParseMessageError;
     #[inline]
     fn from_str(s: &str) -> Result<StatMsg, ParseMessageError> {
-        let msgs: Result<Vec<_>, ParseMessageError> = s.split_terminator('\n')
-            .filter(|ss| !ss.is_empty())
-            .map(parse_msg)
+        let msgs: Result<Vec<_>, ParseMessageError> = parse_lines(s.as_bytes())
+            .map(|line| line.map(|stat| stat.to_msg()))
             .collect();
 
         match msgs {
-            Ok(msg) => {
+            Ok(mut msg) => {
                 if msg.len() == 0 {
-                    Err(ParseMessageError { _priv: () })
+                    Err(ParseMessageError::new(ParseErrorKind::Empty, s.as_bytes(), 0))
                 } else if msg.len() == 1 {
-                    Ok(msg[0].clone())
+                    Ok(msg.pop().unwrap())
                 } else {
                     Ok(StatMsg::Bat(msg))
                 }
@@ -70,70 +193,442 @@ impl FromStr for StatMsg {
     }
 }
 
-fn parse_kind(raw: &str) -> Result<StatKind, ParseMessageError> {
-    match raw {
-        "c" => Ok(StatKind::Counter),
-        "ms" => Ok(StatKind::Timer),
-        "g" => Ok(StatKind::Gauge),
-        "s" => Ok(StatKind::Sets),
-        "h" => Ok(StatKind::Histogram),
-        _ => return Err(ParseMessageError { _priv: () }),
+impl StatKind {
+    /// The type suffix of a statsd line, e.g. `ms` for timers.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            StatKind::Counter => "c",
+            StatKind::Timer => "ms",
+            StatKind::Gauge => "g",
+            StatKind::Sets => "s",
+            StatKind::Histogram => "h",
+        }
     }
 }
 
-fn build_msg(parts: &Vec<&str>) -> Result<StatMsg, ParseMessageError> {
-    let name = parts[0].to_string();
-    let kind = try!(parse_kind(parts[2]));
+impl FromStr for StatKind {
+    type Err = String;
 
-    if parts[1] == "delete" {
-        Ok(StatMsg::Del(kind, name))
-    } else {
-        let value = try!(parts[1].parse());
-        let sr: f64 = if parts.len() > 3 && parts[3].starts_with('@') {
-            try!(parts[3][1..].parse())
-        } else {
-            1.0
+    /// The inverse of `as_str`.
+    fn from_str(s: &str) -> Result<StatKind, String> {
+        parse_kind(s.as_bytes()).ok_or_else(|| format!("unknown metric type `{}`", s))
+    }
+}
+
+impl fmt::Display for StatKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// Writes the message back out as statsd lines that parse into the same message. The one
+/// exception is an absolute negative gauge, which statsd can only express as a reset to zero
+/// followed by a negative delta. Fractional `Gauge` values are written as they are, which
+/// statsd can't parse. `Merge` has no statsd form, it is written as a line of the forwarding
+/// protocol (see `frontends::forward`).
+impl fmt::Display for StatMsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (kind, name, value, sr) = match *self {
+            StatMsg::Bat(ref msgs) => {
+                for (i, msg) in msgs.iter().enumerate() {
+                    if i > 0 {
+                        try!(f.write_str("\n"));
+                    }
+                    try!(msg.fmt(f));
+                }
+                return Ok(());
+            }
+            StatMsg::Del(ref kind, ref name) => return write!(f, "{}:delete|{}", name, kind),
+            StatMsg::Gauge(ref name, value) if value < 0.0 => return write!(f, "{}:0|g\n{}:{}|g", name, name, value),
+            StatMsg::Gauge(ref name, value) => return write!(f, "{}:{}|g", name, value),
+            StatMsg::Merge(ref name, Aggregate::Count(count)) => return write!(f, "c {} {}", count, name),
+            StatMsg::Merge(ref name, Aggregate::Timer(count, ref digest)) => {
+                return write!(f, "t {} {} {}", count, digest.encode(), name)
+            }
+            StatMsg::Merge(ref name, Aggregate::Set(ref hll)) => return write!(f, "s {} {}", hll.encode(), name),
+            StatMsg::Inc(StatKind::Gauge, ref name, value, sr) => {
+                let sign = if value < 0 { "" } else { "+" };
+                try!(write!(f, "{}:{}{}|g", name, sign, value));
+                return write_sample_rate(f, sr);
+            }
+            StatMsg::Set(StatKind::Gauge, ref name, value, sr) if value < 0 => {
+                try!(write!(f, "{}:0|g\n{}:{}|g", name, name, value));
+                return write_sample_rate(f, sr);
+            }
+            StatMsg::Inc(ref kind, ref name, value, sr) |
+            StatMsg::Set(ref kind, ref name, value, sr) => (kind, name, value, sr),
         };
+        try!(write!(f, "{}:{}|{}", name, value, kind));
+        write_sample_rate(f, sr)
+    }
+}
+
+fn write_sample_rate(f: &mut fmt::Formatter, sr: f64) -> fmt::Result {
+    if sr < 1.0 {
+        write!(f, "|@{}", sr)
+    } else {
+        Ok(())
+    }
+}
+
+/// What to do with a packet when only some of its lines parse.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ParseMode {
+    /// Keep every valid line and report the bad ones.
+    #[default]
+    Lenient,
+    /// Reject the whole packet if any line is bad, the way `FromStr` does.
+    Strict,
+}
+
+/// The result of `StatMsg::parse_packet`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedPacket {
+    /// The accepted lines as a single message, or a `Bat` of them. `None` if nothing was accepted.
+    pub msg: Option<StatMsg>,
+    /// One error for every rejected line, in packet order.
+    pub errors: Vec<ParseMessageError>,
+}
 
-        match kind {
-            StatKind::Counter => Ok(StatMsg::Inc(kind, name, value, sr)),
-            StatKind::Gauge if parts[1].starts_with('+') || parts[1].starts_with('-') => {
-                Ok(StatMsg::Inc(kind, name, value, sr))
+impl StatMsg {
+    /// Parses every line of a packet, collecting errors per line instead of stopping at the
+    /// first one. What happens to the valid lines of a partly bad packet depends on `mode`.
+    pub fn parse_packet(raw: &[u8], mode: ParseMode) -> ParsedPacket {
+        let mut msgs = Vec::new();
+        let mut errors = Vec::new();
+        for line in parse_lines(raw) {
+            match line {
+                Ok(stat) => msgs.push(stat.to_msg()),
+                Err(err) => errors.push(err),
             }
-            StatKind::Gauge | StatKind::Timer | StatKind::Sets | StatKind::Histogram => {
-                Ok(StatMsg::Set(kind, name, value, sr))
+        }
+
+        if msgs.is_empty() && errors.is_empty() {
+            errors.push(ParseMessageError::new(ParseErrorKind::Empty, raw, 0));
+        }
+        if mode == ParseMode::Strict && !errors.is_empty() {
+            msgs.clear();
+        }
+        ParsedPacket {
+            msg: StatMsg::from_msgs(msgs),
+            errors: errors,
+        }
+    }
+
+    /// The metric name, `None` for a `Bat`.
+    pub fn name(&self) -> Option<&str> {
+        match *self {
+            StatMsg::Inc(_, ref name, _, _) |
+            StatMsg::Set(_, ref name, _, _) |
+            StatMsg::Del(_, ref name) |
+            StatMsg::Gauge(ref name, _) |
+            StatMsg::Merge(ref name, _) => Some(name),
+            StatMsg::Bat(_) => None,
+        }
+    }
+
+    /// The kind of bucket the message is about, `None` for a `Bat`.
+    pub fn kind(&self) -> Option<StatKind> {
+        match *self {
+            StatMsg::Inc(ref kind, ..) | StatMsg::Set(ref kind, ..) | StatMsg::Del(ref kind, _) => Some(kind.clone()),
+            StatMsg::Gauge(..) => Some(StatKind::Gauge),
+            StatMsg::Merge(_, Aggregate::Count(_)) => Some(StatKind::Counter),
+            StatMsg::Merge(_, Aggregate::Timer(..)) => Some(StatKind::Timer),
+            StatMsg::Merge(_, Aggregate::Set(_)) => Some(StatKind::Sets),
+            StatMsg::Bat(_) => None,
+        }
+    }
+
+    pub fn name_mut(&mut self) -> Option<&mut String> {
+        match *self {
+            StatMsg::Inc(_, ref mut name, _, _) |
+            StatMsg::Set(_, ref mut name, _, _) |
+            StatMsg::Del(_, ref mut name) |
+            StatMsg::Gauge(ref mut name, _) |
+            StatMsg::Merge(ref mut name, _) => Some(name),
+            StatMsg::Bat(_) => None,
+        }
+    }
+
+    /// Calls `f` on the name of this message, or every message in a `Bat`.
+    pub fn map_names<F: FnMut(&mut String)>(&mut self, f: &mut F) {
+        match *self {
+            StatMsg::Bat(ref mut msgs) => {
+                for msg in msgs.iter_mut() {
+                    msg.map_names(f);
+                }
             }
+            ref mut msg => f(msg.name_mut().unwrap()),
+        }
+    }
+
+    /// Folds a list of messages into one, batching them up if there is more than one.
+    pub fn from_msgs(mut msgs: Vec<StatMsg>) -> Option<StatMsg> {
+        match msgs.len() {
+            0 => None,
+            1 => msgs.pop(),
+            _ => Some(StatMsg::Bat(msgs)),
         }
     }
 }
 
-fn parse_msg(raw: &str) -> Result<StatMsg, ParseMessageError> {
-    let parts: Vec<&str> = raw.split(|c| c == ':' || c == '|')
-        .filter(|ss| !ss.is_empty())
-        .collect();
+/// A single metric line borrowed from the packet it was parsed out of. Mirrors `StatMsg`
+/// without owning the name so that parsing a packet doesn't touch the heap. Only lines with
+/// DogStatsD tags own their name, which carries the tags (see `tags`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatRef<'a> {
+    Inc(StatKind, Cow<'a, str>, i64, f64),
+    Set(StatKind, Cow<'a, str>, i64, f64),
+    Del(StatKind, Cow<'a, str>),
+}
 
-    match parts.len() {
-        0 => Err(ParseMessageError { _priv: () }),
-        1 => Ok(StatMsg::Inc(StatKind::Counter, parts[0].to_string(), 1, 1.0)),
-        2 => {
-            let name = parts[0].to_string();
-            match parse_kind(parts[1]) {
-                Err(err) => {
-                    if let Ok(value) = parts[1].parse() {
-                        Ok(StatMsg::Inc(StatKind::Counter, name, value, 1.0))
-                    } else {
-                        Err(err)
+impl<'a> StatRef<'a> {
+    pub fn name(&self) -> &str {
+        match *self {
+            StatRef::Inc(_, ref name, _, _) |
+            StatRef::Set(_, ref name, _, _) |
+            StatRef::Del(_, ref name) => name,
+        }
+    }
+
+    pub fn kind(&self) -> &StatKind {
+        match *self {
+            StatRef::Inc(ref kind, _, _, _) |
+            StatRef::Set(ref kind, _, _, _) |
+            StatRef::Del(ref kind, _) => kind,
+        }
+    }
+
+    pub fn to_msg(&self) -> StatMsg {
+        self.to_msg_named(self.name().to_string())
+    }
+
+    /// Like `to_msg`, but with `name` instead of the borrowed one, e.g. a sanitized copy.
+    pub fn to_msg_named(&self, name: String) -> StatMsg {
+        match *self {
+            StatRef::Inc(ref kind, _, value, sr) => StatMsg::Inc(kind.clone(), name, value, sr),
+            StatRef::Set(ref kind, _, value, sr) => StatMsg::Set(kind.clone(), name, value, sr),
+            StatRef::Del(ref kind, _) => StatMsg::Del(kind.clone(), name),
+        }
+    }
+}
+
+/// Iterator over the metric lines of a raw packet, see `parse_lines`.
+pub struct Lines<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Result<StatRef<'a>, ParseMessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.rest.is_empty() {
+            let (line, rest) = match self.rest.iter().position(|&b| b == b'\n') {
+                Some(end) => (&self.rest[..end], &self.rest[end + 1..]),
+                None => (self.rest, &self.rest[self.rest.len()..]),
+            };
+            self.rest = rest;
+            if !line.is_empty() {
+                return Some(parse_line(line));
+            }
+        }
+        None
+    }
+}
+
+/// Parses a packet of newline separated metric lines without allocating. Only metric names
+/// are checked for valid UTF-8, everything else is matched as ASCII.
+pub fn parse_lines<'a>(buf: &'a [u8]) -> Lines<'a> {
+    Lines { rest: buf }
+}
+
+fn parse_kind(raw: &[u8]) -> Option<StatKind> {
+    match raw {
+        b"c" => Some(StatKind::Counter),
+        b"ms" => Some(StatKind::Timer),
+        b"g" => Some(StatKind::Gauge),
+        b"s" => Some(StatKind::Sets),
+        b"h" => Some(StatKind::Histogram),
+        _ => None,
+    }
+}
+
+fn parse_int(raw: &[u8]) -> Option<i64> {
+    let (negative, digits) = match raw.first() {
+        Some(&b'-') => (true, &raw[1..]),
+        Some(&b'+') => (false, &raw[1..]),
+        _ => (false, raw),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+
+    let mut value: i64 = 0;
+    for &b in digits {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        let digit = (b - b'0') as i64;
+        value = value.checked_mul(10).and_then(|v| {
+            if negative {
+                v.checked_sub(digit)
+            } else {
+                v.checked_add(digit)
+            }
+        })?;
+    }
+    Some(value)
+}
+
+fn parse_sample_rate(raw: &[u8]) -> Option<f64> {
+    if !raw.is_ascii() {
+        return None;
+    }
+    // Plain ASCII is always valid UTF-8, so skip the second validation pass.
+    let raw = unsafe { from_utf8_unchecked(raw) };
+    match raw.parse::<f64>() {
+        Ok(sr) if sr > 0.0 && sr <= 1.0 => Some(sr),
+        _ => None,
+    }
+}
+
+// DogStatsD style `#tag,key:value` lists, every tag needs a non-empty key. `;` and `=` would
+// break the tagged name they end up in.
+fn valid_tags(raw: &[u8]) -> bool {
+    raw.split(|&b| b == b',')
+        .all(|tag| !tag.is_empty() && tag[0] != b':' && tags::valid(tag))
+}
+
+// Adds DogStatsD tags to `name`, a tag without a value is `true` the way Telegraf reads them.
+fn tagged(name: &str, raw: &str) -> String {
+    let (measurement, mut tags) = tags::split(name);
+    for tag in raw.split(',') {
+        let mut pair = tag.splitn(2, ':');
+        let key = pair.next().unwrap_or(tag);
+        tags.push((key, pair.next().unwrap_or("true")));
+    }
+    tags::join(measurement, &tags)
+}
+
+/// Parses a single metric line, see `parse_lines`.
+///
+/// A line is `name[:value][|type][|@rate][|#tags]`. A bare name counts as an increment of one,
+/// and a missing value falls back to the default for the type. Tags are added to the name,
+/// `name|#env:prod` becomes `name;env=prod`.
+pub fn parse_line<'a>(raw: &'a [u8]) -> Result<StatRef<'a>, ParseMessageError> {
+    let mut sections = raw.split(|&b| b == b'|');
+    let head = sections.next().unwrap_or(raw);
+    let (name, value) = match head.iter().position(|&b| b == b':') {
+        Some(end) => (&head[..end], Some(&head[end + 1..])),
+        None => (head, None),
+    };
+    if name.is_empty() {
+        return Err(ParseMessageError::at(ParseErrorKind::EmptyName, raw, name));
+    }
+    let name = match from_utf8(name) {
+        Ok(name) => name,
+        Err(_) => return Err(ParseMessageError::at(ParseErrorKind::BadName, raw, name)),
+    };
+    let value = value.and_then(|v| if v.is_empty() { None } else { Some(v) });
+    let kind = sections.next().and_then(|k| if k.is_empty() { None } else { Some(k) });
+
+    let mut sr = 1.0;
+    let mut tags = None;
+    for section in sections {
+        if section.starts_with(b"@") {
+            sr = match parse_sample_rate(&section[1..]) {
+                Some(sr) => sr,
+                None => return Err(ParseMessageError::at(ParseErrorKind::BadSampleRate, raw, section)),
+            };
+        } else if section.starts_with(b"#") {
+            if !valid_tags(&section[1..]) {
+                return Err(ParseMessageError::at(ParseErrorKind::BadTag, raw, section));
+            }
+            tags = match from_utf8(&section[1..]) {
+                Ok(tags) => Some(tags),
+                Err(_) => return Err(ParseMessageError::at(ParseErrorKind::BadTag, raw, section)),
+            };
+        }
+    }
+    let name = match tags {
+        Some(tags) => Cow::Owned(tagged(name, tags)),
+        None => Cow::Borrowed(name),
+    };
+
+    match (kind, value) {
+        (None, None) => Ok(StatRef::Inc(StatKind::Counter, name, 1, 1.0)),
+        // `name:value` without a type is a counter, unless the "value" is really a type.
+        (None, Some(value)) => {
+            match parse_kind(value) {
+                Some(kind) => Ok(default_msg(kind, name)),
+                None => {
+                    match parse_int(value) {
+                        Some(n) => Ok(StatRef::Inc(StatKind::Counter, name, n, 1.0)),
+                        None => Err(ParseMessageError::at(ParseErrorKind::BadType, raw, value)),
                     }
                 }
-                Ok(StatKind::Counter) => Ok(StatMsg::Inc(StatKind::Counter, name, 1, 1.0)),
-                Ok(StatKind::Gauge) => Ok(StatMsg::Inc(StatKind::Gauge, name, 0, 1.0)),
-                Ok(kind) => Ok(StatMsg::Set(kind, name, 0, 1.0)),
             }
         }
-        _ => build_msg(&parts),
+        (Some(kind_raw), value) => {
+            let kind = match parse_kind(kind_raw) {
+                Some(kind) => kind,
+                None => return Err(ParseMessageError::at(ParseErrorKind::BadType, raw, kind_raw)),
+            };
+            let value = match value {
+                Some(value) => value,
+                None => return Ok(default_msg(kind, name)),
+            };
+            if value == b"delete" {
+                return Ok(StatRef::Del(kind, name));
+            }
+            let n = match parse_int(value) {
+                Some(n) => n,
+                None => return Err(ParseMessageError::at(ParseErrorKind::BadValue, raw, value)),
+            };
+
+            match kind {
+                StatKind::Counter => Ok(StatRef::Inc(kind, name, n, sr)),
+                StatKind::Gauge if value.starts_with(b"+") || value.starts_with(b"-") => {
+                    Ok(StatRef::Inc(kind, name, n, sr))
+                }
+                StatKind::Gauge | StatKind::Timer | StatKind::Sets | StatKind::Histogram => {
+                    Ok(StatRef::Set(kind, name, n, sr))
+                }
+            }
+        }
     }
 }
 
+// What a line with a type but no value means.
+fn default_msg<'a>(kind: StatKind, name: Cow<'a, str>) -> StatRef<'a> {
+    match kind {
+        StatKind::Counter => StatRef::Inc(StatKind::Counter, name, 1, 1.0),
+        StatKind::Gauge => StatRef::Inc(StatKind::Gauge, name, 0, 1.0),
+        kind => StatRef::Set(kind, name, 0, 1.0),
+    }
+}
+
+#[test]
+fn test_display_round_trip() {
+    let msgs = vec![StatMsg::Inc(StatKind::Counter, "hits".to_string(), 3, 0.25),
+                    StatMsg::Inc(StatKind::Counter, "misses".to_string(), -1, 1.0),
+                    StatMsg::Set(StatKind::Timer, "latency".to_string(), 320, 0.1),
+                    StatMsg::Set(StatKind::Histogram, "size".to_string(), 1024, 1.0),
+                    StatMsg::Set(StatKind::Gauge, "load".to_string(), 7, 1.0),
+                    StatMsg::Inc(StatKind::Gauge, "load".to_string(), 2, 1.0),
+                    StatMsg::Inc(StatKind::Gauge, "load".to_string(), -2, 1.0),
+                    StatMsg::Set(StatKind::Sets, "users".to_string(), 42, 1.0),
+                    StatMsg::Del(StatKind::Gauge, "load".to_string())];
+    for msg in msgs.iter() {
+        assert_eq!(&msg.to_string().parse::<StatMsg>().unwrap(), msg);
+    }
+    let batch = StatMsg::Bat(msgs);
+    assert_eq!(batch.to_string().parse::<StatMsg>().unwrap(), batch);
+
+    assert_eq!(StatMsg::Set(StatKind::Gauge, "temp".to_string(), -4, 1.0).to_string(),
+               "temp:0|g\ntemp:-4|g");
+}
+
 #[test]
 fn test_counter() {
     let actual: StatMsg = "test.key:1|c".parse().unwrap();
@@ -148,6 +643,7 @@ fn test_counter_with_rate() {
     assert_eq!(actual, expected);
 }
 
+#[test]
 fn test_timer() {
     let actual: StatMsg = "test.key:123|ms".parse().unwrap();
     let expected = StatMsg::Set(StatKind::Timer, "test.key".to_string(), 123, 1.0);
@@ -246,6 +742,122 @@ fn test_no_value() {
     assert_eq!(actual, expected);
 }
 
+#[test]
+fn test_parse_lines_borrows() {
+    let packet = b"gorets:1|c\nglork:320|ms|@0.1\n\ngaugor:-5|g";
+    let lines: Vec<_> = parse_lines(packet).collect();
+    assert_eq!(lines,
+               vec![Ok(StatRef::Inc(StatKind::Counter, Cow::Borrowed("gorets"), 1, 1.0)),
+                    Ok(StatRef::Set(StatKind::Timer, Cow::Borrowed("glork"), 320, 0.1)),
+                    Ok(StatRef::Inc(StatKind::Gauge, Cow::Borrowed("gaugor"), -5, 1.0))]);
+    let name = lines[0].as_ref().unwrap().name();
+    assert_eq!(name.as_ptr(), packet.as_ptr());
+}
+
+#[test]
+fn test_parse_lines_reports_each_line() {
+    let lines: Vec<_> = parse_lines(b"good:1|c\nbad:1|x\nalso.good:2|c").collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].is_ok());
+    assert!(lines[1].is_err());
+    assert_eq!(lines[2], Ok(StatRef::Inc(StatKind::Counter, Cow::Borrowed("also.good"), 2, 1.0)));
+}
+
+#[test]
+fn test_parse_line_bad_utf8_name() {
+    let err = parse_line(b"bad\xff.key:1|c").unwrap_err();
+    assert_eq!(err.kind(), ParseErrorKind::BadName);
+    assert_eq!(err.line(), "bad\u{fffd}.key:1|c");
+}
+
+#[test]
+fn test_parse_line_bad_value() {
+    for raw in &[&b"test.key:1\xff|c"[..], b"test.key:99999999999999999999|c", b"test.key:+|g"] {
+        let err = parse_line(raw).unwrap_err();
+        assert_eq!(err.kind(), ParseErrorKind::BadValue);
+        assert_eq!(err.offset(), 9);
+    }
+}
+
+#[test]
+fn test_parse_error_diagnostics() {
+    let cases: &[(&str, ParseErrorKind, usize)] = &[
+        (":1|c", ParseErrorKind::EmptyName, 0),
+        ("test.key:1|x", ParseErrorKind::BadType, 11),
+        ("test.key:delete", ParseErrorKind::BadType, 9),
+        ("test.key:abc|c", ParseErrorKind::BadValue, 9),
+        ("test.key:1|c|@fast", ParseErrorKind::BadSampleRate, 13),
+        ("test.key:1|c|@0", ParseErrorKind::BadSampleRate, 13),
+        ("test.key:1|c|@1.5", ParseErrorKind::BadSampleRate, 13),
+        ("test.key:1|c|#env:prod,,host", ParseErrorKind::BadTag, 13),
+        ("test.key:1|c|#:prod", ParseErrorKind::BadTag, 13),
+    ];
+    for &(raw, kind, offset) in cases {
+        let err = raw.parse::<StatMsg>().unwrap_err();
+        assert_eq!((raw, err.kind(), err.offset()), (raw, kind, offset));
+        assert_eq!(err.line(), raw);
+    }
+    assert_eq!("\n\n".parse::<StatMsg>().unwrap_err().kind(), ParseErrorKind::Empty);
+}
+
+#[test]
+fn test_parse_line_tags() {
+    let actual = parse_line(b"test.key;region=eu:1|c|@0.5|#env:prod,canary").unwrap();
+    assert_eq!(actual, StatRef::Inc(StatKind::Counter, "test.key;canary=true;env=prod;region=eu".into(), 1, 0.5));
+    assert_eq!(parse_line(b"test.key:1|c|#env:a=b").unwrap_err().kind(), ParseErrorKind::BadTag);
+}
+
+#[test]
+fn test_parse_error_counts() {
+    let counts = ParseErrorCounts::new();
+    for line in parse_lines(b"a:1|x\nb:1|y\n:1|c\nok:1|c") {
+        if let Err(err) = line {
+            counts.record(err.kind());
+        }
+    }
+    assert_eq!(counts.get(ParseErrorKind::BadType), 2);
+    assert_eq!(counts.get(ParseErrorKind::EmptyName), 1);
+    assert_eq!(counts.total(), 3);
+}
+
+#[test]
+fn test_parse_packet_lenient() {
+    let actual = StatMsg::parse_packet(b"good:1|c\nbad:1|x\nalso.good:5|g\n:2|c", ParseMode::Lenient);
+    assert_eq!(actual.msg,
+               Some(StatMsg::Bat(vec![
+                   StatMsg::Inc(StatKind::Counter, "good".to_string(), 1, 1.0),
+                   StatMsg::Set(StatKind::Gauge, "also.good".to_string(), 5, 1.0),
+               ])));
+    let kinds: Vec<_> = actual.errors.iter().map(|err| (err.line(), err.kind())).collect();
+    assert_eq!(kinds,
+               vec![("bad:1|x", ParseErrorKind::BadType), (":2|c", ParseErrorKind::EmptyName)]);
+}
+
+#[test]
+fn test_parse_packet_lenient_single() {
+    let actual = StatMsg::parse_packet(b"bad:1|x\ngood:1|c", ParseMode::default());
+    assert_eq!(actual.msg, Some(StatMsg::Inc(StatKind::Counter, "good".to_string(), 1, 1.0)));
+    assert_eq!(actual.errors.len(), 1);
+}
+
+#[test]
+fn test_parse_packet_strict() {
+    let actual = StatMsg::parse_packet(b"good:1|c\nbad:1|x\nworse:x|c", ParseMode::Strict);
+    assert_eq!(actual.msg, None);
+    assert_eq!(actual.errors.len(), 2);
+
+    let actual = StatMsg::parse_packet(b"good:1|c\nfine:2|c", ParseMode::Strict);
+    assert_eq!(actual.msg, "good:1|c\nfine:2|c".parse().ok());
+    assert!(actual.errors.is_empty());
+}
+
+#[test]
+fn test_parse_packet_empty() {
+    let actual = StatMsg::parse_packet(b"\n\n", ParseMode::Lenient);
+    assert_eq!(actual.msg, None);
+    assert_eq!(actual.errors[0].kind(), ParseErrorKind::Empty);
+}
+
 // Metric Types
 // $KEY = metric name/bucket
 // $PCT = configured percentile thresholds for timer metrics
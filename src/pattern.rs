@@ -0,0 +1,92 @@
+//! Name patterns for settings that apply to some metrics only.
+
+use std::fmt;
+use regex;
+
+/// A shell style pattern on metric names: `*` matches any run of characters, dots included,
+/// `?` matches exactly one. Everything else matches itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob {
+    pattern: String,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        Glob { pattern: pattern.to_string() }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// The same pattern as an anchored regular expression, every wildcard a capture group.
+    pub fn to_regex(&self) -> String {
+        let mut out = String::from("^");
+        let mut literal = String::new();
+        for c in self.pattern.chars() {
+            if c == '*' || c == '?' {
+                out.push_str(&regex::escape(&literal));
+                literal.clear();
+                out.push_str(if c == '*' { "(.*)" } else { "(.)" });
+            } else {
+                literal.push(c);
+            }
+        }
+        out.push_str(&regex::escape(&literal));
+        out.push('$');
+        out
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let pattern: Vec<char> = self.pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        let (mut p, mut n) = (0, 0);
+        // Where the last `*` was and how much of the name it took, to backtrack to.
+        let mut star = None;
+        while n < name.len() {
+            match pattern.get(p) {
+                Some(&'*') => {
+                    star = Some((p, n));
+                    p += 1;
+                }
+                Some(&c) if c == '?' || c == name[n] => {
+                    p += 1;
+                    n += 1;
+                }
+                _ => {
+                    match star {
+                        Some((star_p, star_n)) => {
+                            star = Some((star_p, star_n + 1));
+                            p = star_p + 1;
+                            n = star_n + 1;
+                        }
+                        None => return false,
+                    }
+                }
+            }
+        }
+        pattern[p..].iter().all(|&c| c == '*')
+    }
+}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.pattern.fmt(f)
+    }
+}
+
+#[test]
+fn test_glob() {
+    let glob = Glob::new("api.*.latency");
+    assert!(glob.matches("api.users.latency"));
+    assert!(glob.matches("api.users.v2.latency"));
+    assert!(glob.matches("api..latency"));
+    assert!(!glob.matches("api.users.latency.p99"));
+    assert!(!glob.matches("web.users.latency"));
+    assert!(Glob::new("*").matches(""));
+    assert!(Glob::new("db.?").matches("db.1"));
+    assert!(!Glob::new("db.?").matches("db.10"));
+    assert!(Glob::new("*.*.sql").matches("a.b.c.sql"));
+    assert!(!Glob::new("exact").matches("exactly"));
+    assert_eq!(Glob::new("api.*.v?").to_regex(), r"^api\.(.*)\.v(.)$");
+}
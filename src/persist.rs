@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::time::Duration;
+use aggregator::Aggregator;
+
+/// Bumped whenever the layout of the state file changes. Files written with another version
+/// are refused instead of being half understood.
+pub const STATE_VERSION: u32 = 1;
+const MAGIC: &str = "rustatsd-state";
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    Version(String),
+    Corrupt(usize, String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::Io(ref err) => write!(f, "{}", err),
+            StateError::Version(ref header) => {
+                write!(f, "unsupported header {:?}, expected \"{} {}\"", header, MAGIC, STATE_VERSION)
+            }
+            StateError::Corrupt(line, ref content) => write!(f, "bad entry on line {}: {:?}", line, content),
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(err: io::Error) -> StateError {
+        StateError::Io(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, StateError>;
+
+/// The part of the aggregator that survives a restart. Gauges are always kept because deltas
+/// only make sense on top of the last value, sets and counters only when asked for.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct State {
+    pub gauges: BTreeMap<String, f64>,
+    pub counters: BTreeMap<String, f64>,
+    pub sets: BTreeMap<String, Vec<i64>>,
+}
+
+impl State {
+    /// Writes the state as text: a version header followed by one `<kind> <value> <name>` line
+    /// per bucket. The name goes last so it may contain anything but a newline.
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        try!(writeln!(out, "{} {}", MAGIC, STATE_VERSION));
+        for (name, value) in self.gauges.iter() {
+            try!(writeln!(out, "g {} {}", value, name));
+        }
+        for (name, value) in self.counters.iter() {
+            try!(writeln!(out, "c {} {}", value, name));
+        }
+        for (name, members) in self.sets.iter() {
+            if members.is_empty() {
+                try!(writeln!(out, "s - {}", name));
+            } else {
+                let members: Vec<String> = members.iter().map(|m| m.to_string()).collect();
+                try!(writeln!(out, "s {} {}", members.join(","), name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: BufRead>(input: R) -> Result<State> {
+        let mut lines = input.lines();
+        let header = match lines.next() {
+            Some(header) => try!(header),
+            None => return Err(StateError::Version(String::new())),
+        };
+        if header != format!("{} {}", MAGIC, STATE_VERSION) {
+            return Err(StateError::Version(header));
+        }
+
+        let mut state = State::default();
+        for (i, line) in lines.enumerate() {
+            let line = try!(line);
+            if line.is_empty() {
+                continue;
+            }
+            if state.read_entry(&line).is_none() {
+                return Err(StateError::Corrupt(i + 2, line));
+            }
+        }
+        Ok(state)
+    }
+
+    fn read_entry(&mut self, line: &str) -> Option<()> {
+        let mut parts = line.splitn(3, ' ');
+        let (kind, value, name) = (parts.next()?, parts.next()?, parts.next()?);
+        if name.is_empty() {
+            return None;
+        }
+        match kind {
+            "g" => {
+                self.gauges.insert(name.to_string(), value.parse().ok()?);
+            }
+            "c" => {
+                self.counters.insert(name.to_string(), value.parse().ok()?);
+            }
+            "s" => {
+                let mut members = Vec::new();
+                if value != "-" {
+                    for member in value.split(',') {
+                        members.push(member.parse().ok()?);
+                    }
+                }
+                self.sets.insert(name.to_string(), members);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// Where and how often the aggregator state is saved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateFile {
+    pub path: PathBuf,
+    /// Time between periodic saves, the state is also saved on shutdown.
+    pub interval: Duration,
+    pub sets: bool,
+    pub counters: bool,
+}
+
+impl StateFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> StateFile {
+        StateFile {
+            path: path.as_ref().to_path_buf(),
+            interval: Duration::from_secs(60),
+            sets: false,
+            counters: false,
+        }
+    }
+
+    /// Saves the aggregator state, going through a temporary file so a crash halfway never
+    /// leaves a truncated snapshot behind.
+    pub fn save(&self, aggregator: &Aggregator) -> io::Result<()> {
+        let state = aggregator.state(self.sets, self.counters);
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(state.write(&mut file));
+            try!(file.sync_all());
+        }
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Loads a previously saved state into `aggregator`. A missing file is not an error, it
+    /// just means there is nothing to restore yet.
+    pub fn restore(&self, aggregator: &mut Aggregator) -> Result<usize> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(StateError::Io(err)),
+        };
+        let mut state = try!(State::read(BufReader::new(file)));
+        if !self.sets {
+            state.sets.clear();
+        }
+        if !self.counters {
+            state.counters.clear();
+        }
+        let restored = state.gauges.len() + state.counters.len() + state.sets.len();
+        aggregator.restore(state);
+        Ok(restored)
+    }
+}
+
+#[test]
+fn test_state_round_trip() {
+    let mut state = State::default();
+    state.gauges.insert("host1.load".to_string(), 1.5);
+    state.gauges.insert("name with spaces".to_string(), -3.0);
+    state.counters.insert("hits".to_string(), 0.0);
+    state.sets.insert("users".to_string(), vec![3, 7]);
+    state.sets.insert("empty".to_string(), vec![]);
+
+    let mut buf = Vec::new();
+    state.write(&mut buf).unwrap();
+    assert!(buf.starts_with(b"rustatsd-state 1\n"));
+    assert_eq!(State::read(&buf[..]).unwrap(), state);
+}
+
+#[test]
+fn test_state_rejects_other_versions() {
+    match State::read(&b"rustatsd-state 2\ng 1 load\n"[..]) {
+        Err(StateError::Version(header)) => assert_eq!(header, "rustatsd-state 2"),
+        other => panic!("unexpected {:?}", other),
+    }
+    match State::read(&b"rustatsd-state 1\ng one load\n"[..]) {
+        Err(StateError::Corrupt(line, _)) => assert_eq!(line, 2),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_state_file_restores_gauges() {
+    use aggregator::AggregatorOptions;
+    use metrics::StatMsg;
+
+    let path = ::std::env::temp_dir().join(format!("rustatsd-state-{}", ::std::process::id()));
+    let file = StateFile { sets: true, ..StateFile::new(&path) };
+    let mut agg = Aggregator::new(AggregatorOptions::default());
+    agg.process("load:5|g\nusers:1|s\nhits:1|c".parse::<StatMsg>().unwrap());
+    file.save(&agg).unwrap();
+
+    let mut restarted = Aggregator::new(AggregatorOptions::default());
+    assert_eq!(file.restore(&mut restarted).unwrap(), 2);
+    restarted.process("load:+2|g".parse::<StatMsg>().unwrap());
+    let snapshot = restarted.flush(0);
+    assert_eq!(snapshot.gauges["load"], 7.0);
+    assert!(snapshot.sets["users"].contains(&1));
+    assert!(snapshot.counters.is_empty());
+    fs::remove_file(&path).unwrap();
+}
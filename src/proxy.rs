@@ -0,0 +1,333 @@
+//! Proxy mode, RuStatsD's take on etsy's `proxy.js`: every line the frontends parse is
+//! forwarded to one of several statsd nodes instead of being aggregated here. A name always
+//! hashes to the same node, so timers and sets stay correct when aggregation is spread over a
+//! cluster. Nodes are health checked through their admin interface and the names of a node that
+//! is down move to the next node on the ring until it is back.
+
+use mio::*;
+use mio::channel::{channel, Receiver, Sender};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::time::{Duration, Instant};
+use client::{Packer, DEFAULT_MTU};
+use config::{ProxyConfig, ProxyNode};
+use metrics::StatMsg;
+use sync_token::{Token, TokenSource};
+
+const RX_TOKEN: mio::Token = mio::Token(0);
+// How long the loop may sleep before it looks at the shutdown token again.
+const SHUTDOWN_CHECK: u64 = 200;
+
+/// Consistent hashing of names onto nodes. Every node is placed on the ring `vnodes` times and
+/// a name belongs to the first node at or after its own hash. Taking a node away only moves the
+/// names that were on it.
+#[derive(Debug)]
+pub struct HashRing {
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// Places the nodes by their keys (`host:port`), which keeps the ring the same on every
+    /// proxy that lists the same nodes, in any order.
+    pub fn new<S: AsRef<str>>(keys: &[S], vnodes: usize) -> HashRing {
+        let mut points = Vec::with_capacity(keys.len() * vnodes);
+        for (node, key) in keys.iter().enumerate() {
+            for vnode in 0..vnodes {
+                points.push((hash(format!("{}-{}", key.as_ref(), vnode).as_bytes()), node));
+            }
+        }
+        points.sort_unstable();
+        HashRing { points: points }
+    }
+
+    /// The node `name` goes to, skipping nodes `is_up` says are down. `None` if all of them are.
+    pub fn route<F: Fn(usize) -> bool>(&self, name: &str, is_up: F) -> Option<usize> {
+        let start = match self.points.binary_search(&(hash(name.as_bytes()), 0)) {
+            Ok(i) | Err(i) => i,
+        };
+        let len = self.points.len();
+        (0..len).map(|i| self.points[(start + i) % len].1).find(|&node| is_up(node))
+    }
+}
+
+// FNV-1a with murmur3's finalizer on top, FNV alone clusters the similar vnode keys. Stable
+// across builds and platforms, unlike std's hasher.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h = bytes.iter().fold(0xcbf29ce484222325u64, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+struct Node {
+    key: String,
+    addr: SocketAddr,
+    admin_addr: SocketAddr,
+    packer: Packer,
+}
+
+/// Takes the place of the `Flusher` in proxy mode: reads what the frontends send and forwards
+/// it, packed into datagrams, to the nodes.
+pub struct Proxy {
+    ring: HashRing,
+    nodes: Vec<Node>,
+    up: Arc<Vec<AtomicBool>>,
+    check_interval: Duration,
+    socket: UdpSocket,
+    rx: Receiver<StatMsg>,
+    shutdown: Option<Arc<Token>>,
+}
+
+impl Proxy {
+    /// Resolves every node, failing if one can't be.
+    pub fn new(config: &ProxyConfig) -> io::Result<(Proxy, Sender<StatMsg>)> {
+        let mut nodes = Vec::with_capacity(config.nodes.len());
+        for node in config.nodes.iter() {
+            nodes.push(Node {
+                key: format!("{}:{}", node.host, node.port),
+                addr: try!(resolve(node, node.port)),
+                admin_addr: try!(resolve(node, node.admin_port)),
+                packer: Packer::new(DEFAULT_MTU),
+            });
+        }
+        let keys: Vec<&str> = nodes.iter().map(|node| node.key.as_str()).collect();
+        let ring = HashRing::new(&keys, config.vnodes);
+        let bind = match nodes.first() {
+            Some(node) if node.addr.is_ipv6() => "[::]:0",
+            _ => "0.0.0.0:0",
+        };
+        let (tx, rx) = channel::<StatMsg>();
+        let proxy = Proxy {
+            ring: ring,
+            // Nodes count as up until a check says otherwise.
+            up: Arc::new(nodes.iter().map(|_| AtomicBool::new(true)).collect()),
+            nodes: nodes,
+            check_interval: config.check_interval,
+            socket: try!(UdpSocket::bind(bind)),
+            rx: rx,
+            shutdown: None,
+        };
+        Ok((proxy, tx))
+    }
+
+    /// Stops the proxy, after forwarding what it already received, once `token` is triggered.
+    pub fn set_shutdown(&mut self, token: Arc<Token>) {
+        self.shutdown = Some(token);
+    }
+
+    /// Forwards until every sender has hung up or shutdown was requested, health checking the
+    /// nodes on another thread meanwhile.
+    pub fn run(&mut self) {
+        let poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(1024);
+        poll.register(&self.rx, RX_TOKEN, Ready::readable(), PollOpt::edge()).unwrap();
+
+        let stop_checks = TokenSource::new();
+        let checker = {
+            let targets = self.nodes.iter().map(|node| (node.key.clone(), node.admin_addr)).collect();
+            let up = self.up.clone();
+            let interval = self.check_interval;
+            let stop = stop_checks.get_token();
+            thread::spawn(move || check_health(targets, up, interval, stop))
+        };
+        loop {
+            if self.shutdown.as_ref().is_some_and(|token| token.is_triggered()) {
+                self.drain();
+                break;
+            }
+            poll.poll(&mut events, Some(Duration::from_millis(SHUTDOWN_CHECK))).unwrap();
+            if events.iter().next().is_some() && !self.drain() {
+                break;
+            }
+        }
+        stop_checks.trigger();
+        let _ = checker.join();
+    }
+
+    // Forwards everything received so far. Returns false once all senders are gone.
+    fn drain(&mut self) -> bool {
+        let connected = loop {
+            match self.rx.try_recv() {
+                Ok(msg) => self.forward(&msg),
+                Err(TryRecvError::Empty) => break true,
+                Err(TryRecvError::Disconnected) => break false,
+            }
+        };
+        let socket = &self.socket;
+        for node in self.nodes.iter_mut() {
+            let addr = node.addr;
+            if let Err(err) = node.packer.flush(|packet| socket.send_to(packet, addr).map(|_| ())) {
+                warn!("Could not forward to {}: {}", node.key, err);
+            }
+        }
+        connected
+    }
+
+    fn forward(&mut self, msg: &StatMsg) {
+        let name = match *msg {
+            StatMsg::Bat(ref msgs) => {
+                for msg in msgs {
+                    self.forward(msg);
+                }
+                return;
+            }
+            // The admin interface's deletes are for this node's buckets, which a proxy has none of.
+            StatMsg::Del(..) => return,
+            // Pre-aggregated buckets have no statsd line to forward.
+            StatMsg::Merge(..) => return,
+            StatMsg::Inc(_, ref name, _, _) |
+            StatMsg::Set(_, ref name, _, _) |
+            StatMsg::Gauge(ref name, _) => name,
+        };
+        let up = &self.up;
+        let node = match self.ring.route(name, |node| up[node].load(Ordering::Relaxed)) {
+            Some(node) => &mut self.nodes[node],
+            None => {
+                debug!("Dropping {}, every node is down", name);
+                return;
+            }
+        };
+        let (socket, addr) = (&self.socket, node.addr);
+        if let Err(err) = node.packer.push(&msg.to_string(), |packet| socket.send_to(packet, addr).map(|_| ())) {
+            warn!("Could not forward to {}: {}", node.key, err);
+        }
+    }
+}
+
+fn resolve(node: &ProxyNode, port: u16) -> io::Result<SocketAddr> {
+    let host = node.host.trim_start_matches('[').trim_end_matches(']');
+    match try!((host, port).to_socket_addrs()).next() {
+        Some(addr) => Ok(addr),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", node.host))),
+    }
+}
+
+// Asks every node's admin interface for `health` once per `interval` until `stop` is
+// triggered, marking the nodes that don't answer `health: up` in time as down.
+fn check_health(targets: Vec<(String, SocketAddr)>, up: Arc<Vec<AtomicBool>>, interval: Duration, stop: Arc<Token>) {
+    while !stop.is_triggered() {
+        let started = Instant::now();
+        for (i, &(ref key, addr)) in targets.iter().enumerate() {
+            let healthy = match ask_health(addr, interval) {
+                Ok(healthy) => healthy,
+                Err(err) => {
+                    debug!("Health check of {} failed: {}", key, err);
+                    false
+                }
+            };
+            if up[i].swap(healthy, Ordering::Relaxed) != healthy {
+                if healthy {
+                    info!("Node {} is back up", key);
+                } else {
+                    warn!("Node {} is down, routing its metrics to the other nodes", key);
+                }
+            }
+        }
+        while !stop.is_triggered() && started.elapsed() < interval {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn ask_health(addr: SocketAddr, timeout: Duration) -> io::Result<bool> {
+    let mut stream = try!(TcpStream::connect_timeout(&addr, timeout));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.set_write_timeout(Some(timeout)));
+    try!(stream.write_all(b"health\n"));
+    let mut answer = String::new();
+    try!(BufReader::new(stream).read_line(&mut answer));
+    Ok(answer.trim_end() == "health: up")
+}
+
+#[test]
+fn test_hash_ring() {
+    let keys = ["10.0.0.1:8125", "10.0.0.2:8125", "10.0.0.3:8125"];
+    let ring = HashRing::new(&keys, 160);
+    let names: Vec<String> = (0..3000).map(|i| format!("api.requests.{}", i)).collect();
+    let routes: Vec<usize> = names.iter().map(|name| ring.route(name, |_| true).unwrap()).collect();
+    for node in 0..3 {
+        let share = routes.iter().filter(|&&n| n == node).count();
+        assert!(share > 700 && share < 1300, "node {} got {} names", node, share);
+    }
+
+    // The order nodes are listed in doesn't matter.
+    let reordered_keys = [keys[2], keys[0], keys[1]];
+    let reordered = HashRing::new(&reordered_keys, 160);
+    for (name, &node) in names.iter().zip(routes.iter()) {
+        assert_eq!(reordered_keys[reordered.route(name, |_| true).unwrap()], keys[node]);
+    }
+
+    // Only the names of a node that is down move.
+    for (name, &node) in names.iter().zip(routes.iter()) {
+        let rerouted = ring.route(name, |n| n != 1).unwrap();
+        if node == 1 {
+            assert!(rerouted != 1);
+        } else {
+            assert_eq!(rerouted, node);
+        }
+    }
+    assert_eq!(ring.route("a", |_| false), None);
+}
+
+#[test]
+fn test_proxy_reroutes() {
+    use std::net::TcpListener;
+    use std::collections::HashSet;
+
+    // Node 0 is healthy, node 1's admin port refuses connections.
+    let receivers = [UdpSocket::bind("127.0.0.1:0").unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap()];
+    let admin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let admin_port = admin.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in admin.incoming() {
+            let mut stream = stream.unwrap();
+            let mut line = String::new();
+            BufReader::new(stream.try_clone().unwrap()).read_line(&mut line).unwrap();
+            stream.write_all(b"health: up\n").unwrap();
+        }
+    });
+    let node = |socket: &UdpSocket, admin_port| {
+        ProxyNode {
+            host: "127.0.0.1".to_string(),
+            port: socket.local_addr().unwrap().port(),
+            admin_port: admin_port,
+        }
+    };
+    let mut config = ProxyConfig::new(vec![node(&receivers[0], admin_port), node(&receivers[1], closed)]);
+    config.check_interval = Duration::from_millis(20);
+    let (mut proxy, tx) = Proxy::new(&config).unwrap();
+    let shutdown = TokenSource::new();
+    proxy.set_shutdown(shutdown.get_token());
+    let up = proxy.up.clone();
+    let running = thread::spawn(move || proxy.run());
+
+    let start = Instant::now();
+    while up[1].load(Ordering::Relaxed) && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(up[0].load(Ordering::Relaxed));
+    assert!(!up[1].load(Ordering::Relaxed));
+    let msgs: Vec<StatMsg> = (0..20).map(|i| format!("t{}:{}|ms", i, i).parse().unwrap()).collect();
+    tx.send(StatMsg::Bat(msgs)).unwrap();
+
+    let mut received = HashSet::new();
+    let mut buf = [0; 2048];
+    receivers[0].set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    while received.len() < 20 {
+        let len = receivers[0].recv(&mut buf).unwrap();
+        received.extend(String::from_utf8_lossy(&buf[..len]).lines().map(|line| line.to_string()));
+    }
+    assert!(received.contains("t7:7|ms"));
+    receivers[1].set_nonblocking(true).unwrap();
+    assert!(receivers[1].recv(&mut buf).is_err());
+
+    shutdown.trigger();
+    running.join().unwrap();
+}
@@ -0,0 +1,139 @@
+//! Ordered rewrite and routing rules, applied to every metric the flusher receives before it
+//! is aggregated. Every rule whose pattern matches the current name, and whose kinds include
+//! the metric's, applies its action and later rules see the result. A `Drop` ends it.
+
+use regex::{self, Regex};
+use metrics::StatKind;
+use pattern::Glob;
+use tags;
+
+/// What a matching rule does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Replaces the name, `$1` or `${name}` stand for the pattern's groups.
+    Rename(String),
+    Drop,
+    Prefix(String),
+    /// Sets a tag (see `tags`), the value can refer to the pattern's groups like `Rename`. A
+    /// value that would hold a `;` or `=` leaves the name as it is.
+    Tag(String, String),
+    /// Sends the bucket to these backends only.
+    Route(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Regex,
+    /// Empty for every kind.
+    kinds: Vec<StatKind>,
+    action: Action,
+}
+
+impl PartialEq for Rule {
+    fn eq(&self, other: &Rule) -> bool {
+        self.pattern.as_str() == other.pattern.as_str() && self.kinds == other.kinds &&
+        self.action == other.action
+    }
+}
+
+impl Rule {
+    /// A rule for names matching the regular expression `pattern`, which isn't anchored.
+    pub fn new(pattern: &str, kinds: Vec<StatKind>, action: Action) -> Result<Rule, regex::Error> {
+        Ok(Rule {
+            pattern: try!(Regex::new(pattern)),
+            kinds: kinds,
+            action: action,
+        })
+    }
+
+    /// A rule for names matching `glob`, whose wildcards are the groups.
+    pub fn glob(glob: &Glob, kinds: Vec<StatKind>, action: Action) -> Rule {
+        Rule::new(&glob.to_regex(), kinds, action).expect("globs are valid regular expressions")
+    }
+}
+
+/// Where a metric ends up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Dropped,
+    /// The final name, and the backends it goes to, `None` for all of them.
+    Kept(String, Option<Vec<String>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> Rules {
+        Rules { rules: rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn apply(&self, kind: &StatKind, name: &str) -> Outcome {
+        let mut name = name.to_string();
+        let mut route = None;
+        for rule in self.rules.iter() {
+            if !rule.kinds.is_empty() && !rule.kinds.contains(kind) {
+                continue;
+            }
+            let captures = match rule.pattern.captures(&name) {
+                Some(captures) => captures,
+                None => continue,
+            };
+            let expand = |template: &str| {
+                let mut out = String::new();
+                captures.expand(template, &mut out);
+                out
+            };
+            let renamed = match rule.action {
+                Action::Rename(ref template) => expand(template),
+                Action::Drop => return Outcome::Dropped,
+                Action::Prefix(ref prefix) => format!("{}{}", prefix, name),
+                Action::Tag(ref key, ref value) => {
+                    let value = expand(value);
+                    if !tags::valid(value.as_bytes()) {
+                        continue;
+                    }
+                    tags::set(&name, key, &value)
+                }
+                Action::Route(ref backends) => {
+                    route = Some(backends.clone());
+                    continue;
+                }
+            };
+            name = renamed;
+        }
+        Outcome::Kept(name, route)
+    }
+}
+
+#[test]
+fn test_rules() {
+    let rename = Action::Rename("app.$1.requests".to_string());
+    let tag = Action::Tag("service".to_string(), "$service".to_string());
+    let route = Action::Route(vec!["graphite".to_string()]);
+    let prefix = Action::Prefix("timers.".to_string());
+    let rules = Rules::new(vec![Rule::new(r"^legacy\.(\w+)\.count$", vec![], rename).unwrap(),
+                                Rule::glob(&Glob::new("debug.*"), vec![], Action::Drop),
+                                Rule::glob(&Glob::new("*.latency"), vec![StatKind::Timer], prefix),
+                                Rule::new(r"^app\.(?P<service>\w+)\.", vec![], tag).unwrap(),
+                                Rule::glob(&Glob::new("app.*"), vec![StatKind::Counter], route)]);
+    assert_eq!(rules.apply(&StatKind::Counter, "legacy.users.count"),
+               Outcome::Kept("app.users.requests;service=users".to_string(),
+                             Some(vec!["graphite".to_string()])));
+    assert_eq!(rules.apply(&StatKind::Gauge, "debug.queue"), Outcome::Dropped);
+    assert_eq!(rules.apply(&StatKind::Timer, "db.latency"), Outcome::Kept("timers.db.latency".to_string(), None));
+    assert_eq!(rules.apply(&StatKind::Gauge, "db.latency"), Outcome::Kept("db.latency".to_string(), None));
+    // A captured value can't smuggle in other tags.
+    let tag = Action::Tag("user".to_string(), "$1".to_string());
+    let rules = Rules::new(vec![Rule::new(r"^login\.(.*)$", vec![], tag).unwrap()]);
+    assert_eq!(rules.apply(&StatKind::Counter, "login.bob"),
+               Outcome::Kept("login.bob;user=bob".to_string(), None));
+    assert_eq!(rules.apply(&StatKind::Counter, "login.x;admin=true"),
+               Outcome::Kept("login.x;admin=true".to_string(), None));
+}
@@ -0,0 +1,185 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use tags;
+
+/// Rules for rewriting metric names into something a backend will accept. The measurement,
+/// tag keys and tag values of a tagged name (see `tags`) are sanitized one by one.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum KeySanitizer {
+    /// Pass names through untouched.
+    Off,
+    /// etsy statsd's `keyNameSanitize`: runs of whitespace become `_`, `/` becomes `-` and
+    /// anything outside `[a-zA-Z0-9_.-]` is stripped.
+    #[default]
+    Etsy,
+    /// Prometheus metric names: anything outside `[a-zA-Z0-9_:]` (dots included) becomes `_` and
+    /// a leading digit gets a `_` prefix.
+    Prometheus,
+}
+
+impl FromStr for KeySanitizer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<KeySanitizer, String> {
+        match s {
+            "off" | "none" => Ok(KeySanitizer::Off),
+            "etsy" => Ok(KeySanitizer::Etsy),
+            "prometheus" => Ok(KeySanitizer::Prometheus),
+            _ => Err(format!("unknown key sanitizer `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for KeySanitizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeySanitizer::Off => "off".fmt(f),
+            KeySanitizer::Etsy => "etsy".fmt(f),
+            KeySanitizer::Prometheus => "prometheus".fmt(f),
+        }
+    }
+}
+
+impl KeySanitizer {
+    /// Sanitizes `name`, only allocating when something actually has to change.
+    pub fn sanitize<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match *self {
+            KeySanitizer::Off => Cow::Borrowed(name),
+            KeySanitizer::Etsy => {
+                if name.chars().all(etsy_allowed) {
+                    Cow::Borrowed(name)
+                } else {
+                    Cow::Owned(sanitize_tagged(name, etsy))
+                }
+            }
+            KeySanitizer::Prometheus => {
+                let leading_digit = name.starts_with(|c: char| c.is_ascii_digit());
+                if !leading_digit && !name.is_empty() && name.chars().all(prometheus_allowed) {
+                    Cow::Borrowed(name)
+                } else {
+                    Cow::Owned(sanitize_tagged(name, prometheus))
+                }
+            }
+        }
+    }
+
+    /// Sanitizes `name` in place.
+    pub fn apply(&self, name: &mut String) {
+        let sanitized = match self.sanitize(name) {
+            Cow::Borrowed(_) => return,
+            Cow::Owned(sanitized) => sanitized,
+        };
+        *name = sanitized;
+    }
+}
+
+// Tags whose key or value is sanitized away are dropped.
+fn sanitize_tagged(name: &str, sanitize: fn(&str) -> String) -> String {
+    if !name.contains(';') {
+        return sanitize(name);
+    }
+    let (measurement, tags) = tags::split(name);
+    let measurement = sanitize(measurement);
+    if measurement.is_empty() {
+        return measurement;
+    }
+    let tags: Vec<(String, String)> = tags.into_iter()
+        .map(|(key, value)| (sanitize(key), sanitize(value)))
+        .filter(|tag| !tag.0.is_empty() && !tag.1.is_empty())
+        .collect();
+    let tags: Vec<(&str, &str)> = tags.iter().map(|tag| (tag.0.as_str(), tag.1.as_str())).collect();
+    tags::join(&measurement, &tags)
+}
+
+fn etsy_allowed(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+fn etsy(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut in_whitespace = false;
+    for c in name.chars() {
+        if c.is_whitespace() {
+            if !in_whitespace {
+                out.push('_');
+            }
+            in_whitespace = true;
+            continue;
+        }
+        in_whitespace = false;
+        if c == '/' {
+            out.push('-');
+        } else if etsy_allowed(c) {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn prometheus_allowed(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+fn prometheus(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 1);
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        out.push('_');
+    }
+    for c in name.chars() {
+        out.push(if prometheus_allowed(c) { c } else { '_' });
+    }
+    out
+}
+
+#[test]
+fn test_sanitize_etsy() {
+    let cases = [("test.key", "test.key"),
+                 ("test key", "test_key"),
+                 ("test \t  key", "test_key"),
+                 (" leading", "_leading"),
+                 ("path/to/key", "path-to-key"),
+                 ("test.key!@#$%^&*()", "test.key"),
+                 ("dash-and_underscore.0k", "dash-and_underscore.0k"),
+                 ("caf\u{e9}.visits", "caf.visits"),
+                 ("tab\tand/slash and#hash", "tab_and-slash_andhash"),
+                 ("!!!", ""),
+                 ("", "")];
+    for &(raw, expected) in cases.iter() {
+        assert_eq!((raw, &*KeySanitizer::Etsy.sanitize(raw)), (raw, expected));
+    }
+}
+
+#[test]
+fn test_sanitize_keeps_tags() {
+    assert_eq!(KeySanitizer::Etsy.sanitize("api requests;route=/home;env=prod"),
+               "api_requests;env=prod;route=-home");
+    assert_eq!(KeySanitizer::Etsy.sanitize("hits;!!!=x;env=!"), "hits");
+    assert_eq!(KeySanitizer::Prometheus.sanitize("api.requests;env=prod"), "api_requests;env=prod");
+}
+
+#[test]
+fn test_sanitize_prometheus() {
+    let cases = [("test.key", "test_key"),
+                 ("http_requests_total", "http_requests_total"),
+                 ("api:latency", "api:latency"),
+                 ("5xx.errors", "_5xx_errors"),
+                 ("path/to-key", "path_to_key"),
+                 ("caf\u{e9}", "caf_"),
+                 ("", "_")];
+    for &(raw, expected) in cases.iter() {
+        assert_eq!((raw, &*KeySanitizer::Prometheus.sanitize(raw)), (raw, expected));
+    }
+}
+
+#[test]
+fn test_sanitize_borrows_clean_names() {
+    match KeySanitizer::Etsy.sanitize("already.clean-name_0") {
+        Cow::Borrowed(_) => {}
+        Cow::Owned(name) => panic!("allocated {:?} for a clean name", name),
+    }
+    let mut name = "test key/path".to_string();
+    KeySanitizer::Etsy.apply(&mut name);
+    assert_eq!(name, "test_key-path");
+    assert_eq!(KeySanitizer::Off.sanitize("a b/c!"), "a b/c!");
+}
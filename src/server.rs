@@ -0,0 +1,555 @@
+//! Runs a complete server on background threads, for embedding RuStatsD in another program
+//! or an integration test.
+//!
+//! ```no_run
+//! use rustatsd::server::ServerBuilder;
+//!
+//! let server = ServerBuilder::new().start().unwrap();
+//! // ... send metrics to server.udp_addr()
+//! let snapshot = server.flush_now().unwrap();
+//! println!("{:?}", snapshot.counters);
+//! server.shutdown();
+//! ```
+
+use mio::channel::Sender;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use aggregator::{Aggregator, Snapshot};
+use backends::{console, forward, graphite, Backend};
+use config::Config;
+use flusher::{Command, Flusher};
+use frontends::admin::AdminServer;
+use frontends::carbon::{CarbonServer, CarbonUdpReader, PointParser, Protocol};
+use frontends::forward::ForwardServer;
+use frontends::http::HttpServer;
+use frontends::parser::PacketParser;
+use frontends::udp_server::{UdpError, UdpReader};
+use metrics::StatMsg;
+use proxy::Proxy;
+#[cfg(unix)]
+use frontends::unix_server::{UnixDatagramReader, UnixStreamServer};
+use stats::ServerStats;
+use sync_token::TokenSource;
+
+#[derive(Debug)]
+pub enum ServerError {
+    Udp(UdpError),
+    Admin(io::Error),
+    Http(io::Error),
+    Unix(PathBuf, io::Error),
+    Carbon(io::Error),
+    Forward(io::Error),
+    Proxy(io::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerError::Udp(ref err) => write!(f, "could not bind UDP listener: {:?}", err),
+            ServerError::Admin(ref err) => write!(f, "could not bind admin interface: {}", err),
+            ServerError::Http(ref err) => write!(f, "could not bind HTTP listener: {}", err),
+            ServerError::Unix(ref path, ref err) => {
+                write!(f, "could not bind Unix socket {}: {}", path.display(), err)
+            }
+            ServerError::Carbon(ref err) => write!(f, "could not bind carbon listener: {}", err),
+            ServerError::Forward(ref err) => write!(f, "could not bind forward listener: {}", err),
+            ServerError::Proxy(ref err) => write!(f, "could not set up proxy: {}", err),
+            ServerError::Io(ref err) => write!(f, "could not start server: {}", err),
+        }
+    }
+}
+
+impl error::Error for ServerError {}
+
+impl From<UdpError> for ServerError {
+    fn from(err: UdpError) -> ServerError {
+        ServerError::Udp(err)
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> ServerError {
+        ServerError::Io(err)
+    }
+}
+
+/// Sets up a server, see `start`.
+pub struct ServerBuilder {
+    config: Config,
+    backends: Vec<Box<dyn Backend>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    /// Starts from `Config::default()`, except that UDP binds an ephemeral port and the admin
+    /// interface stays off, so several servers can run side by side in one process.
+    pub fn new() -> ServerBuilder {
+        let mut config = Config::default();
+        config.udp.port = 0;
+        config.admin.enabled = false;
+        ServerBuilder::from_config(config)
+    }
+
+    /// Takes every setting from `config`, like the `rustatsd` binary does.
+    pub fn from_config(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config: config,
+            backends: Vec::new(),
+        }
+    }
+
+    /// Where the UDP frontend listens, port 0 picks a free one (see `ServerHandle::udp_addr`).
+    pub fn udp(mut self, host: &str, port: u16) -> ServerBuilder {
+        self.config.udp.host = host.to_string();
+        self.config.udp.port = port;
+        self
+    }
+
+    pub fn flush_interval(mut self, interval: Duration) -> ServerBuilder {
+        self.config.aggregator.flush_interval = interval;
+        self
+    }
+
+    /// Only flush to the backends passed to `backend`, ignoring the ones named in the config.
+    pub fn without_config_backends(mut self) -> ServerBuilder {
+        self.config.backends.clear();
+        self
+    }
+
+    /// Adds a backend next to the ones named in the config. A proxy doesn't flush, `start` refuses
+    /// backends in proxy mode.
+    pub fn backend<B: Backend + 'static>(mut self, backend: B) -> ServerBuilder {
+        self.backends.push(Box::new(backend));
+        self
+    }
+
+    /// Binds every listener, then starts the frontends and the flusher on their own threads.
+    pub fn start(self) -> Result<ServerHandle, ServerError> {
+        let config = self.config;
+        if config.proxy.is_some() && !self.backends.is_empty() {
+            return Err(ServerError::Proxy(io::Error::new(io::ErrorKind::InvalidInput,
+                                                         "a proxy can't flush to backends")));
+        }
+        let shutdown = TokenSource::new();
+        let stats = Arc::new(ServerStats::new());
+
+        let mut readers = try!(UdpReader::bind_all(&config.udp));
+        let udp_addrs = try!(readers.iter().map(|reader| reader.local_addr()).collect());
+        let admin = if config.admin.enabled {
+            let mut admin = try!(AdminServer::bind(&config.admin).map_err(ServerError::Admin));
+            try!(admin.set_shutdown(shutdown.get_token()));
+            Some(admin)
+        } else {
+            None
+        };
+        let http = if config.http.enabled {
+            // The flusher's loop wakes up at least once per flush interval.
+            let max_age = config.aggregator.flush_interval * 2 + Duration::from_secs(5);
+            let mut http = try!(HttpServer::bind(&config.http, stats.clone(), max_age).map_err(ServerError::Http));
+            try!(http.set_shutdown(shutdown.get_token()));
+            Some(http)
+        } else {
+            None
+        };
+        #[cfg(unix)]
+        let (unix_datagram, unix_stream) = try!(bind_unix(&config, &shutdown));
+        #[cfg(not(unix))]
+        {
+            if config.unix_datagram.is_some() || config.unix_stream.is_some() {
+                return Err(ServerError::Io(io::Error::new(io::ErrorKind::Other,
+                                                          "Unix sockets are not supported on this platform")));
+            }
+        }
+        let carbon = if config.carbon.enabled {
+            Some(try!(bind_carbon(&config, &shutdown).map_err(ServerError::Carbon)))
+        } else {
+            None
+        };
+        let forward = if config.forward_listener.enabled {
+            let mut server = try!(ForwardServer::bind(&config.forward_listener).map_err(ServerError::Forward));
+            try!(server.set_shutdown(shutdown.get_token()));
+            Some(server)
+        } else {
+            None
+        };
+        stats.set_frontends_bound(true);
+
+        let (tx, commands, main_loop): (_, _, Box<dyn FnOnce() + Send>) = match config.proxy {
+            Some(ref proxy) => {
+                let (mut proxy, tx) = try!(Proxy::new(proxy).map_err(ServerError::Proxy));
+                proxy.set_shutdown(shutdown.get_token());
+                (tx, None, Box::new(move || proxy.run()))
+            }
+            None => {
+                let (mut flusher, tx) = build_flusher(&config, self.backends, &stats);
+                flusher.set_shutdown(shutdown.get_token());
+                (tx, Some(flusher.commands()), Box::new(move || flusher.run()))
+            }
+        };
+
+        let mut handle = ServerHandle {
+            commands: commands,
+            stats: stats.clone(),
+            udp_addrs: udp_addrs,
+            admin_addr: None,
+            http_addr: None,
+            flusher: None,
+            threads: Vec::new(),
+            shutdown: shutdown,
+        };
+        for (i, mut reader) in readers.drain(..).enumerate() {
+            reader.set_shutdown(handle.shutdown.get_token());
+            let tx = tx.clone();
+            let parser = PacketParser::new(stats.clone(), &config.parser);
+            handle.threads.push(try!(thread::Builder::new()
+                .name(format!("udp-{}", i))
+                .spawn(move || reader.run(parser, tx))));
+        }
+        #[cfg(unix)]
+        {
+            if let Some(mut reader) = unix_datagram {
+                let tx = tx.clone();
+                let parser = PacketParser::new(stats.clone(), &config.parser);
+                handle.threads.push(try!(thread::Builder::new()
+                    .name("unix-datagram".to_string())
+                    .spawn(move || reader.run(parser, tx))));
+            }
+            if let Some(server) = unix_stream {
+                let tx = tx.clone();
+                let stats = stats.clone();
+                let parser = config.parser.clone();
+                handle.threads.push(try!(thread::Builder::new()
+                    .name("unix-stream".to_string())
+                    .spawn(move || server.run(stats, &parser, tx))));
+            }
+        }
+        if let Some((plaintext, udp, pickle)) = carbon {
+            for (name, server) in [("carbon-plaintext", plaintext), ("carbon-pickle", pickle)] {
+                let tx = tx.clone();
+                let stats = stats.clone();
+                let parser = config.parser.clone();
+                handle.threads.push(try!(thread::Builder::new()
+                    .name(name.to_string())
+                    .spawn(move || server.run(stats, &parser, tx))));
+            }
+            let tx = tx.clone();
+            let parser = PointParser::new(stats.clone(), &config.parser);
+            handle.threads.push(try!(thread::Builder::new()
+                .name("carbon-udp".to_string())
+                .spawn(move || udp.run(parser, tx))));
+        }
+        if let Some(server) = forward {
+            let tx = tx.clone();
+            let stats = stats.clone();
+            let log_bad_lines = config.parser.log_bad_lines_per_minute;
+            handle.threads.push(try!(thread::Builder::new()
+                .name("forward".to_string())
+                .spawn(move || server.run(stats, log_bad_lines, tx))));
+        }
+        if let Some(admin) = admin {
+            handle.admin_addr = Some(try!(admin.local_addr()));
+            let tx = tx.clone();
+            handle.threads.push(try!(thread::Builder::new()
+                .name("admin".to_string())
+                .spawn(move || admin.run(tx))));
+        }
+        if let Some(mut http) = http {
+            if config.http.ingest {
+                http.set_ingest(PacketParser::new(stats.clone(), &config.parser), tx.clone());
+            }
+            handle.http_addr = Some(try!(http.local_addr()));
+            handle.threads.push(try!(thread::Builder::new()
+                .name("http".to_string())
+                .spawn(move || http.run())));
+        }
+        drop(tx);
+        let name = if config.proxy.is_some() { "proxy" } else { "flusher" };
+        handle.flusher = Some(try!(thread::Builder::new()
+            .name(name.to_string())
+            .spawn(main_loop)));
+        Ok(handle)
+    }
+}
+
+fn build_flusher(config: &Config,
+                 extra: Vec<Box<dyn Backend>>,
+                 stats: &Arc<ServerStats>)
+                 -> (Flusher, Sender<StatMsg>) {
+    let mut backends: Vec<Box<dyn Backend>> = Vec::new();
+    for name in config.backends.iter() {
+        match name.as_str() {
+            "console" => backends.push(Box::new(console::ConsoleBackend::new(&config.console))),
+            "graphite" => backends.push(Box::new(graphite::GraphiteBackend::new(&config.graphite))),
+            "forward" => backends.push(Box::new(forward::ForwardBackend::new(&config.forward))),
+            _ => unreachable!(),
+        }
+    }
+    backends.extend(extra);
+    for sketch in config.aggregator.timer_sketches.iter() {
+        info!("Timers matching {} are sketched, percentiles {}",
+              sketch.pattern,
+              sketch.kind.digest().error_bound());
+    }
+    for interval in config.aggregator.intervals.iter() {
+        info!("Series matching {} are flushed every {:?}", interval.pattern, interval.interval);
+    }
+    for rollup in config.aggregator.rollups.iter() {
+        info!("Series matching {} are rolled up by {:?}", rollup.pattern, rollup.tags);
+    }
+
+    let mut aggregator = Aggregator::new(config.aggregator.clone());
+    if let Some(ref file) = config.persistence {
+        match file.restore(&mut aggregator) {
+            Ok(count) => info!("Restored {} buckets from {}", count, file.path.display()),
+            Err(err) => warn!("Ignoring state in {}: {}", file.path.display(), err),
+        }
+    }
+    let (mut flusher, tx) = Flusher::new(aggregator, backends);
+    flusher.set_stats(stats.clone());
+    if config.self_metrics.enabled {
+        flusher.set_self_metrics(&config.self_metrics.prefix);
+    }
+    if let Some(file) = config.persistence.clone() {
+        flusher.set_state_file(file);
+    }
+    if !config.rules.is_empty() {
+        flusher.set_rules(config.rules.clone());
+    }
+    if !config.templates.is_empty() {
+        flusher.set_templates(config.templates.clone());
+    }
+    (flusher, tx)
+}
+
+#[cfg(unix)]
+fn bind_unix(config: &Config,
+             shutdown: &TokenSource)
+             -> Result<(Option<UnixDatagramReader>, Option<UnixStreamServer>), ServerError> {
+    let datagram = match config.unix_datagram {
+        Some(ref socket) => {
+            let mut reader = try!(UnixDatagramReader::bind(socket)
+                .map_err(|err| ServerError::Unix(socket.path.clone(), err)));
+            try!(reader.set_shutdown(shutdown.get_token()));
+            Some(reader)
+        }
+        None => None,
+    };
+    let stream = match config.unix_stream {
+        Some(ref socket) => {
+            let mut server = try!(UnixStreamServer::bind(socket)
+                .map_err(|err| ServerError::Unix(socket.path.clone(), err)));
+            try!(server.set_shutdown(shutdown.get_token()));
+            Some(server)
+        }
+        None => None,
+    };
+    Ok((datagram, stream))
+}
+
+// The plaintext TCP and UDP listeners, which share a port like carbon's, and the pickle one.
+fn bind_carbon(config: &Config, shutdown: &TokenSource) -> io::Result<(CarbonServer, CarbonUdpReader, CarbonServer)> {
+    let carbon = &config.carbon;
+    let mut plaintext = try!(CarbonServer::bind(&carbon.host, carbon.plaintext_port, Protocol::Plaintext));
+    try!(plaintext.set_shutdown(shutdown.get_token()));
+    let mut udp = try!(CarbonUdpReader::bind(&carbon.host, carbon.plaintext_port));
+    try!(udp.set_shutdown(shutdown.get_token()));
+    let mut pickle = try!(CarbonServer::bind(&carbon.host, carbon.pickle_port, Protocol::Pickle));
+    try!(pickle.set_shutdown(shutdown.get_token()));
+    Ok((plaintext, udp, pickle))
+}
+
+/// A running server. Dropping the handle shuts the server down just like `shutdown` does.
+pub struct ServerHandle {
+    shutdown: TokenSource,
+    // `None` in proxy mode, which doesn't aggregate.
+    commands: Option<Sender<Command>>,
+    stats: Arc<ServerStats>,
+    udp_addrs: Vec<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    flusher: Option<JoinHandle<()>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The address `[udp]`'s own listener is bound to.
+    pub fn udp_addr(&self) -> SocketAddr {
+        self.udp_addrs[0]
+    }
+
+    /// Every UDP socket's address, one per worker, listeners in config order.
+    pub fn udp_addrs(&self) -> &[SocketAddr] {
+        &self.udp_addrs
+    }
+
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+
+    pub fn stats(&self) -> &ServerStats {
+        &self.stats
+    }
+
+    /// Flushes to every backend right away and returns what was flushed, `None` if the server
+    /// is already shutting down or runs as a proxy.
+    pub fn flush_now(&self) -> Option<Snapshot> {
+        self.ask(Command::Flush)
+    }
+
+    /// What the next flush would contain, without flushing. `None` like for `flush_now`.
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        self.ask(Command::Snapshot)
+    }
+
+    /// Stops every frontend, flushes one last time and waits for all threads to finish.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn ask<F: FnOnce(mpsc::Sender<Snapshot>) -> Command>(&self, command: F) -> Option<Snapshot> {
+        let (reply, rx) = mpsc::channel();
+        let commands = match self.commands {
+            Some(ref commands) => commands,
+            None => return None,
+        };
+        if commands.send(command(reply)).is_err() {
+            return None;
+        }
+        rx.recv().ok()
+    }
+
+    fn stop(&mut self) {
+        self.shutdown.trigger();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[test]
+fn test_embedded_server() {
+    use std::net::UdpSocket;
+    use std::sync::Mutex;
+    use std::time::Instant;
+    use client::{Client, UdpSink};
+
+    struct Recorder(Arc<Mutex<Vec<Snapshot>>>);
+
+    impl Backend for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn flush(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+            self.0.lock().unwrap().push(snapshot.clone());
+            Ok(())
+        }
+    }
+
+    let flushed = Arc::new(Mutex::new(Vec::new()));
+    let server = ServerBuilder::new()
+        .udp("127.0.0.1", 0)
+        .flush_interval(Duration::from_secs(3600))
+        .without_config_backends()
+        .backend(Recorder(flushed.clone()))
+        .start()
+        .unwrap();
+    let client = Client::new(UdpSink::connect(server.udp_addr()).unwrap());
+    client.count("hits", 3).send().unwrap();
+    client.gauge("load", 7).send().unwrap();
+    client.flush().unwrap();
+
+    // UDP delivery is asynchronous, wait for the reader to pick the packet up.
+    let start = Instant::now();
+    while server.stats().metrics_received() < 2 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let snapshot = server.snapshot().unwrap();
+    assert_eq!(snapshot.counters["hits"], 3.0);
+    assert!(flushed.lock().unwrap().is_empty());
+
+    let snapshot = server.flush_now().unwrap();
+    assert_eq!(snapshot.gauges["load"], 7.0);
+    assert_eq!(flushed.lock().unwrap().len(), 1);
+    assert_eq!(server.snapshot().unwrap().counters["hits"], 0.0);
+
+    let addr = server.udp_addr();
+    server.shutdown();
+    // The final flush went out and the port is free again.
+    assert_eq!(flushed.lock().unwrap().len(), 2);
+    UdpSocket::bind(addr).unwrap();
+}
+
+#[test]
+fn test_client_tags_reach_the_aggregator() {
+    use std::time::Instant;
+    use client::{Client, UdpSink};
+
+    let server = ServerBuilder::new()
+        .udp("127.0.0.1", 0)
+        .flush_interval(Duration::from_secs(3600))
+        .without_config_backends()
+        .start()
+        .unwrap();
+    let client = Client::new(UdpSink::connect(server.udp_addr()).unwrap()).with_tag("env", "prod");
+    client.count("hits", 3).tag("route", "/home").send().unwrap();
+    client.gauge("load", 7).send().unwrap();
+    client.flush().unwrap();
+
+    let start = Instant::now();
+    while server.stats().metrics_received() < 2 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    // The default etsy sanitizer cleans up the tag value but keeps the tags.
+    let snapshot = server.snapshot().unwrap();
+    assert_eq!(snapshot.counters["hits;env=prod;route=-home"], 3.0);
+    assert_eq!(snapshot.gauges["load;env=prod"], 7.0);
+    server.shutdown();
+}
+
+#[test]
+fn test_proxy_refuses_backends() {
+    use config::{ProxyConfig, ProxyNode};
+
+    let mut config = Config::default();
+    config.udp.port = 0;
+    config.admin.enabled = false;
+    let node = ProxyNode {
+        host: "127.0.0.1".to_string(),
+        port: 8125,
+        admin_port: 8126,
+    };
+    config.proxy = Some(ProxyConfig::new(vec![node]));
+    let console = console::ConsoleBackend::new(&Default::default());
+    match ServerBuilder::from_config(config).backend(console).start() {
+        Err(ServerError::Proxy(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+        Err(err) => panic!("unexpected {}", err),
+        Ok(_) => panic!("proxy started with a backend"),
+    }
+}
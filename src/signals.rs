@@ -0,0 +1,32 @@
+use rustatsd::sync_token::TokenSource;
+
+/// Turns SIGINT and SIGTERM into a triggered `TokenSource`. Must be called before any other
+/// thread is spawned: the signals get blocked here and every later thread inherits that mask,
+/// leaving a single thread to `sigwait` for them.
+#[cfg(unix)]
+pub fn shutdown_on_signal(source: TokenSource) {
+    use libc;
+    use std::mem;
+    use std::thread;
+
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ::std::ptr::null_mut());
+
+        thread::Builder::new()
+            .name("signals".to_string())
+            .spawn(move || {
+                let mut signal = 0;
+                libc::sigwait(&set, &mut signal);
+                info!("Received signal {}", signal);
+                source.trigger();
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(not(unix))]
+pub fn shutdown_on_signal(_: TokenSource) {}
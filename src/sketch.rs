@@ -0,0 +1,960 @@
+//! Mergeable summaries of timers and sets, for buckets that are aggregated in more than one
+//! place (see `backends::forward`), or that see too many samples to keep them all (see
+//! `AggregatorOptions::timer_sketches`). Merging the summaries of two halves of the data gives
+//! the summary of all of it.
+
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::f64;
+use std::f64::consts::PI;
+use std::fmt;
+use std::mem;
+
+/// Relative error of `DDSketch` quantiles unless configured otherwise.
+pub const DEFAULT_RELATIVE_ERROR: f64 = 0.01;
+/// Compression of `TDigest`s unless configured otherwise.
+pub const DEFAULT_COMPRESSION: f64 = 100.0;
+// Values closer to zero than this are counted as zero.
+const MIN_VALUE: f64 = 1e-9;
+// A t-digest compresses once it buffered this many times its compression in points.
+const BUFFER_FACTOR: f64 = 5.0;
+
+/// How far the quantiles of a `Digest` can be from the exact ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorBound {
+    /// Within this fraction of the exact value.
+    Relative(f64),
+    /// Within about this fraction of the count, in rank, of the exact value. Tighter towards
+    /// the extremes.
+    Rank(f64),
+}
+
+impl fmt::Display for ErrorBound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorBound::Relative(error) => write!(f, "within {}% of the value", error * 100.0),
+            ErrorBound::Rank(error) => write!(f, "within about {}% in rank", error * 100.0),
+        }
+    }
+}
+
+/// A mergeable summary of timer samples, to read quantiles from. Count, sum, sum of squares,
+/// min and max are kept exactly whatever the kind.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Digest {
+    DDSketch(DDSketch),
+    TDigest(TDigest),
+}
+
+impl Default for Digest {
+    fn default() -> Digest {
+        Digest::DDSketch(DDSketch::default())
+    }
+}
+
+impl Digest {
+    pub fn ddsketch(relative_error: f64) -> Digest {
+        Digest::DDSketch(DDSketch::new(relative_error))
+    }
+
+    pub fn tdigest(compression: f64) -> Digest {
+        Digest::TDigest(TDigest::new(compression))
+    }
+
+    pub fn error_bound(&self) -> ErrorBound {
+        match *self {
+            Digest::DDSketch(ref sketch) => ErrorBound::Relative(sketch.relative_error()),
+            Digest::TDigest(ref digest) => ErrorBound::Rank(digest.rank_error()),
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        match *self {
+            Digest::DDSketch(ref mut sketch) => sketch.add(value),
+            Digest::TDigest(ref mut digest) => digest.add(value),
+        }
+    }
+
+    /// Adds a uniform `sample` of `count` values, each sampled value standing for its share of
+    /// them. The exact sum, sum of squares, minimum and maximum of all values are known.
+    pub fn add_sample(&mut self, sample: &[f64], count: u64, sum: f64, sum_squares: f64, min: f64, max: f64) {
+        if sample.is_empty() || count == 0 {
+            return;
+        }
+        let len = sample.len() as u128;
+        for (i, &value) in sample.iter().enumerate() {
+            // Shares that add up to exactly `count`.
+            let i = i as u128;
+            let n = ((i + 1) * count as u128 / len - i * count as u128 / len) as u64;
+            if n > 0 {
+                match *self {
+                    Digest::DDSketch(ref mut sketch) => sketch.add_bin(value, n),
+                    Digest::TDigest(ref mut digest) => digest.add_bin(value, n),
+                }
+            }
+        }
+        let moments = Moments {
+            count: count,
+            sum: sum,
+            sum_squares: sum_squares,
+            min: min,
+            max: max,
+        };
+        match *self {
+            Digest::DDSketch(ref mut sketch) => sketch.moments.merge(&moments),
+            Digest::TDigest(ref mut digest) => digest.moments.merge(&moments),
+        }
+    }
+
+    /// Adds everything counted in `other`. Digests of another kind or accuracy are added bin
+    /// by bin, which adds their error on top.
+    pub fn merge(&mut self, other: &Digest) {
+        match (self, other) {
+            (Digest::DDSketch(sketch), Digest::DDSketch(other)) => sketch.merge(other),
+            (Digest::TDigest(digest), Digest::TDigest(other)) => digest.merge(other),
+            (Digest::DDSketch(sketch), other) => {
+                for (value, n) in other.bins() {
+                    sketch.add_bin(value, n);
+                }
+                sketch.moments.merge(other.moments());
+            }
+            (Digest::TDigest(digest), other) => {
+                for (value, n) in other.bins() {
+                    digest.add_bin(value, n);
+                }
+                digest.moments.merge(other.moments());
+            }
+        }
+    }
+
+    /// Folds buffered samples in, which makes reading quantiles cheaper.
+    pub fn compress(&mut self) {
+        if let Digest::TDigest(ref mut digest) = *self {
+            digest.compress();
+        }
+    }
+
+    fn moments(&self) -> &Moments {
+        match *self {
+            Digest::DDSketch(ref sketch) => &sketch.moments,
+            Digest::TDigest(ref digest) => &digest.moments,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.moments().count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.moments().sum
+    }
+
+    pub fn sum_squares(&self) -> f64 {
+        self.moments().sum_squares
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        self.moments().min()
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        self.moments().max()
+    }
+
+    /// The value at rank `rank` (0 based) of the sorted samples, within the error bound. The
+    /// first and last ranks are the exact min and max.
+    pub fn nth(&self, rank: u64) -> Option<f64> {
+        let moments = self.moments();
+        if rank == 0 {
+            return moments.min();
+        }
+        if rank + 1 == moments.count {
+            return moments.max();
+        }
+        let value = match *self {
+            Digest::DDSketch(ref sketch) => sketch.nth(rank),
+            Digest::TDigest(ref digest) => digest.nth(rank),
+        };
+        value.map(|value| value.max(moments.min).min(moments.max))
+    }
+
+    /// The `q` quantile, `q` in [0, 1].
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.is_empty() {
+            return None;
+        }
+        self.nth((q * (self.count() - 1) as f64).floor() as u64)
+    }
+
+    /// Every bin's (or centroid's) value and count, smallest value first.
+    pub fn bins<'a>(&'a self) -> Box<dyn Iterator<Item = (f64, u64)> + 'a> {
+        match *self {
+            Digest::DDSketch(ref sketch) => sketch.bins(),
+            Digest::TDigest(ref digest) => Box::new(digest.centroids().into_owned().into_iter()),
+        }
+    }
+
+    /// A single token of text, see `DDSketch::encode` and `TDigest::encode`.
+    pub fn encode(&self) -> String {
+        match *self {
+            Digest::DDSketch(ref sketch) => sketch.encode(),
+            Digest::TDigest(ref digest) => digest.encode(),
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Digest> {
+        match text.strip_prefix(TDIGEST_TAG) {
+            Some(text) => TDigest::decode(text).map(Digest::TDigest),
+            None => DDSketch::decode(text).map(Digest::DDSketch),
+        }
+    }
+}
+
+// The exact part of a digest.
+#[derive(Debug, Clone, PartialEq)]
+struct Moments {
+    count: u64,
+    sum: f64,
+    sum_squares: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Moments {
+    fn new() -> Moments {
+        Moments {
+            count: 0,
+            sum: 0.0,
+            sum_squares: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.sum_squares += value * value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn merge(&mut self, other: &Moments) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.sum_squares += other.sum_squares;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    fn min(&self) -> Option<f64> {
+        if self.count == 0 { None } else { Some(self.min) }
+    }
+
+    fn max(&self) -> Option<f64> {
+        if self.count == 0 { None } else { Some(self.max) }
+    }
+
+    fn encode(&self) -> String {
+        format!("{}/{}/{}/{}/{}", self.count, self.sum, self.sum_squares, self.min, self.max)
+    }
+
+    // Refuses anything but finite numbers, except for the infinite bounds of no values.
+    fn decode(fields: &[&str]) -> Option<Moments> {
+        let moments = Moments {
+            count: fields[0].parse().ok()?,
+            sum: fields[1].parse().ok()?,
+            sum_squares: fields[2].parse().ok()?,
+            min: fields[3].parse().ok()?,
+            max: fields[4].parse().ok()?,
+        };
+        if !moments.sum.is_finite() || !moments.sum_squares.is_finite() || moments.sum_squares < 0.0 {
+            return None;
+        }
+        if moments.count == 0 {
+            return if moments.sum == 0.0 { Some(Moments::new()) } else { None };
+        }
+        if !(moments.min.is_finite() && moments.max.is_finite() && moments.min <= moments.max) {
+            return None;
+        }
+        Some(moments)
+    }
+}
+
+/// A DDSketch: timer samples counted in logarithmic bins, so every quantile is within
+/// `relative_error` of the exact one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DDSketch {
+    relative_error: f64,
+    gamma_ln: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zeros: u64,
+    moments: Moments,
+}
+
+impl Default for DDSketch {
+    fn default() -> DDSketch {
+        DDSketch::new(DEFAULT_RELATIVE_ERROR)
+    }
+}
+
+impl DDSketch {
+    /// `relative_error` must be in (0, 1).
+    pub fn new(relative_error: f64) -> DDSketch {
+        assert!(relative_error > 0.0 && relative_error < 1.0);
+        DDSketch {
+            relative_error: relative_error,
+            gamma_ln: ((1.0 + relative_error) / (1.0 - relative_error)).ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zeros: 0,
+            moments: Moments::new(),
+        }
+    }
+
+    pub fn relative_error(&self) -> f64 {
+        self.relative_error
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.add_bin(value, 1);
+        self.moments.add(value);
+    }
+
+    // Counts `n` samples of `value` in the bins only.
+    fn add_bin(&mut self, value: f64, n: u64) {
+        if value > MIN_VALUE {
+            *self.positive.entry(self.index(value)).or_insert(0) += n;
+        } else if value < -MIN_VALUE {
+            *self.negative.entry(self.index(-value)).or_insert(0) += n;
+        } else {
+            self.zeros += n;
+        }
+    }
+
+    /// Adds everything counted in `other`. Sketches with another relative error are binned
+    /// again, which adds that sketch's error on top.
+    pub fn merge(&mut self, other: &DDSketch) {
+        let same = other.relative_error == self.relative_error;
+        for (&index, &n) in other.positive.iter() {
+            let index = if same { index } else { self.index(other.value(index)) };
+            *self.positive.entry(index).or_insert(0) += n;
+        }
+        for (&index, &n) in other.negative.iter() {
+            let index = if same { index } else { self.index(other.value(index)) };
+            *self.negative.entry(index).or_insert(0) += n;
+        }
+        self.zeros += other.zeros;
+        self.moments.merge(&other.moments);
+    }
+
+    fn nth(&self, rank: u64) -> Option<f64> {
+        self.bins().scan(0, |seen, (value, n)| {
+                *seen += n;
+                Some((*seen, value))
+            })
+            .find(|&(seen, _)| seen > rank)
+            .map(|(_, value)| value)
+    }
+
+    /// Every bin's value and count, smallest value first.
+    pub fn bins<'a>(&'a self) -> Box<dyn Iterator<Item = (f64, u64)> + 'a> {
+        let negative = self.negative.iter().rev().map(move |(&index, &n)| (-self.value(index), n));
+        let zeros = Some((0.0, self.zeros)).into_iter().filter(|&(_, n)| n > 0);
+        let positive = self.positive.iter().map(move |(&index, &n)| (self.value(index), n));
+        Box::new(negative.chain(zeros).chain(positive))
+    }
+
+    fn index(&self, value: f64) -> i32 {
+        (value.ln() / self.gamma_ln).ceil() as i32
+    }
+
+    // The value in the middle of a bin, relative error wise.
+    fn value(&self, index: i32) -> f64 {
+        let gamma = self.gamma_ln.exp();
+        2.0 * (index as f64 * self.gamma_ln).exp() / (gamma + 1.0)
+    }
+
+    /// `relative_error/count/sum/sum_squares/min/max/zeros/positive/negative` where the bins
+    /// are `index=count` pairs separated by commas.
+    pub fn encode(&self) -> String {
+        let bins = |bins: &BTreeMap<i32, u64>| {
+            bins.iter().map(|(index, n)| format!("{}={}", index, n)).collect::<Vec<_>>().join(",")
+        };
+        format!("{}/{}/{}/{}/{}",
+                self.relative_error,
+                self.moments.encode(),
+                self.zeros,
+                bins(&self.positive),
+                bins(&self.negative))
+    }
+
+    pub fn decode(text: &str) -> Option<DDSketch> {
+        let fields: Vec<&str> = text.split('/').collect();
+        if fields.len() != 9 {
+            return None;
+        }
+        let relative_error: f64 = fields[0].parse().ok()?;
+        if !(relative_error > 0.0 && relative_error < 1.0) {
+            return None;
+        }
+        let mut sketch = DDSketch::new(relative_error);
+        sketch.moments = Moments::decode(&fields[1..6])?;
+        sketch.zeros = fields[6].parse().ok()?;
+        sketch.positive = decode_bins(fields[7])?;
+        sketch.negative = decode_bins(fields[8])?;
+        let binned = sketch.positive
+            .values()
+            .chain(sketch.negative.values())
+            .try_fold(sketch.zeros, |binned, &n| binned.checked_add(n))?;
+        if binned != sketch.moments.count {
+            return None;
+        }
+        Some(sketch)
+    }
+}
+
+fn decode_bins(text: &str) -> Option<BTreeMap<i32, u64>> {
+    let mut bins = BTreeMap::new();
+    for bin in text.split(',').filter(|bin| !bin.is_empty()) {
+        let mut parts = bin.splitn(2, '=');
+        let (index, n) = (parts.next()?, parts.next()?);
+        bins.insert(index.parse().ok()?, n.parse().ok()?);
+    }
+    Some(bins)
+}
+
+// Encoded t-digests start with this, DDSketches with their relative error.
+const TDIGEST_TAG: &str = "td/";
+
+/// A merging t-digest: samples clustered in centroids that are small near the extremes and
+/// larger in the middle, so quantiles are within a rank error that shrinks towards the tails.
+/// Uses the k1 scale function, at most about `compression` centroids are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TDigest {
+    compression: f64,
+    // (mean, weight), by mean.
+    centroids: Vec<(f64, u64)>,
+    // Points not merged into the centroids yet.
+    buffer: Vec<(f64, u64)>,
+    moments: Moments,
+}
+
+impl Default for TDigest {
+    fn default() -> TDigest {
+        TDigest::new(DEFAULT_COMPRESSION)
+    }
+}
+
+impl TDigest {
+    /// `compression` must be at least 10.
+    pub fn new(compression: f64) -> TDigest {
+        assert!(compression >= 10.0);
+        TDigest {
+            compression: compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            moments: Moments::new(),
+        }
+    }
+
+    pub fn compression(&self) -> f64 {
+        self.compression
+    }
+
+    /// The rank error of the median, the largest one.
+    pub fn rank_error(&self) -> f64 {
+        PI / (2.0 * self.compression)
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.add_bin(value, 1);
+        self.moments.add(value);
+    }
+
+    // Counts `n` samples of `value` in the centroids only.
+    fn add_bin(&mut self, value: f64, n: u64) {
+        self.buffer.push((value, n));
+        self.compress_if_full();
+    }
+
+    pub fn merge(&mut self, other: &TDigest) {
+        self.buffer.extend_from_slice(&other.centroids);
+        self.buffer.extend_from_slice(&other.buffer);
+        self.moments.merge(&other.moments);
+        self.compress_if_full();
+    }
+
+    fn compress_if_full(&mut self) {
+        if self.buffer.len() as f64 >= BUFFER_FACTOR * self.compression {
+            self.compress();
+        }
+    }
+
+    pub fn compress(&mut self) {
+        if !self.buffer.is_empty() {
+            self.centroids = self.centroids().into_owned();
+            self.buffer.clear();
+        }
+    }
+
+    /// The centroids with the buffered points merged in, by mean.
+    pub fn centroids<'a>(&'a self) -> Cow<'a, [(f64, u64)]> {
+        if self.buffer.is_empty() {
+            return Cow::Borrowed(&self.centroids);
+        }
+        let mut points = self.centroids.clone();
+        points.extend_from_slice(&self.buffer);
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(cmp::Ordering::Equal));
+        let total = points.iter().map(|&(_, n)| n).sum::<u64>() as f64;
+
+        let mut merged: Vec<(f64, u64)> = Vec::with_capacity(self.compression as usize);
+        let mut points = points.into_iter();
+        let mut current = match points.next() {
+            Some(point) => point,
+            None => return Cow::Owned(merged),
+        };
+        // Weight left of the current centroid, and how far it can grow.
+        let mut before = 0.0;
+        let mut limit = total * self.q_limit(0.0);
+        for (value, n) in points {
+            if before + (current.1 + n) as f64 <= limit {
+                let weight = current.1 + n;
+                current.0 += (value - current.0) * n as f64 / weight as f64;
+                current.1 = weight;
+            } else {
+                before += current.1 as f64;
+                limit = total * self.q_limit(before / total);
+                merged.push(current);
+                current = (value, n);
+            }
+        }
+        merged.push(current);
+        Cow::Owned(merged)
+    }
+
+    // The largest quantile a centroid starting at `q` can reach: one unit further on the k1
+    // scale, k(q) = compression / 2π · asin(2q - 1).
+    fn q_limit(&self, q: f64) -> f64 {
+        let k = self.compression / (2.0 * PI) * (2.0 * q - 1.0).asin() + 1.0;
+        if k >= self.compression / 4.0 {
+            1.0
+        } else {
+            ((k * 2.0 * PI / self.compression).sin() + 1.0) / 2.0
+        }
+    }
+
+    // Interpolates between the centers of the centroids around `rank`, and the exact min and
+    // max at the ends.
+    fn nth(&self, rank: u64) -> Option<f64> {
+        let count = self.moments.count;
+        if rank >= count {
+            return None;
+        }
+        let target = rank as f64 + 0.5;
+        let mut left = (0.5, self.moments.min);
+        let mut seen = 0.0;
+        for &(mean, n) in self.centroids().iter() {
+            let center = (seen + n as f64 / 2.0, mean);
+            if center.0 >= target {
+                return Some(interpolate(left, center, target));
+            }
+            left = center;
+            seen += n as f64;
+        }
+        Some(interpolate(left, (count as f64 - 0.5, self.moments.max), target))
+    }
+
+    /// `td/compression/count/sum/sum_squares/min/max/centroids` where the centroids are
+    /// `mean:weight` pairs separated by commas.
+    pub fn encode(&self) -> String {
+        let centroids: Vec<String> = self.centroids()
+            .iter()
+            .map(|&(mean, n)| format!("{}:{}", mean, n))
+            .collect();
+        format!("{}{}/{}/{}", TDIGEST_TAG, self.compression, self.moments.encode(), centroids.join(","))
+    }
+
+    // Without the tag.
+    fn decode(text: &str) -> Option<TDigest> {
+        let fields: Vec<&str> = text.split('/').collect();
+        if fields.len() != 7 {
+            return None;
+        }
+        let compression: f64 = fields[0].parse().ok()?;
+        if !(compression >= 10.0 && compression.is_finite()) {
+            return None;
+        }
+        let mut digest = TDigest::new(compression);
+        digest.moments = Moments::decode(&fields[1..6])?;
+        for centroid in fields[6].split(',').filter(|centroid| !centroid.is_empty()) {
+            let mut parts = centroid.splitn(2, ':');
+            let (mean, n): (f64, u64) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+            if !mean.is_finite() || n == 0 || digest.centroids.last().is_some_and(|&(last, _)| last > mean) {
+                return None;
+            }
+            digest.centroids.push((mean, n));
+        }
+        let weight = digest.centroids.iter().try_fold(0u64, |weight, &(_, n)| weight.checked_add(n))?;
+        if weight != digest.moments.count {
+            return None;
+        }
+        Some(digest)
+    }
+}
+
+fn interpolate((x0, y0): (f64, f64), (x1, y1): (f64, f64), x: f64) -> f64 {
+    if x1 <= x0 {
+        return y1;
+    }
+    y0 + (y1 - y0) * ((x - x0) / (x1 - x0)).clamp(0.0, 1.0)
+}
+
+// 2^12 registers, about 1.6% standard error in 4KB.
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+// Sets up to this many members keep their members' hashes instead of registers, in about the
+// same memory. Hashing is a bijection, so those are counted exactly.
+const EXACT_LIMIT: usize = REGISTERS / 8;
+// Above this many used registers the dense encoding is the shorter one.
+const SPARSE_LIMIT: usize = REGISTERS / 4;
+
+/// A HyperLogLog estimating the number of distinct set members. Small sets are counted
+/// exactly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HyperLogLog {
+    repr: Repr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Repr {
+    Exact(BTreeSet<u64>),
+    Registers(Vec<u8>),
+}
+
+impl Default for HyperLogLog {
+    fn default() -> HyperLogLog {
+        HyperLogLog::new()
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog { repr: Repr::Exact(BTreeSet::new()) }
+    }
+
+    pub fn insert(&mut self, member: i64) {
+        self.insert_hash(mix(member as u64));
+    }
+
+    fn insert_hash(&mut self, hash: u64) {
+        let full = match self.repr {
+            Repr::Exact(ref mut hashes) => {
+                hashes.insert(hash);
+                hashes.len() > EXACT_LIMIT
+            }
+            Repr::Registers(ref mut registers) => {
+                let register = (hash >> (64 - PRECISION)) as usize;
+                let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+                if rank > registers[register] {
+                    registers[register] = rank;
+                }
+                false
+            }
+        };
+        if full {
+            self.spill();
+        }
+    }
+
+    // Switches to registers once the set is too large to count exactly.
+    fn spill(&mut self) {
+        let hashes = match self.repr {
+            Repr::Exact(ref mut hashes) => mem::take(hashes),
+            Repr::Registers(_) => return,
+        };
+        self.repr = Repr::Registers(vec![0; REGISTERS]);
+        for hash in hashes {
+            self.insert_hash(hash);
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        match other.repr {
+            Repr::Exact(ref hashes) => {
+                for &hash in hashes.iter() {
+                    self.insert_hash(hash);
+                }
+            }
+            Repr::Registers(ref theirs) => {
+                self.spill();
+                if let Repr::Registers(ref mut registers) = self.repr {
+                    for (mine, &theirs) in registers.iter_mut().zip(theirs.iter()) {
+                        *mine = cmp::max(*mine, theirs);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The estimated number of distinct members. Sets too large to be counted exactly but still
+    /// small next to the number of registers are estimated by linear counting.
+    pub fn estimate(&self) -> f64 {
+        let registers = match self.repr {
+            Repr::Exact(ref hashes) => return hashes.len() as f64,
+            Repr::Registers(ref registers) => registers,
+        };
+        let m = REGISTERS as f64;
+        let zeros = registers.iter().filter(|&&r| r == 0).count();
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m /
+                  registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum::<f64>();
+        if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+
+    /// A single token of text: `x` followed by the member hashes in hex while the set is exact,
+    /// then `s` followed by `register=rank` pairs of the used registers, or `d` followed by every
+    /// register in hex once that is shorter. Lists are separated by commas.
+    pub fn encode(&self) -> String {
+        let registers = match self.repr {
+            Repr::Exact(ref hashes) => {
+                let hashes: Vec<String> = hashes.iter().map(|hash| format!("{:x}", hash)).collect();
+                return format!("x{}", hashes.join(","));
+            }
+            Repr::Registers(ref registers) => registers,
+        };
+        let used: Vec<String> = registers.iter()
+            .enumerate()
+            .filter(|&(_, &rank)| rank > 0)
+            .map(|(register, rank)| format!("{}={}", register, rank))
+            .collect();
+        if used.len() <= SPARSE_LIMIT {
+            format!("s{}", used.join(","))
+        } else {
+            let hex: Vec<String> = registers.iter().map(|rank| format!("{:02x}", rank)).collect();
+            format!("d{}", hex.concat())
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<HyperLogLog> {
+        if let Some(hashes) = text.strip_prefix('x') {
+            let mut exact = BTreeSet::new();
+            for hash in hashes.split(',').filter(|hash| !hash.is_empty()) {
+                exact.insert(u64::from_str_radix(hash, 16).ok()?);
+            }
+            if exact.len() > EXACT_LIMIT {
+                return None;
+            }
+            return Some(HyperLogLog { repr: Repr::Exact(exact) });
+        }
+        let mut registers = vec![0; REGISTERS];
+        if let Some(used) = text.strip_prefix('s') {
+            for pair in used.split(',').filter(|pair| !pair.is_empty()) {
+                let mut parts = pair.splitn(2, '=');
+                let register: usize = parts.next()?.parse().ok()?;
+                *registers.get_mut(register)? = parts.next()?.parse().ok()?;
+            }
+        } else if let Some(hex) = text.strip_prefix('d') {
+            if hex.len() != REGISTERS * 2 || !hex.is_ascii() {
+                return None;
+            }
+            for (i, rank) in registers.iter_mut().enumerate() {
+                *rank = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+            }
+        } else {
+            return None;
+        }
+        Some(HyperLogLog { repr: Repr::Registers(registers) })
+    }
+}
+
+// splitmix64's finalizer, consecutive members end up far apart.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[test]
+fn test_digest_merge_and_encode() {
+    let (mut low, mut high, mut all) = (Digest::default(), Digest::default(), Digest::default());
+    for i in 1..1001 {
+        let value = i as f64;
+        all.add(value);
+        if i <= 500 { low.add(value) } else { high.add(value) }
+    }
+    low.merge(&high);
+    assert_eq!(low, all);
+    assert_eq!(all.count(), 1000);
+    assert_eq!(all.sum(), 500500.0);
+    assert_eq!(all.min(), Some(1.0));
+    assert_eq!(all.max(), Some(1000.0));
+    let median = all.quantile(0.5).unwrap();
+    assert!((median - 500.0).abs() <= 500.0 * 0.01, "median {}", median);
+    assert_eq!(Digest::decode(&all.encode()), Some(all.clone()));
+
+    let mut mixed = Digest::ddsketch(0.02);
+    for &value in [-5.0, 0.0, 3.0].iter() {
+        mixed.add(value);
+    }
+    assert_eq!(mixed.bins().map(|(_, n)| n).collect::<Vec<_>>(), vec![1, 1, 1]);
+    assert!((mixed.nth(0).unwrap() + 5.0).abs() < 0.1);
+    assert_eq!(Digest::decode(&mixed.encode()), Some(mixed.clone()));
+    all.merge(&mixed);
+    assert_eq!(all.count(), 1003);
+    assert_eq!(all.min(), Some(-5.0));
+    assert_eq!(Digest::decode("0.01/2/1/1/1/1/0/0=1/"), None);
+    // Bin counts that wrap around to the total, and moments that aren't numbers.
+    assert_eq!(Digest::decode("0.01/1/1/1/1/1/0/0=18446744073709551615,1=2/"), None);
+    assert_eq!(Digest::decode("0.01/1/NaN/1/1/1/0/0=1/"), None);
+    assert_eq!(Digest::decode("0.01/1/1/inf/1/1/0/0=1/"), None);
+    assert_eq!(Digest::decode("0.01/1/1/1/-inf/1/0/0=1/"), None);
+}
+
+#[test]
+fn test_tdigest_merge_and_encode() {
+    let (mut low, mut high) = (Digest::tdigest(50.0), Digest::tdigest(50.0));
+    for i in 1..10001 {
+        let value = i as f64;
+        if i % 2 == 0 { low.add(value) } else { high.add(value) }
+    }
+    low.merge(&high);
+    assert_eq!(low.count(), 10000);
+    assert_eq!(low.sum(), 50005000.0);
+    assert_eq!(low.quantile(0.0), Some(1.0));
+    assert_eq!(low.quantile(1.0), Some(10000.0));
+    assert!(low.bins().count() <= 50);
+    assert_eq!(low.bins().map(|(_, n)| n).sum::<u64>(), 10000);
+    let median = low.quantile(0.5).unwrap();
+    assert!((median - 5000.0).abs() <= 10000.0 * low.error_bound_rank(), "median {}", median);
+    let decoded = Digest::decode(&low.encode()).unwrap();
+    assert_eq!(decoded.encode(), low.encode());
+    assert_eq!(decoded.quantile(0.99), low.quantile(0.99));
+
+    // Few samples are kept as they are.
+    let mut small = Digest::tdigest(100.0);
+    for &value in [5.0, -1.0, 3.0].iter() {
+        small.add(value);
+    }
+    assert_eq!((small.nth(0), small.nth(1), small.nth(2)), (Some(-1.0), Some(3.0), Some(5.0)));
+
+    // Kinds mix, the digest merged into keeps its kind.
+    let mut sketch = Digest::default();
+    sketch.add(2.0);
+    sketch.merge(&small);
+    assert_eq!(sketch.count(), 4);
+    assert_eq!(sketch.min(), Some(-1.0));
+    assert_eq!(sketch.error_bound(), ErrorBound::Relative(DEFAULT_RELATIVE_ERROR));
+    small.merge(&sketch);
+    assert_eq!(small.count(), 7);
+    assert_eq!(Digest::decode("td/100/2/1/1/1/1/1:1"), None);
+    assert_eq!(Digest::decode("td/100/2/6/20/2/4/4:1,2:1"), None);
+    assert_eq!(Digest::decode("td/100/1/1/1/1/1/1:18446744073709551615,2:2"), None);
+    assert_eq!(Digest::decode("td/100/1/NaN/1/1/1/1:1"), None);
+    assert_eq!(Digest::decode("td/100/1/1/1/1/inf/1:1"), None);
+}
+
+#[cfg(test)]
+impl Digest {
+    fn error_bound_rank(&self) -> f64 {
+        match self.error_bound() {
+            ErrorBound::Rank(error) => error,
+            ErrorBound::Relative(_) => panic!("not a t-digest"),
+        }
+    }
+}
+
+#[test]
+fn test_digest_accuracy() {
+    // Uniform in (0, 1], from consecutive hashes.
+    let uniform = |i: u64| ((mix(i) >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+    let exponential: Vec<f64> = (0..50000).map(|i| -uniform(i).ln() * 100.0).collect();
+    let lognormal: Vec<f64> = (0..50000)
+        .map(|i| {
+            // Box-Muller.
+            let normal = (-2.0 * uniform(2 * i).ln()).sqrt() * (2.0 * PI * uniform(2 * i + 1)).cos();
+            (3.0 + normal).exp()
+        })
+        .collect();
+    let flat: Vec<f64> = (0..50000).map(|i| uniform(i) * 1000.0 - 500.0).collect();
+
+    for samples in [exponential, lognormal, flat].iter() {
+        let mut sorted = samples.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // Each half goes in its own digest, the quantiles are read after merging them.
+        let (mut sketch, mut digest) = (Digest::ddsketch(0.01), Digest::tdigest(100.0));
+        let (mut sketch_half, mut digest_half) = (sketch.clone(), digest.clone());
+        for (i, &value) in samples.iter().enumerate() {
+            if i % 2 == 0 { sketch.add(value) } else { sketch_half.add(value) }
+            if i < samples.len() / 2 { digest.add(value) } else { digest_half.add(value) }
+        }
+        sketch.merge(&sketch_half);
+        digest.merge(&digest_half);
+        digest.compress();
+
+        for &q in [0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 0.99, 0.999].iter() {
+            let rank = (q * (sorted.len() - 1) as f64).floor() as usize;
+            let exact = sorted[rank];
+            let estimate = sketch.quantile(q).unwrap();
+            assert!((estimate - exact).abs() <= exact.abs() * 0.01 + 1e-9,
+                    "ddsketch q{} {} vs {}", q, estimate, exact);
+            let estimate = digest.quantile(q).unwrap();
+            let estimated_rank = sorted.iter().filter(|&&value| value < estimate).count() as f64;
+            let error = (estimated_rank - rank as f64).abs() / sorted.len() as f64;
+            assert!(error <= digest.error_bound_rank() * (4.0 * q * (1.0 - q)).sqrt() + 1e-3,
+                    "tdigest q{} {} vs {}, rank error {}", q, estimate, exact, error);
+        }
+    }
+}
+
+#[test]
+fn test_hyperloglog() {
+    let (mut a, mut b) = (HyperLogLog::new(), HyperLogLog::new());
+    for member in 0..10 {
+        a.insert(member);
+        a.insert(member);
+    }
+    assert_eq!(a.estimate(), 10.0);
+    assert!(a.encode().starts_with('x'));
+    for member in 0..20000 {
+        b.insert(member * 7);
+    }
+    let estimate = b.estimate();
+    assert!((estimate - 20000.0).abs() < 20000.0 * 0.05, "estimate {}", estimate);
+    assert_eq!(HyperLogLog::decode(&a.encode()), Some(a.clone()));
+    assert!(b.encode().starts_with('d'));
+    assert_eq!(HyperLogLog::decode(&b.encode()), Some(b.clone()));
+
+    // Members of both count once.
+    let mut c = HyperLogLog::new();
+    for member in 5..15 {
+        c.insert(member);
+    }
+    a.merge(&c);
+    assert_eq!(a.estimate(), 15.0);
+    let mut d = b.clone();
+    d.merge(&a);
+    assert!(d.estimate() >= b.estimate());
+    a.merge(&b);
+    assert_eq!(a, d);
+
+    // Past the exact limit, small sets are still counted closely.
+    let mut e = HyperLogLog::new();
+    for member in 0..600 {
+        e.insert(member);
+    }
+    assert!(e.encode().starts_with('s'));
+    assert!((e.estimate() - 600.0).abs() < 12.0, "estimate {}", e.estimate());
+    assert_eq!(HyperLogLog::decode(&e.encode()), Some(e.clone()));
+    assert_eq!(HyperLogLog::decode("s5000=1"), None);
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use metrics::ParseErrorCounts;
+
+/// Outcome of the flushes to one backend, times are unix seconds.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BackendStatus {
+    pub last_success: Option<u64>,
+    pub last_error: Option<(u64, String)>,
+    /// Whether the most recent flush failed.
+    pub failing: bool,
+}
+
+/// What the flusher last reported about itself.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FlushStatus {
+    /// Last time the flusher's event loop came around.
+    pub heartbeat: Option<Instant>,
+    /// Unix time of the last flush.
+    pub last_flush: Option<u64>,
+    /// Buckets in the last flush.
+    pub num_stats: usize,
+    pub backends: BTreeMap<String, BackendStatus>,
+}
+
+/// Counters the server keeps about itself, shared by every frontend and read back once per
+/// flush to report the `statsd.*` self-metrics and by the HTTP status endpoints.
+#[derive(Debug)]
+pub struct ServerStats {
+    started: Instant,
+    packets_received: AtomicUsize,
+    metrics_received: AtomicUsize,
+    parse_errors: ParseErrorCounts,
+    frontends_bound: AtomicBool,
+    flush: Mutex<FlushStatus>,
+}
+
+impl Default for ServerStats {
+    fn default() -> ServerStats {
+        ServerStats {
+            started: Instant::now(),
+            packets_received: AtomicUsize::new(0),
+            metrics_received: AtomicUsize::new(0),
+            parse_errors: ParseErrorCounts::new(),
+            frontends_bound: AtomicBool::new(false),
+            flush: Mutex::new(FlushStatus::default()),
+        }
+    }
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        ServerStats::default()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Set once every configured listener is bound.
+    pub fn set_frontends_bound(&self, bound: bool) {
+        self.frontends_bound.store(bound, Ordering::Relaxed);
+    }
+
+    pub fn frontends_bound(&self) -> bool {
+        self.frontends_bound.load(Ordering::Relaxed)
+    }
+
+    /// Called by the flusher every time its event loop comes around.
+    pub fn heartbeat(&self) {
+        self.flush.lock().unwrap().heartbeat = Some(Instant::now());
+    }
+
+    pub fn record_flush(&self, timestamp: u64, num_stats: usize) {
+        let mut flush = self.flush.lock().unwrap();
+        flush.last_flush = Some(timestamp);
+        flush.num_stats = num_stats;
+    }
+
+    pub fn record_backend(&self, name: &str, timestamp: u64, result: Result<(), String>) {
+        let mut flush = self.flush.lock().unwrap();
+        let status = flush.backends.entry(name.to_string()).or_default();
+        status.failing = result.is_err();
+        match result {
+            Ok(()) => status.last_success = Some(timestamp),
+            Err(err) => status.last_error = Some((timestamp, err)),
+        }
+    }
+
+    pub fn flush_status(&self) -> FlushStatus {
+        self.flush.lock().unwrap().clone()
+    }
+
+    /// Counts one packet (or one line, for stream frontends) and the metrics accepted from it.
+    pub fn record_packet(&self, metrics: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.metrics_received.fetch_add(metrics, Ordering::Relaxed);
+    }
+
+    pub fn parse_errors(&self) -> &ParseErrorCounts {
+        &self.parse_errors
+    }
+
+    pub fn packets_received(&self) -> usize {
+        self.packets_received.load(Ordering::Relaxed)
+    }
+
+    pub fn metrics_received(&self) -> usize {
+        self.metrics_received.load(Ordering::Relaxed)
+    }
+
+    /// Bad lines of every kind seen so far.
+    pub fn bad_lines_seen(&self) -> usize {
+        self.parse_errors.total()
+    }
+}
+
+#[test]
+fn test_server_stats() {
+    use metrics::ParseErrorKind;
+
+    let stats = ServerStats::new();
+    stats.record_packet(3);
+    stats.record_packet(0);
+    stats.parse_errors().record(ParseErrorKind::BadType);
+    assert_eq!(stats.packets_received(), 2);
+    assert_eq!(stats.metrics_received(), 3);
+    assert_eq!(stats.bad_lines_seen(), 1);
+
+    stats.record_backend("graphite", 10, Err("connection refused".to_string()));
+    stats.record_backend("graphite", 20, Ok(()));
+    let status = &stats.flush_status().backends["graphite"];
+    assert_eq!(status.last_success, Some(20));
+    assert_eq!(status.last_error, Some((10, "connection refused".to_string())));
+    assert!(!status.failing);
+}
@@ -25,6 +25,12 @@ impl TokenSource {
     }
 }
 
+impl Default for TokenSource {
+    fn default() -> TokenSource {
+        TokenSource::new()
+    }
+}
+
 impl Token {
     pub fn is_triggered(&self) -> bool {
         self.is_triggered.load(Ordering::Relaxed)
@@ -0,0 +1,214 @@
+//! Tags travel in bucket names the way Graphite 1.1 writes tagged series,
+//! `name;key=value;key=value`, with the keys sorted so every series has one name.
+//! `Templates` turn dotted names into tagged ones.
+
+use std::str::FromStr;
+use pattern::Glob;
+
+/// The name without its tags, and the tags.
+pub fn split(name: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut parts = name.split(';');
+    let measurement = parts.next().unwrap_or("");
+    let tags = parts.filter_map(|tag| {
+            let mut pair = tag.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if !key.is_empty() => Some((key, value)),
+                _ => None,
+            }
+        })
+        .collect();
+    (measurement, tags)
+}
+
+/// The name of `measurement` tagged with `tags`, whatever order they come in. Later tags win
+/// over earlier ones with the same key.
+pub fn join(measurement: &str, tags: &[(&str, &str)]) -> String {
+    let mut sorted: Vec<(&str, &str)> = Vec::with_capacity(tags.len());
+    for &(key, value) in tags.iter() {
+        match sorted.binary_search_by(|&(other, _)| other.cmp(key)) {
+            Ok(i) => sorted[i].1 = value,
+            Err(i) => sorted.insert(i, (key, value)),
+        }
+    }
+    let mut name = measurement.to_string();
+    for (key, value) in sorted {
+        name.push(';');
+        name.push_str(key);
+        name.push('=');
+        name.push_str(value);
+    }
+    name
+}
+
+/// Whether `part` can be a tag key or value, a `;` or `=` in it would change what the name says.
+pub fn valid(part: &[u8]) -> bool {
+    !part.iter().any(|&b| b == b';' || b == b'=')
+}
+
+/// Sets tag `key` of `name` to `value`.
+pub fn set(name: &str, key: &str, value: &str) -> String {
+    let (measurement, mut tags) = split(name);
+    tags.push((key, value));
+    join(measurement, &tags)
+}
+
+/// A dotted name for a tagged series that never had one, e.g. a rollup or a series tagged by
+/// its client: the measurement followed by the tag values in key order.
+pub fn dotted(name: &str) -> String {
+    let (measurement, tags) = split(name);
+    let mut dotted = measurement.to_string();
+    for (_, value) in tags {
+        dotted.push('.');
+        dotted.push_str(value);
+    }
+    dotted
+}
+
+/// A Telegraf style graphite template, `[filter] template [key=value,...]`, e.g.
+/// `*.*.api.* env.host.measurement* source=statsd`.
+///
+/// The filter is matched segment by segment against the first segments of a dotted name, `*`
+/// and `?` within a segment. Every segment of the template names what the name's segment at the
+/// same position is: `measurement` is part of the measurement, `measurement*` is the rest of the
+/// name, an empty segment is skipped and anything else is a tag key. Segments past the end of
+/// the template are dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    filter: Vec<Glob>,
+    parts: Vec<String>,
+    defaults: Vec<(String, String)>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Template, String> {
+        let mut fields: Vec<&str> = s.split_whitespace().collect();
+        let mut defaults = Vec::new();
+        if fields.len() > 1 && fields[fields.len() - 1].contains('=') {
+            for tag in fields.pop().unwrap().split(',') {
+                let mut pair = tag.splitn(2, '=');
+                match (pair.next(), pair.next()) {
+                    (Some(key), Some(value)) if !key.is_empty() => {
+                        defaults.push((key.to_string(), value.to_string()))
+                    }
+                    _ => return Err(format!("bad default tag `{}` in template `{}`", tag, s)),
+                }
+            }
+        }
+        let (filter, template) = match fields.len() {
+            1 => (None, fields[0]),
+            2 => (Some(fields[0]), fields[1]),
+            _ => return Err(format!("template `{}` should be `[filter] template [key=value,...]`", s)),
+        };
+        let parts: Vec<String> = template.split('.').map(|part| part.to_string()).collect();
+        if let Some(i) = parts.iter().position(|part| part.ends_with('*')) {
+            if parts[i] != "measurement*" || i + 1 != parts.len() {
+                return Err(format!("only a trailing `measurement*` may end with `*` in `{}`", s));
+            }
+        }
+        Ok(Template {
+            filter: filter.map(|filter| filter.split('.').map(Glob::new).collect()).unwrap_or_default(),
+            parts: parts,
+            defaults: defaults,
+        })
+    }
+}
+
+impl Template {
+    pub fn matches(&self, name: &str) -> bool {
+        let segments: Vec<&str> = name.split('.').collect();
+        segments.len() >= self.filter.len() &&
+        self.filter.iter().zip(segments.iter()).all(|(glob, segment)| glob.matches(segment))
+    }
+
+    /// The tagged name for the dotted `name`, which may have tags already.
+    pub fn apply(&self, name: &str) -> String {
+        let (dotted, existing) = split(name);
+        let segments: Vec<&str> = dotted.split('.').collect();
+        let mut measurement = Vec::new();
+        let mut tags: Vec<(&str, String)> = Vec::new();
+        for (i, part) in self.parts.iter().enumerate() {
+            if i >= segments.len() {
+                break;
+            }
+            match part.as_str() {
+                "measurement" => measurement.push(segments[i]),
+                "measurement*" => measurement.extend_from_slice(&segments[i..]),
+                "" => {}
+                key => {
+                    // Segments naming the same tag are joined.
+                    match tags.iter_mut().find(|tag| tag.0 == key) {
+                        Some(tag) => {
+                            tag.1.push('.');
+                            tag.1.push_str(segments[i]);
+                        }
+                        None => tags.push((key, segments[i].to_string())),
+                    }
+                }
+            }
+        }
+        let measurement = if measurement.is_empty() { dotted.to_string() } else { measurement.join(".") };
+        let mut all: Vec<(&str, &str)> = self.defaults
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        all.extend(tags.iter().map(|&(key, ref value)| (key, value.as_str())));
+        all.extend(existing);
+        join(&measurement, &all)
+    }
+}
+
+/// Templates tried in order, the first whose filter matches a name applies. Put templates
+/// without a filter last.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Templates {
+    templates: Vec<Template>,
+}
+
+impl Templates {
+    pub fn new(templates: Vec<Template>) -> Templates {
+        Templates { templates: templates }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    /// The tagged name for `name`, `None` if no template matches.
+    pub fn apply(&self, name: &str) -> Option<String> {
+        let dotted = split(name).0;
+        self.templates.iter().find(|template| template.matches(dotted)).map(|template| template.apply(name))
+    }
+}
+
+#[test]
+fn test_tags() {
+    assert_eq!(split("api.requests"), ("api.requests", vec![]));
+    assert_eq!(split("api.requests;host=web01;env=prod"),
+               ("api.requests", vec![("host", "web01"), ("env", "prod")]));
+    assert_eq!(join("api.requests", &[("host", "web01"), ("env", "prod")]),
+               "api.requests;env=prod;host=web01");
+    assert_eq!(set("api.requests;host=web01", "env", "prod"), "api.requests;env=prod;host=web01");
+    assert_eq!(set("api.requests;host=web01", "host", "web02"), "api.requests;host=web02");
+    assert_eq!(dotted("api.requests;env=prod;host=web01"), "api.requests.prod.web01");
+}
+
+#[test]
+fn test_templates() {
+    let templates = Templates::new(vec!["*.*.api.* env.host.measurement* source=statsd".parse().unwrap(),
+                                        "db.* .measurement.table.op".parse().unwrap(),
+                                        "region.region.measurement".parse().unwrap()]);
+    assert_eq!(templates.apply("prod.web01.api.requests").unwrap(),
+               "api.requests;env=prod;host=web01;source=statsd");
+    assert_eq!(templates.apply("db.queries.users.select").unwrap(), "queries;op=select;table=users");
+    // Existing tags are kept, dropped segments are gone.
+    assert_eq!(templates.apply("db.queries.users.select.extra;shard=3").unwrap(),
+               "queries;op=select;shard=3;table=users");
+    assert_eq!(templates.apply("us.east.cpu").unwrap(), "cpu;region=us.east");
+    assert_eq!(Templates::new(vec!["web.* host.measurement*".parse().unwrap()]).apply("api.x"), None);
+
+    assert!("a b c d".parse::<Template>().is_err());
+    assert!("measurement*.host".parse::<Template>().is_err());
+    assert!("host.measurement =x".parse::<Template>().is_err());
+}